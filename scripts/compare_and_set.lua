@@ -0,0 +1,8 @@
+-- Replaces a value only if it has not changed since it was read.
+-- KEYS[1]: key, ARGV[1]: value read earlier, ARGV[2]: new value.
+-- Returns 1 when the value was replaced, 0 otherwise.
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+return 1
@@ -0,0 +1,9 @@
+-- Atomically claims a hostname for a VM unless another VM holds it.
+-- KEYS[1]: hostname index, ARGV[1]: lowercased hostname, ARGV[2]: VM name.
+-- Returns the VM holding the hostname afterwards.
+local holder = redis.call('HGET', KEYS[1], ARGV[1])
+if holder then
+    return holder
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+return ARGV[2]
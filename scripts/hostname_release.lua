@@ -0,0 +1,8 @@
+-- Releases a hostname only if the given VM still holds it.
+-- KEYS[1]: hostname index, ARGV[1]: lowercased hostname, ARGV[2]: VM name.
+-- Returns 1 when the hostname was released, 0 otherwise.
+if redis.call('HGET', KEYS[1], ARGV[1]) ~= ARGV[2] then
+    return 0
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+return 1
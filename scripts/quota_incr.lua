@@ -0,0 +1,9 @@
+-- Atomically claims one slot of a namespace quota.
+-- KEYS[1]: namespace VM counter, ARGV[1]: maximum number of VMs.
+-- Returns 1 and increments the counter when under quota, 0 otherwise.
+local count = tonumber(redis.call('GET', KEYS[1]) or '0')
+if count >= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('INCR', KEYS[1])
+return 1
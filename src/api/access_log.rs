@@ -0,0 +1,339 @@
+//! Access logging in Common Log Format, e.g.
+//! `10.0.0.5 - - [10/Oct/2024:13:55:36 +0000] "GET /list HTTP/1.1" 200 2326`.
+//! A request is logged once its response body has been sent, with the
+//! bytes actually sent.
+
+#[cfg(feature = "warp")]
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use chrono::{DateTime, Utc};
+use http_body::SizeHint;
+use hyper::body::{Buf, HttpBody};
+#[cfg(feature = "warp")]
+use hyper::http::header::CONTENT_LENGTH;
+use hyper::http::{HeaderMap, Method, StatusCode};
+#[cfg(feature = "warp")]
+use warp::{Filter, Reply};
+
+#[cfg(feature = "warp")]
+use crate::reply::Response;
+
+/// What the log line needs to know about the request.
+struct RequestInfo {
+    client: String,
+    method: Method,
+    target: String,
+    received: DateTime<Utc>,
+}
+
+impl RequestInfo {
+    fn new(
+        headers: &HeaderMap,
+        remote: Option<SocketAddr>,
+        method: Method,
+        path: &str,
+        query: Option<&str>,
+    ) -> Self {
+        let target = match query {
+            Some(query) if !query.is_empty() => format!("{}?{}", path, query),
+            _ => path.to_string(),
+        };
+        RequestInfo {
+            client: client_address(headers, remote),
+            method,
+            target,
+            received: Utc::now(),
+        }
+    }
+
+    fn log(&self, status: StatusCode, bytes: u64) {
+        tracing::info!("{}", format_line(self, status.as_u16(), bytes));
+    }
+}
+
+/// A response body that logs its request after the last chunk has been
+/// sent, or when it is dropped unfinished, e.g. because the client went
+/// away, with the bytes sent until then.
+struct LoggedBody<B> {
+    inner: B,
+    /// Taken once the request is logged.
+    pending: Option<(RequestInfo, StatusCode)>,
+    sent: u64,
+}
+
+impl<B> LoggedBody<B> {
+    fn new(inner: B, info: RequestInfo, status: StatusCode) -> Self {
+        LoggedBody {
+            inner,
+            pending: Some((info, status)),
+            sent: 0,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some((info, status)) = self.pending.take() {
+            info.log(status, self.sent);
+        }
+    }
+}
+
+impl<B: HttpBody + Unpin> HttpBody for LoggedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(data))) => {
+                self.sent += data.remaining() as u64;
+                if self.inner.is_end_stream() {
+                    self.finish();
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        polled
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// The client address: the first `X-Forwarded-For` entry when a proxy set
+/// one, else the peer address, else `-` (e.g. on UNIX sockets).
+pub(super) fn client_address(headers: &HeaderMap, remote: Option<SocketAddr>) -> String {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|client| client.trim().to_string())
+        .filter(|client| !client.is_empty())
+        .or_else(|| remote.map(|addr| addr.ip().to_string()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// The peer address, known when the server recorded it in `ConnectInfo`.
+#[cfg(feature = "axum")]
+pub(super) fn remote_address<B>(request: &axum::http::Request<B>) -> Option<SocketAddr> {
+    request
+        .extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|info| info.0)
+}
+
+#[cfg(feature = "warp")]
+fn request_info() -> impl Filter<Extract = (RequestInfo,), Error = Infallible> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    warp::header::headers_cloned()
+        .and(warp::addr::remote())
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(query)
+        .map(
+            |headers: HeaderMap,
+             remote: Option<SocketAddr>,
+             method: Method,
+             path: warp::path::FullPath,
+             query: String| {
+                RequestInfo::new(&headers, remote, method, path.as_str(), Some(&query))
+            },
+        )
+}
+
+fn format_line(info: &RequestInfo, status: u16, bytes: u64) -> String {
+    format!(
+        "{} - - [{}] \"{} {} HTTP/1.1\" {} {}",
+        info.client,
+        info.received.format("%d/%b/%Y:%H:%M:%S %z"),
+        info.method,
+        info.target,
+        status,
+        bytes,
+    )
+}
+
+/// Wraps `api` so that every request is logged once its reply is sent.
+#[cfg(feature = "warp")]
+pub fn log_request<F, R>(api: F) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    request_info()
+        .and(api)
+        .map(|info: RequestInfo, reply: R| logged(info, reply.into_response()))
+}
+
+/// `response` with its body wrapped in a `LoggedBody`. warp replies carry
+/// a `hyper::Body`, which can only wrap a stream; streams have no length,
+/// so a known one is kept as `Content-Length` lest the reply be sent
+/// chunked. Empty bodies, which must not always carry that header, have
+/// nothing to send and are logged at once.
+#[cfg(feature = "warp")]
+fn logged(info: RequestInfo, response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    match body.size_hint().exact() {
+        Some(0) => {
+            info.log(parts.status, 0);
+            return Response::from_parts(parts, body);
+        }
+        Some(len) => {
+            parts.headers.entry(CONTENT_LENGTH).or_insert(len.into());
+        }
+        None => {}
+    }
+    let mut body = LoggedBody::new(body, info, parts.status);
+    let stream = futures_util::stream::poll_fn(move |cx| Pin::new(&mut body).poll_data(cx));
+    Response::from_parts(parts, hyper::Body::wrap_stream(stream))
+}
+
+/// Middleware logging every request once its response is sent.
+#[cfg(feature = "axum")]
+pub async fn log_request(
+    request: axum::http::Request<hyper::Body>,
+    next: axum::middleware::Next<hyper::Body>,
+) -> axum::response::Response {
+    let info = RequestInfo::new(
+        request.headers(),
+        remote_address(&request),
+        request.method().clone(),
+        request.uri().path(),
+        request.uri().query(),
+    );
+    let response = next.run(request).await;
+    let status = response.status();
+    response.map(|body| axum::body::boxed(LoggedBody::new(body, info, status)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::body::Bytes;
+    use hyper::Body;
+    use regex::Regex;
+
+    use super::*;
+    use crate::api::routes;
+    use crate::test_util::{request, test_settings};
+
+    /// Collects everything the subscriber writes.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        /// Captures the events of the current thread until the guard is
+        /// dropped.
+        fn install(&self) -> tracing::subscriber::DefaultGuard {
+            let writer = self.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .without_time()
+                .with_target(false)
+                .finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn output(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_line() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        // Unknown routes are rejected without touching Redis.
+        let api = routes(crate::state::AppState::new(test_settings()).unwrap());
+        let response = request()
+            .method("GET")
+            .path("/no/such/route?verbose=1")
+            .header("x-forwarded-for", "10.0.0.5, 192.168.1.1")
+            .reply(&api)
+            .await;
+
+        let output = capture.output();
+        let line = Regex::new(
+            r#"10\.0\.0\.5 - - \[\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} \+0000\] "GET /no/such/route\?verbose=1 HTTP/1\.1" (\d{3}) (\d+)"#,
+        )
+        .unwrap();
+        let captures = line.captures(&output).expect(&output);
+        assert_eq!(captures[1].parse::<u16>().unwrap(), response.status());
+        assert_eq!(captures[2].parse::<usize>().unwrap(), response.body().len());
+    }
+
+    fn request_info() -> RequestInfo {
+        RequestInfo::new(&HeaderMap::new(), None, Method::GET, "/watch", None)
+    }
+
+    #[tokio::test]
+    async fn test_logged_once_the_body_is_sent() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let (mut sender, body) = Body::channel();
+        let mut body = LoggedBody::new(body, request_info(), StatusCode::OK);
+        sender.send_data(Bytes::from("first,")).await.unwrap();
+        body.data().await.unwrap().unwrap();
+        assert_eq!(capture.output(), "");
+
+        sender.send_data(Bytes::from("last")).await.unwrap();
+        drop(sender);
+        body.data().await.unwrap().unwrap();
+        assert!(body.data().await.is_none());
+        assert!(capture.output().contains("\"GET /watch HTTP/1.1\" 200 10"));
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_bodies_are_logged_when_dropped() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let (mut sender, body) = Body::channel();
+        let mut body = LoggedBody::new(body, request_info(), StatusCode::OK);
+        sender.send_data(Bytes::from("partial")).await.unwrap();
+        body.data().await.unwrap().unwrap();
+        drop(body);
+        assert!(capture.output().contains("\"GET /watch HTTP/1.1\" 200 7"));
+        assert_eq!(capture.output().lines().count(), 1);
+    }
+}
@@ -0,0 +1,1027 @@
+//! Registry-wide maintenance operations.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use hyper::http::header::CONTENT_TYPE;
+use serde::Deserialize;
+use serde_json::{json, Map};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::audit;
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::backup;
+use crate::error::RegistryError;
+use crate::events;
+use crate::models::{VMStatus, VM};
+use crate::reply::{self, Response};
+use crate::schema;
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Value `POST /admin/reset-all` requires in its `X-Confirm` header.
+const RESET_CONFIRMATION: &str = "I understand this will delete everything";
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct CompactQuery {
+    older_than_days: Option<u32>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let backup = warp::post()
+        .and(warp::path!("admin" / "backup"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .then(backup_registry)
+        .and_then(or_reject);
+
+    let restore = warp::post()
+        .and(warp::path!("admin" / "restore"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(restore_registry)
+        .and_then(or_reject);
+
+    let compact_audit_logs = warp::post()
+        .and(warp::path!("admin" / "compact-audit-logs"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::query::<CompactQuery>())
+        .and(with_state(state.clone()))
+        .then(compact_audit_logs)
+        .and_then(or_reject);
+
+    let redis_info = warp::get()
+        .and(warp::path!("admin" / "redis-info"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .then(redis_info)
+        .and_then(or_reject);
+
+    let pool_stats = warp::get()
+        .and(warp::path!("admin" / "pool-stats"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .map(pool_stats);
+
+    let reindex = warp::post()
+        .and(warp::path!("admin" / "reindex"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .then(reindex)
+        .and_then(or_reject);
+
+    let event_stream_stats = warp::get()
+        .and(warp::path!("admin" / "event-stream-stats"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .then(event_stream_stats)
+        .and_then(or_reject);
+
+    let simulate_event = warp::post()
+        .and(warp::path!("admin" / "simulate-event"))
+        .and(simulation_enabled(&state))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(simulate_event)
+        .and_then(or_reject);
+
+    let schema = warp::get()
+        .and(warp::path!("admin" / "schema"))
+        .and(require_role(state.clone(), Role::Viewer))
+        .map(vm_schema);
+
+    let export_prometheus = warp::post()
+        .and(warp::path!("admin" / "export-prometheus"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .then(export_prometheus)
+        .and_then(or_reject);
+
+    let reset_all = warp::post()
+        .and(warp::path!("admin" / "reset-all"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::header::optional::<String>("x-confirm"))
+        .and(with_state(state))
+        .then(reset_all)
+        .and_then(or_reject);
+
+    backup
+        .or(restore)
+        .or(reindex)
+        .or(compact_audit_logs)
+        .or(redis_info)
+        .or(pool_stats)
+        .or(event_stream_stats)
+        .or(simulate_event)
+        .or(reset_all)
+        .or(schema)
+        .or(export_prometheus)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::http::HeaderMap;
+    use axum::routing::{get, post};
+
+    use super::extract::{self, Admin, Json, Query, RequireRole, Viewer};
+
+    let router = axum::Router::new()
+        .route(
+            "/admin/backup",
+            post(|_: RequireRole<Admin>, state| backup_registry(state)),
+        )
+        .route(
+            "/admin/restore",
+            post(|_: RequireRole<Admin>, state, Json(request)| restore_registry(request, state)),
+        )
+        .route(
+            "/admin/reindex",
+            post(|_: RequireRole<Admin>, state| reindex(state)),
+        )
+        .route(
+            "/admin/compact-audit-logs",
+            post(|_: RequireRole<Admin>, Query(query), state| compact_audit_logs(query, state)),
+        )
+        .route(
+            "/admin/redis-info",
+            get(|_: RequireRole<Admin>, state| redis_info(state)),
+        )
+        .route(
+            "/admin/pool-stats",
+            get(|_: RequireRole<Admin>, state| async move { pool_stats(state) }),
+        )
+        .route(
+            "/admin/event-stream-stats",
+            get(|_: RequireRole<Admin>, state| event_stream_stats(state)),
+        )
+        .route(
+            "/admin/reset-all",
+            post(|_: RequireRole<Admin>, headers: HeaderMap, state| {
+                reset_all(extract::header(&headers, "x-confirm"), state)
+            }),
+        )
+        .route(
+            "/admin/schema",
+            get(|_: RequireRole<Viewer>| async { vm_schema() }),
+        )
+        .route(
+            "/admin/export-prometheus",
+            post(|_: RequireRole<Admin>, state| export_prometheus(state)),
+        );
+    // Simulation endpoints do not exist unless they are enabled.
+    let router = if state.settings.enable_simulation_endpoints {
+        router.route(
+            "/admin/simulate-event",
+            post(|_: RequireRole<Admin>, state, Json(notification)| {
+                simulate_event(notification, state)
+            }),
+        )
+    } else {
+        router
+    };
+    router.with_state(state)
+}
+
+/// The JSON Schema of VM records, for clients that build registration forms.
+fn vm_schema() -> Response {
+    reply::with_header(
+        reply::json(&*schema::VM_SCHEMA_DOCUMENT),
+        CONTENT_TYPE,
+        "application/schema+json",
+    )
+}
+
+/// VM counts, statuses and uptimes in the Prometheus text exposition
+/// format, for scraping domain metrics alongside the registry's own.
+async fn export_prometheus(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let now = Utc::now();
+    let mut con = state.connection().await?;
+    let mut vms = Vec::new();
+    for vm in storage::list_vms(&mut con).await? {
+        let entries = storage::audit_entries(&mut con, &vm.name).await?;
+        let uptime = audit::uptime(&vm.name, &entries, now);
+        vms.push((vm, uptime));
+    }
+    Ok(reply::with_header(
+        prometheus_text(&vms),
+        CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// Renders one `ghaf_vms` count per status, and per VM its uptime and a
+/// `ghaf_vm_status` series for every status, set to 1 for the current one.
+fn prometheus_text(vms: &[(VM, audit::Uptime)]) -> String {
+    let mut text = String::new();
+    text.push_str("# HELP ghaf_vms Number of registered VMs in each status.\n");
+    text.push_str("# TYPE ghaf_vms gauge\n");
+    for status in VMStatus::ALL {
+        let count = vms.iter().filter(|(vm, _)| vm.status == status).count();
+        let _ = writeln!(text, "ghaf_vms{{status=\"{}\"}} {}", status.as_str(), count);
+    }
+    text.push_str("# HELP ghaf_vm_uptime_seconds Total time the VM has been running.\n");
+    text.push_str("# TYPE ghaf_vm_uptime_seconds counter\n");
+    for (vm, uptime) in vms {
+        let _ = writeln!(
+            text,
+            "ghaf_vm_uptime_seconds{{name=\"{}\",namespace=\"{}\"}} {}",
+            label_value(&vm.name),
+            label_value(&vm.namespace),
+            uptime.total_uptime_secs
+        );
+    }
+    text.push_str("# HELP ghaf_vm_status Whether the VM is in the given status.\n");
+    text.push_str("# TYPE ghaf_vm_status gauge\n");
+    for (vm, _) in vms {
+        for status in VMStatus::ALL {
+            let _ = writeln!(
+                text,
+                "ghaf_vm_status{{name=\"{}\",status=\"{}\"}} {}",
+                label_value(&vm.name),
+                status.as_str(),
+                u8::from(vm.status == status)
+            );
+        }
+    }
+    text
+}
+
+/// Escapes a Prometheus label value.
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Deletes every registry key, for wiping test environments between runs.
+/// The `X-Confirm` header must carry `RESET_CONFIRMATION`.
+async fn reset_all(
+    confirm: Option<String>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    if confirm.as_deref() != Some(RESET_CONFIRMATION) {
+        return Err(RegistryError::BadRequest(format!(
+            "X-Confirm header must be \"{}\"",
+            RESET_CONFIRMATION
+        )));
+    }
+    let mut con = state.connection().await?;
+    let deleted_keys = storage::delete_all(&mut con).await?;
+    Ok(reply::json(&json!({ "deleted_keys": deleted_keys })))
+}
+
+/// Subscribers of the registry's event channels, plus any other `ghaf:*`
+/// channel someone listens on, and the messages this registry published.
+async fn event_stream_stats(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let mut names: Vec<String> = redis::cmd("PUBSUB")
+        .arg("CHANNELS")
+        .arg("ghaf:*")
+        .query_async(&mut con)
+        .await
+        .map_err(RegistryError::from)?;
+    for channel in [events::VM_EVENTS_CHANNEL, events::VM_CHANGES_CHANNEL] {
+        if !names.iter().any(|name| name == channel) {
+            names.push(channel.to_string());
+        }
+    }
+    names.sort();
+    let subscribers: Vec<(String, u64)> = redis::cmd("PUBSUB")
+        .arg("NUMSUB")
+        .arg(names)
+        .query_async(&mut con)
+        .await
+        .map_err(RegistryError::from)?;
+    let channels: Vec<_> = subscribers
+        .into_iter()
+        .map(|(name, subscribers)| json!({ "name": name, "subscribers": subscribers }))
+        .collect();
+    Ok(reply::json(&json!({
+        "channels": channels,
+        "total_published_since_start": state.published_count(),
+    })))
+}
+
+/// Hides simulation endpoints, as if they did not exist, unless
+/// `Settings.enable_simulation_endpoints` is set.
+#[cfg(feature = "warp")]
+fn simulation_enabled(state: &AppState) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let enabled = state.settings.enable_simulation_endpoints;
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Publishes a made-up event on the VM event channel without touching any
+/// VM record, so consumers can test their handlers.
+async fn simulate_event(
+    notification: events::Notification,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let receivers = events::publish_raw(&mut con, &notification).await?;
+    Ok(reply::json(&json!({
+        "published": notification,
+        "receivers": receivers,
+    })))
+}
+
+/// Dumps every registry key to a new archive in `Settings.backup_dir`.
+async fn backup_registry(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let archive = backup::dump(&mut con).await?;
+    let file = backup::write_archive(&state.settings.backup_dir, &archive).await?;
+    Ok(reply::json(&json!({
+        "file": file,
+        "timestamp": archive.timestamp,
+        "keys": archive.keys.len(),
+    })))
+}
+
+/// Writes the keys of an archive in `Settings.backup_dir` back to Redis.
+async fn restore_registry(
+    request: RestoreRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let archive = backup::read_archive(&state.settings.backup_dir, &request.file).await?;
+    let mut con = state.connection().await?;
+    backup::restore(&mut con, &archive).await?;
+    Ok(reply::json(&json!({
+        "file": request.file,
+        "timestamp": archive.timestamp,
+        "keys": archive.keys.len(),
+    })))
+}
+
+/// Rebuilds every secondary index from the VM records. Safe to repeat.
+async fn reindex(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms_processed = storage::reindex(&mut con).await?;
+    Ok(reply::json(&json!({
+        "vms_processed": vms_processed,
+        "indexes_rebuilt": storage::index_names(),
+    })))
+}
+
+/// Drops audit log entries older than `older_than_days`, by default
+/// `Settings.audit_retention_days`.
+async fn compact_audit_logs(
+    query: CompactQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let days = query
+        .older_than_days
+        .unwrap_or(state.settings.audit_retention_days);
+    let mut con = state.connection().await?;
+    let report = audit::compact(&mut con, Duration::days(i64::from(days))).await?;
+    Ok(reply::json(&report))
+}
+
+/// `INFO` fields reported by `GET /admin/redis-info`. Only these are
+/// passed on, so credentials and other configuration never leave Redis.
+const REDIS_INFO_TEXT_FIELDS: &[&str] = &["used_memory_human"];
+const REDIS_INFO_COUNT_FIELDS: &[&str] = &[
+    "connected_clients",
+    "keyspace_hits",
+    "keyspace_misses",
+    "uptime_in_seconds",
+];
+
+/// Splits the `field:value` lines of an `INFO` reply, skipping section
+/// headers.
+fn parse_redis_info(info: &str) -> HashMap<&str, &str> {
+    info.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.trim_end().split_once(':'))
+        .collect()
+}
+
+/// Selected Redis server metrics; fields the server did not report are
+/// null.
+async fn redis_info(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let info: String = redis::cmd("INFO")
+        .query_async(&mut con)
+        .await
+        .map_err(RegistryError::from)?;
+    let fields = parse_redis_info(&info);
+    let mut report = Map::new();
+    for &name in REDIS_INFO_TEXT_FIELDS {
+        report.insert(name.to_string(), json!(fields.get(name)));
+    }
+    for &name in REDIS_INFO_COUNT_FIELDS {
+        let count = fields.get(name).and_then(|value| value.parse::<u64>().ok());
+        report.insert(name.to_string(), json!(count));
+    }
+    Ok(reply::json(&report))
+}
+
+/// Redis connection usage in the terms of a connection pool. Handlers
+/// share one multiplexed connection instead of drawing from a pool, so the
+/// pool holds at most that connection, and it stays available however many
+/// handlers use it. `GET /metrics` exports the same figures.
+fn pool_stats(StateExtension(state): StateExtension<Arc<AppState>>) -> Response {
+    let stats = state.connection_stats();
+    reply::json(&json!({
+        "size": stats.size,
+        "available": stats.available,
+        "waiting": stats.waiting,
+        "max_size": stats.max_size,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::models::VMStatus;
+    use crate::settings::Settings;
+    use crate::state::RedisConnection;
+    use crate::storage;
+    use crate::test_util::{
+        json_body, redis_state, redis_state_with, register, request, sample_vm, test_settings,
+    };
+    use chrono::{Duration, Utc};
+    use redis::AsyncCommands;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let backup_dir = std::env::temp_dir().join(format!("ghaf-backup-{}", uuid::Uuid::new_v4()));
+        let settings = Settings {
+            backup_dir: backup_dir.clone(),
+            ..crate::test_util::test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for name in ["backup-vm-1", "backup-vm-2"] {
+            let mut vm = sample_vm(name);
+            vm.mime_types = vec!["text/plain".to_string()];
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let list = || request().method("GET").path("/list").reply(&api);
+        let before = json_body(&list().await);
+
+        let response = request()
+            .method("POST")
+            .path("/admin/backup")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let file = json_body(&response)["file"].as_str().unwrap().to_string();
+        assert!(backup_dir.join(&file).is_file());
+
+        let mut con = ctx.state.connection().await.unwrap();
+        redis::cmd("FLUSHDB")
+            .query_async::<_, ()>(&mut con)
+            .await
+            .unwrap();
+        assert_eq!(json_body(&list().await), json!([]));
+
+        let restore = |file: &str| {
+            request()
+                .method("POST")
+                .path("/admin/restore")
+                .json(&json!({ "file": file }))
+                .reply(&api)
+        };
+        assert_eq!(restore(&file).await.status(), 200);
+        assert_eq!(json_body(&list().await), before);
+        let response = request()
+            .method("GET")
+            .path("/vms/by-mime?type=text/plain")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        assert_eq!(restore("../etc/passwd").await.status(), 400);
+        assert_eq!(restore("missing.json").await.status(), 400);
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact_audit_logs() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut con = ctx.state.connection().await.unwrap();
+        let days_ago = |days: i64| (Utc::now() - Duration::days(days)).timestamp();
+        for (name, entries) in [
+            (
+                "gui-vm",
+                vec![("registered", 90), ("started", 31), ("patched", 2)],
+            ),
+            ("net-vm", vec![("registered", 40)]),
+            ("audio-vm", vec![("registered", 1)]),
+        ] {
+            for (entry, age) in entries {
+                con.zadd::<_, _, _, ()>(storage::audit_key(name), entry, days_ago(age))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let response = request()
+            .method("POST")
+            .path("/admin/compact-audit-logs?older_than_days=30")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({ "vms_compacted": 2, "entries_removed": 3 })
+        );
+        let gui: Vec<String> = con
+            .zrange(storage::audit_key("gui-vm"), 0, -1)
+            .await
+            .unwrap();
+        assert_eq!(gui, vec!["patched"]);
+        let audio: Vec<String> = con
+            .zrange(storage::audit_key("audio-vm"), 0, -1)
+            .await
+            .unwrap();
+        assert_eq!(audio, vec!["registered"]);
+    }
+
+    #[test]
+    fn test_parse_redis_info() {
+        let info = "# Server\r\nredis_version:7.2.4\r\nuptime_in_seconds:3600\r\n\r\n\
+                    # Replication\r\nrole:slave\r\nmaster_host:10.0.0.1\r\n";
+        let fields = super::parse_redis_info(info);
+        assert_eq!(fields.get("uptime_in_seconds"), Some(&"3600"));
+        assert_eq!(fields.get("master_host"), Some(&"10.0.0.1"));
+        assert_eq!(fields.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_redis_info() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let response = request()
+            .method("GET")
+            .path("/admin/redis-info")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert!(body["used_memory_human"].is_string());
+        for field in [
+            "connected_clients",
+            "keyspace_hits",
+            "keyspace_misses",
+            "uptime_in_seconds",
+        ] {
+            assert!(body[field].is_u64(), "{} missing", field);
+        }
+        assert_eq!(body.as_object().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_pool_stats() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let stats = || {
+            request()
+                .method("GET")
+                .path("/admin/pool-stats")
+                .reply(&api)
+        };
+        let expected = json!({ "size": 1, "available": 1, "waiting": 0, "max_size": 1 });
+        assert_eq!(json_body(&stats().await), expected);
+
+        // Handles share the connection, so holding one takes nothing from
+        // the pool.
+        let held = ctx.state.connection().await.unwrap();
+        assert_eq!(json_body(&stats().await), expected);
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn test_pool_stats_before_connecting() {
+        let api = routes(crate::state::AppState::new(test_settings()).unwrap());
+        let response = request()
+            .method("GET")
+            .path("/admin/pool-stats")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            json_body(&response),
+            json!({ "size": 0, "available": 0, "waiting": 0, "max_size": 1 })
+        );
+    }
+
+    async fn members(con: &mut RedisConnection, key: &str) -> Vec<String> {
+        let mut members: Vec<String> = con.smembers(key).await.unwrap();
+        members.sort();
+        members
+    }
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut first = sample_vm("index-vm-1");
+        first.mime_types = vec!["text/plain".to_string()];
+        first.tags = ["desktop".to_string()].into();
+        let mut second = sample_vm("index-vm-2");
+        second.addresses.ip = "10.0.0.9".to_string();
+        second.mime_types = vec!["text/plain".to_string()];
+        second.labels = [("env".to_string(), "prod".to_string())].into();
+        for vm in [&first, &second] {
+            assert_eq!(register(&api, vm).await.status(), 200);
+        }
+        request()
+            .method("POST")
+            .path("/run/index-vm-2")
+            .reply(&api)
+            .await;
+
+        let mut con = ctx.state.connection().await.unwrap();
+        let ip_key = storage::ip_key(&first.addresses.ip);
+        let running_key = storage::state_key(crate::models::VMStatus::Running);
+        let namespace_key = storage::namespace_count_key("default");
+        con.srem::<_, _, ()>(&ip_key, "index-vm-1").await.unwrap();
+        con.sadd::<_, _, ()>(storage::tag_key("desktop"), "ghost-vm")
+            .await
+            .unwrap();
+        con.sadd::<_, _, ()>(storage::tag_key("stale"), "index-vm-2")
+            .await
+            .unwrap();
+        con.sadd::<_, _, ()>(&running_key, "index-vm-1")
+            .await
+            .unwrap();
+        con.del::<_, ()>(storage::label_key("env", "prod"))
+            .await
+            .unwrap();
+        con.set::<_, _, ()>(&namespace_key, 99).await.unwrap();
+        let route: String = con
+            .hget(storage::MIME_INDEX_KEY, "text/plain")
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let response = request()
+                .method("POST")
+                .path("/admin/reindex")
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+            let body = json_body(&response);
+            assert_eq!(body["vms_processed"], 2);
+            assert!(body["indexes_rebuilt"]
+                .as_array()
+                .unwrap()
+                .contains(&json!("label")));
+
+            assert_eq!(members(&mut con, &ip_key).await, ["index-vm-1"]);
+            assert_eq!(
+                members(&mut con, &storage::tag_key("desktop")).await,
+                ["index-vm-1"]
+            );
+            assert!(members(&mut con, &storage::tag_key("stale"))
+                .await
+                .is_empty());
+            assert_eq!(members(&mut con, &running_key).await, ["index-vm-2"]);
+            assert_eq!(
+                members(&mut con, &storage::label_key("env", "prod")).await,
+                ["index-vm-2"]
+            );
+            assert_eq!(
+                members(&mut con, &storage::mime_key("text/plain")).await,
+                ["index-vm-1", "index-vm-2"]
+            );
+            let count: u32 = con.get(&namespace_key).await.unwrap();
+            assert_eq!(count, 2);
+            let rebuilt: String = con
+                .hget(storage::MIME_INDEX_KEY, "text/plain")
+                .await
+                .unwrap();
+            assert_eq!(rebuilt, route);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulate_event() {
+        let settings = Settings {
+            enable_simulation_endpoints: true,
+            api_tokens: [("admin-token".to_string(), crate::auth::Role::Admin)].into(),
+            ..crate::test_util::test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut pubsub = ctx.state.pubsub().await.unwrap();
+        pubsub
+            .subscribe(crate::events::VM_EVENTS_CHANNEL)
+            .await
+            .unwrap();
+
+        let simulate = |token: &str| {
+            request()
+                .method("POST")
+                .path("/admin/simulate-event")
+                .header("authorization", format!("Bearer {}", token))
+                .json(&json!({ "event": "crashed", "name": "phantom-vm", "status": "Failed" }))
+                .reply(&api)
+        };
+        assert_eq!(simulate("wrong-token").await.status(), 401);
+        let response = simulate("admin-token").await;
+        assert_eq!(response.status(), 200);
+        assert!(json_body(&response)["receivers"].as_u64().unwrap() >= 1);
+
+        let mut messages = pubsub.on_message();
+        let payload: String = futures_util::StreamExt::next(&mut messages)
+            .await
+            .unwrap()
+            .get_payload()
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(event["event"], "crashed");
+        assert_eq!(event["name"], "phantom-vm");
+        assert_eq!(event["namespace"], "default");
+        assert_eq!(event["status"], "Failed");
+        drop(messages);
+
+        let mut con = ctx.state.connection().await.unwrap();
+        assert!(storage::get_vm(&mut con, "phantom-vm")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_simulate_event_disabled() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let response = request()
+            .method("POST")
+            .path("/admin/simulate-event")
+            .json(&json!({ "event": "crashed", "name": "phantom-vm" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_stats() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut pubsub = ctx.state.pubsub().await.unwrap();
+        pubsub
+            .subscribe(crate::events::VM_EVENTS_CHANNEL)
+            .await
+            .unwrap();
+
+        let stats = || request().path("/admin/event-stream-stats").reply(&api);
+        let response = stats().await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert_eq!(body["total_published_since_start"], 0);
+        let channels = body["channels"].as_array().unwrap();
+        let events = channels
+            .iter()
+            .find(|channel| channel["name"] == crate::events::VM_EVENTS_CHANNEL)
+            .unwrap();
+        assert!(events["subscribers"].as_u64().unwrap() >= 1);
+        assert!(channels
+            .iter()
+            .any(|channel| channel["name"] == crate::events::VM_CHANGES_CHANNEL));
+
+        // Registering and unregistering each publish a change; publishing an
+        // event directly counts too.
+        assert_eq!(register(&api, &sample_vm("counted-vm")).await.status(), 200);
+        request()
+            .method("DELETE")
+            .path("/unregister/counted-vm")
+            .reply(&api)
+            .await;
+        let mut con = ctx.state.connection().await.unwrap();
+        crate::events::publish(&mut con, "custom", &sample_vm("counted-vm"))
+            .await
+            .unwrap();
+        let response = stats().await;
+        assert_eq!(json_body(&response)["total_published_since_start"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_reset_all() {
+        let settings = Settings {
+            api_tokens: [
+                ("operator-token".to_string(), Role::Operator),
+                ("admin-token".to_string(), Role::Admin),
+            ]
+            .into(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut con = ctx.state.connection().await.unwrap();
+        con.set::<_, _, ()>("other-app:key", "kept").await.unwrap();
+        for name in ["reset-a", "reset-b", "reset-c"] {
+            let response = request()
+                .method("POST")
+                .path("/register")
+                .header("authorization", "Bearer admin-token")
+                .json(&sample_vm(name))
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+        }
+
+        let reset = |token: &str, confirm: Option<&str>| {
+            let request = request()
+                .method("POST")
+                .path("/admin/reset-all")
+                .header("authorization", format!("Bearer {}", token));
+            match confirm {
+                Some(confirm) => request.header("x-confirm", confirm),
+                None => request,
+            }
+        };
+        let confirmation = "I understand this will delete everything";
+        assert_eq!(reset("admin-token", None).reply(&api).await.status(), 400);
+        assert_eq!(
+            reset("admin-token", Some("yes")).reply(&api).await.status(),
+            400
+        );
+        assert_eq!(
+            reset("operator-token", Some(confirmation))
+                .reply(&api)
+                .await
+                .status(),
+            403
+        );
+        let response = reset("admin-token", Some(confirmation)).reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert!(json_body(&response)["deleted_keys"].as_u64().unwrap() >= 3);
+
+        let response = request()
+            .method("GET")
+            .path("/list")
+            .header("authorization", "Bearer admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response), json!([]));
+        let other: String = con.get("other-app:key").await.unwrap();
+        assert_eq!(other, "kept");
+    }
+
+    #[tokio::test]
+    async fn test_vm_schema() {
+        let api = routes(crate::state::AppState::new(test_settings()).unwrap());
+        let response = request()
+            .method("GET")
+            .path("/admin/schema")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/schema+json"
+        );
+        let schema = json_body(&response);
+        jsonschema::draft7::meta::validate(&schema).unwrap();
+        assert_eq!(schema["title"], "VM");
+        assert!(schema["properties"]["priority"]["description"]
+            .as_str()
+            .unwrap()
+            .starts_with("Start-up priority"));
+    }
+
+    /// Samples of a Prometheus text body keyed by series, e.g.
+    /// `ghaf_vms{status="running"}`.
+    fn parse_prometheus(text: &str) -> std::collections::HashMap<String, f64> {
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                (series.to_string(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut vm = sample_vm("quoted\"vm");
+        vm.status = VMStatus::Running;
+        let uptime = crate::audit::Uptime {
+            name: vm.name.clone(),
+            total_uptime_secs: 86400,
+            start_count: 1,
+        };
+        let samples = parse_prometheus(&super::prometheus_text(&[(vm, uptime)]));
+        assert_eq!(samples["ghaf_vms{status=\"running\"}"], 1.0);
+        assert_eq!(samples["ghaf_vms{status=\"stopped\"}"], 0.0);
+        assert_eq!(
+            samples["ghaf_vm_uptime_seconds{name=\"quoted\\\"vm\",namespace=\"default\"}"],
+            86400.0
+        );
+        assert_eq!(
+            samples["ghaf_vm_status{name=\"quoted\\\"vm\",status=\"running\"}"],
+            1.0
+        );
+        assert_eq!(
+            samples["ghaf_vm_status{name=\"quoted\\\"vm\",status=\"failed\"}"],
+            0.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_prometheus() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for name in ["prom-a", "prom-b", "prom-c"] {
+            assert_eq!(register(&api, &sample_vm(name)).await.status(), 200);
+        }
+        for path in ["/run/prom-a", "/run/prom-b", "/stop/prom-b"] {
+            let response = request().method("POST").path(path).reply(&api).await;
+            assert_eq!(response.status(), 200, "{}", path);
+        }
+
+        let response = request()
+            .method("POST")
+            .path("/admin/export-prometheus")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let samples = parse_prometheus(std::str::from_utf8(response.body()).unwrap());
+        assert_eq!(samples["ghaf_vms{status=\"running\"}"], 1.0);
+        assert_eq!(samples["ghaf_vms{status=\"stopped\"}"], 1.0);
+        assert_eq!(samples["ghaf_vms{status=\"registered\"}"], 1.0);
+        assert_eq!(
+            samples["ghaf_vm_status{name=\"prom-a\",status=\"running\"}"],
+            1.0
+        );
+        assert_eq!(
+            samples["ghaf_vm_status{name=\"prom-b\",status=\"running\"}"],
+            0.0
+        );
+        assert_eq!(
+            samples["ghaf_vm_status{name=\"prom-b\",status=\"stopped\"}"],
+            1.0
+        );
+        assert_eq!(
+            samples["ghaf_vm_uptime_seconds{name=\"prom-c\",namespace=\"default\"}"],
+            0.0
+        );
+        assert!(
+            samples.contains_key("ghaf_vm_uptime_seconds{name=\"prom-a\",namespace=\"default\"}")
+        );
+    }
+}
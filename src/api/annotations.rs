@@ -0,0 +1,201 @@
+//! Freeform notes operators attach to VMs, e.g. why one was rebooted.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Deserialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::Annotation;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Longest annotation text, in characters.
+const MAX_ANNOTATION_CHARS: usize = 2048;
+
+/// Annotations returned when a request does not name a limit.
+const DEFAULT_ANNOTATIONS: isize = 20;
+
+#[derive(Deserialize)]
+struct AnnotateRequest {
+    text: String,
+    author: String,
+}
+
+#[derive(Deserialize)]
+struct AnnotationsQuery {
+    limit: Option<isize>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let annotate = warp::post()
+        .and(warp::path!("vm" / String / "annotate"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(annotate_vm)
+        .and_then(or_reject);
+
+    let annotations = warp::get()
+        .and(warp::path!("vm" / String / "annotations"))
+        .and(warp::query::<AnnotationsQuery>())
+        .and(with_state(state))
+        .then(list_annotations)
+        .and_then(or_reject);
+
+    annotate.or(annotations)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Json, Operator, Path, Query, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/annotate",
+            post(
+                |Path(name), _: RequireRole<Operator>, state, Json(request)| {
+                    annotate_vm(name, request, state)
+                },
+            ),
+        )
+        .route(
+            "/vm/:name/annotations",
+            get(|Path(name), Query(query), state| list_annotations(name, query, state)),
+        )
+        .with_state(state)
+}
+
+fn validate(request: &AnnotateRequest) -> Result<(), RegistryError> {
+    if request.text.trim().is_empty() || request.author.trim().is_empty() {
+        return Err(RegistryError::Validation(
+            "annotation text and author must not be empty".to_string(),
+        ));
+    }
+    let chars = request.text.chars().count();
+    if chars > MAX_ANNOTATION_CHARS {
+        return Err(RegistryError::Validation(format!(
+            "annotation has {} characters, at most {} are allowed",
+            chars, MAX_ANNOTATION_CHARS
+        )));
+    }
+    Ok(())
+}
+
+async fn annotate_vm(
+    name: String,
+    request: AnnotateRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validate(&request)?;
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let annotation = Annotation {
+        text: request.text,
+        author: request.author,
+        timestamp: Utc::now(),
+    };
+    storage::add_annotation(&mut con, &name, &annotation).await?;
+    Ok(reply::json(&annotation))
+}
+
+/// The VM's last `limit` annotations, oldest first.
+async fn list_annotations(
+    name: String,
+    query: AnnotationsQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let limit = query.limit.unwrap_or(DEFAULT_ANNOTATIONS);
+    if limit < 1 {
+        return Err(RegistryError::Validation(
+            "limit must be at least 1".to_string(),
+        ));
+    }
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let annotations = storage::recent_annotations(&mut con, &name, limit).await?;
+    Ok(reply::json(&annotations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let annotation = |text: String, author: &str| AnnotateRequest {
+            text,
+            author: author.to_string(),
+        };
+        assert!(validate(&annotation("observed high CPU".to_string(), "alice")).is_ok());
+        assert!(validate(&annotation("ä".repeat(2048), "alice")).is_ok());
+        assert!(validate(&annotation("a".repeat(2049), "alice")).is_err());
+        assert!(validate(&annotation(" ".to_string(), "alice")).is_err());
+        assert!(validate(&annotation("note".to_string(), "")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_annotations() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("noted-vm")).await.status(), 200);
+        for text in ["rebooted to apply security patch", "observed high CPU"] {
+            let response = request()
+                .method("POST")
+                .path("/vm/noted-vm/annotate")
+                .json(&json!({ "text": text, "author": "alice" }))
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(json_body(&response)["text"], text);
+            // Annotations are scored by the millisecond.
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vm/noted-vm/annotations")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let annotations = body.as_array().unwrap();
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations[0]["text"], "rebooted to apply security patch");
+        assert_eq!(annotations[1]["text"], "observed high CPU");
+        assert_eq!(annotations[1]["author"], "alice");
+        assert!(annotations[0]["timestamp"].as_str() <= annotations[1]["timestamp"].as_str());
+
+        let response = request()
+            .method("GET")
+            .path("/vm/noted-vm/annotations?limit=1")
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response)[0]["text"], "observed high CPU");
+
+        let response = request()
+            .method("POST")
+            .path("/vm/noted-vm/annotate")
+            .json(&json!({ "text": "x".repeat(2049), "author": "alice" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+}
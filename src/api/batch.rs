@@ -0,0 +1,324 @@
+//! Operations over many VMs in one request.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::auth::{self, Caller, Role};
+use crate::error::RegistryError;
+use crate::models::{VMStatus, VM};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct BatchStatusRequest {
+    names: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BatchStatusResponse {
+    results: HashMap<String, VMStatusResult>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum VMStatusResult {
+    Found {
+        status: VMStatus,
+        updated_at: Option<DateTime<Utc>>,
+    },
+    Missing {
+        error: &'static str,
+    },
+}
+
+#[derive(Deserialize)]
+struct BulkStatusUpdate {
+    names: Vec<String>,
+    action: BulkAction,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum BulkAction {
+    /// Running a VM that is not running.
+    Start,
+    /// Stopping a running VM.
+    Stop,
+    /// Returning a stopped or failed VM to `Registered`.
+    Reset,
+}
+
+impl BulkAction {
+    fn target(self) -> VMStatus {
+        match self {
+            BulkAction::Start => VMStatus::Running,
+            BulkAction::Stop => VMStatus::Stopped,
+            BulkAction::Reset => VMStatus::Registered,
+        }
+    }
+
+    fn allowed_from(self, status: VMStatus) -> bool {
+        match self {
+            BulkAction::Start => status != VMStatus::Running,
+            BulkAction::Stop => status == VMStatus::Running,
+            BulkAction::Reset => matches!(status, VMStatus::Stopped | VMStatus::Failed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BulkStatusResponse {
+    results: HashMap<String, String>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let batch_status = warp::post()
+        .and(warp::path!("vms" / "batch-status"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(batch_status)
+        .and_then(or_reject);
+
+    let bulk_status_update = warp::post()
+        .and(warp::path!("vms" / "bulk-status-update"))
+        .and(warp::body::json())
+        .and(auth::caller(state.clone()))
+        .and(with_state(state))
+        .then(bulk_status_update)
+        .and_then(or_reject);
+
+    batch_status.or(bulk_status_update)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::post;
+
+    use super::extract::Json;
+
+    axum::Router::new()
+        .route(
+            "/vms/batch-status",
+            post(|state, Json(request)| batch_status(request, state)),
+        )
+        .route(
+            "/vms/bulk-status-update",
+            post(|caller, state, Json(request)| bulk_status_update(request, caller, state)),
+        )
+        .with_state(state)
+}
+
+/// Returns the status of every requested VM, fetched in one round-trip.
+async fn batch_status(
+    request: BatchStatusRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::lookup_vms(&mut con, &request.names).await?;
+    let results = request
+        .names
+        .into_iter()
+        .zip(vms)
+        .map(|(name, vm)| {
+            let result = match vm {
+                Some(vm) => VMStatusResult::Found {
+                    status: vm.status,
+                    updated_at: vm.updated_at,
+                },
+                None => VMStatusResult::Missing { error: "not_found" },
+            };
+            (name, result)
+        })
+        .collect();
+    Ok(reply::json(&BatchStatusResponse { results }))
+}
+
+/// Why `action` cannot be applied to `vm`, if it cannot. Sealed VMs may
+/// only be stopped, and only by an admin, as with `POST /stop/:name`.
+fn check_transition(vm: &VM, action: BulkAction, caller: Option<&Caller>) -> Result<(), String> {
+    auth::authorize_owner(caller, vm).map_err(|_| "forbidden".to_string())?;
+    let is_admin = caller.is_some_and(|caller| caller.role == Role::Admin);
+    if vm.sealed && !(action == BulkAction::Stop && is_admin) {
+        return Err("sealed".to_string());
+    }
+    if !action.allowed_from(vm.status) {
+        return Err(format!("invalid state {:?}", vm.status));
+    }
+    Ok(())
+}
+
+/// Applies `action` to every named VM that exists and is in a state the
+/// action allows, and reports `ok` or the reason it was skipped per VM.
+async fn bulk_status_update(
+    request: BulkStatusUpdate,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::lookup_vms(&mut con, &request.names).await?;
+    let mut results = HashMap::new();
+    for (name, vm) in request.names.into_iter().zip(vms) {
+        let checked = match &vm {
+            Some(vm) => check_transition(vm, request.action, caller.as_ref()),
+            None => Err("not found".to_string()),
+        };
+        let result = match checked {
+            Ok(()) => match storage::set_status(&mut con, &name, request.action.target()).await {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("error: {}", e.client_message()),
+            },
+            Err(reason) => format!("error: {}", reason),
+        };
+        results.insert(name, result);
+    }
+    Ok(reply::json(&BulkStatusResponse { results }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[test]
+    fn test_bulk_transitions() {
+        let mut vm = sample_vm("bulk-vm");
+        for (status, action, allowed) in [
+            (VMStatus::Registered, BulkAction::Start, true),
+            (VMStatus::Running, BulkAction::Start, false),
+            (VMStatus::Failed, BulkAction::Start, true),
+            (VMStatus::Running, BulkAction::Stop, true),
+            (VMStatus::Stopped, BulkAction::Stop, false),
+            (VMStatus::Stopped, BulkAction::Reset, true),
+            (VMStatus::Failed, BulkAction::Reset, true),
+            (VMStatus::Running, BulkAction::Reset, false),
+        ] {
+            vm.status = status;
+            assert_eq!(
+                check_transition(&vm, action, None).is_ok(),
+                allowed,
+                "{:?} from {:?}",
+                action,
+                status
+            );
+        }
+        vm.status = VMStatus::Running;
+        assert_eq!(check_transition(&vm, BulkAction::Stop, None), Ok(()));
+        assert_eq!(
+            check_transition(&vm, BulkAction::Start, None),
+            Err("invalid state Running".to_string())
+        );
+        vm.sealed = true;
+        assert_eq!(
+            check_transition(&vm, BulkAction::Stop, None),
+            Err("sealed".to_string())
+        );
+        let admin = Caller {
+            role: Role::Admin,
+            identity: None,
+        };
+        assert!(check_transition(&vm, BulkAction::Stop, Some(&admin)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_batch_status() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for name in ["vm-a", "vm-b", "vm-c"] {
+            register(&api, &sample_vm(name)).await;
+        }
+        request().method("POST").path("/run/vm-b").reply(&api).await;
+
+        let response = request()
+            .method("POST")
+            .path("/vms/batch-status")
+            .json(&json!({ "names": ["vm-a", "vm-b", "vm-c", "vm-x", "vm-y"] }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let results = &json_body(&response)["results"];
+        assert_eq!(results.as_object().unwrap().len(), 5);
+        assert_eq!(results["vm-a"]["status"], "Registered");
+        assert_eq!(results["vm-b"]["status"], "Running");
+        assert!(chrono::DateTime::parse_from_rfc3339(
+            results["vm-c"]["updated_at"].as_str().unwrap()
+        )
+        .is_ok());
+        assert_eq!(results["vm-x"], json!({ "error": "not_found" }));
+        assert_eq!(results["vm-y"], json!({ "error": "not_found" }));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_status_update() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for name in ["bulk-a", "bulk-b", "bulk-c"] {
+            register(&api, &sample_vm(name)).await;
+        }
+        request()
+            .method("POST")
+            .path("/run/bulk-b")
+            .reply(&api)
+            .await;
+
+        let update = |names: &[&str], action: &str| {
+            request()
+                .method("POST")
+                .path("/vms/bulk-status-update")
+                .json(&json!({ "names": names, "action": action }))
+        };
+        let response = update(&["bulk-a", "bulk-b", "bulk-c", "bulk-x"], "Start")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({ "results": {
+                "bulk-a": "ok",
+                "bulk-b": "error: invalid state Running",
+                "bulk-c": "ok",
+                "bulk-x": "error: not found",
+            } })
+        );
+
+        let response = update(&["bulk-a", "bulk-b"], "Stop").reply(&api).await;
+        assert_eq!(
+            json_body(&response)["results"],
+            json!({ "bulk-a": "ok", "bulk-b": "ok" })
+        );
+        let response = update(&["bulk-a", "bulk-c"], "Reset").reply(&api).await;
+        assert_eq!(
+            json_body(&response)["results"],
+            json!({ "bulk-a": "ok", "bulk-c": "error: invalid state Running" })
+        );
+
+        let response = request()
+            .method("POST")
+            .path("/vms/batch-status")
+            .json(&json!({ "names": ["bulk-a", "bulk-b", "bulk-c"] }))
+            .reply(&api)
+            .await;
+        let results = &json_body(&response)["results"];
+        assert_eq!(results["bulk-a"]["status"], "Registered");
+        assert_eq!(results["bulk-b"]["status"], "Stopped");
+        assert_eq!(results["bulk-c"]["status"], "Running");
+
+        let response = update(&["bulk-a"], "Pause").reply(&api).await;
+        assert_eq!(response.status(), 400);
+    }
+}
@@ -0,0 +1,173 @@
+//! Queries over the capability index.
+
+use std::sync::Arc;
+
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::models::{VMStatus, VM};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let capability_matrix = warp::get()
+        .and(warp::path!("vms" / "capability-matrix"))
+        .and(with_state(state.clone()))
+        .then(get_capability_matrix)
+        .and_then(or_reject);
+
+    let capability_gaps = warp::get()
+        .and(warp::path!("vms" / "capability-gaps"))
+        .and(with_state(state))
+        .then(get_capability_gaps)
+        .and_then(or_reject);
+
+    capability_matrix.or(capability_gaps)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    axum::Router::new()
+        .route("/vms/capability-matrix", get(get_capability_matrix))
+        .route("/vms/capability-gaps", get(get_capability_gaps))
+        .with_state(state)
+}
+
+/// Returns every capability with the VMs providing it.
+async fn get_capability_matrix(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let matrix = storage::capability_matrix(&mut con).await?;
+    Ok(reply::json(&matrix))
+}
+
+/// Whether `vm` can serve its capabilities: it is not a template and has
+/// not stopped or failed.
+fn provides(vm: &VM) -> bool {
+    !vm.is_template && !matches!(vm.status, VMStatus::Stopped | VMStatus::Failed)
+}
+
+/// Capabilities some VM once provided that no registered VM able to serve
+/// them provides any more.
+async fn get_capability_gaps(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let matrix = storage::capability_matrix(&mut con).await?;
+    let mut uncovered = Vec::new();
+    for capability in storage::known_capabilities(&mut con).await? {
+        let providers = matrix.get(&capability).map_or(&[][..], Vec::as_slice);
+        let vms = storage::get_vms(&mut con, providers).await?;
+        if !vms.iter().any(provides) {
+            uncovered.push(capability);
+        }
+    }
+    Ok(reply::json(&json!({ "uncovered": uncovered })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_capability_matrix() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, capabilities) in [
+            ("clipboard-vm", vec!["clipboard"]),
+            ("compositor-vm", vec!["clipboard", "display"]),
+            ("audio-vm", vec!["audio-playback"]),
+            ("net-vm", vec![]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.capabilities = capabilities.into_iter().map(String::from).collect();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let matrix = || {
+            request()
+                .method("GET")
+                .path("/vms/capability-matrix")
+                .reply(&api)
+        };
+
+        assert_eq!(
+            json_body(&matrix().await),
+            json!({
+                "audio-playback": ["audio-vm"],
+                "clipboard": ["clipboard-vm", "compositor-vm"],
+                "display": ["compositor-vm"],
+            })
+        );
+
+        request()
+            .method("PATCH")
+            .path("/vm/compositor-vm")
+            .json(&json!({ "capabilities": ["display"] }))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            json_body(&matrix().await)["clipboard"],
+            json!(["clipboard-vm"])
+        );
+
+        let mut invalid = sample_vm("bad-vm");
+        invalid.capabilities = vec!["Not Valid".to_string()];
+        assert_eq!(register(&api, &invalid).await.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_capability_gaps() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, capabilities) in [
+            ("webrtc-vm", vec!["webrtc"]),
+            ("clipboard-vm", vec!["clipboard", "display"]),
+            ("compositor-vm", vec!["display"]),
+            ("printer-vm", vec!["printing"]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.capabilities = capabilities.into_iter().map(String::from).collect();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let gaps = || {
+            request()
+                .method("GET")
+                .path("/vms/capability-gaps")
+                .reply(&api)
+        };
+        assert_eq!(json_body(&gaps().await), json!({ "uncovered": [] }));
+
+        let response = request()
+            .method("DELETE")
+            .path("/unregister/webrtc-vm")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        for path in ["/run/clipboard-vm", "/stop/clipboard-vm", "/run/printer-vm"] {
+            request().method("POST").path(path).reply(&api).await;
+        }
+        let response = gaps().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({ "uncovered": ["clipboard", "webrtc"] })
+        );
+    }
+}
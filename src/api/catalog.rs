@@ -0,0 +1,105 @@
+//! Operator-facing summary of the registered VMs.
+
+use std::sync::Arc;
+
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("vms" / "catalog"))
+        .and(with_state(state))
+        .then(get_catalog)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    axum::Router::new()
+        .route("/vms/catalog", get(get_catalog))
+        .with_state(state)
+}
+
+/// Lists every VM with just its name, description, status and type, for
+/// display in a management UI.
+async fn get_catalog(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms(&mut con).await?;
+    let catalog: Vec<_> = vms
+        .iter()
+        .map(|vm| {
+            json!({
+                "name": vm.name,
+                "description": vm.description,
+                "status": vm.status,
+                "type": vm.vm_type.system_app,
+            })
+        })
+        .collect();
+    Ok(reply::json(&catalog))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::models::SystemAppType;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_catalog() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut gui = sample_vm("gui-vm");
+        gui.description = Some("Desktop compositor".to_string());
+        gui.vm_type.system_app = SystemAppType::App;
+        for vm in [&gui, &sample_vm("net-vm")] {
+            assert_eq!(register(&api, vm).await.status(), 200);
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/catalog")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!([
+                {
+                    "name": "gui-vm",
+                    "description": "Desktop compositor",
+                    "status": "Registered",
+                    "type": "App",
+                },
+                {
+                    "name": "net-vm",
+                    "description": null,
+                    "status": "Registered",
+                    "type": "System",
+                },
+            ])
+        );
+
+        let mut verbose = sample_vm("verbose-vm");
+        verbose.description = Some("x".repeat(1025));
+        assert_eq!(register(&api, &verbose).await.status(), 422);
+    }
+}
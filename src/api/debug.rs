@@ -0,0 +1,99 @@
+//! Troubleshooting endpoints, only built with the `debug-endpoints` feature.
+
+use std::sync::Arc;
+
+use redis::AsyncCommands;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("vm" / String / "raw"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(with_state(state))
+        .then(get_raw_vm)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    use super::extract::{Admin, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/raw",
+            get(|Path(name), _: RequireRole<Admin>, state| get_raw_vm(name, state)),
+        )
+        .with_state(state)
+}
+
+/// Returns the VM record exactly as stored, without decryption or
+/// migration.
+async fn get_raw_vm(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let raw: Option<String> = con
+        .get(storage::vm_key(&name))
+        .await
+        .map_err(RegistryError::from)?;
+    let raw = raw.ok_or(RegistryError::NotFound(name))?;
+    Ok(reply::with_header(
+        raw,
+        "content-type",
+        "text/plain; charset=utf-8",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::migration;
+    use crate::test_util::{redis_state, register, request, sample_vm};
+
+    #[tokio::test]
+    async fn test_raw_vm() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("raw-vm")).await.status(), 200);
+
+        let response = request()
+            .method("GET")
+            .path("/vm/raw-vm/raw")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+        let stored: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(stored["name"], "raw-vm");
+        assert_eq!(stored["schema_version"], migration::SCHEMA_VERSION);
+        assert_eq!(stored["addresses"]["ip"], "127.0.0.1");
+
+        let response = request()
+            .method("GET")
+            .path("/vm/missing-vm/raw")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
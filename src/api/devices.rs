@@ -0,0 +1,257 @@
+//! Per-VM device configuration: display and GPU setup, audio devices.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::models::{AudioConfig, AudioDevice, DisplayConfig};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::validation;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let set_display = warp::post()
+        .and(warp::path!("vm" / String / "display-config"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(set_display_config)
+        .and_then(or_reject);
+
+    let get_display = warp::get()
+        .and(warp::path!("vm" / String / "display-config"))
+        .and(with_state(state.clone()))
+        .then(get_display_config)
+        .and_then(or_reject);
+
+    let set_audio = warp::post()
+        .and(warp::path!("vm" / String / "audio-config"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(set_audio_config)
+        .and_then(or_reject);
+
+    let get_audio = warp::get()
+        .and(warp::path!("vm" / String / "audio-config"))
+        .and(with_state(state.clone()))
+        .then(get_audio_config)
+        .and_then(or_reject);
+
+    let sources = warp::get()
+        .and(warp::path!("vms" / "audio-sources"))
+        .and(with_state(state.clone()))
+        .then(|state| list_audio_devices(state, |config: AudioConfig| config.sources))
+        .and_then(or_reject);
+
+    let sinks = warp::get()
+        .and(warp::path!("vms" / "audio-sinks"))
+        .and(with_state(state))
+        .then(|state| list_audio_devices(state, |config: AudioConfig| config.sinks))
+        .and_then(or_reject);
+
+    set_display
+        .or(get_display)
+        .or(set_audio)
+        .or(get_audio)
+        .or(sources)
+        .or(sinks)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    use super::extract::{Json, Path};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/display-config",
+            get(|Path(name), state| get_display_config(name, state))
+                .post(|Path(name), state, Json(config)| set_display_config(name, config, state)),
+        )
+        .route(
+            "/vm/:name/audio-config",
+            get(|Path(name), state| get_audio_config(name, state))
+                .post(|Path(name), state, Json(config)| set_audio_config(name, config, state)),
+        )
+        .route(
+            "/vms/audio-sources",
+            get(|state| list_audio_devices(state, |config: AudioConfig| config.sources)),
+        )
+        .route(
+            "/vms/audio-sinks",
+            get(|state| list_audio_devices(state, |config: AudioConfig| config.sinks)),
+        )
+        .with_state(state)
+}
+
+async fn set_display_config(
+    name: String,
+    config: DisplayConfig,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_display_config(&config)?;
+    let mut con = state.connection().await?;
+    storage::require_unsealed_vm(&mut con, &name).await?;
+    storage::save_display_config(&mut con, &name, &config).await?;
+    Ok(reply::json(&config))
+}
+
+async fn get_display_config(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let config = storage::get_display_config(&mut con, &name)
+        .await?
+        .ok_or(RegistryError::ConfigNotSet(name, "display"))?;
+    Ok(reply::json(&config))
+}
+
+async fn set_audio_config(
+    name: String,
+    config: AudioConfig,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_audio_config(&config)?;
+    let mut con = state.connection().await?;
+    storage::require_unsealed_vm(&mut con, &name).await?;
+    storage::save_audio_config(&mut con, &name, &config).await?;
+    Ok(reply::json(&config))
+}
+
+async fn get_audio_config(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let config = storage::get_audio_config(&mut con, &name)
+        .await?
+        .ok_or(RegistryError::ConfigNotSet(name, "audio"))?;
+    Ok(reply::json(&config))
+}
+
+/// Maps each VM advertising devices of one kind to those devices.
+async fn list_audio_devices(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+    devices: fn(AudioConfig) -> Vec<AudioDevice>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let listing: BTreeMap<String, Vec<AudioDevice>> = storage::list_audio_configs(&mut con)
+        .await?
+        .into_iter()
+        .map(|(name, config)| (name, devices(config)))
+        .filter(|(_, devices)| !devices.is_empty())
+        .collect();
+    Ok(reply::json(&listing))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_display_config() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        register(&api, &sample_vm("gui-vm")).await;
+        let get = || {
+            request()
+                .method("GET")
+                .path("/vm/gui-vm/display-config")
+                .reply(&api)
+        };
+        assert_eq!(get().await.status(), 404);
+
+        let config = json!({
+            "display_server": "Wayland",
+            "resolution": [1920, 1080],
+            "gpu_passthrough": true,
+            "gpu_device_id": "0000:00:02.0",
+        });
+        let response = request()
+            .method("POST")
+            .path("/vm/gui-vm/display-config")
+            .json(&config)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = get().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response), config);
+
+        let response = request()
+            .method("POST")
+            .path("/vm/gui-vm/display-config")
+            .json(&json!({ "display_server": "X11", "gpu_passthrough": true }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+        assert_eq!(
+            json_body(&response)["message"],
+            "gpu_device_id is required with gpu_passthrough"
+        );
+        assert_eq!(json_body(&get().await), config);
+    }
+
+    #[tokio::test]
+    async fn test_audio_config() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        register(&api, &sample_vm("audio-vm")).await;
+        register(&api, &sample_vm("chat-vm")).await;
+        let post = |name: &str, config: serde_json::Value| {
+            request()
+                .method("POST")
+                .path(&format!("/vm/{}/audio-config", name))
+                .json(&config)
+                .reply(&api)
+        };
+
+        let speakers =
+            json!({ "name": "speakers", "description": "Built-in", "sample_rate": 48000 });
+        let mic = json!({ "name": "mic", "description": null, "sample_rate": 16000 });
+        let response = post("audio-vm", json!({ "sinks": [speakers], "sources": [mic] })).await;
+        assert_eq!(response.status(), 200);
+        let response = post("chat-vm", json!({ "sources": [mic] })).await;
+        assert_eq!(response.status(), 200);
+
+        let response = post(
+            "chat-vm",
+            json!({ "sinks": [{ "name": "odd", "sample_rate": 22050 }] }),
+        )
+        .await;
+        assert_eq!(response.status(), 422);
+
+        let list = |path: &str| request().method("GET").path(path).reply(&api);
+        assert_eq!(
+            json_body(&list("/vms/audio-sinks").await),
+            json!({ "audio-vm": [speakers] })
+        );
+        assert_eq!(
+            json_body(&list("/vms/audio-sources").await),
+            json!({ "audio-vm": [mic], "chat-vm": [mic] })
+        );
+        let response = list("/vm/chat-vm/audio-config").await;
+        assert_eq!(
+            json_body(&response),
+            json!({ "sinks": [], "sources": [mic] })
+        );
+    }
+}
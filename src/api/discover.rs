@@ -0,0 +1,156 @@
+//! Finding VMs that are up but not yet registered.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::discovery;
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Longest per-probe timeout a request may ask for.
+const MAX_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiscoverRequest {
+    ip_ranges: Vec<String>,
+    /// Not supported yet: probing vsock needs an `AF_VSOCK` socket, which
+    /// tokio does not provide. Requests naming a range are rejected.
+    #[serde(default)]
+    vsock_cid_range: Option<[u32; 2]>,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+fn default_timeout_ms() -> u64 {
+    500
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("vms" / "discover"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .then(discover_vms)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::post;
+
+    use super::extract::{Json, Operator, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vms/discover",
+            post(|_: RequireRole<Operator>, state, Json(request)| discover_vms(request, state)),
+        )
+        .with_state(state)
+}
+
+/// Probes a port on every address in the requested ranges and lists the
+/// hosts that answer, flagging those a registered VM already declares.
+async fn discover_vms(
+    request: DiscoverRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    if request.vsock_cid_range.is_some() {
+        return Err(RegistryError::Validation(
+            "vsock discovery is not supported".to_string(),
+        ));
+    }
+    if request.timeout_ms == 0 || request.timeout_ms > MAX_TIMEOUT_MS {
+        return Err(RegistryError::Validation(format!(
+            "timeout_ms must be between 1 and {}",
+            MAX_TIMEOUT_MS
+        )));
+    }
+    let networks = discovery::parse_ranges(&request.ip_ranges)?;
+    let mut con = state.connection().await?;
+    let registered: HashSet<IpAddr> = storage::list_vms(&mut con)
+        .await?
+        .iter()
+        .filter_map(|vm| vm.addresses.ip.parse().ok())
+        .collect();
+    let found = discovery::discover(
+        state.prober.as_ref(),
+        &networks,
+        request.port,
+        Duration::from_millis(request.timeout_ms),
+        &registered,
+    )
+    .await;
+    Ok(reply::json(&found))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+
+    #[tokio::test]
+    async fn test_discover_vms() {
+        let Some(mut ctx) = redis_state().await else {
+            return;
+        };
+        ctx.state.prober = std::sync::Arc::new(crate::discovery::tests::mock_prober());
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("discovered-vm");
+        vm.addresses.ip = "192.168.1.10".to_string();
+        assert_eq!(register(&api, &vm).await.status(), 200);
+
+        let response = request()
+            .method("POST")
+            .path("/vms/discover")
+            .json(&serde_json::json!({ "ip_ranges": ["192.168.1.0/24"], "timeout_ms": 50 }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!([
+                { "ip": "192.168.1.3", "open_port": 22, "already_registered": false },
+                { "ip": "192.168.1.10", "open_port": 22, "already_registered": true },
+            ])
+        );
+
+        let response = request()
+            .method("POST")
+            .path("/vms/discover")
+            .json(&serde_json::json!({ "ip_ranges": ["10.0.0.0/8"] }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+
+        let response = request()
+            .method("POST")
+            .path("/vms/discover")
+            .json(&serde_json::json!({ "ip_ranges": [], "vsock_cid_range": [3, 100] }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+}
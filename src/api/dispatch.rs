@@ -0,0 +1,229 @@
+//! Listings for tooling that decides which VMs to start.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Serialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::models::{VMStatus, VM};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Boot readiness of every VM except templates. A VM is ready when it and
+/// all of its dependencies are running.
+#[derive(Serialize, Default)]
+struct BootReport {
+    ready: Vec<String>,
+    not_ready: Vec<NotReady>,
+    failed: Vec<String>,
+    all_ready: bool,
+}
+
+#[derive(Serialize)]
+struct NotReady {
+    name: String,
+    status: VMStatus,
+    /// Dependencies that are not running or not registered.
+    missing_deps: Vec<String>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let one_shot = warp::get()
+        .and(warp::path!("vms" / "by-run-type" / "one-shot"))
+        .and(with_state(state.clone()))
+        .then(list_pending_one_shot_vms)
+        .and_then(or_reject);
+
+    let boot_report = warp::get()
+        .and(warp::path!("vms" / "boot-report"))
+        .and(with_state(state))
+        .then(get_boot_report)
+        .and_then(or_reject);
+
+    one_shot.or(boot_report)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    axum::Router::new()
+        .route("/vms/by-run-type/one-shot", get(list_pending_one_shot_vms))
+        .route("/vms/boot-report", get(get_boot_report))
+        .with_state(state)
+}
+
+/// One-shot VMs still in `Registered`, i.e. ready to be dispatched.
+async fn list_pending_one_shot_vms(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_pending_one_shot_vms(&mut con).await?;
+    Ok(reply::json(&vms))
+}
+
+fn boot_report(vms: &[VM]) -> BootReport {
+    let statuses: HashMap<&str, VMStatus> =
+        vms.iter().map(|vm| (vm.name.as_str(), vm.status)).collect();
+    let mut report = BootReport::default();
+    for vm in vms.iter().filter(|vm| !vm.is_template) {
+        if vm.status == VMStatus::Failed {
+            report.failed.push(vm.name.clone());
+            continue;
+        }
+        let missing_deps: Vec<String> = vm
+            .dependencies
+            .iter()
+            .filter(|dep| statuses.get(dep.as_str()) != Some(&VMStatus::Running))
+            .cloned()
+            .collect();
+        if vm.status == VMStatus::Running && missing_deps.is_empty() {
+            report.ready.push(vm.name.clone());
+        } else {
+            report.not_ready.push(NotReady {
+                name: vm.name.clone(),
+                status: vm.status,
+                missing_deps,
+            });
+        }
+    }
+    report.all_ready = report.not_ready.is_empty() && report.failed.is_empty();
+    report
+}
+
+async fn get_boot_report(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms(&mut con).await?;
+    Ok(reply::json(&boot_report(&vms)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::models::{RunType, VMStatus};
+    use crate::storage;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+
+    #[tokio::test]
+    async fn test_pending_one_shot_vms() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, run_type) in [
+            ("service-vm", RunType::LongRun),
+            ("job-vm-1", RunType::OneShot),
+            ("job-vm-2", RunType::OneShot),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.vm_type.run_type = run_type;
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let pending = || async {
+            let response = request()
+                .method("GET")
+                .path("/vms/by-run-type/one-shot")
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+            json_body(&response)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|vm| vm["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pending().await, ["job-vm-1", "job-vm-2"]);
+
+        request()
+            .method("POST")
+            .path("/run/job-vm-1")
+            .reply(&api)
+            .await;
+        assert_eq!(pending().await, ["job-vm-2"]);
+
+        let response = request()
+            .method("PATCH")
+            .path("/vm/job-vm-2")
+            .json(&serde_json::json!({
+                "vm_type": { "system_app": "App", "run_type": "LongRun" }
+            }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(pending().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_boot_report() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, dependencies) in [
+            ("net-vm", vec![]),
+            ("gui-vm", vec!["net-vm"]),
+            ("app-vm", vec!["gui-vm", "audio-vm"]),
+            ("audio-vm", vec![]),
+            ("broken-vm", vec![]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.dependencies = dependencies.into_iter().map(String::from).collect();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        for path in ["/run/net-vm", "/run/gui-vm", "/run/app-vm"] {
+            request().method("POST").path(path).reply(&api).await;
+        }
+        let mut con = ctx.state.connection().await.unwrap();
+        storage::set_status(&mut con, "broken-vm", VMStatus::Failed)
+            .await
+            .unwrap();
+
+        let report = || async {
+            let response = request()
+                .method("GET")
+                .path("/vms/boot-report")
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+            json_body(&response)
+        };
+        assert_eq!(
+            report().await,
+            serde_json::json!({
+                "ready": ["gui-vm", "net-vm"],
+                "not_ready": [
+                    { "name": "app-vm", "status": "Running", "missing_deps": ["audio-vm"] },
+                    { "name": "audio-vm", "status": "Registered", "missing_deps": [] },
+                ],
+                "failed": ["broken-vm"],
+                "all_ready": false,
+            })
+        );
+
+        request()
+            .method("POST")
+            .path("/run/audio-vm")
+            .reply(&api)
+            .await;
+        request()
+            .method("POST")
+            .path("/run/broken-vm")
+            .reply(&api)
+            .await;
+        let body = report().await;
+        assert_eq!(body["not_ready"], serde_json::json!([]));
+        assert_eq!(body["all_ready"], true);
+    }
+}
@@ -0,0 +1,219 @@
+//! Detecting configuration drift between VM records.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::diff;
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct DiffRequest {
+    a: String,
+    b: String,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let config_hash = warp::get()
+        .and(warp::path!("vm" / String / "config-hash"))
+        .and(with_state(state.clone()))
+        .then(get_config_hash)
+        .and_then(or_reject);
+
+    let diff = warp::post()
+        .and(warp::path!("vms" / "diff"))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .then(diff_vms)
+        .and_then(or_reject);
+
+    config_hash.or(diff)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Json, Path};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/config-hash",
+            get(|Path(name), state| get_config_hash(name, state)),
+        )
+        .route(
+            "/vms/diff",
+            post(|state, Json(request)| diff_vms(request, state)),
+        )
+        .with_state(state)
+}
+
+/// SHA-256 of the configuration fields serialized with sorted keys and no
+/// whitespace, so equal configurations always hash alike.
+async fn get_config_hash(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    let fields = vm.config_fields().map_err(RegistryError::from)?;
+    let canonical = serde_json::to_string(&fields).map_err(RegistryError::from)?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+    let sha256: String = digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let fields_included: Vec<&String> = fields.keys().collect();
+    Ok(reply::json(&json!({
+        "sha256": sha256,
+        "fields_included": fields_included,
+    })))
+}
+
+/// Compares the configurations of two registered VMs; `added` fields only
+/// exist in `b`, `removed` ones only in `a`.
+async fn diff_vms(
+    request: DiffRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let a = storage::require_vm(&mut con, &request.a).await?;
+    let b = storage::require_vm(&mut con, &request.b).await?;
+    let diff = diff::vm_diff(&a, &b).map_err(RegistryError::from)?;
+    let changed: Map<String, Value> = diff
+        .changed
+        .into_iter()
+        .map(|(path, (a, b))| (path, json!({ "a": a, "b": b })))
+        .collect();
+    Ok(reply::json(&json!({
+        "a": request.a,
+        "b": request.b,
+        "added": diff.added,
+        "removed": diff.removed,
+        "changed": changed,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_config_hash() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("hash-vm")).await.status(), 200);
+        let config_hash = || async {
+            let response = request()
+                .method("GET")
+                .path("/vm/hash-vm/config-hash")
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+            json_body(&response)
+        };
+
+        let body = config_hash().await;
+        let original = body["sha256"].as_str().unwrap().to_string();
+        assert_eq!(original.len(), 64);
+        let fields = body["fields_included"].as_array().unwrap();
+        assert!(fields.contains(&json!("addresses")));
+        assert!(!fields.contains(&json!("status")));
+
+        // Lifecycle changes leave the configuration alone.
+        request()
+            .method("POST")
+            .path("/run/hash-vm")
+            .reply(&api)
+            .await;
+        request()
+            .method("POST")
+            .path("/vm/hash-vm/heartbeat")
+            .reply(&api)
+            .await;
+        assert_eq!(config_hash().await["sha256"], original);
+
+        let response = request()
+            .method("PATCH")
+            .path("/vm/hash-vm")
+            .json(&json!({ "priority": 7 }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_ne!(config_hash().await["sha256"], original);
+
+        let response = request()
+            .method("GET")
+            .path("/vm/missing-vm/config-hash")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_diff_vms() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let production = sample_vm("prod-vm");
+        let mut staging = sample_vm("staging-vm");
+        staging.namespace = "staging".to_string();
+        staging.addresses.ip = "10.0.0.2".to_string();
+        staging.priority = 5;
+        for vm in [&production, &staging] {
+            assert_eq!(register(&api, vm).await.status(), 200);
+        }
+        // Only lifecycle fields differ, so they must not show up.
+        request()
+            .method("POST")
+            .path("/run/prod-vm")
+            .reply(&api)
+            .await;
+
+        let diff = |a: &str, b: &str| {
+            request()
+                .method("POST")
+                .path("/vms/diff")
+                .json(&json!({ "a": a, "b": b }))
+                .reply(&api)
+        };
+        let response = diff("prod-vm", "staging-vm").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({
+                "a": "prod-vm",
+                "b": "staging-vm",
+                "added": {},
+                "removed": {},
+                "changed": {
+                    "addresses.ip": { "a": "127.0.0.1", "b": "10.0.0.2" },
+                    "namespace": { "a": "default", "b": "staging" },
+                    "priority": { "a": 0, "b": 5 },
+                },
+            })
+        );
+        assert_eq!(
+            json_body(&diff("prod-vm", "prod-vm").await)["changed"],
+            json!({})
+        );
+        assert_eq!(diff("prod-vm", "missing-vm").await.status(), 404);
+    }
+}
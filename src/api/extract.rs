@@ -0,0 +1,171 @@
+//! Extractors for the axum routes. They fail the way the warp filters do,
+//! with the registry's JSON error responses.
+
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Request};
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+
+use crate::auth::{self, Caller, Role};
+use crate::error::{self, RegistryError};
+use crate::state::{AppState, StateExtension};
+
+/// Path parameters. A parameter that does not parse, e.g. a port that is
+/// not a number, means that no route matched.
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Path::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(value)) => Ok(Path(value)),
+            Err(_) => Err(error::not_found().into_response()),
+        }
+    }
+}
+
+/// The query string deserialized into `T`; a missing one reads as empty.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = RegistryError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Query::from_request_parts(parts, state).await {
+            Ok(axum::extract::Query(value)) => Ok(Query(value)),
+            Err(_) => Err(RegistryError::BadRequest(
+                "Invalid query string".to_string(),
+            )),
+        }
+    }
+}
+
+/// A JSON body deserialized into `T`.
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S, hyper::Body> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(
+        request: Request<hyper::Body>,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        parse_json(&body)
+            .map(Json)
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+/// `body` deserialized into `T`, failing like warp's `body::json`.
+pub fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, RegistryError> {
+    serde_json::from_slice(body)
+        .map_err(|e| RegistryError::BadRequest(format!("Request body deserialize error: {}", e)))
+}
+
+/// The router's state, which the routes set to the `Arc<AppState>` the
+/// handlers take.
+#[async_trait]
+impl<S> FromRequestParts<S> for StateExtension<S>
+where
+    S: Clone + Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(_: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(StateExtension(state.clone()))
+    }
+}
+
+/// The caller presenting the request's bearer token. Extracted as
+/// `Option<Caller>`, which is `None` rather than a rejection when the token
+/// is missing or unknown; routes that require a role add `RequireRole`.
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Caller {
+    type Rejection = RegistryError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        auth::resolve_caller(state, authorization(&parts.headers))
+            .ok_or(RegistryError::Unauthorized)
+    }
+}
+
+/// A role `RequireRole` can demand.
+pub trait RequiredRole {
+    const ROLE: Role;
+}
+
+pub struct Viewer;
+pub struct Operator;
+pub struct Admin;
+
+impl RequiredRole for Viewer {
+    const ROLE: Role = Role::Viewer;
+}
+
+impl RequiredRole for Operator {
+    const ROLE: Role = Role::Operator;
+}
+
+impl RequiredRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Rejects requests whose bearer token does not grant at least `R`.
+pub struct RequireRole<R>(PhantomData<R>);
+
+#[async_trait]
+impl<R: RequiredRole> FromRequestParts<Arc<AppState>> for RequireRole<R> {
+    type Rejection = RegistryError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        auth::check_role(state, authorization(&parts.headers), R::ROLE)?;
+        Ok(RequireRole(PhantomData))
+    }
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The value of the header `name`, if the request has it.
+pub fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
@@ -0,0 +1,586 @@
+//! Queries over the recorded history of VM records, their audit logs and
+//! the timeline of events across all VMs.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::audit;
+use crate::error::RegistryError;
+use crate::events::{self, Notification};
+use crate::models::{RunType, VMStatus};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Events returned when a request does not name a limit.
+const DEFAULT_RECENT_EVENTS: isize = 50;
+
+/// Hours covered by the activity heatmap, the current one included.
+const HEATMAP_HOURS: i64 = 24;
+
+#[derive(Deserialize)]
+struct StateAtQuery {
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct RecentEventsQuery {
+    limit: Option<isize>,
+    /// Unix time in milliseconds.
+    since: Option<i64>,
+}
+
+/// Activity in the hour starting at `hour`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct HourBucket {
+    hour: DateTime<Utc>,
+    starts: u32,
+    stops: u32,
+    registrations: u32,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let state_at = warp::get()
+        .and(warp::path!("vm" / String / "state-at"))
+        .and(warp::query::<StateAtQuery>())
+        .and(with_state(state.clone()))
+        .then(get_state_at)
+        .and_then(or_reject);
+
+    let recent_events = warp::get()
+        .and(warp::path!("vms" / "recent-events"))
+        .and(warp::query::<RecentEventsQuery>())
+        .and(with_state(state.clone()))
+        .then(get_recent_events)
+        .and_then(or_reject);
+
+    let heatmap = warp::get()
+        .and(warp::path!("vms" / "heatmap"))
+        .and(with_state(state.clone()))
+        .then(get_heatmap)
+        .and_then(or_reject);
+
+    let uptime_report = warp::get()
+        .and(warp::path!("vms" / "uptime-report"))
+        .and(with_state(state.clone()))
+        .then(get_uptime_report)
+        .and_then(or_reject);
+
+    let downtime_report = warp::get()
+        .and(warp::path!("vms" / "downtime-report"))
+        .and(with_state(state))
+        .then(get_downtime_report)
+        .and_then(or_reject);
+
+    state_at
+        .or(recent_events)
+        .or(heatmap)
+        .or(uptime_report)
+        .or(downtime_report)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    use super::extract::{Path, Query};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/state-at",
+            get(|Path(name), Query(query), state| get_state_at(name, query, state)),
+        )
+        .route(
+            "/vms/recent-events",
+            get(|Query(query), state| get_recent_events(query, state)),
+        )
+        .route("/vms/heatmap", get(get_heatmap))
+        .route("/vms/uptime-report", get(get_uptime_report))
+        .route("/vms/downtime-report", get(get_downtime_report))
+        .with_state(state)
+}
+
+/// The VM record as it was at `timestamp`, rebuilt from its history; 404
+/// when the VM was not registered then.
+async fn get_state_at(
+    name: String,
+    query: StateAtQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let history = storage::list_vm_events(&mut con, &name).await?;
+    let vm =
+        events::derive_state_at(&history, query.timestamp).ok_or(RegistryError::NotFound(name))?;
+    Ok(reply::json(&vm))
+}
+
+/// The latest events across all VMs, newest first.
+async fn get_recent_events(
+    query: RecentEventsQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_EVENTS);
+    if !(1..=events::TIMELINE_MAXLEN).contains(&limit) {
+        return Err(RegistryError::Validation(format!(
+            "limit must be between 1 and {}",
+            events::TIMELINE_MAXLEN
+        )));
+    }
+    let mut con = state.connection().await?;
+    let recent = events::recent_events(&mut con, query.since.unwrap_or(0), limit).await?;
+    Ok(reply::json(&recent))
+}
+
+/// Starts, stops and registrations per hour over the last
+/// `HEATMAP_HOURS` hours, oldest first, counted from the event timeline.
+async fn get_heatmap(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let now = Utc::now();
+    let mut con = state.connection().await?;
+    let since = heatmap_start(now).timestamp_millis();
+    let timeline = events::recent_events(&mut con, since, events::TIMELINE_MAXLEN).await?;
+    Ok(reply::json(&heatmap(&timeline, now)))
+}
+
+fn heatmap_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    let current = now.duration_trunc(Duration::hours(1)).unwrap_or(now);
+    current - Duration::hours(HEATMAP_HOURS - 1)
+}
+
+fn heatmap(timeline: &[Notification], now: DateTime<Utc>) -> Vec<HourBucket> {
+    let start = heatmap_start(now);
+    let mut buckets: Vec<HourBucket> = (0..HEATMAP_HOURS)
+        .map(|hour| HourBucket {
+            hour: start + Duration::hours(hour),
+            starts: 0,
+            stops: 0,
+            registrations: 0,
+        })
+        .collect();
+    for notification in timeline {
+        let hour = (notification.timestamp - start).num_hours();
+        if notification.timestamp < start || hour >= HEATMAP_HOURS {
+            continue;
+        }
+        let bucket = &mut buckets[hour as usize];
+        match (notification.event.as_str(), notification.status) {
+            ("registered", _) => bucket.registrations += 1,
+            ("status_changed", VMStatus::Running) => bucket.starts += 1,
+            ("status_changed", VMStatus::Stopped) => bucket.stops += 1,
+            _ => {}
+        }
+    }
+    buckets
+}
+
+/// Total running time of every VM according to its audit log, longest
+/// first, then by name.
+async fn get_uptime_report(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let now = Utc::now();
+    let mut con = state.connection().await?;
+    let mut report = Vec::new();
+    for vm in storage::list_vms(&mut con).await? {
+        let entries = storage::audit_entries(&mut con, &vm.name).await?;
+        report.push(audit::uptime(&vm.name, &entries, now));
+    }
+    report.sort_by(|a, b| {
+        b.total_uptime_secs
+            .cmp(&a.total_uptime_secs)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(reply::json(&report))
+}
+
+/// Downtimes of the `LongRun` VMs that have had any according to their
+/// audit logs, longest first, then by name. One-shot VMs are expected to
+/// stop.
+async fn get_downtime_report(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let now = Utc::now();
+    let mut con = state.connection().await?;
+    let mut report = Vec::new();
+    for vm in storage::list_vms(&mut con).await? {
+        if vm.vm_type.run_type != RunType::LongRun {
+            continue;
+        }
+        let entries = storage::audit_entries(&mut con, &vm.name).await?;
+        report.extend(audit::downtime(&vm.name, &entries, now));
+    }
+    report.sort_by(|a, b| {
+        b.longest_downtime_secs
+            .cmp(&a.longest_downtime_secs)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(reply::json(&report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use chrono::SecondsFormat;
+    use redis::AsyncCommands;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_state_at() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let before = Utc::now();
+        assert_eq!(register(&api, &sample_vm("history-vm")).await.status(), 200);
+        let registered = Utc::now();
+        request()
+            .method("PATCH")
+            .path("/vm/history-vm")
+            .json(&json!({ "priority": 4 }))
+            .reply(&api)
+            .await;
+        request()
+            .method("POST")
+            .path("/run/history-vm")
+            .reply(&api)
+            .await;
+        let running = Utc::now();
+        request()
+            .method("DELETE")
+            .path("/unregister/history-vm")
+            .reply(&api)
+            .await;
+
+        let state_at = |at: chrono::DateTime<Utc>| {
+            let at = at.to_rfc3339_opts(SecondsFormat::Micros, true);
+            request()
+                .method("GET")
+                .path(&format!("/vm/history-vm/state-at?timestamp={}", at))
+                .reply(&api)
+        };
+        assert_eq!(state_at(before).await.status(), 404);
+        let vm = json_body(&state_at(registered).await);
+        assert_eq!(
+            (vm["priority"].clone(), vm["status"].clone()),
+            (json!(0), json!("Registered"))
+        );
+        let vm = json_body(&state_at(running).await);
+        assert_eq!(
+            (vm["priority"].clone(), vm["status"].clone()),
+            (json!(4), json!("Running"))
+        );
+        assert_eq!(state_at(Utc::now()).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_recent_events() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let since = Utc::now().timestamp_millis();
+        assert_eq!(
+            register(&api, &sample_vm("timeline-vm")).await.status(),
+            200
+        );
+        for path in ["/run/timeline-vm", "/stop/timeline-vm"] {
+            let response = request().method("POST").path(path).reply(&api).await;
+            assert_eq!(response.status(), 200);
+        }
+
+        let response = request()
+            .method("GET")
+            .path(&format!("/vms/recent-events?limit=1000&since={}", since))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let events: Vec<(&str, &str)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["name"] == "timeline-vm")
+            .map(|event| {
+                (
+                    event["event"].as_str().unwrap(),
+                    event["status"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("status_changed", "Stopped"),
+                ("status_changed", "Running"),
+                ("registered", "Registered"),
+            ]
+        );
+
+        let response = request()
+            .method("GET")
+            .path("/vms/recent-events?limit=0")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+
+    fn event(event: &str, status: VMStatus, timestamp: &str) -> Notification {
+        Notification {
+            event: event.to_string(),
+            name: "gui-vm".to_string(),
+            namespace: "default".to_string(),
+            status,
+            timestamp: timestamp.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let now = "2024-01-02T10:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        let timeline = [
+            event("status_changed", VMStatus::Running, "2024-01-02T10:29:00Z"),
+            event("status_changed", VMStatus::Stopped, "2024-01-02T10:05:00Z"),
+            event("status_changed", VMStatus::Running, "2024-01-02T10:00:00Z"),
+            event("registered", VMStatus::Registered, "2024-01-02T09:59:59Z"),
+            event("status_changed", VMStatus::Failed, "2024-01-02T09:10:00Z"),
+            event("updated", VMStatus::Running, "2024-01-02T09:00:00Z"),
+            event("registered", VMStatus::Registered, "2024-01-01T11:00:00Z"),
+            event("registered", VMStatus::Registered, "2024-01-01T10:59:59Z"),
+        ];
+        let buckets = heatmap(&timeline, now);
+        assert_eq!(buckets.len(), 24);
+        assert_eq!(
+            buckets[0],
+            HourBucket {
+                hour: "2024-01-01T11:00:00Z".parse().unwrap(),
+                starts: 0,
+                stops: 0,
+                registrations: 1,
+            }
+        );
+        assert_eq!(
+            (
+                buckets[22].starts,
+                buckets[22].stops,
+                buckets[22].registrations
+            ),
+            (0, 0, 1)
+        );
+        assert_eq!(
+            buckets[23],
+            HourBucket {
+                hour: "2024-01-02T10:00:00Z".parse().unwrap(),
+                starts: 2,
+                stops: 1,
+                registrations: 0,
+            }
+        );
+        let total: u32 = buckets[1..22]
+            .iter()
+            .map(|bucket| bucket.starts + bucket.stops + bucket.registrations)
+            .sum();
+        assert_eq!(total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_heatmap() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut con = ctx.state.connection().await.unwrap();
+        let two_hours_ago = Utc::now() - Duration::hours(2);
+        let mut pipe = redis::pipe();
+        for (i, status) in [VMStatus::Running, VMStatus::Running, VMStatus::Stopped]
+            .into_iter()
+            .enumerate()
+        {
+            let mut notification = event(
+                "status_changed",
+                status,
+                &two_hours_ago.to_rfc3339_opts(SecondsFormat::Micros, true),
+            );
+            notification.name = format!("heatmap-vm-{}", i);
+            events::queue_publish(&mut pipe, events::VM_EVENTS_CHANNEL, &notification).unwrap();
+            pipe.ignore();
+        }
+        pipe.query_async::<_, ()>(&mut con).await.unwrap();
+
+        let response = request()
+            .method("GET")
+            .path("/vms/heatmap")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let buckets = body.as_array().unwrap();
+        assert_eq!(buckets.len(), 24);
+        let hour = two_hours_ago
+            .duration_trunc(Duration::hours(1))
+            .unwrap()
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+        let bucket = buckets
+            .iter()
+            .find(|bucket| bucket["hour"] == hour)
+            .unwrap();
+        assert!(bucket["starts"].as_u64().unwrap() >= 2);
+        assert!(bucket["stops"].as_u64().unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_uptime_report() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut con = ctx.state.connection().await.unwrap();
+        let start = Utc::now() - Duration::days(2);
+        let at = |hours: i64| start + Duration::hours(hours);
+        for (name, transitions) in [
+            (
+                "uptime-a",
+                vec![
+                    ("started", 0),
+                    ("stopped", 2),
+                    ("started", 5),
+                    ("stopped", 6),
+                ],
+            ),
+            ("uptime-b", vec![("started", 0), ("stopped", 10)]),
+            ("uptime-c", vec![]),
+        ] {
+            assert_eq!(register(&api, &sample_vm(name)).await.status(), 200);
+            for (kind, hours) in transitions {
+                let entry = json!({ "kind": kind, "timestamp": at(hours) });
+                con.zadd::<_, _, _, ()>(
+                    storage::audit_key(name),
+                    entry.to_string(),
+                    at(hours).timestamp(),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/uptime-report")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let report: Vec<&serde_json::Value> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["name"].as_str().unwrap().starts_with("uptime-"))
+            .collect();
+        assert_eq!(
+            report,
+            [
+                &json!({ "name": "uptime-b", "total_uptime_secs": 36000, "start_count": 1 }),
+                &json!({ "name": "uptime-a", "total_uptime_secs": 10800, "start_count": 2 }),
+                &json!({ "name": "uptime-c", "total_uptime_secs": 0, "start_count": 0 }),
+            ]
+        );
+
+        request()
+            .method("POST")
+            .path("/run/uptime-c")
+            .reply(&api)
+            .await;
+        let entries = storage::audit_entries(&mut con, "uptime-c").await.unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&entries[0]).unwrap();
+        assert_eq!(entry["kind"], "started");
+    }
+
+    #[tokio::test]
+    async fn test_downtime_report() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut con = ctx.state.connection().await.unwrap();
+        let start = Utc::now() - Duration::days(2);
+        let at = |hours: i64| start + Duration::hours(hours);
+        for (name, run_type, transitions) in [
+            (
+                "downtime-a",
+                RunType::LongRun,
+                vec![
+                    ("started", "Running", 0),
+                    ("stopped", "Failed", 1),
+                    ("started", "Running", 4),
+                    ("stopped", "Stopped", 5),
+                    ("started", "Running", 6),
+                ],
+            ),
+            (
+                "downtime-b",
+                RunType::LongRun,
+                vec![
+                    ("started", "Running", 0),
+                    ("stopped", "Stopped", 2),
+                    ("started", "Running", 3),
+                ],
+            ),
+            (
+                "downtime-job",
+                RunType::OneShot,
+                vec![("started", "Running", 0), ("stopped", "Stopped", 1)],
+            ),
+            (
+                "downtime-c",
+                RunType::LongRun,
+                vec![("started", "Running", 0)],
+            ),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.vm_type.run_type = run_type;
+            assert_eq!(register(&api, &vm).await.status(), 200);
+            for (kind, status, hours) in transitions {
+                let entry = json!({ "kind": kind, "status": status, "timestamp": at(hours) });
+                con.zadd::<_, _, _, ()>(
+                    storage::audit_key(name),
+                    entry.to_string(),
+                    at(hours).timestamp(),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/downtime-report")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let report: Vec<&serde_json::Value> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["name"].as_str().unwrap().starts_with("downtime-"))
+            .collect();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0]["name"], "downtime-a");
+        assert_eq!(report[0]["longest_downtime_secs"], 3 * 3600);
+        assert_eq!(report[0]["last_downtime_start"], json!(at(5)));
+        assert_eq!(report[0]["last_downtime_end"], json!(at(6)));
+        assert_eq!(report[1]["name"], "downtime-b");
+        assert_eq!(report[1]["longest_downtime_secs"], 3600);
+    }
+}
@@ -0,0 +1,573 @@
+//! `Idempotency-Key` support for POST requests. The first successful
+//! response for a key is cached in Redis and replayed for retries, so a
+//! client can safely repeat a request whose reply it never saw. The key is
+//! reserved before the request is handled, so a duplicate arriving while
+//! the first request is still running gets 409 instead of running too.
+//! Keys are scoped to the `Authorization` header the request carried, so a
+//! cached response is only replayed to a caller presenting the same
+//! credentials.
+//! Streamed and non-JSON responses are passed through uncached.
+
+#[cfg(feature = "warp")]
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::{header, HeaderMap, Method, StatusCode};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+#[cfg(feature = "warp")]
+use warp::Filter;
+
+use super::is_media_type;
+#[cfg(feature = "warp")]
+use super::with_state;
+use crate::error::{ErrorResponse, RegistryError};
+use crate::reply::{self, Response};
+use crate::state::AppState;
+#[cfg(feature = "warp")]
+use crate::state::StateExtension;
+use crate::storage;
+
+/// Cached responses expire after 24 hours.
+const IDEMPOTENCY_TTL_SECS: usize = 24 * 60 * 60;
+
+/// A reservation expires after 5 minutes, so a key whose request never
+/// finished, e.g. because the registry stopped, can be used again.
+const RESERVATION_TTL_SECS: usize = 5 * 60;
+
+/// What is stored under an idempotency key.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Entry {
+    /// The first request with the key is still being handled.
+    InProgress {
+        path: String,
+    },
+    Done(CachedResponse),
+}
+
+impl Entry {
+    fn path(&self) -> &str {
+        match self {
+            Entry::InProgress { path } => path,
+            Entry::Done(cached) => &cached.path,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedResponse {
+    path: String,
+    status: u16,
+    content_type: Option<String>,
+    /// The body's bytes in base64.
+    body: String,
+}
+
+impl CachedResponse {
+    fn into_response(self) -> Response {
+        let body = match STANDARD.decode(&self.body) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!(error = %e, "cannot decode cached response body");
+                return internal_error();
+            }
+        };
+        let mut response = Response::new(body.into());
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        if let Some(content_type) = self
+            .content_type
+            .and_then(|value| value.parse::<header::HeaderValue>().ok())
+        {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, content_type);
+        }
+        response
+    }
+}
+
+fn internal_error() -> Response {
+    let body = ErrorResponse::new("Internal", "Internal server error.");
+    reply::with_status(reply::json(&body), StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// A POST request's idempotency key and the scope it is cached in.
+struct IdempotencyKey {
+    key: String,
+    /// SHA-256 of the request's `Authorization` header in hex, or
+    /// `anonymous` when it has none.
+    scope: String,
+}
+
+impl IdempotencyKey {
+    /// The idempotency key of a POST request, if it carries one.
+    fn of_request(method: &Method, headers: &HeaderMap) -> Option<Self> {
+        let key = headers.get("idempotency-key")?.to_str().ok()?;
+        (method == Method::POST).then(|| IdempotencyKey {
+            key: key.to_string(),
+            scope: credentials_scope(headers),
+        })
+    }
+
+    fn redis_key(&self) -> String {
+        storage::idempotency_key(&self.scope, &self.key)
+    }
+}
+
+fn credentials_scope(headers: &HeaderMap) -> String {
+    let Some(authorization) = headers.get(header::AUTHORIZATION) else {
+        return "anonymous".to_string();
+    };
+    let digest = ring::digest::digest(&ring::digest::SHA256, authorization.as_bytes());
+    digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `response` may be cached: only successful JSON replies of known
+/// length are, so streams such as NDJSON progress reports reach the client
+/// unbuffered.
+fn is_cacheable<B: HttpBody>(response: &hyper::Response<B>) -> bool {
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| is_media_type(value, "application/json"));
+    response.status().is_success() && is_json && response.body().size_hint().exact().is_some()
+}
+
+/// Wraps `api` so that POST requests with an `Idempotency-Key` header are
+/// answered from the cache when the key has been seen before, and refused
+/// while another request with the key is in progress.
+#[cfg(feature = "warp")]
+pub fn wrap<F, R>(
+    state: Arc<AppState>,
+    api: F,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    let idempotency_key = warp::method()
+        .and(warp::header::headers_cloned())
+        .map(|method: Method, headers: HeaderMap| IdempotencyKey::of_request(&method, &headers));
+
+    let cached = idempotency_key
+        .and(warp::path::full())
+        .and(with_state(state.clone()))
+        .and_then(
+            |key: Option<IdempotencyKey>,
+             path: warp::path::FullPath,
+             StateExtension(state): StateExtension<Arc<AppState>>| async move {
+                let Some(key) = key else {
+                    return Err(warp::reject::not_found());
+                };
+                reserve_or_replay(&key, path.as_str(), &state)
+                    .await
+                    .ok_or_else(warp::reject::not_found)
+            },
+        );
+
+    let fresh = idempotency_key
+        .and(warp::path::full())
+        .and(with_state(state))
+        .and(api)
+        .then(
+            |key: Option<IdempotencyKey>,
+             path: warp::path::FullPath,
+             StateExtension(state): StateExtension<Arc<AppState>>,
+             reply: R| async move {
+                let response = reply.into_response();
+                match key {
+                    Some(key) if is_cacheable(&response) => {
+                        remember(&key, path.as_str(), &state, response).await
+                    }
+                    Some(key) => {
+                        release(&state, &key).await;
+                        response
+                    }
+                    None => response,
+                }
+            },
+        );
+
+    cached.or(fresh).unify()
+}
+
+/// Middleware answering POST requests with an `Idempotency-Key` header
+/// from the cache when the key has been seen before, and refusing them
+/// while another request with the key is in progress.
+#[cfg(feature = "axum")]
+pub async fn wrap(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: axum::http::Request<hyper::Body>,
+    next: axum::middleware::Next<hyper::Body>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let key = IdempotencyKey::of_request(request.method(), request.headers());
+    let path = request.uri().path().to_string();
+    if let Some(key) = &key {
+        if let Some(response) = reserve_or_replay(key, &path, &state).await {
+            return response.into_response();
+        }
+    }
+    let response = next.run(request).await;
+    match key {
+        Some(key) if is_cacheable(&response) => remember(&key, &path, &state, response)
+            .await
+            .into_response(),
+        Some(key) => {
+            release(&state, &key).await;
+            response
+        }
+        None => response,
+    }
+}
+
+/// Reserves the key for this request, and returns `None` when the request
+/// is to reach the API. Otherwise the answer for a key already in use:
+/// the cached response, or 409 while the request it was first used for is
+/// still in progress.
+async fn reserve_or_replay(key: &IdempotencyKey, path: &str, state: &AppState) -> Option<Response> {
+    if Uuid::parse_str(&key.key).is_err() {
+        return Some(
+            RegistryError::BadRequest("Idempotency-Key must be a UUID".to_string()).into_response(),
+        );
+    }
+    let entry = match reserve(state, key, path).await {
+        Ok(entry) => entry?,
+        Err(e) => {
+            tracing::warn!(key = %key.key, error = %e, "idempotency lookup failed");
+            return None;
+        }
+    };
+    if entry.path() != path {
+        return Some(
+            RegistryError::Validation(
+                "Idempotency-Key was already used for a different request".to_string(),
+            )
+            .into_response(),
+        );
+    }
+    match entry {
+        Entry::InProgress { .. } => Some(
+            RegistryError::Conflict(
+                "A request with this Idempotency-Key is still in progress".to_string(),
+            )
+            .into_response(),
+        ),
+        Entry::Done(cached) => Some(cached.into_response()),
+    }
+}
+
+/// Buffers a cacheable API `response` and caches it for the key before
+/// passing it on.
+async fn remember<B>(
+    key: &IdempotencyKey,
+    path: &str,
+    state: &AppState,
+    response: hyper::Response<B>,
+) -> Response
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: std::fmt::Display,
+{
+    let (parts, body) = response.into_parts();
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!(key = %key.key, error = %e, "cannot buffer response");
+            release(state, key).await;
+            return internal_error();
+        }
+    };
+    let cached = CachedResponse {
+        path: path.to_string(),
+        status: parts.status.as_u16(),
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from),
+        body: STANDARD.encode(&bytes),
+    };
+    if let Err(e) = store(state, key, &Entry::Done(cached)).await {
+        tracing::warn!(key = %key.key, error = %e, "cannot cache response");
+    }
+    Response::from_parts(parts, bytes.into())
+}
+
+/// Marks the key as in progress for a request to `path` unless it is in
+/// use, and returns what is stored under it if so.
+async fn reserve(
+    state: &AppState,
+    key: &IdempotencyKey,
+    path: &str,
+) -> Result<Option<Entry>, RegistryError> {
+    let mut con = state.connection().await?;
+    let marker = serde_json::to_string(&Entry::InProgress {
+        path: path.to_string(),
+    })?;
+    loop {
+        let reserved: Option<String> = redis::cmd("SET")
+            .arg(key.redis_key())
+            .arg(&marker)
+            .arg("NX")
+            .arg("EX")
+            .arg(RESERVATION_TTL_SECS)
+            .query_async(&mut con)
+            .await?;
+        if reserved.is_some() {
+            return Ok(None);
+        }
+        // The entry may expire between the two commands; then try again.
+        let raw: Option<String> = con.get(key.redis_key()).await?;
+        if let Some(raw) = raw {
+            return Ok(Some(serde_json::from_str(&raw)?));
+        }
+    }
+}
+
+/// Replaces the key's reservation with `entry`.
+async fn store(state: &AppState, key: &IdempotencyKey, entry: &Entry) -> Result<(), RegistryError> {
+    let mut con = state.connection().await?;
+    con.set_ex::<_, _, ()>(
+        key.redis_key(),
+        serde_json::to_string(entry)?,
+        IDEMPOTENCY_TTL_SECS,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Gives up the key's reservation, for a request whose response is not
+/// cached, so that it can be retried.
+async fn release(state: &AppState, key: &IdempotencyKey) {
+    let released = async {
+        let mut con = state.connection().await?;
+        con.del::<_, ()>(key.redis_key()).await?;
+        Ok::<_, RegistryError>(())
+    };
+    if let Err(e) = released.await {
+        tracing::warn!(key = %key.key, error = %e, "cannot release idempotency key");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::models::VM;
+    use crate::reply::Reply;
+    use crate::test_util::{
+        json_body, redis_state, redis_state_with, register, request, sample_vm, test_settings,
+    };
+    use hyper::Body;
+    use std::convert::Infallible;
+
+    #[test]
+    fn test_cacheable_responses() {
+        let json = reply::json(&serde_json::json!({ "ok": true }));
+        assert!(is_cacheable(&json));
+        let mut charset = Response::new(Body::from("{}"));
+        charset.headers_mut().insert(
+            header::CONTENT_TYPE,
+            "application/json; charset=utf-8".parse().unwrap(),
+        );
+        assert!(is_cacheable(&charset));
+        assert!(!is_cacheable(&"VM started.".into_response()));
+
+        let chunks = futures_util::stream::iter(vec![Ok::<_, Infallible>("{}\n")]);
+        let mut streamed = Response::new(Body::wrap_stream(chunks));
+        streamed
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert!(!is_cacheable(&streamed));
+    }
+
+    #[tokio::test]
+    async fn test_cached_bodies_keep_their_bytes() {
+        let bytes = vec![0x1f, 0x8b, 0xff, 0x00, 0xfe];
+        let cached = CachedResponse {
+            path: "/debug/snapshot".to_string(),
+            status: 200,
+            content_type: Some("application/gzip".to_string()),
+            body: STANDARD.encode(&bytes),
+        };
+        let body = cached.into_response().into_body();
+        let replayed = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(replayed.as_ref(), bytes.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_registration() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let key = uuid::Uuid::new_v4().to_string();
+        let vm = sample_vm("retried-vm");
+        let send = || {
+            request()
+                .method("POST")
+                .path("/register")
+                .header("idempotency-key", &key)
+                .json(&vm)
+                .reply(&api)
+        };
+
+        let first = send().await;
+        assert_eq!(first.status(), 200);
+        let second = send().await;
+        assert_eq!(second.status(), 200);
+        assert_eq!(second.headers()["content-type"], "application/json");
+        assert_eq!(json_body(&second), json_body(&first));
+
+        // Without a key the usual duplicate detection applies.
+        let vm = sample_vm("plain-vm");
+        assert_eq!(register(&api, &vm).await.status(), 200);
+        assert_eq!(register(&api, &vm).await.status(), 409);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_duplicates() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let key = uuid::Uuid::new_v4().to_string();
+        let vm = sample_vm("raced-vm");
+        let send = || {
+            request()
+                .method("POST")
+                .path("/register")
+                .header("idempotency-key", &key)
+                .json(&vm)
+                .reply(&api)
+        };
+
+        // The duplicate is replayed the first response if that is done by
+        // the time it arrives, or else refused; it never runs the
+        // registration a second time.
+        let (first, second) = tokio::join!(send(), send());
+        for response in [&first, &second] {
+            match response.status().as_u16() {
+                200 => assert_eq!(json_body(response)["name"], "raced-vm"),
+                409 => assert_eq!(json_body(response)["error"], "Conflict"),
+                status => panic!("unexpected status {}", status),
+            }
+        }
+        assert!(first.status() == 200 || second.status() == 200);
+    }
+
+    #[tokio::test]
+    async fn test_keys_in_progress_are_refused() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let key = IdempotencyKey {
+            key: uuid::Uuid::new_v4().to_string(),
+            scope: "anonymous".to_string(),
+        };
+        let send = |vm: VM| {
+            request()
+                .method("POST")
+                .path("/register")
+                .header("idempotency-key", &key.key)
+                .json(&vm)
+                .reply(&api)
+        };
+
+        assert!(reserve(&ctx.state, &key, "/register")
+            .await
+            .unwrap()
+            .is_none());
+        let response = send(sample_vm("pending-vm")).await;
+        assert_eq!(response.status(), 409);
+        assert_eq!(
+            json_body(&response)["message"],
+            "A request with this Idempotency-Key is still in progress"
+        );
+
+        // A request that fails gives the key up, so it can be retried.
+        release(&ctx.state, &key).await;
+        register(&api, &sample_vm("taken-vm")).await;
+        let response = send(sample_vm("taken-vm")).await;
+        assert_eq!(json_body(&response)["error"], "AlreadyExists");
+        assert_eq!(send(sample_vm("pending-vm")).await.status(), 200);
+        assert_eq!(send(sample_vm("pending-vm")).await.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_errors() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .header("idempotency-key", "not-a-uuid")
+            .json(&sample_vm("bad-key-vm"))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 400);
+
+        let key = uuid::Uuid::new_v4().to_string();
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .header("idempotency-key", &key)
+            .json(&sample_vm("key-vm"))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = request()
+            .method("POST")
+            .path("/run/key-vm")
+            .header("idempotency-key", &key)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_scoped_to_credentials() {
+        let mut settings = test_settings();
+        settings.api_tokens = [
+            ("alice-token".to_string(), Role::Operator),
+            ("mallory-token".to_string(), Role::Operator),
+        ]
+        .into();
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let key = uuid::Uuid::new_v4().to_string();
+        let send = |token: &str| {
+            request()
+                .method("POST")
+                .path("/register")
+                .header("authorization", format!("Bearer {}", token))
+                .header("idempotency-key", &key)
+                .json(&sample_vm("scoped-vm"))
+                .reply(&api)
+        };
+
+        assert_eq!(send("alice-token").await.status(), 200);
+        assert_eq!(send("alice-token").await.status(), 200);
+        // Another caller reusing the key is not shown Alice's response.
+        assert_eq!(send("mallory-token").await.status(), 409);
+    }
+}
@@ -0,0 +1,383 @@
+//! The NixOS images VMs run: lookups for security audits, and rolling
+//! updates to a new build.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use futures_util::future::join_all;
+use futures_util::stream;
+use hyper::http::header;
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::auth::{self, Caller};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::{VMStatus, VmImage, VM};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::validation;
+
+#[derive(Deserialize)]
+struct RollingUpdateRequest {
+    old_image_id: String,
+    new_image_id: String,
+    /// Version of the new build, if known.
+    #[serde(default)]
+    new_version: Option<String>,
+    /// VMs updated at the same time.
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Step {
+    Stopping,
+    Updating,
+    Starting,
+    Done,
+    Failed,
+}
+
+/// One line of the rolling update's NDJSON progress report.
+#[derive(Serialize, Debug)]
+struct Progress {
+    vm: String,
+    step: Step,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let by_image = warp::get()
+        .and(warp::path!("vms" / "by-image" / String))
+        .and(with_state(state.clone()))
+        .then(get_vms_by_image)
+        .and_then(or_reject);
+
+    let rolling_update = warp::post()
+        .and(warp::path!("vms" / "rolling-update"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(auth::caller(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .then(rolling_update)
+        .and_then(or_reject);
+
+    by_image.or(rolling_update)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Json, Operator, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vms/by-image/:id",
+            get(|Path(id), state| get_vms_by_image(id, state)),
+        )
+        .route(
+            "/vms/rolling-update",
+            post(|_: RequireRole<Operator>, caller, state, Json(request)| {
+                rolling_update(caller, request, state)
+            }),
+        )
+        .with_state(state)
+}
+
+async fn get_vms_by_image(
+    id: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms_by_image(&mut con, &id).await?;
+    Ok(reply::json(&vms))
+}
+
+/// `image` moved to the new build of `target`. The store path keeps its
+/// name, e.g. `/nix/store/{old}-nixos-system` becomes
+/// `/nix/store/{new}-nixos-system`.
+fn retarget(image: &VmImage, target: &RollingUpdateRequest) -> VmImage {
+    let name = image
+        .store_path
+        .strip_prefix(&format!("/nix/store/{}-", image.id))
+        .unwrap_or_default();
+    VmImage {
+        id: target.new_image_id.clone(),
+        store_path: format!("/nix/store/{}-{}", target.new_image_id, name),
+        version: target.new_version.clone(),
+    }
+}
+
+/// Moves every VM running `old_image_id` to `new_image_id`, `concurrency`
+/// VMs at a time; each batch finishes before the next starts. Running VMs
+/// are stopped, updated and started again; other VMs are only updated.
+/// Progress is streamed as one JSON object per line. A VM that cannot be
+/// updated, e.g. because it is sealed, is reported `failed` and left as is.
+async fn rolling_update(
+    caller: Option<Caller>,
+    request: RollingUpdateRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_image_id(&request.old_image_id)?;
+    validation::validate_image_id(&request.new_image_id)?;
+    if request.concurrency == 0 {
+        return Err(RegistryError::Validation(
+            "concurrency must be at least 1".to_string(),
+        ));
+    }
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms_by_image(&mut con, &request.old_image_id).await?;
+    drop(con);
+
+    let (progress, updates) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for batch in vms.chunks(request.concurrency) {
+            join_all(
+                batch
+                    .iter()
+                    .map(|vm| update_vm(&state, caller.as_ref(), vm, &request, &progress)),
+            )
+            .await;
+        }
+    });
+    let lines = stream::unfold(updates, |mut updates| async move {
+        let progress = updates.recv().await?;
+        let line = serde_json::to_string(&progress).expect("progress serializes") + "\n";
+        Some((Ok::<_, Infallible>(line), updates))
+    });
+    Ok(reply::with_header(
+        Response::new(Body::wrap_stream(lines)),
+        header::CONTENT_TYPE,
+        "application/x-ndjson",
+    ))
+}
+
+async fn update_vm(
+    state: &AppState,
+    caller: Option<&Caller>,
+    vm: &VM,
+    target: &RollingUpdateRequest,
+    progress: &mpsc::UnboundedSender<Progress>,
+) {
+    let report = |step: Step, error: Option<String>| {
+        // The client hanging up does not stop the update.
+        let _ = progress.send(Progress {
+            vm: vm.name.clone(),
+            step,
+            error,
+        });
+    };
+    let result = async {
+        auth::authorize_owner(caller, vm)?;
+        if vm.sealed {
+            return Err(RegistryError::Locked(vm.name.clone()));
+        }
+        let mut con = state.connection().await?;
+        let running = vm.status == VMStatus::Running;
+        if running {
+            report(Step::Stopping, None);
+            storage::set_status(&mut con, &vm.name, VMStatus::Stopped).await?;
+        }
+        report(Step::Updating, None);
+        let previous = storage::require_vm(&mut con, &vm.name).await?;
+        let mut updated = previous.clone();
+        updated.image = previous.image.as_ref().map(|image| retarget(image, target));
+        storage::save_vm(&mut con, &mut updated, Some(&previous)).await?;
+        if running {
+            report(Step::Starting, None);
+            storage::set_status(&mut con, &vm.name, VMStatus::Running).await?;
+        }
+        Ok::<_, RegistryError>(())
+    }
+    .await;
+    match result {
+        Ok(()) => report(Step::Done, None),
+        Err(e) => report(Step::Failed, Some(e.client_message())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::models::VmImage;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    const VULNERABLE: &str = "0c7xp0ar4b2ny3mwbkrhfpbbb8yqn2s1";
+    const PATCHED: &str = "9ak2hwq6k1vd5z1v0m8kbyfq4rjnpl3c";
+
+    fn image(id: &str) -> Option<VmImage> {
+        Some(VmImage {
+            id: id.to_string(),
+            store_path: format!("/nix/store/{}-nixos-system-gui-vm", id),
+            version: Some("24.05".to_string()),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_vms_by_image() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, id) in [
+            ("gui-vm", VULNERABLE),
+            ("audio-vm", VULNERABLE),
+            ("net-vm", PATCHED),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.image = image(id);
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+
+        let by_image = |id: &str| request().path(&format!("/vms/by-image/{}", id)).reply(&api);
+        let names = |response: &hyper::http::Response<hyper::body::Bytes>| -> Vec<String> {
+            json_body(response)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|vm| vm["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(names(&by_image(VULNERABLE).await), ["audio-vm", "gui-vm"]);
+
+        // Moving a VM to another image moves it in the index too.
+        let response = request()
+            .method("PATCH")
+            .path("/vm/gui-vm")
+            .json(&json!({ "image": image(PATCHED) }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(names(&by_image(VULNERABLE).await), ["audio-vm"]);
+        assert_eq!(names(&by_image(PATCHED).await), ["gui-vm", "net-vm"]);
+
+        let mut vm = sample_vm("bad-image-vm");
+        vm.image = image("not-a-store-hash");
+        let response = register(&api, &vm).await;
+        assert_eq!(response.status(), 422);
+        let mut vm = sample_vm("bad-image-vm");
+        vm.image = image(PATCHED);
+        vm.image.as_mut().unwrap().store_path = format!("/nix/store/{}-x", VULNERABLE);
+        assert_eq!(register(&api, &vm).await.status(), 422);
+    }
+
+    #[test]
+    fn test_retarget() {
+        let target = super::RollingUpdateRequest {
+            old_image_id: VULNERABLE.to_string(),
+            new_image_id: PATCHED.to_string(),
+            new_version: Some("24.11".to_string()),
+            concurrency: 1,
+        };
+        let image = super::retarget(&image(VULNERABLE).unwrap(), &target);
+        assert_eq!(image.id, PATCHED);
+        assert_eq!(
+            image.store_path,
+            format!("/nix/store/{}-nixos-system-gui-vm", PATCHED)
+        );
+        assert_eq!(image.version.as_deref(), Some("24.11"));
+    }
+
+    #[tokio::test]
+    async fn test_rolling_update() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for name in ["a-vm", "b-vm", "c-vm", "d-vm"] {
+            let mut vm = sample_vm(name);
+            vm.image = image(VULNERABLE);
+            assert_eq!(register(&api, &vm).await.status(), 200);
+            if name != "d-vm" {
+                let path = format!("/run/{}", name);
+                request().method("POST").path(&path).reply(&api).await;
+            }
+        }
+
+        let response = request()
+            .method("POST")
+            .path("/vms/rolling-update")
+            .json(&json!({
+                "old_image_id": VULNERABLE,
+                "new_image_id": PATCHED,
+                "concurrency": 2,
+            }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let lines: Vec<(String, String)> = std::str::from_utf8(response.body())
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let progress: serde_json::Value = serde_json::from_str(line).unwrap();
+                (
+                    progress["vm"].as_str().unwrap().to_string(),
+                    progress["step"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        let steps = |name: &str| -> Vec<&str> {
+            lines
+                .iter()
+                .filter(|(vm, _)| vm == name)
+                .map(|(_, step)| step.as_str())
+                .collect()
+        };
+        for name in ["a-vm", "b-vm", "c-vm"] {
+            assert_eq!(steps(name), ["stopping", "updating", "starting", "done"]);
+        }
+        // VMs that were not running are updated but not started.
+        assert_eq!(steps("d-vm"), ["updating", "done"]);
+        // The second batch starts only once the first one is done.
+        let position = |name: &str, step: &str| {
+            lines
+                .iter()
+                .position(|(vm, s)| vm == name && s == step)
+                .unwrap()
+        };
+        for first in ["a-vm", "b-vm"] {
+            for second in ["c-vm", "d-vm"] {
+                let started = if second == "c-vm" {
+                    "stopping"
+                } else {
+                    "updating"
+                };
+                assert!(position(first, "done") < position(second, started));
+            }
+        }
+
+        let response = request()
+            .path(&format!("/vms/by-image/{}", PATCHED))
+            .reply(&api)
+            .await;
+        let vms = json_body(&response);
+        assert_eq!(vms.as_array().unwrap().len(), 4);
+        assert_eq!(vms[0]["status"], "Running");
+        assert_eq!(vms[3]["status"], "Registered");
+        let response = request()
+            .path(&format!("/vms/by-image/{}", VULNERABLE))
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response), json!([]));
+    }
+}
@@ -0,0 +1,168 @@
+//! Bulk registration from NixOS MicroVM module definitions.
+
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+use super::{check_vm, claim_namespace};
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::models::VM;
+use crate::nixos;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("vms" / "import-nixos-module"))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .then(import_nixos_module)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::post;
+
+    use super::extract::Json;
+
+    axum::Router::new()
+        .route(
+            "/vms/import-nixos-module",
+            post(|state, Json(module)| import_nixos_module(module, state)),
+        )
+        .with_state(state)
+}
+
+/// Registers every enabled VM of a `config.ghaf.virtualization.microvm`
+/// attribute set. All definitions are converted and validated before any is
+/// written, so a bad definition leaves the registry untouched.
+async fn import_nixos_module(
+    module: Value,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let definitions = module.as_object().ok_or_else(|| {
+        RegistryError::BadRequest("expected the MicroVM attribute set as an object".to_string())
+    })?;
+    let mut con = state.connection().await?;
+    let mut vms = Vec::new();
+    let mut skipped = Vec::new();
+    for (attr, definition) in definitions {
+        if !nixos::is_enabled(definition) {
+            skipped.push(attr.clone());
+            continue;
+        }
+        let mut definition = definition.clone();
+        if let Some(fields) = definition.as_object_mut() {
+            fields
+                .entry("name")
+                .or_insert_with(|| Value::String(attr.clone()));
+        }
+        let mut vm = nixos::nixos_module_to_vm(&definition)?;
+        check_vm(&mut vm, &state).await?;
+        if vms.iter().any(|other: &VM| other.name == vm.name)
+            || storage::get_vm(&mut con, &vm.name).await?.is_some()
+        {
+            return Err(RegistryError::AlreadyExists(vm.name));
+        }
+        vms.push(vm);
+    }
+    for (claimed, vm) in vms.iter().enumerate() {
+        if let Err(e) = claim_namespace(&mut con, &state, vm, None).await {
+            for vm in &vms[..claimed] {
+                storage::release_namespace_slot(&mut con, &vm.namespace).await?;
+            }
+            return Err(e);
+        }
+    }
+    for vm in &mut vms {
+        storage::save_vm(&mut con, vm, None).await?;
+    }
+    let registered: Vec<&str> = vms.iter().map(|vm| vm.name.as_str()).collect();
+    Ok(reply::json(
+        &json!({ "registered": registered, "skipped": skipped }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    fn module() -> serde_json::Value {
+        json!({
+            "net-vm": { "ipAddress": "192.168.100.1", "vsockCID": 1, "priority": 10 },
+            "gui-vm": {
+                "ipAddress": "192.168.100.3",
+                "vsockCID": 3,
+                "dependsOn": ["net-vm"],
+                "mimeTypes": ["application/pdf"]
+            },
+            "audio-vm": { "enable": false, "ipAddress": "192.168.100.5", "vsockCID": 5 }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_import_nixos_module() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let import = |body: serde_json::Value| {
+            request()
+                .method("POST")
+                .path("/vms/import-nixos-module")
+                .json(&body)
+                .reply(&api)
+        };
+
+        let response = import(module()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({ "registered": ["gui-vm", "net-vm"], "skipped": ["audio-vm"] })
+        );
+
+        let response = request()
+            .method("GET")
+            .path("/vms/startup-order")
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response), json!([["net-vm"], ["gui-vm"]]));
+
+        // Re-importing clashes with the existing records.
+        assert_eq!(import(module()).await.status(), 409);
+    }
+
+    #[tokio::test]
+    async fn test_import_is_all_or_nothing() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        register(&api, &sample_vm("keep-vm")).await;
+
+        let response = request()
+            .method("POST")
+            .path("/vms/import-nixos-module")
+            .json(&json!({
+                "net-vm": { "ipAddress": "192.168.100.1", "vsockCID": 1 },
+                "zathura-vm": { "ipAddress": "192.168.100.9" }
+            }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+
+        let response = request().method("GET").path("/list").reply(&api).await;
+        assert_eq!(json_body(&response).as_array().unwrap().len(), 1);
+    }
+}
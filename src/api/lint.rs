@@ -0,0 +1,319 @@
+//! Dry runs of VM registrations, e.g. from CI pipelines. Nothing here
+//! writes to Redis.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::LazyLock;
+
+use jsonschema::Validator;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, validated_json, with_state};
+use crate::dns;
+use crate::error::RegistryError;
+use crate::models::VM;
+use crate::reply::{self, Response};
+use crate::schema;
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::topology;
+use crate::validation;
+
+#[derive(Deserialize, JsonSchema)]
+struct BulkRegisterRequest {
+    vms: Vec<VM>,
+}
+
+static BULK_REGISTER_SCHEMA: LazyLock<Validator> =
+    LazyLock::new(schema::compile::<BulkRegisterRequest>);
+
+/// What registering a batch of VMs would do. Every VM lands in exactly one
+/// list; `valid` is true when all of them would be created.
+#[derive(Serialize, Default)]
+struct Plan {
+    to_create: Vec<String>,
+    to_conflict: Vec<String>,
+    quota_violations: Vec<String>,
+    invalid: Vec<String>,
+    valid: bool,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let lint = warp::post()
+        .and(warp::path!("vms" / "lint"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(lint_vm)
+        .and_then(or_reject);
+
+    let plan = warp::post()
+        .and(warp::path!("vms" / "plan"))
+        .and(validated_json(&BULK_REGISTER_SCHEMA))
+        .and(with_state(state))
+        .then(plan_registration)
+        .and_then(or_reject);
+
+    lint.or(plan)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::post;
+
+    use super::extract::Json;
+
+    axum::Router::new()
+        .route("/vms/lint", post(|state, Json(body)| lint_vm(body, state)))
+        .route(
+            "/vms/plan",
+            post(|state, Json(body)| async move {
+                plan_registration(super::validate(&BULK_REGISTER_SCHEMA, body)?, state).await
+            }),
+        )
+        .with_state(state)
+}
+
+/// Everything that would keep `vm` from being registered, plus dependencies
+/// that are not registered and dependency cycles it would close.
+async fn problems(mut vm: VM, state: &AppState) -> Result<Vec<String>, RegistryError> {
+    let mut errors: Vec<String> = validation::lint_vm(&vm)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let resolver = state.resolver.as_ref();
+    if let Err(e) =
+        dns::confirm_dns_name(resolver, &mut vm.addresses, state.settings.validate_dns).await
+    {
+        errors.push(e.to_string());
+    }
+
+    let mut con = state.connection().await?;
+    let mut vms = storage::list_vms(&mut con).await?;
+    if vms.iter().any(|other| other.name == vm.name) {
+        errors.push(RegistryError::AlreadyExists(vm.name.clone()).to_string());
+    }
+    if let Some(&quota) = state.settings.namespace_quotas.get(&vm.namespace) {
+        let count = vms
+            .iter()
+            .filter(|other| other.namespace == vm.namespace)
+            .count();
+        if count >= quota as usize {
+            errors.push(RegistryError::QuotaExceeded(vm.namespace.clone()).to_string());
+        }
+    }
+    let registered: HashSet<&str> = vms.iter().map(|other| other.name.as_str()).collect();
+    for dependency in &vm.dependencies {
+        if *dependency != vm.name && !registered.contains(dependency.as_str()) {
+            errors.push(format!("dependency '{}' is not registered", dependency));
+        }
+    }
+
+    vms.retain(|other| !other.is_template && other.name != vm.name);
+    vms.push(vm);
+    if let Err(cycle) = topology::topological_sort(&vms) {
+        errors.push(cycle.to_string());
+    }
+    Ok(errors)
+}
+
+/// Validates a VM definition without writing it. Reports every problem
+/// found instead of stopping at the first.
+async fn lint_vm(
+    body: serde_json::Value,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let errors = match serde_json::from_value::<VM>(body) {
+        Ok(vm) => problems(vm, &state).await?,
+        Err(e) => vec![format!("invalid VM definition: {}", e)],
+    };
+    Ok(reply::json(&if errors.is_empty() {
+        json!({ "valid": true })
+    } else {
+        json!({ "valid": false, "errors": errors })
+    }))
+}
+
+/// Sorts each VM of the request by what registering the whole batch in
+/// order would do to it. VMs planned for creation count against their
+/// namespace quota for the VMs after them.
+async fn plan_registration(
+    request: BulkRegisterRequest,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let existing = storage::list_vms(&mut con).await?;
+    let mut taken: HashSet<String> = existing.iter().map(|vm| vm.name.clone()).collect();
+    let mut namespace_counts: HashMap<String, usize> = HashMap::new();
+    for vm in &existing {
+        *namespace_counts.entry(vm.namespace.clone()).or_default() += 1;
+    }
+
+    let mut plan = Plan::default();
+    for mut vm in request.vms {
+        let resolver = state.resolver.as_ref();
+        let checked =
+            dns::confirm_dns_name(resolver, &mut vm.addresses, state.settings.validate_dns)
+                .await
+                .and_then(|()| validation::validate_vm(&vm));
+        let count = namespace_counts.entry(vm.namespace.clone()).or_default();
+        let quota = state.settings.namespace_quotas.get(&vm.namespace).copied();
+        if checked.is_err() {
+            plan.invalid.push(vm.name);
+        } else if !taken.insert(vm.name.clone()) {
+            plan.to_conflict.push(vm.name);
+        } else if quota.is_some_and(|quota| *count >= quota as usize) {
+            plan.quota_violations.push(vm.name);
+        } else {
+            *count += 1;
+            plan.to_create.push(vm.name);
+        }
+    }
+    plan.valid =
+        plan.to_conflict.is_empty() && plan.quota_violations.is_empty() && plan.invalid.is_empty();
+    Ok(reply::json(&plan))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::settings::Settings;
+    use crate::test_util::{
+        json_body, redis_state_with, register, request, sample_vm, test_settings,
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_lint_vm() {
+        let settings = Settings {
+            namespace_quotas: [("ci".to_string(), 1)].into_iter().collect(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut ci_vm = sample_vm("ci-vm");
+        ci_vm.namespace = "ci".to_string();
+        assert_eq!(register(&api, &ci_vm).await.status(), 200);
+        let mut dependent = sample_vm("dependent-vm");
+        dependent.dependencies = vec!["lint-vm".to_string()];
+        assert_eq!(register(&api, &dependent).await.status(), 200);
+        let lint = |body: serde_json::Value| {
+            request()
+                .method("POST")
+                .path("/vms/lint")
+                .json(&body)
+                .reply(&api)
+        };
+
+        let mut vm = sample_vm("lint-vm");
+        vm.namespace = "ci".to_string();
+        vm.mime_types = vec!["not a mime type".to_string()];
+        vm.tags = ["Bad Tag".to_string()].into_iter().collect();
+        vm.dependencies = vec!["dependent-vm".to_string(), "ghost-vm".to_string()];
+        let response = lint(serde_json::to_value(&vm).unwrap()).await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert_eq!(body["valid"], false);
+        let errors: Vec<&str> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error.as_str().unwrap())
+            .collect();
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        for expected in [
+            "not a mime type",
+            "Bad Tag",
+            "namespace 'ci' has reached its VM quota",
+            "dependency 'ghost-vm' is not registered",
+            "dependency cycle",
+        ] {
+            assert!(
+                errors.iter().any(|error| error.contains(expected)),
+                "no error mentions '{}': {:?}",
+                expected,
+                errors
+            );
+        }
+
+        let body = json_body(&lint(serde_json::to_value(sample_vm("clean-vm")).unwrap()).await);
+        assert_eq!(body, json!({ "valid": true }));
+        let response = request()
+            .method("GET")
+            .path("/vm/clean-vm")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+        let body = json_body(&lint(json!({ "name": "incomplete-vm" })).await);
+        assert_eq!(body["valid"], false);
+    }
+
+    #[tokio::test]
+    async fn test_plan_registration() {
+        let settings = Settings {
+            namespace_quotas: [("ci".to_string(), 1)].into_iter().collect(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(
+            register(&api, &sample_vm("plan-existing")).await.status(),
+            200
+        );
+        let in_ci = |name: &str| {
+            let mut vm = sample_vm(name);
+            vm.namespace = "ci".to_string();
+            vm
+        };
+        let mut invalid = sample_vm("plan-invalid");
+        invalid.namespace = "Not A Namespace".to_string();
+        let plan = |vms: Vec<crate::models::VM>| {
+            request()
+                .method("POST")
+                .path("/vms/plan")
+                .json(&json!({ "vms": vms }))
+                .reply(&api)
+        };
+
+        let response = plan(vec![
+            sample_vm("plan-new"),
+            sample_vm("plan-existing"),
+            in_ci("plan-ci-1"),
+            in_ci("plan-ci-2"),
+            sample_vm("plan-new"),
+            invalid,
+        ])
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({
+                "to_create": ["plan-new", "plan-ci-1"],
+                "to_conflict": ["plan-existing", "plan-new"],
+                "quota_violations": ["plan-ci-2"],
+                "invalid": ["plan-invalid"],
+                "valid": false,
+            })
+        );
+
+        let body = json_body(&plan(vec![sample_vm("plan-new")]).await);
+        assert_eq!(body["valid"], true);
+        let response = request()
+            .method("GET")
+            .path("/vm/plan-new")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
@@ -0,0 +1,585 @@
+//! Agent heartbeats and detection of VMs whose agent has gone quiet.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use hyper::http::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::audit;
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::events;
+use crate::models::{VMStatus, VM};
+use crate::reply::{self, Response};
+use crate::state::{AppState, RedisConnection, StateExtension};
+use crate::storage;
+
+/// Seconds within which a VM's agent must have sent a heartbeat for the VM
+/// to count as ready.
+const READINESS_HEARTBEAT_SECS: i64 = 60;
+
+#[derive(Deserialize)]
+struct StaleQuery {
+    threshold_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ReapQuery {
+    threshold_secs: Option<u64>,
+    #[serde(default)]
+    delete: bool,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let heartbeat = warp::post()
+        .and(warp::path!("vm" / String / "heartbeat"))
+        .and(with_state(state.clone()))
+        .then(heartbeat)
+        .and_then(or_reject);
+
+    let stale = warp::get()
+        .and(warp::path!("vms" / "stale"))
+        .and(warp::query::<StaleQuery>())
+        .and(with_state(state.clone()))
+        .then(list_stale)
+        .and_then(or_reject);
+
+    let readiness = warp::get()
+        .and(warp::path!("vm" / String / "readiness"))
+        .and(with_state(state.clone()))
+        .then(get_readiness)
+        .and_then(or_reject);
+
+    let liveness = warp::get()
+        .and(warp::path!("vm" / String / "liveness"))
+        .and(with_state(state.clone()))
+        .then(get_liveness)
+        .and_then(or_reject);
+
+    let lease = warp::get()
+        .and(warp::path!("vm" / String / "lease"))
+        .and(with_state(state.clone()))
+        .then(get_lease)
+        .and_then(or_reject);
+
+    let reap = warp::post()
+        .and(warp::path!("admin" / "reap-stale"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::query::<ReapQuery>())
+        .and(with_state(state))
+        .then(reap_stale)
+        .and_then(or_reject);
+
+    heartbeat
+        .or(stale)
+        .or(readiness)
+        .or(liveness)
+        .or(lease)
+        .or(reap)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Admin, Path, Query, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/heartbeat",
+            post(|Path(name), state| heartbeat(name, state)),
+        )
+        .route(
+            "/vms/stale",
+            get(|Query(query), state| list_stale(query, state)),
+        )
+        .route(
+            "/vm/:name/readiness",
+            get(|Path(name), state| get_readiness(name, state)),
+        )
+        .route(
+            "/vm/:name/liveness",
+            get(|Path(name), state| get_liveness(name, state)),
+        )
+        .route(
+            "/vm/:name/lease",
+            get(|Path(name), state| get_lease(name, state)),
+        )
+        .route(
+            "/admin/reap-stale",
+            post(|_: RequireRole<Admin>, Query(query), state| reap_stale(query, state)),
+        )
+        .with_state(state)
+}
+
+/// The requested staleness threshold, or the configured default.
+fn threshold(secs: Option<u64>, state: &AppState) -> Result<Duration, RegistryError> {
+    let secs = secs.unwrap_or(state.settings.stale_threshold_secs);
+    i64::try_from(secs)
+        .ok()
+        .and_then(Duration::try_seconds)
+        .ok_or_else(|| RegistryError::BadRequest("threshold_secs is too large".to_string()))
+}
+
+async fn heartbeat(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::record_heartbeat(&mut con, &name).await?;
+    Ok(reply::json(
+        &json!({ "name": vm.name, "last_heartbeat_at": vm.last_heartbeat_at }),
+    ))
+}
+
+/// Running VMs without a heartbeat for more than `threshold_secs`.
+async fn list_stale(
+    query: StaleQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let threshold = threshold(query.threshold_secs, &state)?;
+    let mut con = state.connection().await?;
+    let vms = storage::list_stale_vms(&mut con, threshold).await?;
+    Ok(reply::json(&vms))
+}
+
+/// Why `vm` is not ready to accept requests: it must be running, its
+/// dependencies (`dependencies`, looked up in the same order) must be
+/// running and its agent must have sent a heartbeat recently.
+fn readiness_reasons(vm: &VM, dependencies: &[Option<VM>], now: DateTime<Utc>) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if vm.status != VMStatus::Running {
+        reasons.push(format!("VM is {:?}, not Running", vm.status));
+    }
+    for (name, dependency) in vm.dependencies.iter().zip(dependencies) {
+        match dependency {
+            None => reasons.push(format!("dependency '{}' is not registered", name)),
+            Some(dependency) if dependency.status != VMStatus::Running => {
+                reasons.push(format!("dependency '{}' is {:?}", name, dependency.status))
+            }
+            Some(_) => {}
+        }
+    }
+    match vm.last_heartbeat_at {
+        None => reasons.push("no heartbeat received".to_string()),
+        Some(at) if now - at > Duration::seconds(READINESS_HEARTBEAT_SECS) => reasons.push(
+            format!("last heartbeat was {}s ago", (now - at).num_seconds()),
+        ),
+        Some(_) => {}
+    }
+    reasons
+}
+
+/// 200 when the VM is ready to accept requests, else 503 with the reasons.
+async fn get_readiness(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    let dependencies = storage::lookup_vms(&mut con, &vm.dependencies).await?;
+    let reasons = readiness_reasons(&vm, &dependencies, Utc::now());
+    if reasons.is_empty() {
+        Ok(reply::with_status(
+            reply::json(&json!({ "ready": true })),
+            StatusCode::OK,
+        ))
+    } else {
+        Ok(reply::with_status(
+            reply::json(&json!({ "ready": false, "reasons": reasons })),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ))
+    }
+}
+
+/// 200 when the VM's agent sent a heartbeat, or statistics were recorded
+/// for it, within `liveness_threshold_secs`; else 503.
+async fn get_liveness(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let threshold_secs = state.settings.liveness_threshold_secs;
+    let threshold = threshold(Some(threshold_secs), &state)?;
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    let stats_at = storage::stats_timestamp(&mut con, &name).await?;
+    let last_seen = vm.last_heartbeat_at.max(stats_at);
+    let alive = last_seen.is_some_and(|seen| Utc::now() - seen <= threshold);
+    let status = if alive {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(reply::with_status(
+        reply::json(&json!({
+            "alive": alive,
+            "last_seen": last_seen,
+            "threshold_secs": threshold_secs,
+        })),
+        status,
+    ))
+}
+
+async fn get_lease(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    Ok(reply::json(&lease_status(&mut con, &vm).await?))
+}
+
+/// Whether `vm` is leased, by whom (only the first 8 characters of the
+/// token) and until when. A lease lasts as long as the TTL on the VM key.
+pub(super) async fn lease_status(
+    con: &mut RedisConnection,
+    vm: &VM,
+) -> Result<Value, RegistryError> {
+    let ttl = storage::vm_ttl(con, &vm.name).await?;
+    let expires_at = ttl
+        .and_then(|ttl| i64::try_from(ttl).ok())
+        .and_then(Duration::try_seconds)
+        .map(|ttl| Utc::now() + ttl);
+    let token_prefix = vm
+        .lease_token
+        .as_ref()
+        .filter(|_| ttl.is_some())
+        .map(|token| token.chars().take(8).collect::<String>());
+    Ok(json!({
+        "has_lease": ttl.is_some(),
+        "lease_token_prefix": token_prefix,
+        "expires_at": expires_at,
+        "ttl_remaining_seconds": ttl,
+    }))
+}
+
+/// Marks every stale VM `Failed` and announces it with a `reaped` event;
+/// with `delete=true` the VMs are unregistered as well.
+async fn reap_stale(
+    query: ReapQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let threshold = threshold(query.threshold_secs, &state)?;
+    let mut con = state.connection().await?;
+    let stale = storage::list_stale_vms(&mut con, threshold).await?;
+    let mut reaped = Vec::with_capacity(stale.len());
+    for previous in stale {
+        let mut vm = previous.clone();
+        vm.status = VMStatus::Failed;
+        storage::save_vm(&mut con, &mut vm, Some(&previous)).await?;
+        audit::record_audit_event(&mut con, &vm.name, None, &previous, &vm).await?;
+        events::publish(&mut con, "reaped", &vm).await?;
+        reaped.push(vm);
+    }
+    if query.delete {
+        storage::delete_vms(&mut con, &reaped).await?;
+    }
+    let names: Vec<&str> = reaped.iter().map(|vm| vm.name.as_str()).collect();
+    Ok(reply::json(&json!({
+        "reaped": names,
+        "action": if query.delete { "deleted" } else { "failed" },
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::events::VM_EVENTS_CHANNEL;
+    use crate::models::VMStatus;
+    use crate::state::{AppState, RedisConnection};
+    use crate::storage;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use chrono::{Duration, Utc};
+    use futures_util::StreamExt;
+    use redis::AsyncCommands;
+
+    /// Overwrites the stored heartbeat of `name` with one from an hour ago.
+    async fn backdate_heartbeat(con: &mut RedisConnection, name: &str) {
+        let mut vm = storage::require_vm(con, name).await.unwrap();
+        vm.last_heartbeat_at = Some(Utc::now() - Duration::hours(1));
+        let _: () = con
+            .set(
+                format!("ghaf:vm:{}", name),
+                serde_json::to_string(&vm).unwrap(),
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn start(api_state: &AppState, names: &[&str]) {
+        let api = routes(api_state.clone());
+        for name in names {
+            assert_eq!(register(&api, &sample_vm(name)).await.status(), 200);
+            let path = format!("/run/{}", name);
+            request().method("POST").path(&path).reply(&api).await;
+        }
+    }
+
+    #[test]
+    fn test_readiness_reasons() {
+        let now = Utc::now();
+        let mut vm = sample_vm("gui-vm");
+        vm.status = VMStatus::Running;
+        vm.dependencies = vec!["net-vm".to_string(), "audio-vm".to_string()];
+        vm.last_heartbeat_at = Some(now - Duration::seconds(5));
+        let mut net = sample_vm("net-vm");
+        net.status = VMStatus::Running;
+        let mut audio = sample_vm("audio-vm");
+        audio.status = VMStatus::Running;
+        assert!(
+            super::readiness_reasons(&vm, &[Some(net.clone()), Some(audio.clone())], now)
+                .is_empty()
+        );
+
+        audio.status = VMStatus::Stopped;
+        vm.last_heartbeat_at = Some(now - Duration::seconds(90));
+        assert_eq!(
+            super::readiness_reasons(&vm, &[None, Some(audio)], now),
+            [
+                "dependency 'net-vm' is not registered",
+                "dependency 'audio-vm' is Stopped",
+                "last heartbeat was 90s ago",
+            ]
+        );
+
+        vm.status = VMStatus::Registered;
+        vm.dependencies.clear();
+        vm.last_heartbeat_at = None;
+        assert_eq!(
+            super::readiness_reasons(&vm, &[], now),
+            ["VM is Registered, not Running", "no heartbeat received"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        start(&ctx.state, &["ready-vm"]).await;
+        let readiness = || request().path("/vm/ready-vm/readiness").reply(&api);
+
+        let response = readiness().await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({ "ready": false, "reasons": ["no heartbeat received"] })
+        );
+
+        request()
+            .method("POST")
+            .path("/vm/ready-vm/heartbeat")
+            .reply(&api)
+            .await;
+        let response = readiness().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response), serde_json::json!({ "ready": true }));
+
+        let mut con = ctx.state.connection().await.unwrap();
+        backdate_heartbeat(&mut con, "ready-vm").await;
+        let response = readiness().await;
+        assert_eq!(response.status(), 503);
+        let body = json_body(&response);
+        assert_eq!(body["ready"], false);
+        assert!(body["reasons"][0]
+            .as_str()
+            .unwrap()
+            .starts_with("last heartbeat was"));
+    }
+
+    #[tokio::test]
+    async fn test_liveness() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        start(&ctx.state, &["live-vm"]).await;
+        let liveness = || request().path("/vm/live-vm/liveness").reply(&api);
+
+        let response = liveness().await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({ "alive": false, "last_seen": null, "threshold_secs": 300 })
+        );
+
+        let mut con = ctx.state.connection().await.unwrap();
+        request()
+            .method("POST")
+            .path("/vm/live-vm/heartbeat")
+            .reply(&api)
+            .await;
+        let response = liveness().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["alive"], true);
+
+        backdate_heartbeat(&mut con, "live-vm").await;
+        let response = liveness().await;
+        assert_eq!(response.status(), 503);
+        let body = json_body(&response);
+        assert_eq!(body["alive"], false);
+        assert!(body["last_seen"].is_string());
+
+        // A fresh statistics sample is evidence of life too.
+        let _: () = con
+            .hset(
+                storage::stats_key("live-vm"),
+                "timestamp",
+                Utc::now().timestamp(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(liveness().await.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_stale_vms() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        start(&ctx.state, &["quiet-vm", "alive-vm"]).await;
+        assert_eq!(register(&api, &sample_vm("stopped-vm")).await.status(), 200);
+        let response = request()
+            .method("POST")
+            .path("/vm/alive-vm/heartbeat")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let mut con = ctx.state.connection().await.unwrap();
+        for name in ["quiet-vm", "stopped-vm"] {
+            backdate_heartbeat(&mut con, name).await;
+        }
+
+        let stale = |threshold: u64| {
+            request()
+                .method("GET")
+                .path(&format!("/vms/stale?threshold_secs={}", threshold))
+                .reply(&api)
+        };
+        let response = stale(300).await;
+        assert_eq!(response.status(), 200);
+        let names: Vec<String> = json_body(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|vm| vm["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["quiet-vm"]);
+        assert_eq!(json_body(&stale(7200).await), serde_json::json!([]));
+
+        let response = request()
+            .method("POST")
+            .path("/vm/missing-vm/heartbeat")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_reap_stale() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        start(&ctx.state, &["stale-vm", "gone-vm", "alive-vm"]).await;
+        let mut con = ctx.state.connection().await.unwrap();
+        for name in ["stale-vm", "gone-vm"] {
+            backdate_heartbeat(&mut con, name).await;
+        }
+
+        let client = redis::Client::open(ctx.state.settings.redis_url.as_str()).unwrap();
+        let mut pubsub = client.get_async_connection().await.unwrap().into_pubsub();
+        pubsub.subscribe(VM_EVENTS_CHANNEL).await.unwrap();
+
+        let reap = |path: &'static str| request().method("POST").path(path).reply(&api);
+        let response = reap("/admin/reap-stale").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({"reaped": ["gone-vm", "stale-vm"], "action": "failed"})
+        );
+        for name in ["gone-vm", "stale-vm"] {
+            let vm = storage::require_vm(&mut con, name).await.unwrap();
+            assert_eq!(vm.status, VMStatus::Failed);
+        }
+        let alive = storage::require_vm(&mut con, "alive-vm").await.unwrap();
+        assert_eq!(alive.status, VMStatus::Running);
+
+        let mut messages = pubsub.on_message();
+        for name in ["gone-vm", "stale-vm"] {
+            let payload: String = messages.next().await.unwrap().get_payload().unwrap();
+            let event: serde_json::Value = serde_json::from_str(&payload).unwrap();
+            assert_eq!(event["event"], "reaped");
+            assert_eq!(event["name"], name);
+            assert_eq!(event["status"], "Failed");
+        }
+
+        // Reaped VMs are no longer running, so a second pass finds nothing.
+        let response = reap("/admin/reap-stale?delete=true").await;
+        assert_eq!(json_body(&response)["reaped"], serde_json::json!([]));
+
+        start(&ctx.state, &["deleted-vm"]).await;
+        backdate_heartbeat(&mut con, "deleted-vm").await;
+        let response = reap("/admin/reap-stale?delete=true&threshold_secs=60").await;
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({"reaped": ["deleted-vm"], "action": "deleted"})
+        );
+        assert!(storage::get_vm(&mut con, "deleted-vm")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lease() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("leased-vm");
+        vm.lease_token = Some("a1b2c3d4e5f6".to_string());
+        assert_eq!(register(&api, &vm).await.status(), 200);
+        let lease = || {
+            request()
+                .method("GET")
+                .path("/vm/leased-vm/lease")
+                .reply(&api)
+        };
+
+        let body = json_body(&lease().await);
+        assert_eq!(body["has_lease"], false);
+        assert_eq!(body["expires_at"], serde_json::Value::Null);
+
+        let mut con = ctx.state.connection().await.unwrap();
+        let _: () = con.expire("ghaf:vm:leased-vm", 60).await.unwrap();
+        let response = lease().await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert_eq!(body["has_lease"], true);
+        assert_eq!(body["lease_token_prefix"], "a1b2c3d4");
+        let ttl = body["ttl_remaining_seconds"].as_u64().unwrap();
+        assert!((58..=60).contains(&ttl), "{}", ttl);
+        let expires_at: chrono::DateTime<Utc> =
+            serde_json::from_value(body["expires_at"].clone()).unwrap();
+        assert!(
+            (expires_at - Utc::now() - Duration::seconds(60))
+                .num_seconds()
+                .abs()
+                <= 2
+        );
+    }
+}
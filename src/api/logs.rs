@@ -0,0 +1,222 @@
+//! Log lines pushed by VM agents, buffered so they can be read without
+//! logging into the VM.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use hyper::http::StatusCode;
+use serde::Deserialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::{LogEntry, LogLevel};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Lines returned when a request does not name a number.
+const DEFAULT_LINES: usize = 100;
+
+#[derive(Deserialize)]
+struct LogQuery {
+    lines: Option<usize>,
+    level: Option<LogLevel>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let push = warp::post()
+        .and(warp::path!("vm" / String / "log"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(push_log)
+        .and_then(or_reject);
+
+    let read = warp::get()
+        .and(warp::path!("vm" / String / "log"))
+        .and(warp::query::<LogQuery>())
+        .and(with_state(state.clone()))
+        .then(read_log)
+        .and_then(or_reject);
+
+    let clear = warp::delete()
+        .and(warp::path!("vm" / String / "log"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(with_state(state))
+        .then(clear_log)
+        .and_then(or_reject);
+
+    push.or(read).or(clear)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    use super::extract::{Json, Operator, Path, Query, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/log",
+            get(|Path(name), Query(query), state| read_log(name, query, state))
+                .post(|Path(name), state, Json(entry)| push_log(name, entry, state))
+                .delete(|Path(name), _: RequireRole<Operator>, state| clear_log(name, state)),
+        )
+        .with_state(state)
+}
+
+async fn push_log(
+    name: String,
+    mut entry: LogEntry,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    entry.timestamp.get_or_insert_with(Utc::now);
+    storage::append_log(&mut con, &name, &entry).await?;
+    Ok(reply::status(StatusCode::NO_CONTENT))
+}
+
+/// The last `lines` lines of the VM's log, oldest first, only those of
+/// `level` if given.
+async fn read_log(
+    name: String,
+    query: LogQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let log = storage::get_log(&mut con, &name).await?;
+    Ok(reply::json(&tail(
+        log,
+        query.level,
+        query.lines.unwrap_or(DEFAULT_LINES),
+    )))
+}
+
+fn tail(log: Vec<LogEntry>, level: Option<LogLevel>, lines: usize) -> Vec<LogEntry> {
+    let mut matching: Vec<LogEntry> = log
+        .into_iter()
+        .filter(|entry| level.is_none_or(|level| entry.level == level))
+        .collect();
+    let skip = matching.len().saturating_sub(lines);
+    matching.drain(..skip);
+    matching
+}
+
+async fn clear_log(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    storage::clear_log(&mut con, &name).await?;
+    Ok(reply::status(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    fn entry(level: LogLevel, message: &str) -> LogEntry {
+        LogEntry {
+            level,
+            message: message.to_string(),
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_tail() {
+        let log = vec![
+            entry(LogLevel::Info, "a"),
+            entry(LogLevel::Error, "b"),
+            entry(LogLevel::Info, "c"),
+            entry(LogLevel::Error, "d"),
+            entry(LogLevel::Error, "e"),
+        ];
+        let messages = |entries: Vec<LogEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(messages(tail(log.clone(), None, 2)), ["d", "e"]);
+        assert_eq!(
+            messages(tail(log.clone(), Some(LogLevel::Error), 100)),
+            ["b", "d", "e"]
+        );
+        assert_eq!(messages(tail(log.clone(), Some(LogLevel::Info), 1)), ["c"]);
+        assert!(tail(log, Some(LogLevel::Warn), 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_vm_log() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("log-vm")).await.status(), 200);
+        for (level, message) in [
+            ("info", "booted"),
+            ("error", "mount failed"),
+            ("warn", "retrying"),
+            ("error", "mount failed again"),
+            ("info", "gave up"),
+        ] {
+            let response = request()
+                .method("POST")
+                .path("/vm/log-vm/log")
+                .json(&json!({ "level": level, "message": message }))
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 204);
+        }
+        let read = |query: &str| {
+            request()
+                .method("GET")
+                .path(&format!("/vm/log-vm/log{}", query))
+                .reply(&api)
+        };
+
+        let response = read("?level=error").await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let lines = body.as_array().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "mount failed");
+        assert_eq!(lines[1]["message"], "mount failed again");
+        assert!(lines.iter().all(|line| line["level"] == "error"));
+        assert!(lines[0]["timestamp"].is_string());
+
+        let response = read("?lines=2").await;
+        assert_eq!(json_body(&response).as_array().unwrap().len(), 2);
+        assert_eq!(json_body(&response)[1]["message"], "gave up");
+
+        let response = request()
+            .method("DELETE")
+            .path("/vm/log-vm/log")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 204);
+        assert_eq!(json_body(&read("").await), json!([]));
+
+        let response = request()
+            .method("POST")
+            .path("/vm/missing-vm/log")
+            .json(&json!({ "level": "info", "message": "hello" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
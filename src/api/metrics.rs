@@ -0,0 +1,133 @@
+//! The registry's own metrics, in the Prometheus text exposition format.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use hyper::http::header::CONTENT_TYPE;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::with_state;
+use crate::reply::{self, Response};
+use crate::state::{AppState, ConnectionStats, StateExtension};
+
+/// Path of the metrics endpoint, which scrapers poll without credentials.
+pub const METRICS_PATH: &str = "/metrics";
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("metrics"))
+        .and(with_state(state))
+        .map(metrics)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    axum::Router::new()
+        .route(METRICS_PATH, get(|state| async move { metrics(state) }))
+        .with_state(state)
+}
+
+fn metrics(StateExtension(state): StateExtension<Arc<AppState>>) -> Response {
+    reply::with_header(
+        connection_text(&state.connection_stats()),
+        CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+    )
+}
+
+/// Renders the Redis connection usage `GET /admin/pool-stats` reports as
+/// gauges, plus the handles held on the shared connection.
+fn connection_text(stats: &ConnectionStats) -> String {
+    let gauges = [
+        (
+            "ghaf_redis_pool_size",
+            "Redis connections open.",
+            stats.size,
+        ),
+        (
+            "ghaf_redis_pool_available",
+            "Open Redis connections that can take a command.",
+            stats.available,
+        ),
+        (
+            "ghaf_redis_pool_waiting",
+            "Callers waiting for a Redis connection.",
+            stats.waiting,
+        ),
+        (
+            "ghaf_redis_pool_max_size",
+            "Redis connections the registry opens at most.",
+            stats.max_size,
+        ),
+        (
+            "ghaf_redis_connection_handles",
+            "Handles on the shared Redis connection held by handlers and tasks.",
+            stats.handles,
+        ),
+    ];
+    let mut text = String::new();
+    for (name, help, value) in gauges {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} gauge", name);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::state::AppState;
+    use crate::test_util::{redis_state, request, test_settings};
+
+    fn gauge(body: &[u8], name: &str) -> u64 {
+        std::str::from_utf8(body)
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{} missing", name))
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_before_connecting() {
+        let api = routes(AppState::new(test_settings()).unwrap());
+        let response = request().method("GET").path("/metrics").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4"
+        );
+        let body = response.body();
+        assert_eq!(gauge(body, "ghaf_redis_pool_size"), 0);
+        assert_eq!(gauge(body, "ghaf_redis_pool_available"), 0);
+        assert_eq!(gauge(body, "ghaf_redis_pool_waiting"), 0);
+        assert_eq!(gauge(body, "ghaf_redis_pool_max_size"), 1);
+        assert_eq!(gauge(body, "ghaf_redis_connection_handles"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_handles() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let handles = || async {
+            let response = request().method("GET").path("/metrics").reply(&api).await;
+            gauge(response.body(), "ghaf_redis_connection_handles")
+        };
+        let before = handles().await;
+        let held = ctx.state.connection().await.unwrap();
+        assert_eq!(handles().await, before + 1);
+        drop(held);
+        assert_eq!(handles().await, before);
+    }
+}
@@ -0,0 +1,319 @@
+//! MIME type associations and the MIME routing index.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use redis::AsyncCommands;
+use serde::Deserialize;
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::validation;
+
+#[derive(Deserialize)]
+struct MimeQuery {
+    #[serde(rename = "type")]
+    mime_type: String,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let put_mime_types = warp::put()
+        .and(warp::path!("vm" / String / "mime-types"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(put_mime_types)
+        .and_then(or_reject);
+
+    let by_mime = warp::get()
+        .and(warp::path!("vms" / "by-mime"))
+        .and(warp::query::<MimeQuery>())
+        .and(with_state(state.clone()))
+        .then(get_vm_by_mime)
+        .and_then(or_reject);
+
+    let mime_index = warp::get()
+        .and(warp::path!("vms" / "mime-index"))
+        .and(with_state(state.clone()))
+        .then(get_mime_index)
+        .and_then(or_reject);
+
+    let mime_conflicts = warp::get()
+        .and(warp::path!("vms" / "mime-conflicts"))
+        .and(with_state(state))
+        .then(get_mime_conflicts)
+        .and_then(or_reject);
+
+    put_mime_types.or(by_mime).or(mime_index).or(mime_conflicts)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, put};
+
+    use super::extract::{Json, Path, Query};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/mime-types",
+            put(|Path(name), state, Json(mime_types)| put_mime_types(name, mime_types, state)),
+        )
+        .route(
+            "/vms/by-mime",
+            get(|Query(query), state| get_vm_by_mime(query, state)),
+        )
+        .route("/vms/mime-index", get(get_mime_index))
+        .route("/vms/mime-conflicts", get(get_mime_conflicts))
+        .with_state(state)
+}
+
+/// Replaces the full MIME type list of a VM and updates the index with it.
+async fn put_mime_types(
+    name: String,
+    mime_types: Vec<String>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_mime_types(&mime_types)?;
+    let mut con = state.connection().await?;
+    let previous = storage::require_unsealed_vm(&mut con, &name).await?;
+    let mut vm = previous.clone();
+    vm.mime_types = mime_types;
+    storage::save_vm(&mut con, &mut vm, Some(&previous)).await?;
+    Ok(reply::json(&vm))
+}
+
+/// Returns the VM the MIME index routes `type` to.
+async fn get_vm_by_mime(
+    query: MimeQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let route: Option<String> = con
+        .hget(storage::MIME_INDEX_KEY, &query.mime_type)
+        .await
+        .map_err(RegistryError::from)?;
+    let name = route.ok_or(RegistryError::NoMimeHandler(query.mime_type))?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    Ok(reply::json(&vm))
+}
+
+/// Returns the whole MIME routing table, read with a single `HGETALL`.
+async fn get_mime_index(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let index: BTreeMap<String, String> = con
+        .hgetall(storage::MIME_INDEX_KEY)
+        .await
+        .map_err(RegistryError::from)?;
+    Ok(reply::json(&index))
+}
+
+/// MIME types that more than one VM handles, with all their handlers. The
+/// routed types come from one `HGETALL` of the routing table; it only names
+/// the routed VM, so the handlers are read from each type's handler set.
+async fn get_mime_conflicts(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let index: BTreeMap<String, String> = con
+        .hgetall(storage::MIME_INDEX_KEY)
+        .await
+        .map_err(RegistryError::from)?;
+    let mut conflicts = BTreeMap::new();
+    for mime_type in index.into_keys() {
+        let mut handlers: Vec<String> = con
+            .smembers(storage::mime_key(&mime_type))
+            .await
+            .map_err(RegistryError::from)?;
+        if handlers.len() > 1 {
+            handlers.sort();
+            conflicts.insert(mime_type, handlers);
+        }
+    }
+    Ok(reply::json(&json!({ "conflicts": conflicts })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm, TestClient};
+
+    async fn lookup(api: &impl TestClient, mime_type: &str) -> (u16, Option<String>) {
+        let response = request()
+            .method("GET")
+            .path(&format!("/vms/by-mime?type={}", mime_type))
+            .reply(api)
+            .await;
+        let status = response.status().as_u16();
+        let name =
+            (status == 200).then(|| json_body(&response)["name"].as_str().unwrap().to_string());
+        (status, name)
+    }
+
+    #[tokio::test]
+    async fn test_multiple_mime_types() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut viewer = sample_vm("doc-viewer");
+        viewer.mime_types = vec!["application/pdf".to_string(), "image/png".to_string()];
+        let mut editor = sample_vm("doc-editor");
+        editor.mime_types = vec!["text/plain".to_string(), "application/pdf".to_string()];
+        assert_eq!(register(&api, &viewer).await.status(), 200);
+        assert_eq!(register(&api, &editor).await.status(), 200);
+
+        assert_eq!(
+            lookup(&api, "application/pdf").await.1.as_deref(),
+            Some("doc-viewer")
+        );
+        assert_eq!(
+            lookup(&api, "image/png").await.1.as_deref(),
+            Some("doc-viewer")
+        );
+        assert_eq!(
+            lookup(&api, "text/plain").await.1.as_deref(),
+            Some("doc-editor")
+        );
+        assert_eq!(lookup(&api, "video/mp4").await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_put_mime_types_replaces_index_entries() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut viewer = sample_vm("doc-viewer");
+        viewer.mime_types = vec!["application/pdf".to_string(), "image/png".to_string()];
+        let mut editor = sample_vm("doc-editor");
+        editor.mime_types = vec!["application/pdf".to_string()];
+        register(&api, &viewer).await;
+        register(&api, &editor).await;
+
+        let response = request()
+            .method("PUT")
+            .path("/vm/doc-viewer/mime-types")
+            .json(&["image/jpeg"])
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response)["mime_types"],
+            serde_json::json!(["image/jpeg"])
+        );
+
+        assert_eq!(
+            lookup(&api, "image/jpeg").await.1.as_deref(),
+            Some("doc-viewer")
+        );
+        assert_eq!(lookup(&api, "image/png").await.0, 404);
+        // The remaining handler takes over the dropped route.
+        assert_eq!(
+            lookup(&api, "application/pdf").await.1.as_deref(),
+            Some("doc-editor")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mime_index() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, mime_types) in [
+            ("pdf-vm", vec!["application/pdf"]),
+            ("image-vm", vec!["image/png", "image/jpeg"]),
+            ("text-vm", vec!["text/plain"]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.mime_types = mime_types.into_iter().map(String::from).collect();
+            register(&api, &vm).await;
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/mime-index")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({
+                "application/pdf": "pdf-vm",
+                "image/jpeg": "image-vm",
+                "image/png": "image-vm",
+                "text/plain": "text-vm",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_invalid_mime_type() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        register(&api, &sample_vm("doc-viewer")).await;
+
+        let response = request()
+            .method("PUT")
+            .path("/vm/doc-viewer/mime-types")
+            .json(&["not a mime type"])
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_mime_conflicts() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, mime_types) in [
+            ("pdf-vm-1", vec!["application/pdf", "image/png"]),
+            ("pdf-vm-2", vec!["application/pdf"]),
+            ("media-vm", vec!["video/mp4"]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.mime_types = mime_types.into_iter().map(String::from).collect();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let conflicts = || {
+            request()
+                .method("GET")
+                .path("/vms/mime-conflicts")
+                .reply(&api)
+        };
+        let response = conflicts().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({
+                "conflicts": { "application/pdf": ["pdf-vm-1", "pdf-vm-2"] }
+            })
+        );
+
+        request()
+            .method("DELETE")
+            .path("/unregister/pdf-vm-2")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            json_body(&conflicts().await),
+            serde_json::json!({ "conflicts": {} })
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Operations on whole namespaces of VMs.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::VMStatus;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::delete()
+        .and(warp::path!("vms" / "namespace" / String))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::query::<DeleteQuery>())
+        .and(with_state(state))
+        .then(delete_namespace)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::delete;
+
+    use super::extract::{Admin, Path, Query, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vms/namespace/:namespace",
+            delete(
+                |Path(namespace), _: RequireRole<Admin>, Query(query), state| {
+                    delete_namespace(namespace, query, state)
+                },
+            ),
+        )
+        .with_state(state)
+}
+
+/// Unregisters every VM in `namespace` in one transaction. Running VMs are
+/// only removed when `force` is set.
+async fn delete_namespace(
+    namespace: String,
+    query: DeleteQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_namespace_vms(&mut con, &namespace).await?;
+    let running: Vec<&str> = vms
+        .iter()
+        .filter(|vm| vm.status == VMStatus::Running)
+        .map(|vm| vm.name.as_str())
+        .collect();
+    if !running.is_empty() {
+        if !query.force {
+            return Err(RegistryError::Conflict(format!(
+                "VMs still running in namespace '{}': {}",
+                namespace,
+                running.join(", ")
+            )));
+        }
+        for name in &running {
+            tracing::info!(vm = %name, namespace = %namespace, "stopping VM");
+        }
+    }
+    storage::delete_vms(&mut con, &vms).await?;
+    let deleted: Vec<&str> = vms.iter().map(|vm| vm.name.as_str()).collect();
+    Ok(reply::json(
+        &json!({ "namespace": namespace, "deleted": deleted }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::settings::Settings;
+    use crate::test_util::{
+        json_body, redis_state, redis_state_with, register, request, sample_vm,
+    };
+
+    #[tokio::test]
+    async fn test_delete_namespace() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for i in 0..5 {
+            let mut vm = sample_vm(&format!("env-vm-{}", i));
+            vm.namespace = "test-env".to_string();
+            vm.mime_types = vec!["text/plain".to_string()];
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        register(&api, &sample_vm("keep-vm")).await;
+        request()
+            .method("POST")
+            .path("/run/env-vm-2")
+            .reply(&api)
+            .await;
+
+        let delete = |path: &str| request().method("DELETE").path(path).reply(&api);
+        let response = delete("/vms/namespace/test-env").await;
+        assert_eq!(response.status(), 409);
+
+        let response = delete("/vms/namespace/test-env?force=true").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response)["deleted"],
+            serde_json::json!(["env-vm-0", "env-vm-1", "env-vm-2", "env-vm-3", "env-vm-4"])
+        );
+
+        let response = request().method("GET").path("/list").reply(&api).await;
+        let names: Vec<String> = json_body(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|vm| vm["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["keep-vm"]);
+        let response = request()
+            .method("GET")
+            .path("/vms/by-mime?type=text/plain")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_delete_namespace_requires_admin() {
+        let settings = Settings {
+            api_tokens: [
+                ("viewer-token".to_string(), Role::Viewer),
+                ("admin-token".to_string(), Role::Admin),
+            ]
+            .into(),
+            ..crate::test_util::test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+
+        for (token, status) in [
+            (None, 401),
+            (Some("viewer-token"), 403),
+            (Some("admin-token"), 200),
+        ] {
+            let mut req = request().method("DELETE").path("/vms/namespace/test-env");
+            if let Some(token) = token {
+                req = req.header("authorization", format!("Bearer {}", token));
+            }
+            assert_eq!(req.reply(&api).await.status(), status, "{:?}", token);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Network-facing configuration polled by the network VM.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use hyper::http::StatusCode;
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::models::{PortMapping, VMStatus};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::validation;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let firewall_rules = warp::get()
+        .and(warp::path!("vm" / String / "firewall-rules"))
+        .and(with_state(state.clone()))
+        .then(get_firewall_rules)
+        .and_then(or_reject);
+
+    let add_port = warp::post()
+        .and(warp::path!("vm" / String / "ports"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(add_port)
+        .and_then(or_reject);
+
+    let put_ports = warp::put()
+        .and(warp::path!("vm" / String / "ports"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(put_ports)
+        .and_then(or_reject);
+
+    let get_ports = warp::get()
+        .and(warp::path!("vm" / String / "ports"))
+        .and(with_state(state.clone()))
+        .then(get_ports)
+        .and_then(or_reject);
+
+    let delete_port = warp::delete()
+        .and(warp::path!("vm" / String / "ports" / u16))
+        .and(with_state(state.clone()))
+        .then(delete_port)
+        .and_then(or_reject);
+
+    let all_ports = warp::get()
+        .and(warp::path!("vms" / "ports"))
+        .and(with_state(state.clone()))
+        .then(get_all_ports)
+        .and_then(or_reject);
+
+    let conflict_report = warp::get()
+        .and(warp::path!("vms" / "conflict-report"))
+        .and(with_state(state.clone()))
+        .then(get_conflict_report)
+        .and_then(or_reject);
+
+    let ntp_servers = warp::get()
+        .and(warp::path!("vms" / "ntp-servers"))
+        .and(with_state(state.clone()))
+        .then(list_ntp_servers)
+        .and_then(or_reject);
+
+    let dns_config = warp::get()
+        .and(warp::path!("vms" / "dns-config"))
+        .and(with_state(state))
+        .then(get_dns_config)
+        .and_then(or_reject);
+
+    firewall_rules
+        .or(add_port)
+        .or(put_ports)
+        .or(get_ports)
+        .or(delete_port)
+        .or(all_ports)
+        .or(conflict_report)
+        .or(ntp_servers)
+        .or(dns_config)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{delete, get};
+
+    use super::extract::{Json, Path};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/firewall-rules",
+            get(|Path(name), state| get_firewall_rules(name, state)),
+        )
+        .route(
+            "/vm/:name/ports",
+            get(|Path(name), state| get_ports(name, state))
+                .post(|Path(name), state, Json(port)| add_port(name, port, state))
+                .put(|Path(name), state, Json(ports)| put_ports(name, ports, state)),
+        )
+        .route(
+            "/vm/:name/ports/:port",
+            delete(|Path((name, port)), state| delete_port(name, port, state)),
+        )
+        .route("/vms/ports", get(get_all_ports))
+        .route("/vms/conflict-report", get(get_conflict_report))
+        .route("/vms/ntp-servers", get(list_ntp_servers))
+        .route("/vms/dns-config", get(get_dns_config))
+        .with_state(state)
+}
+
+async fn get_firewall_rules(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    Ok(reply::json(&vm.firewall_rules))
+}
+
+/// Adds one mapping, replacing any existing mapping for the same host port.
+async fn add_port(
+    name: String,
+    port: PortMapping,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_port_mappings(std::slice::from_ref(&port))?;
+    let mut con = state.connection().await?;
+    storage::require_unsealed_vm(&mut con, &name).await?;
+    storage::save_ports(&mut con, &name, &[port], false).await?;
+    let ports = storage::get_ports(&mut con, &name).await?;
+    Ok(reply::json(&ports))
+}
+
+/// Replaces all port mappings of a VM.
+async fn put_ports(
+    name: String,
+    ports: Vec<PortMapping>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_port_mappings(&ports)?;
+    let mut con = state.connection().await?;
+    storage::require_unsealed_vm(&mut con, &name).await?;
+    storage::save_ports(&mut con, &name, &ports, true).await?;
+    let ports = storage::get_ports(&mut con, &name).await?;
+    Ok(reply::json(&ports))
+}
+
+async fn get_ports(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let ports = storage::get_ports(&mut con, &name).await?;
+    Ok(reply::json(&ports))
+}
+
+async fn delete_port(
+    name: String,
+    host_port: u16,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_unsealed_vm(&mut con, &name).await?;
+    if !storage::remove_port(&mut con, &name, host_port).await? {
+        return Err(RegistryError::PortNotMapped(host_port));
+    }
+    Ok(reply::with_status("Port mapping removed.", StatusCode::OK))
+}
+
+/// Maps every claimed host port to the VMs claiming it; ports claimed by
+/// more than one VM are also listed under `conflicts`.
+async fn get_all_ports(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let mut ports: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+    for (name, mappings) in storage::list_all_ports(&mut con).await? {
+        for mapping in mappings {
+            let claimants = ports.entry(mapping.host_port).or_default();
+            if !claimants.contains(&name) {
+                claimants.push(name.clone());
+            }
+        }
+    }
+    let conflicts: Vec<u16> = ports
+        .iter()
+        .filter(|(_, claimants)| claimants.len() > 1)
+        .map(|(port, _)| *port)
+        .collect();
+    Ok(reply::json(
+        &json!({ "ports": ports, "conflicts": conflicts }),
+    ))
+}
+
+/// Groups of two or more VMs declaring the same IP address or vsock CID.
+/// Templates are left out, as instances get their own addresses.
+async fn get_conflict_report(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms(&mut con).await?;
+    let mut ips: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut vsocks: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for vm in vms.iter().filter(|vm| !vm.is_template) {
+        ips.entry(&vm.addresses.ip).or_default().push(&vm.name);
+        vsocks
+            .entry(&vm.addresses.vsock)
+            .or_default()
+            .push(&vm.name);
+    }
+    ips.retain(|_, names| names.len() > 1);
+    vsocks.retain(|_, names| names.len() > 1);
+    Ok(reply::json(&json!({
+        "ip_conflicts": ips,
+        "vsock_cid_conflicts": vsocks,
+    })))
+}
+
+/// Every NTP server declared by any VM, sorted and without duplicates.
+async fn list_ntp_servers(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let servers: BTreeSet<String> = storage::list_vms(&mut con)
+        .await?
+        .into_iter()
+        .flat_map(|vm| vm.ntp_servers)
+        .collect();
+    Ok(reply::json(&servers))
+}
+
+/// Resolvers and search domains of all running VMs, each sorted and
+/// without duplicates, for the network VM's dnsmasq configuration.
+async fn get_dns_config(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let mut resolvers = BTreeSet::new();
+    let mut search_domains = BTreeSet::new();
+    for vm in storage::list_vms_in_state(&mut con, VMStatus::Running).await? {
+        resolvers.extend(vm.dns_resolvers);
+        search_domains.extend(vm.dns_search_domains);
+    }
+    Ok(reply::json(&json!({
+        "resolvers": resolvers,
+        "search_domains": search_domains,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::models::{Direction, FirewallRule, Protocol};
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_firewall_rules() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm: serde_json::Value = serde_json::to_value(sample_vm("web-vm")).unwrap();
+        vm["firewall_rules"] = json!([
+            { "direction": "Ingress", "protocol": "Tcp", "port_range": [80, 443], "src_cidr": "192.168.100.0/24" },
+            { "direction": "Egress", "protocol": "Udp", "port_range": [53, 53] },
+        ]);
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .json(&vm)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = request()
+            .method("GET")
+            .path("/vm/web-vm/firewall-rules")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let rules = json_body(&response);
+        assert_eq!(rules.as_array().unwrap().len(), 2);
+        assert_eq!(rules[0]["src_cidr"], "192.168.100.0/24");
+        assert_eq!(rules[1]["port_range"], json!([53, 53]));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_cidr_is_rejected() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("bad-fw-vm");
+        vm.firewall_rules = vec![FirewallRule {
+            direction: Direction::Ingress,
+            protocol: Protocol::Tcp,
+            port_range: (22, 22),
+            src_cidr: Some("10.0.0.0/40".to_string()),
+        }];
+        let response = register(&api, &vm).await;
+        assert_eq!(response.status(), 422);
+        assert_eq!(
+            json_body(&response)["message"],
+            "invalid CIDR '10.0.0.0/40'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_port_mappings() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        register(&api, &sample_vm("web-vm")).await;
+        register(&api, &sample_vm("dev-vm")).await;
+
+        let response = request()
+            .method("PUT")
+            .path("/vm/web-vm/ports")
+            .json(&json!([
+                { "host_port": 8080, "vm_port": 80, "protocol": "Tcp", "service_name": "http" },
+                { "host_port": 8443, "vm_port": 443, "protocol": "Tcp" },
+            ]))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = request()
+            .method("POST")
+            .path("/vm/dev-vm/ports")
+            .json(&json!({ "host_port": 8080, "vm_port": 3000, "protocol": "Tcp" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = request().method("GET").path("/vms/ports").reply(&api).await;
+        assert_eq!(
+            json_body(&response),
+            json!({
+                "ports": { "8080": ["dev-vm", "web-vm"], "8443": ["web-vm"] },
+                "conflicts": [8080],
+            })
+        );
+
+        let delete = |path: &str| request().method("DELETE").path(path).reply(&api);
+        assert_eq!(delete("/vm/dev-vm/ports/8080").await.status(), 200);
+        assert_eq!(delete("/vm/dev-vm/ports/8080").await.status(), 404);
+        let response = request().method("GET").path("/vms/ports").reply(&api).await;
+        assert_eq!(json_body(&response)["conflicts"], json!([]));
+
+        let response = request()
+            .method("GET")
+            .path("/vm/web-vm/ports")
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response)[0]["service_name"], "http");
+
+        let response = request()
+            .method("PUT")
+            .path("/vm/web-vm/ports")
+            .json(&json!([
+                { "host_port": 9000, "vm_port": 80, "protocol": "Tcp" },
+                { "host_port": 9000, "vm_port": 81, "protocol": "Udp" },
+            ]))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_report() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, ip, vsock) in [
+            ("vm-a", "192.168.100.5", "5"),
+            ("vm-b", "192.168.100.5", "6"),
+            ("vm-c", "192.168.100.7", "42"),
+            ("vm-d", "192.168.100.8", "42"),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.addresses.ip = ip.to_string();
+            vm.addresses.vsock = vsock.to_string();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/conflict-report")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            serde_json::json!({
+                "ip_conflicts": { "192.168.100.5": ["vm-a", "vm-b"] },
+                "vsock_cid_conflicts": { "42": ["vm-c", "vm-d"] },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ntp_servers() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut gui = sample_vm("gui-vm");
+        gui.ntp_servers = vec!["192.168.100.1".to_string(), "pool.ntp.org".to_string()];
+        let mut net = sample_vm("net-vm");
+        net.ntp_servers = vec!["fd00::1".to_string(), "192.168.100.1".to_string()];
+        for vm in [&gui, &net] {
+            assert_eq!(register(&api, vm).await.status(), 200);
+        }
+
+        let response = request().path("/vms/ntp-servers").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!(["192.168.100.1", "fd00::1", "pool.ntp.org"])
+        );
+
+        for invalid in ["ntp server", "time_server", ""] {
+            let mut vm = sample_vm("bad-ntp-vm");
+            vm.ntp_servers = vec![invalid.to_string()];
+            let response = register(&api, &vm).await;
+            assert_eq!(response.status(), 422, "{}", invalid);
+            assert_eq!(
+                json_body(&response)["message"],
+                format!("invalid NTP server '{}'", invalid)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_config() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut net = sample_vm("net-vm");
+        net.dns_resolvers = vec!["1.1.1.1".to_string()];
+        let mut gui = sample_vm("gui-vm");
+        gui.dns_resolvers = vec!["192.168.100.1".to_string(), "1.1.1.1".to_string()];
+        gui.dns_search_domains = vec!["ghaf.local".to_string()];
+        let mut idle = sample_vm("idle-vm");
+        idle.dns_resolvers = vec!["9.9.9.9".to_string()];
+        for vm in [&net, &gui, &idle] {
+            assert_eq!(register(&api, vm).await.status(), 200);
+        }
+        for name in ["net-vm", "gui-vm"] {
+            let path = format!("/run/{}", name);
+            request().method("POST").path(&path).reply(&api).await;
+        }
+
+        let response = request().path("/vms/dns-config").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({
+                "resolvers": ["1.1.1.1", "192.168.100.1"],
+                "search_domains": ["ghaf.local"],
+            })
+        );
+
+        for invalid in ["resolver.ghaf.local", "1.1.1", "::g"] {
+            let mut vm = sample_vm("bad-dns-vm");
+            vm.dns_resolvers = vec![invalid.to_string()];
+            let response = register(&api, &vm).await;
+            assert_eq!(response.status(), 422, "{}", invalid);
+            assert_eq!(
+                json_body(&response)["message"],
+                format!("invalid DNS resolver '{}', expected an IP address", invalid)
+            );
+        }
+        let mut vm = sample_vm("bad-dns-vm");
+        vm.dns_search_domains = vec!["-bad.local".to_string()];
+        assert_eq!(register(&api, &vm).await.status(), 422);
+    }
+}
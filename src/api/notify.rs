@@ -0,0 +1,122 @@
+//! Per-VM mailboxes for agents that poll for messages instead of holding a
+//! connection open to the registry.
+
+use std::sync::Arc;
+
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let notify = warp::post()
+        .and(warp::path!("vm" / String / "notify"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(notify_vm)
+        .and_then(or_reject);
+
+    let notifications = warp::get()
+        .and(warp::path!("vm" / String / "notifications"))
+        .and(with_state(state))
+        .then(take_notifications)
+        .and_then(or_reject);
+
+    notify.or(notifications)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Admin, Json, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/notify",
+            post(|Path(name), _: RequireRole<Admin>, state, Json(message)| {
+                notify_vm(name, message, state)
+            }),
+        )
+        .route(
+            "/vm/:name/notifications",
+            get(|Path(name), state| take_notifications(name, state)),
+        )
+        .with_state(state)
+}
+
+/// Queues an arbitrary JSON message for the VM's agent.
+async fn notify_vm(
+    name: String,
+    message: serde_json::Value,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    storage::push_notification(&mut con, &name, &message).await?;
+    Ok(reply::json(&json!({ "queued": name })))
+}
+
+/// Returns the pending messages of the VM and empties its mailbox.
+async fn take_notifications(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let messages = storage::take_notifications(&mut con, &name).await?;
+    Ok(reply::json(&messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_mailbox() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("mail-vm")).await.status(), 200);
+        let notify = |name: &str, message: serde_json::Value| {
+            request()
+                .method("POST")
+                .path(&format!("/vm/{}/notify", name))
+                .json(&message)
+                .reply(&api)
+        };
+        let fetch = || {
+            request()
+                .method("GET")
+                .path("/vm/mail-vm/notifications")
+                .reply(&api)
+        };
+
+        let first = json!({ "kind": "reload", "unit": "waypipe" });
+        let second = json!({ "kind": "shutdown" });
+        assert_eq!(notify("mail-vm", first.clone()).await.status(), 200);
+        assert_eq!(notify("mail-vm", second.clone()).await.status(), 200);
+
+        let response = fetch().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response), json!([first, second]));
+        assert_eq!(json_body(&fetch().await), json!([]));
+
+        assert_eq!(notify("missing-vm", json!({})).await.status(), 404);
+    }
+}
@@ -0,0 +1,333 @@
+//! Lookups of VMs by owner or contact, and ownership transfers. Ownership
+//! itself is checked by the handlers that change VMs; see
+//! `auth::authorize_owner`.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::auth::{self, Caller, Role};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    new_owner: String,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let owned_by = warp::get()
+        .and(warp::path!("vms" / "owned-by" / String))
+        .and(with_state(state.clone()))
+        .then(list_owned_vms)
+        .and_then(or_reject);
+
+    let by_contact = warp::get()
+        .and(warp::path!("vms" / "contact" / String))
+        .and(with_state(state.clone()))
+        .then(list_vms_by_contact)
+        .and_then(or_reject);
+
+    let transfer = warp::post()
+        .and(warp::path!("vm" / String / "transfer-ownership"))
+        .and(warp::body::json())
+        .and(auth::caller(state.clone()))
+        .and(with_state(state))
+        .then(transfer_ownership)
+        .and_then(or_reject);
+
+    owned_by.or(by_contact).or(transfer)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Json, Path};
+
+    axum::Router::new()
+        .route(
+            "/vms/owned-by/:identity",
+            get(|Path(identity), state| list_owned_vms(identity, state)),
+        )
+        .route(
+            "/vms/contact/:email",
+            get(|Path(email), state| list_vms_by_contact(email, state)),
+        )
+        .route(
+            "/vm/:name/transfer-ownership",
+            post(|Path(name), caller, state, Json(request)| {
+                transfer_ownership(name, request, caller, state)
+            }),
+        )
+        .with_state(state)
+}
+
+async fn list_owned_vms(
+    identity: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_owned_vms(&mut con, &identity).await?;
+    Ok(reply::json(&vms))
+}
+
+async fn list_vms_by_contact(
+    email: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms_by_contact(&mut con, &email).await?;
+    Ok(reply::json(&vms))
+}
+
+/// Hands the VM over to `new_owner`. Only the current owner or an admin
+/// may do so, and only an admin may assign a VM that has no owner. The
+/// transfer is recorded in the VM's audit log and announced in its mailbox.
+async fn transfer_ownership(
+    name: String,
+    request: TransferRequest,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let new_owner = request.new_owner.trim();
+    if new_owner.is_empty() {
+        return Err(RegistryError::BadRequest(
+            "new_owner must not be empty".to_string(),
+        ));
+    }
+    let mut con = state.connection().await?;
+    let previous = storage::require_unsealed_vm(&mut con, &name).await?;
+    let is_admin = caller
+        .as_ref()
+        .is_some_and(|caller| caller.role == Role::Admin);
+    if previous.owner.is_none() && !is_admin {
+        return Err(RegistryError::NotOwner(name));
+    }
+    auth::authorize_owner(caller.as_ref(), &previous)?;
+
+    let vm = storage::set_owner(&mut con, &name, new_owner).await?;
+    let transfer = json!({
+        "kind": "ownership_transferred",
+        "from": previous.owner,
+        "to": new_owner,
+        "by": caller.and_then(|caller| caller.identity),
+        "timestamp": Utc::now(),
+    });
+    storage::append_audit_entry(&mut con, &name, &transfer).await?;
+    storage::push_notification(&mut con, &name, &transfer).await?;
+    Ok(reply::json(&vm))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::models::Contact;
+    use crate::settings::Settings;
+    use crate::storage;
+    use crate::test_util::{
+        json_body, redis_state, redis_state_with, register, request, sample_vm, test_settings,
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_list_vms_by_contact() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let contact = |email: &str| Contact {
+            name: "Platform on-call".to_string(),
+            email: email.to_string(),
+            slack: Some("#platform-oncall".to_string()),
+        };
+        for (name, email) in [
+            ("net-vm", Some("oncall@example.com")),
+            ("gui-vm", Some("OnCall@example.com")),
+            ("audio-vm", Some("audio@example.com")),
+            ("admin-vm", None),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.contact = email.map(contact);
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/contact/oncall@example.com")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        let names: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|vm| vm["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["gui-vm", "net-vm"]);
+        assert_eq!(body[0]["contact"]["slack"], "#platform-oncall");
+
+        let mut vm = sample_vm("bad-contact-vm");
+        vm.contact = Some(contact("oncall"));
+        assert_eq!(register(&api, &vm).await.status(), 422);
+
+        let response = request()
+            .method("GET")
+            .path("/vms/contact/nobody@example.com")
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response), json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_owner_access() {
+        let settings = Settings {
+            api_tokens: [
+                ("alice-token".to_string(), Role::Operator),
+                ("bob-token".to_string(), Role::Operator),
+                ("admin-token".to_string(), Role::Admin),
+            ]
+            .into(),
+            token_identities: [
+                ("alice-token".to_string(), "alice".to_string()),
+                ("bob-token".to_string(), "bob".to_string()),
+            ]
+            .into(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("alice-vm");
+        vm.owner = Some("bob".to_string());
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .header("authorization", "Bearer alice-token")
+            .json(&vm)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["owner"], "alice");
+
+        let patch = |token: &str, priority: i32| {
+            request()
+                .method("PATCH")
+                .path("/vm/alice-vm")
+                .header("authorization", format!("Bearer {}", token))
+                .json(&json!({ "priority": priority }))
+        };
+        let response = patch("bob-token", 1).reply(&api).await;
+        assert_eq!(response.status(), 403);
+        assert_eq!(json_body(&response)["error"], "Forbidden");
+        assert_eq!(patch("alice-token", 2).reply(&api).await.status(), 200);
+        assert_eq!(patch("admin-token", 3).reply(&api).await.status(), 200);
+
+        let response = request()
+            .method("POST")
+            .path("/stop/alice-vm")
+            .header("authorization", "Bearer bob-token")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 403);
+
+        let owned_by = |identity: &str| {
+            request()
+                .method("GET")
+                .path(&format!("/vms/owned-by/{}", identity))
+                .reply(&api)
+        };
+        let response = owned_by("alice").await;
+        assert_eq!(response.status(), 200);
+        let owned = json_body(&response);
+        assert_eq!(owned.as_array().unwrap().len(), 1);
+        assert_eq!(owned[0]["name"], "alice-vm");
+        assert_eq!(json_body(&owned_by("bob").await), json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ownership() {
+        let settings = Settings {
+            api_tokens: [
+                ("alice-token".to_string(), Role::Operator),
+                ("bob-token".to_string(), Role::Operator),
+            ]
+            .into(),
+            token_identities: [
+                ("alice-token".to_string(), "alice".to_string()),
+                ("bob-token".to_string(), "bob".to_string()),
+            ]
+            .into(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .header("authorization", "Bearer alice-token")
+            .json(&sample_vm("handoff-vm"))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let transfer = |token: &str| {
+            request()
+                .method("POST")
+                .path("/vm/handoff-vm/transfer-ownership")
+                .header("authorization", format!("Bearer {}", token))
+                .json(&json!({ "new_owner": "bob" }))
+        };
+        assert_eq!(transfer("bob-token").reply(&api).await.status(), 403);
+        let response = transfer("alice-token").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["owner"], "bob");
+
+        let patch = |token: &str| {
+            request()
+                .method("PATCH")
+                .path("/vm/handoff-vm")
+                .header("authorization", format!("Bearer {}", token))
+                .json(&json!({ "priority": 4 }))
+        };
+        assert_eq!(patch("alice-token").reply(&api).await.status(), 403);
+        assert_eq!(patch("bob-token").reply(&api).await.status(), 200);
+
+        let mut con = ctx.state.connection().await.unwrap();
+        let audit = storage::recent_audit_entries(&mut con, "handoff-vm", 10)
+            .await
+            .unwrap();
+        let entries: Vec<serde_json::Value> = audit
+            .iter()
+            .map(|entry| serde_json::from_str(entry).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        let entry = entries
+            .iter()
+            .find(|entry| entry["kind"] == "ownership_transferred")
+            .unwrap();
+        assert_eq!(entry["from"], "alice");
+        assert_eq!(entry["to"], "bob");
+        let messages = storage::take_notifications(&mut con, "handoff-vm")
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["kind"], "ownership_transferred");
+    }
+}
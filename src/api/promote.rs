@@ -0,0 +1,192 @@
+//! Promotion of hardened `App` VMs to `System` VMs.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::auth::Caller;
+#[cfg(feature = "warp")]
+use crate::auth::{self, require_role, Role};
+use crate::error::RegistryError;
+use crate::models::{SystemAppType, VMStatus};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct PromoteRequest {
+    reason: String,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("vm" / String / "promote"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(warp::body::json())
+        .and(auth::caller(state.clone()))
+        .and(with_state(state))
+        .then(promote_vm)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::post;
+
+    use super::extract::{Admin, Json, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/promote",
+            post(
+                |Path(name), _: RequireRole<Admin>, caller, state, Json(request)| {
+                    promote_vm(name, request, caller, state)
+                },
+            ),
+        )
+        .with_state(state)
+}
+
+/// Turns an `App` VM into a `System` VM. A running VM is stopped for the
+/// change and started again afterwards. The promotion and its reason are
+/// recorded in the VM's audit log.
+async fn promote_vm(
+    name: String,
+    request: PromoteRequest,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    if request.reason.trim().is_empty() {
+        return Err(RegistryError::Validation(
+            "a reason is required".to_string(),
+        ));
+    }
+    let mut con = state.connection().await?;
+    let previous = storage::require_unsealed_vm(&mut con, &name).await?;
+    if previous.vm_type.system_app != SystemAppType::App {
+        return Err(RegistryError::Conflict(format!(
+            "VM '{}' is a {} VM, only App VMs can be promoted",
+            name,
+            previous.vm_type.system_app.as_str()
+        )));
+    }
+
+    let was_running = previous.status == VMStatus::Running;
+    let previous = if was_running {
+        storage::set_status(&mut con, &name, VMStatus::Stopped).await?
+    } else {
+        previous
+    };
+    let mut vm = previous.clone();
+    vm.vm_type.system_app = SystemAppType::System;
+    storage::save_vm(&mut con, &mut vm, Some(&previous)).await?;
+    let promotion = json!({
+        "kind": "promoted",
+        "from": SystemAppType::App.as_str(),
+        "to": SystemAppType::System.as_str(),
+        "reason": request.reason,
+        "by": caller.and_then(|caller| caller.identity),
+        "timestamp": Utc::now(),
+    });
+    storage::append_audit_entry(&mut con, &name, &promotion).await?;
+    if was_running {
+        vm = storage::set_status(&mut con, &name, VMStatus::Running).await?;
+    }
+    Ok(reply::json(&vm))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::models::SystemAppType;
+    use crate::settings::Settings;
+    use crate::storage;
+    use crate::test_util::{json_body, redis_state_with, request, sample_vm, test_settings};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_promote_vm() {
+        let settings = Settings {
+            api_tokens: [
+                ("operator-token".to_string(), Role::Operator),
+                ("admin-token".to_string(), Role::Admin),
+            ]
+            .into(),
+            token_identities: [("admin-token".to_string(), "root".to_string())].into(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("hardened-vm");
+        vm.vm_type.system_app = SystemAppType::App;
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .header("authorization", "Bearer admin-token")
+            .json(&vm)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = request()
+            .method("POST")
+            .path("/run/hardened-vm")
+            .header("authorization", "Bearer admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let promote = |token: &str, reason: &str| {
+            request()
+                .method("POST")
+                .path("/vm/hardened-vm/promote")
+                .header("authorization", format!("Bearer {}", token))
+                .json(&json!({ "reason": reason }))
+        };
+        assert_eq!(
+            promote("operator-token", "audited")
+                .reply(&api)
+                .await
+                .status(),
+            403
+        );
+        assert_eq!(promote("admin-token", " ").reply(&api).await.status(), 422);
+
+        let response = promote("admin-token", "passed the security review")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert_eq!(body["vm_type"]["system_app"], "System");
+        assert_eq!(body["status"], "Running");
+
+        let mut con = ctx.state.connection().await.unwrap();
+        let audit = storage::recent_audit_entries(&mut con, "hardened-vm", 10)
+            .await
+            .unwrap();
+        let entries: Vec<serde_json::Value> = audit
+            .iter()
+            .map(|entry| serde_json::from_str(entry).unwrap())
+            .collect();
+        let entry = entries
+            .iter()
+            .find(|entry| entry["kind"] == "promoted")
+            .unwrap();
+        assert_eq!(entry["reason"], "passed the security review");
+        assert_eq!(entry["by"], "root");
+
+        let response = promote("admin-token", "again").reply(&api).await;
+        assert_eq!(response.status(), 409);
+    }
+}
@@ -0,0 +1,112 @@
+//! Per-client rate limiting. Each client may make `rate_limit_per_minute`
+//! requests in a fixed one-minute window that starts with its first
+//! request; clients are told apart by the address the access log records.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::http::HeaderMap;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection};
+
+use super::access_log::client_address;
+#[cfg(feature = "axum")]
+use super::access_log::remote_address;
+use crate::error::RegistryError;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Start of each client's current window and the requests made in it.
+type Windows = HashMap<String, (Instant, u32)>;
+
+/// Counts a request by `client` at `now`. Fails with the seconds left in
+/// the client's window when it has already made `per_minute` requests.
+fn take(windows: &mut Windows, client: String, per_minute: u32, now: Instant) -> Result<(), u64> {
+    windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+    let (start, count) = windows.entry(client).or_insert((now, 0));
+    if *count >= per_minute {
+        let left = WINDOW - now.duration_since(*start);
+        return Err(left.as_secs_f64().ceil() as u64);
+    }
+    *count += 1;
+    Ok(())
+}
+
+/// The windows of every client; clones share them.
+#[derive(Clone)]
+pub struct Limiter {
+    per_minute: Option<u32>,
+    windows: Arc<Mutex<Windows>>,
+}
+
+impl Limiter {
+    pub fn new(per_minute: Option<u32>) -> Self {
+        Limiter {
+            per_minute,
+            windows: Arc::new(Mutex::new(Windows::new())),
+        }
+    }
+
+    /// Fails with `RateLimited` once the request's client has used up its
+    /// window; lets every request through when no limit is set.
+    fn check(&self, headers: &HeaderMap, remote: Option<SocketAddr>) -> Result<(), RegistryError> {
+        let Some(per_minute) = self.per_minute else {
+            return Ok(());
+        };
+        let client = client_address(headers, remote);
+        take(
+            &mut self.windows.lock().unwrap(),
+            client,
+            per_minute,
+            Instant::now(),
+        )
+        .map_err(RegistryError::RateLimited)
+    }
+}
+
+/// Rejects requests over the limit.
+#[cfg(feature = "warp")]
+pub fn limit(limiter: Limiter) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::addr::remote())
+        .and_then(move |headers: HeaderMap, remote: Option<SocketAddr>| {
+            let checked = limiter.check(&headers, remote);
+            async move { checked.map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+}
+
+/// Middleware answering requests over the limit with 429.
+#[cfg(feature = "axum")]
+pub async fn limit(
+    axum::extract::State(limiter): axum::extract::State<Limiter>,
+    request: axum::http::Request<hyper::Body>,
+    next: axum::middleware::Next<hyper::Body>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    match limiter.check(request.headers(), remote_address(&request)) {
+        Ok(()) => next.run(request).await,
+        Err(e) => IntoResponse::into_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows() {
+        let mut windows = Windows::new();
+        let start = Instant::now();
+        assert_eq!(take(&mut windows, "a".to_string(), 2, start), Ok(()));
+        assert_eq!(take(&mut windows, "a".to_string(), 2, start), Ok(()));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(take(&mut windows, "a".to_string(), 2, later), Err(60));
+        assert_eq!(take(&mut windows, "b".to_string(), 2, later), Ok(()));
+        let next = start + WINDOW;
+        assert_eq!(take(&mut windows, "a".to_string(), 2, next), Ok(()));
+    }
+}
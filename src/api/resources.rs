@@ -0,0 +1,281 @@
+//! Aggregate resource use, for the hypervisor controller's headroom checks.
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::audit;
+use crate::error::RegistryError;
+use crate::models::{PriorityClass, VMStatus};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Candidates `GET /vms/eviction-candidates` returns without a `limit`.
+const DEFAULT_EVICTION_CANDIDATES: usize = 5;
+
+#[derive(Deserialize)]
+struct EvictionQuery {
+    class: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct EvictionCandidate {
+    name: String,
+    priority_class: PriorityClass,
+    last_started_at: Option<DateTime<Utc>>,
+    memory_limit_mb: u64,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let resource_summary = warp::get()
+        .and(warp::path!("vms" / "resource-summary"))
+        .and(with_state(state.clone()))
+        .then(get_resource_summary)
+        .and_then(or_reject);
+
+    let eviction_candidates = warp::get()
+        .and(warp::path!("vms" / "eviction-candidates"))
+        .and(warp::query::<EvictionQuery>())
+        .and(with_state(state))
+        .then(get_eviction_candidates)
+        .and_then(or_reject);
+
+    resource_summary.or(eviction_candidates)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    use super::extract::Query;
+
+    axum::Router::new()
+        .route("/vms/resource-summary", get(get_resource_summary))
+        .route(
+            "/vms/eviction-candidates",
+            get(|Query(query), state| get_eviction_candidates(query, state)),
+        )
+        .with_state(state)
+}
+
+/// Sums the vCPUs and memory of every VM that has been started and not
+/// stopped since. All records are read with a single `MGET`.
+async fn get_resource_summary(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_vms(&mut con).await?;
+    let active: Vec<_> = vms
+        .iter()
+        .filter(|vm| !matches!(vm.status, VMStatus::Stopped | VMStatus::Registered))
+        .filter(|vm| !vm.is_template)
+        .collect();
+    Ok(reply::json(&json!({
+        "total_vcpus": active.iter().map(|vm| u64::from(vm.vcpu_count)).sum::<u64>(),
+        "total_memory_mb": active.iter().map(|vm| vm.memory_limit_mb).sum::<u64>(),
+        "vm_count": active.len(),
+    })))
+}
+
+/// The running VMs to stop first when the hypervisor reports memory
+/// pressure. Only VMs of `class` or a lower one are considered; without it,
+/// every class but `critical` is.
+async fn get_eviction_candidates(
+    query: EvictionQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let class = match query.class {
+        Some(class) => class.parse().map_err(RegistryError::Validation)?,
+        None => PriorityClass::High,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_EVICTION_CANDIDATES);
+    if limit == 0 {
+        return Err(RegistryError::Validation(
+            "limit must be at least 1".to_string(),
+        ));
+    }
+    let mut con = state.connection().await?;
+    let mut candidates = Vec::new();
+    for vm in storage::list_vms_in_state(&mut con, VMStatus::Running).await? {
+        if vm.is_template || vm.priority_class < class {
+            continue;
+        }
+        let entries = storage::audit_entries(&mut con, &vm.name).await?;
+        candidates.push(EvictionCandidate {
+            last_started_at: audit::last_started(&entries),
+            name: vm.name,
+            priority_class: vm.priority_class,
+            memory_limit_mb: vm.memory_limit_mb,
+        });
+    }
+    eviction_order(&mut candidates);
+    candidates.truncate(limit);
+    Ok(reply::json(&candidates))
+}
+
+/// Sorts the lowest priority class first, then the VMs started longest ago,
+/// which have the least work in flight, then the ones declaring the most
+/// memory, which free the most. VMs with no recorded start count as the
+/// oldest.
+fn eviction_order(candidates: &mut [EvictionCandidate]) {
+    candidates.sort_by(|a, b| {
+        (
+            Reverse(a.priority_class),
+            a.last_started_at,
+            Reverse(a.memory_limit_mb),
+        )
+            .cmp(&(
+                Reverse(b.priority_class),
+                b.last_started_at,
+                Reverse(b.memory_limit_mb),
+            ))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_eviction_order() {
+        let at = |secs| Some(Utc.timestamp_opt(secs, 0).unwrap());
+        let candidate =
+            |name: &str, priority_class, last_started_at, memory_limit_mb| EvictionCandidate {
+                name: name.to_string(),
+                priority_class,
+                last_started_at,
+                memory_limit_mb,
+            };
+        let mut candidates = vec![
+            candidate("normal-vm", PriorityClass::Normal, at(0), 8192),
+            candidate("low-new", PriorityClass::Low, at(200), 1024),
+            candidate("low-old-small", PriorityClass::Low, at(100), 512),
+            candidate("low-old-big", PriorityClass::Low, at(100), 4096),
+            candidate("low-unknown", PriorityClass::Low, None, 256),
+            candidate("spare-vm", PriorityClass::BestEffort, at(300), 128),
+        ];
+        eviction_order(&mut candidates);
+        let names: Vec<&str> = candidates.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "spare-vm",
+                "low-unknown",
+                "low-old-big",
+                "low-old-small",
+                "low-new",
+                "normal-vm"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resource_summary() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, vcpus, memory, action) in [
+            ("gui-vm", 4, 4096, Some("run")),
+            ("net-vm", 1, 512, Some("run")),
+            ("stopped-vm", 8, 8192, Some("stop")),
+            ("idle-vm", 2, 1024, None),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.vcpu_count = vcpus;
+            vm.memory_limit_mb = memory;
+            assert_eq!(register(&api, &vm).await.status(), 200);
+            if let Some(action) = action {
+                let path = format!("/{}/{}", action, name);
+                request().method("POST").path(&path).reply(&api).await;
+            }
+        }
+
+        let response = request()
+            .method("GET")
+            .path("/vms/resource-summary")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response),
+            json!({ "total_vcpus": 5, "total_memory_mb": 4608, "vm_count": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eviction_candidates() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, class, memory, run) in [
+            ("audio-vm", PriorityClass::Critical, 512, true),
+            ("chat-vm", PriorityClass::Normal, 2048, true),
+            ("old-batch-vm", PriorityClass::Low, 1024, true),
+            ("new-batch-vm", PriorityClass::Low, 4096, true),
+            ("idle-vm", PriorityClass::BestEffort, 256, false),
+            ("spare-vm", PriorityClass::BestEffort, 128, true),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.priority_class = class;
+            vm.memory_limit_mb = memory;
+            assert_eq!(register(&api, &vm).await.status(), 200);
+            if run {
+                let path = format!("/run/{}", name);
+                request().method("POST").path(&path).reply(&api).await;
+            }
+        }
+
+        let candidates = |query: &str| {
+            let path = format!("/vms/eviction-candidates{}", query);
+            let api = &api;
+            async move {
+                let response = request().method("GET").path(&path).reply(api).await;
+                assert_eq!(response.status(), 200, "{}", path);
+                json_body(&response)
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|vm| vm["name"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            candidates("").await,
+            ["spare-vm", "old-batch-vm", "new-batch-vm", "chat-vm"]
+        );
+        assert_eq!(
+            candidates("?class=low&limit=5").await,
+            ["spare-vm", "old-batch-vm", "new-batch-vm"]
+        );
+        assert_eq!(
+            candidates("?class=low&limit=2").await,
+            ["spare-vm", "old-batch-vm"]
+        );
+        assert_eq!(candidates("?class=critical").await.len(), 5);
+
+        let response = request()
+            .method("GET")
+            .path("/vms/eviction-candidates?class=urgent")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+}
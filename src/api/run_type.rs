@@ -0,0 +1,129 @@
+//! Changing whether a registered VM runs continuously or on demand.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::audit;
+use crate::auth::{self, Caller};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::RunType;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct RunTypeRequest {
+    run_type: RunType,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::put()
+        .and(warp::path!("vm" / String / "run-type"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(warp::body::json())
+        .and(auth::caller(state.clone()))
+        .and(with_state(state))
+        .then(set_run_type)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::put;
+
+    use super::extract::{Json, Operator, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/run-type",
+            put(
+                |Path(name), _: RequireRole<Operator>, caller, state, Json(request)| {
+                    set_run_type(name, request, caller, state)
+                },
+            ),
+        )
+        .with_state(state)
+}
+
+/// Sets the VM's run type, e.g. `{"run_type": "one_shot"}`. The VM keeps
+/// its status; the change is recorded in its audit log.
+async fn set_run_type(
+    name: String,
+    request: RunTypeRequest,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let previous = storage::require_unsealed_vm(&mut con, &name).await?;
+    auth::authorize_owner(caller.as_ref(), &previous)?;
+    let mut vm = previous.clone();
+    vm.vm_type.run_type = request.run_type;
+    storage::save_vm(&mut con, &mut vm, Some(&previous)).await?;
+    let by = caller.and_then(|caller| caller.identity);
+    audit::record_audit_event(&mut con, &name, by, &previous, &vm).await?;
+    Ok(reply::json(&vm))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::models::RunType;
+    use crate::storage;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_set_run_type() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("vpn-vm");
+        vm.vm_type.run_type = RunType::LongRun;
+        assert_eq!(register(&api, &vm).await.status(), 200);
+
+        let set = |run_type: serde_json::Value| {
+            request()
+                .method("PUT")
+                .path("/vm/vpn-vm/run-type")
+                .json(&json!({ "run_type": run_type }))
+        };
+        let response = set(json!("one_shot")).reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["vm_type"]["run_type"], "OneShot");
+
+        let mut con = ctx.state.connection().await.unwrap();
+        let one_shot: Vec<String> =
+            redis::AsyncCommands::smembers(&mut con, storage::run_type_key(RunType::OneShot))
+                .await
+                .unwrap();
+        assert!(one_shot.contains(&"vpn-vm".to_string()));
+        let audit = storage::recent_audit_entries(&mut con, "vpn-vm", 10)
+            .await
+            .unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&audit[0]).unwrap();
+        assert_eq!(
+            entry["changed"],
+            json!({ "vm_type.run_type": { "old": "LongRun", "new": "OneShot" } })
+        );
+
+        let response = set(json!("LongRun")).reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["vm_type"]["run_type"], "LongRun");
+
+        let response = set(json!({ "periodic": { "cron_expr": "0 * * * *" } }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+}
@@ -0,0 +1,242 @@
+//! Lifecycle changes scheduled for a later time; `scheduler` carries them
+//! out.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use hyper::http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::ScheduledAction;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct ScheduleStop {
+    stop_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct ScheduleRestart {
+    restart_at: DateTime<Utc>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let schedule_stop = warp::post()
+        .and(warp::path!("vm" / String / "schedule-stop"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(|name, body: ScheduleStop, state| {
+            schedule(ScheduledAction::Stop, name, body.stop_at, state)
+        })
+        .and_then(or_reject);
+
+    let scheduled_stop = warp::get()
+        .and(warp::path!("vm" / String / "scheduled-stop"))
+        .and(with_state(state.clone()))
+        .then(|name, state| get_scheduled(ScheduledAction::Stop, name, state))
+        .and_then(or_reject);
+
+    let schedule_restart = warp::post()
+        .and(warp::path!("vm" / String / "schedule-restart"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(|name, body: ScheduleRestart, state| {
+            schedule(ScheduledAction::Restart, name, body.restart_at, state)
+        })
+        .and_then(or_reject);
+
+    let scheduled_restart = warp::get()
+        .and(warp::path!("vm" / String / "scheduled-restart"))
+        .and(with_state(state.clone()))
+        .then(|name, state| get_scheduled(ScheduledAction::Restart, name, state))
+        .and_then(or_reject);
+
+    let cancel_restart = warp::delete()
+        .and(warp::path!("vm" / String / "scheduled-restart"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(with_state(state))
+        .then(|name, state| cancel(ScheduledAction::Restart, name, state))
+        .and_then(or_reject);
+
+    schedule_stop
+        .or(scheduled_stop)
+        .or(schedule_restart)
+        .or(scheduled_restart)
+        .or(cancel_restart)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Json, Operator, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/schedule-stop",
+            post(
+                |Path(name), _: RequireRole<Operator>, state, Json(body): Json<ScheduleStop>| {
+                    schedule(ScheduledAction::Stop, name, body.stop_at, state)
+                },
+            ),
+        )
+        .route(
+            "/vm/:name/scheduled-stop",
+            get(|Path(name), state| get_scheduled(ScheduledAction::Stop, name, state)),
+        )
+        .route(
+            "/vm/:name/schedule-restart",
+            post(
+                |Path(name), _: RequireRole<Operator>, state, Json(body): Json<ScheduleRestart>| {
+                    schedule(ScheduledAction::Restart, name, body.restart_at, state)
+                },
+            ),
+        )
+        .route(
+            "/vm/:name/scheduled-restart",
+            get(|Path(name), state| get_scheduled(ScheduledAction::Restart, name, state)).delete(
+                |Path(name), _: RequireRole<Operator>, state| {
+                    cancel(ScheduledAction::Restart, name, state)
+                },
+            ),
+        )
+        .with_state(state)
+}
+
+/// Responds with the schedule as `{ "name": ..., "<action>_at": ... }`.
+fn schedule_reply(action: ScheduledAction, name: &str, at: DateTime<Utc>) -> Response {
+    let mut body = serde_json::Map::new();
+    body.insert("name".to_string(), json!(name));
+    body.insert(format!("{}_at", action.as_str()), json!(at));
+    reply::json(&body)
+}
+
+async fn schedule(
+    action: ScheduledAction,
+    name: String,
+    at: DateTime<Utc>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    if at <= Utc::now() {
+        let message = format!("{}_at must be in the future", action.as_str());
+        return Err(RegistryError::BadRequest(message));
+    }
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    storage::schedule_action(&mut con, action, &name, at).await?;
+    Ok(schedule_reply(action, &name, at))
+}
+
+async fn get_scheduled(
+    action: ScheduledAction,
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let at = storage::get_scheduled_action(&mut con, action, &name)
+        .await?
+        .ok_or(RegistryError::NotFound(name.clone()))?;
+    Ok(schedule_reply(action, &name, at))
+}
+
+/// Drops the pending action; 404 when none was scheduled.
+async fn cancel(
+    action: ScheduledAction,
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    if !storage::cancel_scheduled_action(&mut con, action, &name).await? {
+        return Err(RegistryError::NotFound(name));
+    }
+    Ok(reply::status(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_schedule_stop() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("sched-vm")).await.status(), 200);
+        let schedule = |name: &str, stop_at: DateTime<Utc>| {
+            request()
+                .method("POST")
+                .path(&format!("/vm/{}/schedule-stop", name))
+                .json(&json!({ "stop_at": stop_at }))
+                .reply(&api)
+        };
+        let scheduled = || {
+            request()
+                .method("GET")
+                .path("/vm/sched-vm/scheduled-stop")
+                .reply(&api)
+        };
+
+        assert_eq!(scheduled().await.status(), 404);
+        let past = Utc::now() - Duration::minutes(1);
+        assert_eq!(schedule("sched-vm", past).await.status(), 400);
+        let stop_at = Utc::now() + Duration::hours(2);
+        assert_eq!(schedule("missing-vm", stop_at).await.status(), 404);
+        assert_eq!(schedule("sched-vm", stop_at).await.status(), 200);
+
+        let response = scheduled().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["stop_at"], json!(stop_at));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_scheduled_restart() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("reboot-vm")).await.status(), 200);
+        let restart_at = Utc::now() + Duration::hours(1);
+        let response = request()
+            .method("POST")
+            .path("/vm/reboot-vm/schedule-restart")
+            .json(&json!({ "restart_at": restart_at }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["restart_at"], json!(restart_at));
+        let cancel = || {
+            request()
+                .method("DELETE")
+                .path("/vm/reboot-vm/scheduled-restart")
+                .reply(&api)
+        };
+
+        assert_eq!(cancel().await.status(), 204);
+        assert_eq!(cancel().await.status(), 404);
+        let response = request()
+            .method("GET")
+            .path("/vm/reboot-vm/scheduled-restart")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
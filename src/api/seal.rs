@@ -0,0 +1,236 @@
+//! Sealing locks a VM's configuration and lifecycle against accidental
+//! changes, e.g. for the security monitor in production.
+
+use std::sync::Arc;
+
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::auth::{self, Caller};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let seal = warp::post()
+        .and(warp::path!("vm" / String / "seal"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(auth::caller(state.clone()))
+        .and(with_state(state.clone()))
+        .then(|name, caller, state| set_sealed(name, true, caller, state))
+        .and_then(or_reject);
+
+    let unseal = warp::post()
+        .and(warp::path!("vm" / String / "unseal"))
+        .and(require_role(state.clone(), Role::Admin))
+        .and(auth::caller(state.clone()))
+        .and(with_state(state.clone()))
+        .then(|name, caller, state| set_sealed(name, false, caller, state))
+        .and_then(or_reject);
+
+    let locked = warp::get()
+        .and(warp::path!("vms" / "locked"))
+        .and(with_state(state))
+        .then(list_sealed_vms)
+        .and_then(or_reject);
+
+    seal.or(unseal).or(locked)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Admin, Operator, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/seal",
+            post(|Path(name), _: RequireRole<Operator>, caller, state| {
+                set_sealed(name, true, caller, state)
+            }),
+        )
+        .route(
+            "/vm/:name/unseal",
+            post(|Path(name), _: RequireRole<Admin>, caller, state| {
+                set_sealed(name, false, caller, state)
+            }),
+        )
+        .route("/vms/locked", get(list_sealed_vms))
+        .with_state(state)
+}
+
+async fn set_sealed(
+    name: String,
+    sealed: bool,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    auth::authorize_owner(caller.as_ref(), &vm)?;
+    let vm = storage::set_sealed(&mut con, &name, sealed).await?;
+    Ok(reply::json(&vm))
+}
+
+async fn list_sealed_vms(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_sealed_vms(&mut con).await?;
+    Ok(reply::json(&vms))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::settings::Settings;
+    use crate::test_util::{
+        json_body, redis_state, redis_state_with, register, request, sample_vm, test_settings,
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_seal_and_unseal() {
+        let settings = Settings {
+            api_tokens: [
+                ("operator-token".to_string(), Role::Operator),
+                ("admin-token".to_string(), Role::Admin),
+            ]
+            .into(),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("monitor-vm")).await.status(), 200);
+
+        let response = request()
+            .method("POST")
+            .path("/vm/monitor-vm/seal")
+            .header("authorization", "Bearer operator-token")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["sealed"], true);
+
+        let patch = || {
+            request()
+                .method("PATCH")
+                .path("/vm/monitor-vm")
+                .json(&json!({ "priority": 5 }))
+        };
+        let response = patch().reply(&api).await;
+        assert_eq!(response.status(), 423);
+        assert_eq!(json_body(&response)["error"], "Locked");
+        for path in ["/run/monitor-vm", "/vm/monitor-vm/attach-volume"] {
+            let response = request()
+                .method("POST")
+                .path(path)
+                .json(&json!({ "path": "/dev/vdb", "size_gb": 1 }))
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 423, "{}", path);
+        }
+        let response = request()
+            .method("PUT")
+            .path("/vm/monitor-vm/tags")
+            .json(&json!(["production"]))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 423);
+
+        let stop = |token: &str| {
+            request()
+                .method("POST")
+                .path("/stop/monitor-vm")
+                .header("authorization", format!("Bearer {}", token))
+        };
+        assert_eq!(stop("operator-token").reply(&api).await.status(), 423);
+        assert_eq!(stop("admin-token").reply(&api).await.status(), 200);
+
+        let unseal = |token: &str| {
+            request()
+                .method("POST")
+                .path("/vm/monitor-vm/unseal")
+                .header("authorization", format!("Bearer {}", token))
+        };
+        assert_eq!(unseal("operator-token").reply(&api).await.status(), 403);
+        let response = unseal("admin-token").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["sealed"], false);
+
+        let response = patch().reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(json_body(&response)["priority"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_registering_sealed_vms() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut vm = sample_vm("presealed-vm");
+        vm.sealed = true;
+        vm.is_template = true;
+        vm.template_name = Some("browser-template".to_string());
+        let response = register(&api, &vm).await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert_eq!(body["sealed"], false);
+        assert_eq!(body["is_template"], false);
+        assert_eq!(body["template_name"], json!(null));
+
+        let response = request()
+            .method("PATCH")
+            .path("/vm/presealed-vm")
+            .json(&json!({ "priority": 5 }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_locked_vms() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for name in ["monitor-vm", "update-vm", "app-vm"] {
+            assert_eq!(register(&api, &sample_vm(name)).await.status(), 200);
+        }
+        let post = |path: &str| request().method("POST").path(path).reply(&api);
+        assert_eq!(post("/vm/update-vm/seal").await.status(), 200);
+        assert_eq!(post("/vm/monitor-vm/seal").await.status(), 200);
+
+        let locked = || async {
+            let response = request()
+                .method("GET")
+                .path("/vms/locked")
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), 200);
+            json_body(&response)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|vm| vm["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(locked().await, ["monitor-vm", "update-vm"]);
+
+        assert_eq!(post("/vm/update-vm/unseal").await.status(), 200);
+        assert_eq!(locked().await, ["monitor-vm"]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Debug snapshots: everything the registry knows about one VM, bundled
+//! into a single download for support.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use serde::Serialize;
+use serde_json::Value;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+use super::liveness::lease_status;
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::audit;
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::models::{FirewallRule, PortMapping, ScheduledAction, Volume, VM};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+/// Entries of the audit log and of the statistics history included.
+const HISTORY_LIMIT: isize = 100;
+
+#[derive(Serialize)]
+struct DebugSnapshot {
+    captured_at: DateTime<Utc>,
+    vm: VM,
+    /// Entries as stored; ones that are not JSON are kept as strings.
+    audit_log: Vec<Value>,
+    stats_history: Vec<Value>,
+    volumes: Vec<Volume>,
+    ports: Vec<PortMapping>,
+    firewall_rules: Vec<FirewallRule>,
+    /// Pending scheduled actions by name, e.g. `stop`.
+    scheduled: BTreeMap<&'static str, DateTime<Utc>>,
+    lease: Value,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("vm" / String / "debug-snapshot"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(with_state(state))
+        .then(debug_snapshot)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::post;
+
+    use super::extract::{Operator, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/debug-snapshot",
+            post(|Path(name), _: RequireRole<Operator>, state| debug_snapshot(name, state)),
+        )
+        .with_state(state)
+}
+
+fn parse_entries(entries: Vec<String>) -> Vec<Value> {
+    entries
+        .into_iter()
+        .map(|entry| serde_json::from_str(&entry).unwrap_or(Value::String(entry)))
+        .collect()
+}
+
+/// The snapshot as gzip-compressed JSON, served as an attachment named
+/// after the VM and the capture time.
+async fn debug_snapshot(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    let mut scheduled = BTreeMap::new();
+    for action in ScheduledAction::ALL {
+        if let Some(at) = storage::get_scheduled_action(&mut con, action, &name).await? {
+            scheduled.insert(action.as_str(), at);
+        }
+    }
+    let snapshot = DebugSnapshot {
+        captured_at: Utc::now(),
+        audit_log: storage::recent_audit_entries(&mut con, &name, HISTORY_LIMIT)
+            .await?
+            .into_iter()
+            .map(audit::readable_entry)
+            .collect(),
+        stats_history: parse_entries(storage::recent_stats(&mut con, &name, HISTORY_LIMIT).await?),
+        volumes: storage::get_volumes(&mut con, &name).await?,
+        ports: storage::get_ports(&mut con, &name).await?,
+        firewall_rules: vm.firewall_rules.clone(),
+        scheduled,
+        lease: lease_status(&mut con, &vm).await?,
+        vm,
+    };
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, &snapshot).map_err(RegistryError::from)?;
+    let body = encoder
+        .finish()
+        .expect("compressing into memory cannot fail");
+    let filename = format!(
+        "{}-snapshot-{}.json.gz",
+        name,
+        snapshot.captured_at.format("%Y%m%dT%H%M%SZ")
+    );
+    let response = reply::with_header(body, CONTENT_TYPE, "application/gzip");
+    Ok(reply::with_header(
+        response,
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", filename),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use chrono::{Duration, Utc};
+    use flate2::read::GzDecoder;
+    use redis::AsyncCommands;
+    use serde_json::json;
+
+    use crate::api::routes;
+    use crate::models::ScheduledAction;
+    use crate::storage;
+    use crate::test_util::{redis_state, register, request, sample_vm};
+
+    #[tokio::test]
+    async fn test_debug_snapshot() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("support-vm")).await.status(), 200);
+        let mut con = ctx.state.connection().await.unwrap();
+        for sample in 0..150 {
+            con.rpush::<_, _, ()>(
+                storage::stats_history_key("support-vm"),
+                json!({ "cpu_percent": sample }).to_string(),
+            )
+            .await
+            .unwrap();
+        }
+        con.zadd::<_, _, _, ()>(storage::audit_key("support-vm"), "registered", 1)
+            .await
+            .unwrap();
+        let stop_at = Utc::now() + Duration::hours(1);
+        storage::schedule_action(&mut con, ScheduledAction::Stop, "support-vm", stop_at)
+            .await
+            .unwrap();
+
+        let response = request()
+            .method("POST")
+            .path("/vm/support-vm/debug-snapshot")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/gzip");
+        let disposition = response.headers()["content-disposition"].to_str().unwrap();
+        assert!(disposition.starts_with("attachment; filename=\"support-vm-snapshot-"));
+        assert!(disposition.ends_with(".json.gz\""));
+
+        let mut json = String::new();
+        GzDecoder::new(response.body().as_ref())
+            .read_to_string(&mut json)
+            .unwrap();
+        let snapshot: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot["vm"]["name"], "support-vm");
+        assert_eq!(snapshot["audit_log"], json!(["registered"]));
+        let stats = snapshot["stats_history"].as_array().unwrap();
+        assert_eq!(stats.len(), 100);
+        assert_eq!(stats[99]["cpu_percent"], 149);
+        assert!(snapshot["scheduled"]["stop"].is_string());
+        assert_eq!(snapshot["lease"]["has_lease"], false);
+        assert_eq!(snapshot["ports"], json!([]));
+
+        let response = request()
+            .method("POST")
+            .path("/vm/missing-vm/debug-snapshot")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
@@ -0,0 +1,118 @@
+//! Management of the statistics recorded for each VM.
+
+use std::sync::Arc;
+
+use hyper::http::StatusCode;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth::{require_role, Role};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::delete()
+        .and(warp::path!("vm" / String / "stats"))
+        .and(require_role(state.clone(), Role::Operator))
+        .and(with_state(state))
+        .then(reset_stats)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::delete;
+
+    use super::extract::{Operator, Path, RequireRole};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/stats",
+            delete(|Path(name), _: RequireRole<Operator>, state| reset_stats(name, state)),
+        )
+        .with_state(state)
+}
+
+/// Forgets the VM's accumulated statistics, e.g. after it was restarted.
+async fn reset_stats(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    storage::clear_stats(&mut con, &name).await?;
+    Ok(reply::status(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::auth::Role;
+    use crate::settings::Settings;
+    use crate::storage::{stats_history_key, stats_key};
+    use crate::test_util::{redis_state_with, register, request, sample_vm};
+    use redis::AsyncCommands;
+
+    #[tokio::test]
+    async fn test_reset_stats() {
+        let settings = Settings {
+            api_tokens: [
+                ("viewer-token".to_string(), Role::Viewer),
+                ("operator-token".to_string(), Role::Operator),
+            ]
+            .into(),
+            ..crate::test_util::test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("stats-vm")).await.status(), 200);
+
+        let mut con = ctx.state.connection().await.unwrap();
+        con.hset::<_, _, _, ()>(stats_key("stats-vm"), "cpu_percent", "42")
+            .await
+            .unwrap();
+        con.rpush::<_, _, ()>(stats_history_key("stats-vm"), r#"{"cpu_percent":40}"#)
+            .await
+            .unwrap();
+
+        for (token, status) in [
+            (None, 401),
+            (Some("viewer-token"), 403),
+            (Some("operator-token"), 204),
+        ] {
+            let mut req = request().method("DELETE").path("/vm/stats-vm/stats");
+            if let Some(token) = token {
+                req = req.header("authorization", format!("Bearer {}", token));
+            }
+            assert_eq!(req.reply(&api).await.status(), status, "{:?}", token);
+        }
+        let current: Option<String> = con
+            .hget(stats_key("stats-vm"), "cpu_percent")
+            .await
+            .unwrap();
+        assert_eq!(current, None);
+        let history: Vec<String> = con
+            .lrange(stats_history_key("stats-vm"), 0, -1)
+            .await
+            .unwrap();
+        assert!(history.is_empty());
+
+        let response = request()
+            .method("DELETE")
+            .path("/vm/missing-vm/stats")
+            .header("authorization", "Bearer operator-token")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
@@ -0,0 +1,224 @@
+//! VM tags and labels, and lookups by either.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use serde::Deserialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::validation;
+
+#[derive(Deserialize)]
+struct LabelQuery {
+    key: String,
+    value: String,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let put_tags = warp::put()
+        .and(warp::path!("vm" / String / "tags"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .then(put_tags)
+        .and_then(or_reject);
+
+    let by_tag = warp::get()
+        .and(warp::path!("vms" / "by-tag" / String))
+        .and(with_state(state.clone()))
+        .then(get_vms_by_tag)
+        .and_then(or_reject);
+
+    let by_label = warp::get()
+        .and(warp::path!("vms" / "by-label"))
+        .and(warp::query::<LabelQuery>())
+        .and(with_state(state))
+        .then(get_vms_by_label)
+        .and_then(or_reject);
+
+    put_tags.or(by_tag).or(by_label)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, put};
+
+    use super::extract::{Json, Path, Query};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/tags",
+            put(|Path(name), state, Json(tags)| put_tags(name, tags, state)),
+        )
+        .route(
+            "/vms/by-tag/:tag",
+            get(|Path(tag), state| get_vms_by_tag(tag, state)),
+        )
+        .route(
+            "/vms/by-label",
+            get(|Query(query), state| get_vms_by_label(query, state)),
+        )
+        .with_state(state)
+}
+
+/// Replaces the full tag set of a VM; the record and the tag index change
+/// in one transaction.
+async fn put_tags(
+    name: String,
+    tags: BTreeSet<String>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_tags(&tags)?;
+    let mut con = state.connection().await?;
+    let previous = storage::require_unsealed_vm(&mut con, &name).await?;
+    let mut vm = previous.clone();
+    vm.tags = tags;
+    storage::save_vm(&mut con, &mut vm, Some(&previous)).await?;
+    Ok(reply::json(&vm))
+}
+
+async fn get_vms_by_tag(
+    tag: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_tagged_vms(&mut con, &tag).await?;
+    Ok(reply::json(&vms))
+}
+
+/// VMs labelled `key: value`, looked up in the label index.
+async fn get_vms_by_label(
+    query: LabelQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vms = storage::list_labeled_vms(&mut con, &query.key, &query.value).await?;
+    Ok(reply::json(&vms))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_tags() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, tags) in [
+            ("gpu-vm", vec!["production", "gpu-enabled"]),
+            ("prod-vm", vec!["production"]),
+            ("plain-vm", vec![]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.tags = tags.into_iter().map(String::from).collect();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let api = &api;
+        let by_tag = |tag: &str| {
+            let path = format!("/vms/by-tag/{}", tag);
+            async move {
+                let response = request().method("GET").path(&path).reply(api).await;
+                assert_eq!(response.status(), 200);
+                json_body(&response)
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|vm| vm["name"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(by_tag("production").await, vec!["gpu-vm", "prod-vm"]);
+        assert_eq!(by_tag("gpu-enabled").await, vec!["gpu-vm"]);
+
+        let put_tags = |tags: serde_json::Value| {
+            request()
+                .method("PUT")
+                .path("/vm/gpu-vm/tags")
+                .json(&tags)
+                .reply(api)
+        };
+        let response = put_tags(json!(["staging", "gpu-enabled"])).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            json_body(&response)["tags"],
+            json!(["gpu-enabled", "staging"])
+        );
+        assert_eq!(by_tag("production").await, vec!["prod-vm"]);
+        assert_eq!(by_tag("staging").await, vec!["gpu-vm"]);
+
+        assert_eq!(put_tags(json!(["Not Valid"])).await.status(), 422);
+        assert_eq!(by_tag("staging").await, vec!["gpu-vm"]);
+    }
+
+    #[tokio::test]
+    async fn test_labels() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        for (name, labels) in [
+            ("label-a", vec![("env", "prod"), ("team", "net")]),
+            ("label-b", vec![("env", "prod"), ("team", "gui")]),
+            ("label-c", vec![("env", "dev"), ("team", "net")]),
+        ] {
+            let mut vm = sample_vm(name);
+            vm.labels = labels
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            assert_eq!(register(&api, &vm).await.status(), 200);
+        }
+        let api = &api;
+        let by_label = |key: &str, value: &str| {
+            let path = format!("/vms/by-label?key={}&value={}", key, value);
+            async move {
+                let response = request().method("GET").path(&path).reply(api).await;
+                assert_eq!(response.status(), 200);
+                json_body(&response)
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|vm| vm["name"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(by_label("env", "prod").await, vec!["label-a", "label-b"]);
+        assert_eq!(by_label("team", "net").await, vec!["label-a", "label-c"]);
+        assert_eq!(by_label("env", "dev").await, vec!["label-c"]);
+        assert!(by_label("env", "staging").await.is_empty());
+
+        let response = request()
+            .method("PATCH")
+            .path("/vm/label-a")
+            .json(&json!({ "labels": { "env": "staging" } }))
+            .reply(api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(by_label("env", "prod").await, vec!["label-b"]);
+        assert_eq!(by_label("env", "staging").await, vec!["label-a"]);
+        assert_eq!(by_label("team", "net").await, vec!["label-c"]);
+
+        request()
+            .method("DELETE")
+            .path("/unregister/label-c")
+            .reply(api)
+            .await;
+        assert!(by_label("team", "net").await.is_empty());
+        let mut invalid = sample_vm("label-d");
+        invalid.labels = [("env:x".to_string(), "prod".to_string())].into();
+        assert_eq!(register(api, &invalid).await.status(), 422);
+    }
+}
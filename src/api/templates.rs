@@ -0,0 +1,373 @@
+//! Template VMs and the live VMs instantiated from them.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+use super::{check_vm, claim_namespace};
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+#[cfg(feature = "warp")]
+use crate::auth;
+use crate::auth::Caller;
+use crate::error::RegistryError;
+use crate::models::{Addresses, VMStatus};
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct InstantiateRequest {
+    new_name: String,
+    addresses: Addresses,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let templates = warp::get()
+        .and(warp::path!("vms" / "templates"))
+        .and(with_state(state.clone()))
+        .then(list_templates)
+        .and_then(or_reject);
+
+    let instantiate = warp::post()
+        .and(warp::path!("vm" / String / "instantiate"))
+        .and(warp::body::json())
+        .and(auth::caller(state.clone()))
+        .and(with_state(state.clone()))
+        .then(instantiate_template)
+        .and_then(or_reject);
+
+    let effective_config = warp::get()
+        .and(warp::path!("vm" / String / "effective-config"))
+        .and(with_state(state))
+        .then(get_effective_config)
+        .and_then(or_reject);
+
+    templates.or(instantiate).or(effective_config)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{get, post};
+
+    use super::extract::{Json, Path};
+
+    axum::Router::new()
+        .route("/vms/templates", get(list_templates))
+        .route(
+            "/vm/:name/instantiate",
+            post(|Path(name), caller, state, Json(request)| {
+                instantiate_template(name, request, caller, state)
+            }),
+        )
+        .route(
+            "/vm/:name/effective-config",
+            get(|Path(name), state| get_effective_config(name, state)),
+        )
+        .with_state(state)
+}
+
+async fn list_templates(
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let templates = storage::list_templates(&mut con).await?;
+    Ok(reply::json(&templates))
+}
+
+/// Registers a live copy of template `name` under `new_name` with its own
+/// addresses.
+async fn instantiate_template(
+    name: String,
+    request: InstantiateRequest,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let template = storage::require_vm(&mut con, &name).await?;
+    if !template.is_template {
+        return Err(RegistryError::Conflict(format!(
+            "VM '{}' is not a template",
+            name
+        )));
+    }
+    let mut vm = template;
+    vm.template_name = Some(vm.name);
+    vm.name = request.new_name;
+    vm.addresses = request.addresses;
+    vm.is_template = false;
+    vm.sealed = false;
+    vm.owner = caller.and_then(|caller| caller.identity);
+    vm.status = VMStatus::Registered;
+    vm.last_heartbeat_at = None;
+    vm.hostname = None;
+    check_vm(&mut vm, &state).await?;
+    if !storage::claim_vm_name(&mut con, &vm).await? {
+        return Err(RegistryError::AlreadyExists(vm.name));
+    }
+    let saved = async {
+        claim_namespace(&mut con, &state, &vm, None).await?;
+        storage::save_vm(&mut con, &mut vm, None).await
+    }
+    .await;
+    if let Err(e) = saved {
+        storage::release_vm_name(&mut con, &vm.name).await?;
+        return Err(e);
+    }
+    Ok(reply::json(&vm))
+}
+
+/// Fields that identify the instance or only make sense for it; they are
+/// never inherited.
+const INSTANCE_FIELDS: &[&str] = &[
+    "name",
+    "namespace",
+    "addresses",
+    "is_template",
+    "template_name",
+    "sealed",
+    "owner",
+    "hostname",
+];
+
+/// `null`, `0` and empty lists, strings and objects: what a field holds when
+/// it was never declared.
+fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Number(number) => number.as_f64() == Some(0.0),
+        Value::String(string) => string.is_empty(),
+        Value::Array(items) => items.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        Value::Bool(_) => false,
+    }
+}
+
+/// Merges the configuration fields of an instance over those of its
+/// template, returning the merged fields and, per field, whether the value
+/// comes from the `"template"` or the `"instance"`:
+///
+/// * `INSTANCE_FIELDS` always come from the instance.
+/// * A field the instance leaves unset (see `is_unset`) is inherited from
+///   the template.
+/// * Any other field keeps the instance's value. It is attributed to the
+///   template while it still equals the template's value, i.e. was copied
+///   on instantiation and not overridden since.
+fn merge_config(
+    instance: Map<String, Value>,
+    template: Option<&Map<String, Value>>,
+) -> (Map<String, Value>, Map<String, Value>) {
+    let mut config = Map::new();
+    let mut source = Map::new();
+    for (field, value) in instance {
+        let inherited = template
+            .filter(|_| !INSTANCE_FIELDS.contains(&field.as_str()))
+            .and_then(|template| template.get(&field));
+        let (value, from) = match inherited {
+            Some(inherited) if is_unset(&value) && !is_unset(inherited) => {
+                (inherited.clone(), "template")
+            }
+            Some(inherited) if *inherited == value => (value, "template"),
+            _ => (value, "instance"),
+        };
+        source.insert(field.clone(), json!(from));
+        config.insert(field, value);
+    }
+    (config, source)
+}
+
+/// The configuration a VM effectively runs with once fields it does not
+/// set are taken from its template; see `merge_config`. A VM without a
+/// template, or whose template is gone, is reported as is.
+async fn get_effective_config(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    let vm = storage::require_vm(&mut con, &name).await?;
+    let template = match &vm.template_name {
+        Some(template_name) => storage::get_vm(&mut con, template_name).await?,
+        None => None,
+    };
+    let template = template
+        .map(|template| template.config_fields())
+        .transpose()
+        .map_err(RegistryError::from)?;
+    let instance = vm.config_fields().map_err(RegistryError::from)?;
+    let (config, source) = merge_config(instance, template.as_ref());
+    Ok(reply::json(&json!({
+        "name": name,
+        "template_name": template.as_ref().and(vm.template_name),
+        "config": config,
+        "source": source,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_config;
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_instantiate_template() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut template = sample_vm("browser-template");
+        template.is_template = true;
+        template.mime_types = vec!["text/html".to_string()];
+        assert_eq!(register(&api, &template).await.status(), 200);
+        assert_eq!(register(&api, &sample_vm("net-vm")).await.status(), 200);
+
+        let instantiate = |name: &str, new_name: &str| {
+            request()
+                .method("POST")
+                .path(&format!("/vm/{}/instantiate", name))
+                .json(&json!({
+                    "new_name": new_name,
+                    "addresses": { "ip": "192.168.100.20", "vsock": "20" },
+                }))
+                .reply(&api)
+        };
+        let response = instantiate("browser-template", "browser-vm").await;
+        assert_eq!(response.status(), 200);
+        let instance = json_body(&response);
+        assert_eq!(instance["name"], "browser-vm");
+        assert_eq!(instance["is_template"], false);
+        assert_eq!(instance["addresses"]["ip"], "192.168.100.20");
+        assert_eq!(instance["mime_types"], json!(["text/html"]));
+
+        let response = request()
+            .method("GET")
+            .path("/vms/templates")
+            .reply(&api)
+            .await;
+        let templates = json_body(&response);
+        let names: Vec<&str> = templates
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|vm| vm["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["browser-template"]);
+
+        let response = request()
+            .method("GET")
+            .path("/vms/startup-order")
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response), json!([["browser-vm", "net-vm"]]));
+
+        assert_eq!(instantiate("net-vm", "other-vm").await.status(), 409);
+        assert_eq!(
+            instantiate("browser-template", "browser-vm").await.status(),
+            409
+        );
+        assert_eq!(instantiate("missing-vm", "other-vm").await.status(), 404);
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let template = json!({
+            "name": "browser-template",
+            "vcpu_count": 4,
+            "memory_limit_mb": 2048,
+            "capabilities": ["clipboard"],
+            "description": "Browser",
+        });
+        let instance = json!({
+            "name": "browser-vm",
+            "vcpu_count": 0,
+            "memory_limit_mb": 4096,
+            "capabilities": ["clipboard"],
+            "description": null,
+            "priority": 3,
+        });
+        let (config, source) =
+            merge_config(instance.as_object().unwrap().clone(), template.as_object());
+        assert_eq!(
+            json!(config),
+            json!({
+                "name": "browser-vm",
+                "vcpu_count": 4,
+                "memory_limit_mb": 4096,
+                "capabilities": ["clipboard"],
+                "description": "Browser",
+                "priority": 3,
+            })
+        );
+        assert_eq!(
+            json!(source),
+            json!({
+                "name": "instance",
+                "vcpu_count": "template",
+                "memory_limit_mb": "instance",
+                "capabilities": "template",
+                "description": "template",
+                "priority": "instance",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_effective_config() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let mut template = sample_vm("office-template");
+        template.is_template = true;
+        template.vcpu_count = 2;
+        template.capabilities = vec!["printing".to_string()];
+        assert_eq!(register(&api, &template).await.status(), 200);
+        let response = request()
+            .method("POST")
+            .path("/vm/office-template/instantiate")
+            .json(&json!({
+                "new_name": "office-vm",
+                "addresses": { "ip": "192.168.100.30", "vsock": "30" },
+            }))
+            .reply(&api)
+            .await;
+        assert_eq!(json_body(&response)["template_name"], "office-template");
+        request()
+            .method("PATCH")
+            .path("/vm/office-vm")
+            .json(&json!({ "vcpu_count": 6, "capabilities": [] }))
+            .reply(&api)
+            .await;
+
+        let response = request()
+            .method("GET")
+            .path("/vm/office-vm/effective-config")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = json_body(&response);
+        assert_eq!(body["template_name"], "office-template");
+        assert_eq!(body["config"]["vcpu_count"], 6);
+        assert_eq!(body["source"]["vcpu_count"], "instance");
+        assert_eq!(body["config"]["capabilities"], json!(["printing"]));
+        assert_eq!(body["source"]["capabilities"], "template");
+        assert_eq!(body["config"]["addresses"]["ip"], "192.168.100.30");
+        assert_eq!(body["source"]["addresses"], "instance");
+
+        let response = request()
+            .method("GET")
+            .path("/vm/office-template/effective-config")
+            .reply(&api)
+            .await;
+        let body = json_body(&response);
+        assert_eq!(body["template_name"], json!(null));
+        assert_eq!(body["source"]["vcpu_count"], "instance");
+    }
+}
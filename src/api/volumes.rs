@@ -0,0 +1,170 @@
+//! Storage volumes attached to VMs.
+
+use std::sync::Arc;
+
+use hyper::http::StatusCode;
+use uuid::Uuid;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::auth::{self, Caller};
+use crate::error::RegistryError;
+use crate::models::Volume;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+use crate::validation;
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let attach = warp::post()
+        .and(warp::path!("vm" / String / "attach-volume"))
+        .and(warp::body::json())
+        .and(auth::caller(state.clone()))
+        .and(with_state(state.clone()))
+        .then(attach_volume)
+        .and_then(or_reject);
+
+    let detach = warp::delete()
+        .and(warp::path!("vm" / String / "detach-volume" / Uuid))
+        .and(with_state(state.clone()))
+        .then(detach_volume)
+        .and_then(or_reject);
+
+    let list = warp::get()
+        .and(warp::path!("vm" / String / "volumes"))
+        .and(with_state(state))
+        .then(get_volumes)
+        .and_then(or_reject);
+
+    attach.or(detach).or(list)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::{delete, get, post};
+
+    use super::extract::{Json, Path};
+
+    axum::Router::new()
+        .route(
+            "/vm/:name/attach-volume",
+            post(|Path(name), caller, state, Json(volume)| {
+                attach_volume(name, volume, caller, state)
+            }),
+        )
+        .route(
+            "/vm/:name/detach-volume/:id",
+            delete(|Path((name, id)), state| detach_volume(name, id, state)),
+        )
+        .route(
+            "/vm/:name/volumes",
+            get(|Path(name), state| get_volumes(name, state)),
+        )
+        .with_state(state)
+}
+
+/// Attaches one volume and returns it with its id.
+async fn attach_volume(
+    name: String,
+    volume: Volume,
+    caller: Option<Caller>,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    validation::validate_volume(&volume)?;
+    let mut con = state.connection().await?;
+    let vm = storage::require_unsealed_vm(&mut con, &name).await?;
+    auth::authorize_owner(caller.as_ref(), &vm)?;
+    storage::save_volume(&mut con, &name, &volume).await?;
+    Ok(reply::json(&volume))
+}
+
+async fn detach_volume(
+    name: String,
+    id: Uuid,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_unsealed_vm(&mut con, &name).await?;
+    if !storage::remove_volume(&mut con, &name, id).await? {
+        return Err(RegistryError::VolumeNotAttached(id));
+    }
+    Ok(reply::with_status("Volume detached.", StatusCode::OK))
+}
+
+async fn get_volumes(
+    name: String,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    let mut con = state.connection().await?;
+    storage::require_vm(&mut con, &name).await?;
+    let volumes = storage::get_volumes(&mut con, &name).await?;
+    Ok(reply::json(&volumes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::test_util::{json_body, redis_state, register, request, sample_vm};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_attach_and_detach_volumes() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        assert_eq!(register(&api, &sample_vm("storage-vm")).await.status(), 200);
+        let attach = |volume: serde_json::Value| {
+            request()
+                .method("POST")
+                .path("/vm/storage-vm/attach-volume")
+                .json(&volume)
+                .reply(&api)
+        };
+
+        let response = attach(json!({ "path": "/var/lib/vms/data.img", "size_gb": 20 })).await;
+        assert_eq!(response.status(), 200);
+        let data_id = json_body(&response)["id"].as_str().unwrap().to_string();
+        let response = attach(json!({
+            "path": "/var/lib/vms/home.img",
+            "size_gb": 50,
+            "read_only": true,
+            "filesystem": "ext4",
+        }))
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            attach(json!({ "path": "/var/lib/../etc/shadow", "size_gb": 1 }))
+                .await
+                .status(),
+            422
+        );
+
+        let volumes = || {
+            request()
+                .method("GET")
+                .path("/vm/storage-vm/volumes")
+                .reply(&api)
+        };
+        let listed = json_body(&volumes().await);
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+        assert_eq!(listed[1]["filesystem"], "ext4");
+
+        let detach = |id: &str| {
+            request()
+                .method("DELETE")
+                .path(&format!("/vm/storage-vm/detach-volume/{}", id))
+                .reply(&api)
+        };
+        assert_eq!(detach(&data_id).await.status(), 200);
+        assert_eq!(detach(&data_id).await.status(), 404);
+        let listed = json_body(&volumes().await);
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["path"], "/var/lib/vms/home.img");
+    }
+}
@@ -0,0 +1,155 @@
+//! Long-polling watch on VM record changes, in the style of Kubernetes
+//! `?watch=true` list requests.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::stream;
+use hyper::http::header;
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "warp")]
+use super::{or_reject, with_state};
+use crate::error::RegistryError;
+use crate::reply::{self, Response};
+use crate::state::{AppState, StateExtension};
+use crate::storage;
+
+#[derive(Deserialize)]
+struct WatchQuery {
+    #[serde(default)]
+    watch: bool,
+    /// Only report changes to VMs in this namespace.
+    namespace: Option<String>,
+}
+
+#[cfg(feature = "warp")]
+pub fn routes(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("vms"))
+        .and(warp::query::<WatchQuery>())
+        .and(with_state(state))
+        .then(list_or_watch)
+        .and_then(or_reject)
+}
+
+#[cfg(feature = "axum")]
+pub fn routes(state: Arc<AppState>) -> axum::Router {
+    use axum::routing::get;
+
+    use super::extract::Query;
+
+    axum::Router::new()
+        .route(
+            "/vms",
+            get(|Query(query), state| list_or_watch(query, state)),
+        )
+        .with_state(state)
+}
+
+/// Lists all VMs, or with `watch=true` streams one JSON change
+/// notification per line as VMs are registered, updated or unregistered.
+/// The stream ends after `watch_timeout_secs`.
+async fn list_or_watch(
+    query: WatchQuery,
+    StateExtension(state): StateExtension<Arc<AppState>>,
+) -> Result<Response, RegistryError> {
+    if !query.watch {
+        let mut con = state.connection().await?;
+        let vms = storage::list_vms(&mut con).await?;
+        return Ok(reply::json(&vms));
+    }
+    let deadline = Instant::now() + Duration::from_secs(state.settings.watch_timeout_secs);
+    let changes = state.changes.subscribe();
+    let lines = stream::unfold(changes, move |mut changes| {
+        let namespace = query.namespace.clone();
+        async move {
+            loop {
+                let payload = match tokio::time::timeout_at(deadline, changes.recv()).await {
+                    Ok(Ok(payload)) => payload,
+                    // A watcher too slow to keep up misses changes rather
+                    // than holding back the others.
+                    Ok(Err(RecvError::Lagged(_))) => continue,
+                    Ok(Err(RecvError::Closed)) | Err(_) => return None,
+                };
+                if !in_namespace(&payload, namespace.as_deref()) {
+                    continue;
+                }
+                let line = Ok::<_, Infallible>(payload + "\n");
+                return Some((line, changes));
+            }
+        }
+    });
+    Ok(reply::with_header(
+        Response::new(Body::wrap_stream(lines)),
+        header::CONTENT_TYPE,
+        "application/x-ndjson",
+    ))
+}
+
+fn in_namespace(payload: &str, namespace: Option<&str>) -> bool {
+    let Some(namespace) = namespace else {
+        return true;
+    };
+    serde_json::from_str::<Value>(payload)
+        .is_ok_and(|change| change["namespace"].as_str() == Some(namespace))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::settings::Settings;
+    use crate::test_util::{redis_state_with, register, request, sample_vm};
+
+    #[tokio::test]
+    async fn test_watch_vms() {
+        let settings = Settings {
+            watch_timeout_secs: 1,
+            ..crate::test_util::test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let pubsub = crate::events::subscribe(&ctx.state).await.unwrap();
+        tokio::spawn(crate::events::forward(pubsub, ctx.state.changes.clone()));
+        let api = routes(ctx.state.clone());
+
+        let watch = tokio::spawn({
+            let api = api.clone();
+            async move { request().path("/vms?watch=true").reply(&api).await }
+        });
+        while ctx.state.changes.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(register(&api, &sample_vm("watched-vm")).await.status(), 200);
+
+        let response = watch.await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let body = std::str::from_utf8(response.body()).unwrap();
+        let changes: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|change: &serde_json::Value| change["name"] == "watched-vm")
+            .collect();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0]["event"], "registered");
+        assert_eq!(changes[0]["status"], "Registered");
+
+        let response = request().path("/vms").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            crate::test_util::json_body(&response)[0]["name"],
+            "watched-vm"
+        );
+    }
+}
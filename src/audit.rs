@@ -0,0 +1,389 @@
+//! Recording VM updates and starts and stops in the per-VM audit logs,
+//! uptime derived from them, and retention of the logs, which would
+//! otherwise grow without bound.
+
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::diff::{self, Diff};
+use crate::error::RegistryError;
+use crate::models::{VMStatus, VM, VOLATILE_FIELDS};
+use crate::state::{AppState, RedisConnection};
+use crate::storage;
+
+/// Records the update of VM `name` from `previous` to `vm` by `by` as the
+/// field-level diff of their configurations, and a `started` or `stopped`
+/// entry when the VM entered or left `Running`. Updates that change nothing
+/// else are not recorded.
+pub async fn record_audit_event(
+    con: &mut RedisConnection,
+    name: &str,
+    by: Option<String>,
+    previous: &VM,
+    vm: &VM,
+) -> Result<(), RegistryError> {
+    let now = Utc::now();
+    let diff = diff::vm_diff(previous, vm)?;
+    if !diff.is_empty() {
+        let mut entry = diff.to_json();
+        entry["kind"] = json!("updated");
+        entry["by"] = json!(by);
+        entry["timestamp"] = json!(now);
+        storage::append_audit_entry(con, name, &entry).await?;
+    }
+    let kind = match (previous.status, vm.status) {
+        (previous, VMStatus::Running) if previous != VMStatus::Running => "started",
+        (VMStatus::Running, status) if status != VMStatus::Running => "stopped",
+        _ => return Ok(()),
+    };
+    let entry = json!({
+        "kind": kind,
+        "status": vm.status,
+        "by": by,
+        "timestamp": now,
+    });
+    storage::append_audit_entry(con, name, &entry).await
+}
+
+/// Time a VM spent running according to its audit log.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Uptime {
+    pub name: String,
+    pub total_uptime_secs: i64,
+    pub start_count: u32,
+}
+
+/// A `started` or `stopped` audit log entry.
+struct Transition {
+    at: DateTime<Utc>,
+    started: bool,
+    /// The status a stopped VM was left in; unknown for older entries.
+    status: Option<VMStatus>,
+}
+
+/// The starts and stops in `entries`, ordered by time rather than by their
+/// whole-second scores.
+fn transitions(entries: &[String]) -> Vec<Transition> {
+    let mut transitions: Vec<Transition> = entries
+        .iter()
+        .filter_map(|entry| {
+            let entry: Value = serde_json::from_str(entry).ok()?;
+            let started = match entry["kind"].as_str()? {
+                "started" => true,
+                "stopped" => false,
+                _ => return None,
+            };
+            Some(Transition {
+                at: serde_json::from_value(entry["timestamp"].clone()).ok()?,
+                started,
+                status: serde_json::from_value(entry["status"].clone()).ok(),
+            })
+        })
+        .collect();
+    transitions.sort_by_key(|transition| transition.at);
+    transitions
+}
+
+/// Time of the latest `started` entry in `entries`, if compaction kept any.
+pub fn last_started(entries: &[String]) -> Option<DateTime<Utc>> {
+    transitions(entries)
+        .into_iter()
+        .filter(|transition| transition.started)
+        .map(|transition| transition.at)
+        .next_back()
+}
+
+/// Sums the intervals between each `started` entry of the audit log of VM
+/// `name` and the next `stopped` one; a VM still running counts up to
+/// `now`. Only the entries compaction has kept are considered.
+pub fn uptime(name: &str, entries: &[String], now: DateTime<Utc>) -> Uptime {
+    let mut report = Uptime {
+        name: name.to_string(),
+        total_uptime_secs: 0,
+        start_count: 0,
+    };
+    let mut running_since = None;
+    for transition in transitions(entries) {
+        match (transition.started, running_since) {
+            (true, None) => {
+                report.start_count += 1;
+                running_since = Some(transition.at);
+            }
+            (false, Some(since)) => {
+                report.total_uptime_secs += (transition.at - since).num_seconds();
+                running_since = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(since) = running_since {
+        report.total_uptime_secs += (now - since).num_seconds().max(0);
+    }
+    report
+}
+
+/// Periods a VM spent `Stopped` or `Failed` according to its audit log.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Downtime {
+    pub name: String,
+    pub longest_downtime_secs: i64,
+    pub last_downtime_start: DateTime<Utc>,
+    /// `None` while the VM is still down.
+    pub last_downtime_end: Option<DateTime<Utc>>,
+}
+
+/// The downtimes of VM `name`: intervals from a `stopped` entry that left
+/// it `Stopped` or `Failed` to the next `started` one. A VM still down
+/// counts up to `now`. `None` when the log records no downtime.
+pub fn downtime(name: &str, entries: &[String], now: DateTime<Utc>) -> Option<Downtime> {
+    let mut report: Option<Downtime> = None;
+    let mut down_since = None;
+    for transition in transitions(entries) {
+        match (transition.started, down_since) {
+            (false, None)
+                if transition.status.is_none_or(|status| {
+                    matches!(status, VMStatus::Stopped | VMStatus::Failed)
+                }) =>
+            {
+                down_since = Some(transition.at);
+            }
+            (true, Some(since)) => {
+                let secs = (transition.at - since).num_seconds();
+                record_downtime(&mut report, name, since, Some(transition.at), secs);
+                down_since = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(since) = down_since {
+        let secs = (now - since).num_seconds().max(0);
+        record_downtime(&mut report, name, since, None, secs);
+    }
+    report
+}
+
+fn record_downtime(
+    report: &mut Option<Downtime>,
+    name: &str,
+    start: DateTime<Utc>,
+    end: Option<DateTime<Utc>>,
+    secs: i64,
+) {
+    let longest = report
+        .as_ref()
+        .map_or(secs, |report| report.longest_downtime_secs.max(secs));
+    *report = Some(Downtime {
+        name: name.to_string(),
+        longest_downtime_secs: longest,
+        last_downtime_start: start,
+        last_downtime_end: end,
+    });
+}
+
+/// An audit log entry as stored, with older entries that kept the complete
+/// VM JSON in `old_value` and `new_value` converted to the diff form.
+/// Entries that are not JSON are returned as strings.
+pub fn readable_entry(entry: String) -> Value {
+    let Ok(Value::Object(mut fields)) = serde_json::from_str::<Value>(&entry) else {
+        return serde_json::from_str(&entry).unwrap_or(Value::String(entry));
+    };
+    let (Some(old), Some(new)) = (
+        fields.get("old_value").and_then(config_fields),
+        fields.get("new_value").and_then(config_fields),
+    ) else {
+        return Value::Object(fields);
+    };
+    fields.remove("old_value");
+    fields.remove("new_value");
+    if let Value::Object(diff) = Diff::between(&old, &new).to_json() {
+        fields.extend(diff);
+    }
+    Value::Object(fields)
+}
+
+/// The configuration fields of a VM serialized into a JSON string by older
+/// audit log entries.
+fn config_fields(value: &Value) -> Option<Map<String, Value>> {
+    let Ok(Value::Object(mut fields)) = serde_json::from_str(value.as_str()?) else {
+        return None;
+    };
+    fields.retain(|field, _| field != "name" && !VOLATILE_FIELDS.contains(&field.as_str()));
+    Some(fields)
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Audit logs that had at least one entry removed.
+    pub vms_compacted: usize,
+    pub entries_removed: u64,
+}
+
+/// Removes every audit log entry scored before `now - older_than`.
+pub async fn compact(
+    con: &mut RedisConnection,
+    older_than: Duration,
+) -> Result<CompactionReport, RegistryError> {
+    let cutoff = (Utc::now() - older_than).timestamp();
+    let mut report = CompactionReport {
+        vms_compacted: 0,
+        entries_removed: 0,
+    };
+    for name in storage::scan_names(con, storage::AUDIT_KEY_PREFIX).await? {
+        let removed: u64 = redis::cmd("ZREMRANGEBYSCORE")
+            .arg(storage::audit_key(&name))
+            .arg("-inf")
+            .arg(format!("({}", cutoff))
+            .query_async(con)
+            .await?;
+        if removed > 0 {
+            report.vms_compacted += 1;
+            report.entries_removed += removed;
+        }
+    }
+    Ok(report)
+}
+
+/// Time until the next `at` (UTC) strictly after `now`.
+fn until_next(now: DateTime<Utc>, at: NaiveTime) -> Duration {
+    let today = now.date_naive().and_time(at).and_utc();
+    let next = if today > now {
+        today
+    } else {
+        today + Duration::days(1)
+    };
+    next - now
+}
+
+/// Starts daily compaction at `audit_compact_at` when it is configured.
+pub fn spawn(state: AppState) {
+    let Some(at) = state.settings.audit_compact_at else {
+        return;
+    };
+    let retention = Duration::days(i64::from(state.settings.audit_retention_days));
+    tokio::spawn(async move {
+        loop {
+            let wait = until_next(Utc::now(), at).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            let result = match state.connection().await {
+                Ok(mut con) => compact(&mut con, retention).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(report) if report.entries_removed > 0 => tracing::info!(
+                    entries = report.entries_removed,
+                    vms = report.vms_compacted,
+                    "compacted audit logs"
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::error!(error = %e, "audit log compaction failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readable_entry() {
+        let old = json!({ "name": "gui-vm", "priority": 0, "memory": 1024, "status": "Running" });
+        let new = json!({ "name": "gui-vm", "priority": 5, "memory": 1024, "status": "Stopped" });
+        let legacy = json!({
+            "kind": "updated",
+            "old_value": old.to_string(),
+            "new_value": new.to_string(),
+        });
+        assert_eq!(
+            readable_entry(legacy.to_string()),
+            json!({
+                "kind": "updated",
+                "added": {},
+                "removed": {},
+                "changed": { "priority": { "old": 0, "new": 5 } },
+            })
+        );
+        let current = json!({ "kind": "ownership_transferred", "to": "alice" });
+        assert_eq!(readable_entry(current.to_string()), current);
+        assert_eq!(
+            readable_entry("registered".to_string()),
+            json!("registered")
+        );
+    }
+
+    #[test]
+    fn test_uptime() {
+        let at = |time: &str| format!("2024-05-01T{}Z", time);
+        let entry =
+            |kind: &str, time: &str| json!({ "kind": kind, "timestamp": at(time) }).to_string();
+        let entries = vec![
+            entry("started", "08:00:00"),
+            json!({ "kind": "updated", "timestamp": at("08:30:00") }).to_string(),
+            entry("stopped", "09:00:00"),
+            // Out of order within one second of the score.
+            entry("stopped", "10:00:30"),
+            entry("started", "10:00:00"),
+            entry("stopped", "10:00:40"),
+            entry("started", "11:00:00"),
+            "registered".to_string(),
+        ];
+        let now = at("11:15:00").parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            uptime("gui-vm", &entries, now),
+            Uptime {
+                name: "gui-vm".to_string(),
+                total_uptime_secs: 3600 + 30 + 900,
+                start_count: 3,
+            }
+        );
+        assert_eq!(uptime("idle-vm", &[], now).total_uptime_secs, 0);
+    }
+
+    #[test]
+    fn test_downtime() {
+        let at = |time: &str| {
+            format!("2024-05-01T{}Z", time)
+                .parse::<DateTime<Utc>>()
+                .unwrap()
+        };
+        let entry = |kind: &str, status: &str, time: &str| {
+            json!({ "kind": kind, "status": status, "timestamp": at(time) }).to_string()
+        };
+        let entries = vec![
+            entry("started", "Running", "08:00:00"),
+            entry("stopped", "Failed", "09:00:00"),
+            entry("started", "Running", "11:00:00"),
+            entry("stopped", "Stopped", "12:00:00"),
+            entry("started", "Running", "12:30:00"),
+        ];
+        let now = at("13:00:00");
+        assert_eq!(
+            downtime("net-vm", &entries, now),
+            Some(Downtime {
+                name: "net-vm".to_string(),
+                longest_downtime_secs: 7200,
+                last_downtime_start: at("12:00:00"),
+                last_downtime_end: Some(at("12:30:00")),
+            })
+        );
+
+        let mut down = entries.clone();
+        down.push(entry("stopped", "Failed", "12:40:00"));
+        let report = downtime("net-vm", &down, at("16:00:00")).unwrap();
+        assert_eq!(report.longest_downtime_secs, 3 * 3600 + 20 * 60);
+        assert_eq!(report.last_downtime_start, at("12:40:00"));
+        assert_eq!(report.last_downtime_end, None);
+
+        assert_eq!(downtime("net-vm", &entries[..1], now), None);
+    }
+
+    #[test]
+    fn test_until_next() {
+        let at = NaiveTime::from_hms_opt(3, 0, 0).unwrap();
+        let before = "2024-05-01T02:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(until_next(before, at), Duration::minutes(30));
+        let after = "2024-05-01T03:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(until_next(after, at), Duration::days(1));
+    }
+}
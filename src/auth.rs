@@ -0,0 +1,121 @@
+//! Bearer-token authentication. Tokens and the role each one grants are
+//! listed in `Settings.api_tokens`; with no tokens configured every caller
+//! is treated as an admin. `Settings.token_identities` names the holder of
+//! a token, who then owns the VMs registered with it.
+
+#[cfg(feature = "warp")]
+use std::sync::Arc;
+
+use serde::Deserialize;
+#[cfg(feature = "warp")]
+use warp::{Filter, Rejection};
+
+use crate::error::RegistryError;
+use crate::models::VM;
+use crate::state::AppState;
+
+/// Roles in increasing order of privilege; each includes the ones below it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// An authenticated caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub role: Role,
+    /// Set when `Settings.token_identities` names the caller's token.
+    pub identity: Option<String>,
+}
+
+fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Resolves the role of the caller presenting `authorization`.
+fn caller_role(state: &AppState, authorization: Option<&str>) -> Result<Role, RegistryError> {
+    let tokens = &state.settings.api_tokens;
+    if tokens.is_empty() {
+        return Ok(Role::Admin);
+    }
+    bearer_token(authorization)
+        .and_then(|token| tokens.get(token))
+        .copied()
+        .ok_or(RegistryError::Unauthorized)
+}
+
+/// The caller presenting `authorization`, or `None` when its token is
+/// missing or unknown.
+pub fn resolve_caller(state: &AppState, authorization: Option<&str>) -> Option<Caller> {
+    let role = caller_role(state, authorization).ok()?;
+    let identity = bearer_token(authorization)
+        .and_then(|token| state.settings.token_identities.get(token))
+        .cloned();
+    Some(Caller { role, identity })
+}
+
+/// Extracts the caller without rejecting unauthenticated requests; routes
+/// that require a role add `require_role` as well.
+#[cfg(feature = "warp")]
+pub fn caller(
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (Option<Caller>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .map(move |authorization: Option<String>| resolve_caller(&state, authorization.as_deref()))
+}
+
+/// Admins may change any VM; other callers only VMs they own or that have
+/// no owner.
+pub fn authorize_owner(caller: Option<&Caller>, vm: &VM) -> Result<(), RegistryError> {
+    let Some(owner) = &vm.owner else {
+        return Ok(());
+    };
+    match caller {
+        Some(caller) if caller.role == Role::Admin => Ok(()),
+        Some(caller) if caller.identity.as_ref() == Some(owner) => Ok(()),
+        _ => Err(RegistryError::NotOwner(vm.name.clone())),
+    }
+}
+
+/// Fails unless the bearer token in `authorization` grants at least
+/// `required`.
+pub fn check_role(
+    state: &AppState,
+    authorization: Option<&str>,
+    required: Role,
+) -> Result<(), RegistryError> {
+    if caller_role(state, authorization)? >= required {
+        Ok(())
+    } else {
+        Err(RegistryError::Forbidden(required.as_str().to_string()))
+    }
+}
+
+/// Rejects requests whose bearer token does not grant at least `required`.
+#[cfg(feature = "warp")]
+pub fn require_role(
+    state: Arc<AppState>,
+    required: Role,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let checked = check_role(&state, authorization.as_deref(), required);
+            async move { checked.map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+}
@@ -0,0 +1,157 @@
+//! Point-in-time dumps of every `ghaf:*` key and their restoration.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RegistryError;
+use crate::state::RedisConnection;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Archive {
+    pub timestamp: DateTime<Utc>,
+    pub keys: Vec<ArchivedKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub value: ArchivedValue,
+    /// Remaining time to live, for keys that expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum ArchivedValue {
+    String(String),
+    Hash(BTreeMap<String, String>),
+    Set(Vec<String>),
+    Zset(Vec<(String, f64)>),
+    List(Vec<String>),
+}
+
+/// Reads every `ghaf:*` key with its type, value and expiry.
+pub async fn dump(con: &mut RedisConnection) -> Result<Archive, RegistryError> {
+    let mut names = Vec::new();
+    {
+        let mut keys = con.scan_match::<_, String>("ghaf:*").await?;
+        while let Some(key) = keys.next_item().await {
+            names.push(key);
+        }
+    }
+    names.sort();
+    let mut keys = Vec::new();
+    for key in names {
+        let kind: String = redis::cmd("TYPE").arg(&key).query_async(con).await?;
+        let value = match kind.as_str() {
+            "string" => ArchivedValue::String(con.get(&key).await?),
+            "hash" => ArchivedValue::Hash(con.hgetall(&key).await?),
+            "set" => {
+                let mut members: Vec<String> = con.smembers(&key).await?;
+                members.sort();
+                ArchivedValue::Set(members)
+            }
+            "zset" => ArchivedValue::Zset(con.zrange_withscores(&key, 0, -1).await?),
+            "list" => ArchivedValue::List(con.lrange(&key, 0, -1).await?),
+            // Removed since the scan, or a type the registry never writes.
+            _ => continue,
+        };
+        let ttl: i64 = con.ttl(&key).await?;
+        keys.push(ArchivedKey {
+            key,
+            value,
+            ttl_secs: u64::try_from(ttl).ok().filter(|ttl| *ttl > 0),
+        });
+    }
+    Ok(Archive {
+        timestamp: Utc::now(),
+        keys,
+    })
+}
+
+/// Writes every archived key back in one transaction, replacing existing
+/// values of the same keys.
+pub async fn restore(con: &mut RedisConnection, archive: &Archive) -> Result<(), RegistryError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for archived in &archive.keys {
+        let key = &archived.key;
+        if !key.starts_with("ghaf:") {
+            return Err(RegistryError::Validation(format!(
+                "archive contains foreign key '{}'",
+                key
+            )));
+        }
+        pipe.del(key).ignore();
+        match &archived.value {
+            ArchivedValue::String(value) => pipe.set(key, value).ignore(),
+            ArchivedValue::Hash(fields) if fields.is_empty() => continue,
+            ArchivedValue::Hash(fields) => {
+                let fields: Vec<(&String, &String)> = fields.iter().collect();
+                pipe.hset_multiple(key, &fields).ignore()
+            }
+            ArchivedValue::Set(members) if members.is_empty() => continue,
+            ArchivedValue::Set(members) => pipe.sadd(key, members.as_slice()).ignore(),
+            ArchivedValue::Zset(members) if members.is_empty() => continue,
+            ArchivedValue::Zset(members) => {
+                let members: Vec<(f64, &String)> = members
+                    .iter()
+                    .map(|(member, score)| (*score, member))
+                    .collect();
+                pipe.zadd_multiple(key, &members).ignore()
+            }
+            ArchivedValue::List(items) if items.is_empty() => continue,
+            ArchivedValue::List(items) => pipe.rpush(key, items.as_slice()).ignore(),
+        };
+        if let Some(ttl) = archived.ttl_secs {
+            pipe.expire(key, ttl as usize).ignore();
+        }
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    Ok(())
+}
+
+/// Writes `archive` to a new timestamped file in `dir` and returns its name.
+pub async fn write_archive(dir: &Path, archive: &Archive) -> Result<String, RegistryError> {
+    let file = format!(
+        "registry-backup-{}.json",
+        archive.timestamp.format("%Y%m%dT%H%M%S%.3fZ")
+    );
+    let io_error = |e: std::io::Error| RegistryError::Backup(format!("{}: {}", dir.display(), e));
+    tokio::fs::create_dir_all(dir).await.map_err(io_error)?;
+    let json = serde_json::to_vec_pretty(archive)?;
+    tokio::fs::write(dir.join(&file), json)
+        .await
+        .map_err(io_error)?;
+    Ok(file)
+}
+
+/// Reads the archive named `file` from `dir`. Only plain file names are
+/// accepted, so restores cannot read outside the backup directory.
+pub async fn read_archive(dir: &Path, file: &str) -> Result<Archive, RegistryError> {
+    if Path::new(file).file_name().and_then(|name| name.to_str()) != Some(file) {
+        return Err(RegistryError::BadRequest(format!(
+            "invalid backup file name '{}'",
+            file
+        )));
+    }
+    let path: PathBuf = dir.join(file);
+    let raw = match tokio::fs::read(&path).await {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(RegistryError::BadRequest(format!(
+                "no backup named '{}'",
+                file
+            )))
+        }
+        Err(e) => return Err(RegistryError::Backup(format!("{}: {}", path.display(), e))),
+    };
+    serde_json::from_slice(&raw)
+        .map_err(|e| RegistryError::BadRequest(format!("invalid backup '{}': {}", file, e)))
+}
@@ -0,0 +1,193 @@
+//! AES-256-GCM encryption of VM records at rest.
+//!
+//! Encrypted values are stored as `enc:v2:` followed by the base64 encoding
+//! of the 96-bit nonce and the ciphertext with its tag. The Redis key a
+//! value is stored under is authenticated as associated data, so a value
+//! copied to another key fails to decrypt. `enc:v1:` values, written before
+//! keys were bound, have no associated data and are still read. Values
+//! without either prefix are plaintext records written before encryption
+//! was enabled and are read as-is. A strict cipher refuses both, for once
+//! every value has been rewritten as `enc:v2:`.
+
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::error::RegistryError;
+
+const PREFIX: &str = "enc:v2:";
+
+/// Prefix of values encrypted without associated data.
+const LEGACY_PREFIX: &str = "enc:v1:";
+
+pub struct RecordCipher {
+    key: LessSafeKey,
+    rng: SystemRandom,
+    /// Refuse plaintext and `enc:v1:` values.
+    strict: bool,
+}
+
+impl RecordCipher {
+    pub fn new(key: &[u8]) -> Result<Self, RegistryError> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| {
+            RegistryError::Encryption("encryption key must be 32 bytes".to_string())
+        })?;
+        Ok(RecordCipher {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+            strict: false,
+        })
+    }
+
+    /// Makes `decrypt` refuse values not bound to their Redis key when
+    /// `strict` is set.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Loads a key file holding either the 32 raw key bytes or their base64
+    /// encoding.
+    pub fn from_key_file(path: &Path) -> Result<Self, RegistryError> {
+        let raw = std::fs::read(path).map_err(|e| {
+            RegistryError::Encryption(format!("cannot read {}: {}", path.display(), e))
+        })?;
+        if raw.len() == 32 {
+            return Self::new(&raw);
+        }
+        let decoded = STANDARD
+            .decode(raw.trim_ascii())
+            .map_err(|_| RegistryError::Encryption(format!("invalid key in {}", path.display())))?;
+        Self::new(&decoded)
+    }
+
+    /// Encrypts `plaintext` to be stored under the Redis key `context`.
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> Result<String, RegistryError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| RegistryError::Encryption("cannot generate nonce".to_string()))?;
+        let mut sealed = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(context.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| RegistryError::Encryption("encryption failed".to_string()))?;
+        let mut stored = nonce.to_vec();
+        stored.extend_from_slice(&sealed);
+        Ok(format!("{}{}", PREFIX, STANDARD.encode(stored)))
+    }
+
+    /// Decrypts `stored`, read from the Redis key `context`.
+    pub fn decrypt(&self, stored: &str, context: &str) -> Result<String, RegistryError> {
+        let (encoded, aad) = if let Some(encoded) = stored.strip_prefix(PREFIX) {
+            (encoded, Aad::from(context.as_bytes()))
+        } else if self.strict {
+            return Err(RegistryError::Encryption(
+                "record is not encrypted with a key-bound cipher".to_string(),
+            ));
+        } else if let Some(encoded) = stored.strip_prefix(LEGACY_PREFIX) {
+            (encoded, Aad::from(&b""[..]))
+        } else {
+            return Ok(stored.to_string());
+        };
+        let invalid = || RegistryError::Encryption("cannot decrypt record".to_string());
+        let mut sealed = STANDARD.decode(encoded).map_err(|_| invalid())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let nonce =
+            Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).map_err(|_| invalid())?;
+        let plaintext = self
+            .key
+            .open_in_place(nonce, aad, &mut sealed[NONCE_LEN..])
+            .map_err(|_| invalid())?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| invalid())
+    }
+}
+
+/// Whether `stored` was written by `RecordCipher::encrypt`.
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX) || stored.starts_with(LEGACY_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ghaf:vm:vault-vm";
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = RecordCipher::new(&[7; 32]).unwrap();
+        let sealed = cipher.encrypt(r#"{"name":"vault-vm"}"#, KEY).unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("vault-vm"));
+        assert_ne!(
+            sealed,
+            cipher.encrypt(r#"{"name":"vault-vm"}"#, KEY).unwrap()
+        );
+        assert_eq!(
+            cipher.decrypt(&sealed, KEY).unwrap(),
+            r#"{"name":"vault-vm"}"#
+        );
+
+        // Plaintext records are passed through; tampered ones fail.
+        assert_eq!(cipher.decrypt("{}", KEY).unwrap(), "{}");
+        let other = RecordCipher::new(&[8; 32]).unwrap();
+        assert!(other.decrypt(&sealed, KEY).is_err());
+        assert!(RecordCipher::new(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_values_are_bound_to_their_key() {
+        let cipher = RecordCipher::new(&[7; 32]).unwrap();
+        let sealed = cipher.encrypt(r#"{"name":"vault-vm"}"#, KEY).unwrap();
+        assert!(cipher.decrypt(&sealed, "ghaf:vm:other-vm").is_err());
+    }
+
+    /// `plaintext` encrypted the way `enc:v1:` values were written.
+    fn legacy_value(cipher: &RecordCipher, plaintext: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[0] = 1;
+        let mut sealed = plaintext.as_bytes().to_vec();
+        cipher
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .unwrap();
+        let mut stored = nonce.to_vec();
+        stored.extend_from_slice(&sealed);
+        format!("{}{}", LEGACY_PREFIX, STANDARD.encode(stored))
+    }
+
+    #[test]
+    fn test_legacy_values() {
+        let cipher = RecordCipher::new(&[7; 32]).unwrap();
+        let legacy = legacy_value(&cipher, "{}");
+        assert!(is_encrypted(&legacy));
+        assert_eq!(cipher.decrypt(&legacy, KEY).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_strict_cipher_refuses_unbound_values() {
+        let cipher = RecordCipher::new(&[7; 32]).unwrap().strict(true);
+        let sealed = cipher.encrypt("{}", KEY).unwrap();
+        assert_eq!(cipher.decrypt(&sealed, KEY).unwrap(), "{}");
+        let legacy = legacy_value(&cipher, "{}");
+        for stored in ["{}", legacy.as_str()] {
+            assert!(matches!(
+                cipher.decrypt(stored, KEY),
+                Err(RegistryError::Encryption(_))
+            ));
+        }
+    }
+}
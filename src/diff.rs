@@ -0,0 +1,122 @@
+//! Field-level differences between VM configurations.
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Map, Value};
+
+use crate::models::VM;
+
+/// Differences between two configurations, keyed by dotted field path.
+/// Nested objects are compared field by field, other values as a whole.
+#[derive(Default, Debug, PartialEq)]
+pub struct Diff {
+    /// Fields only the new configuration has.
+    pub added: Map<String, Value>,
+    /// Fields only the old configuration has.
+    pub removed: Map<String, Value>,
+    /// Old and new value of each field both have but with different values.
+    pub changed: BTreeMap<String, (Value, Value)>,
+}
+
+impl Diff {
+    /// Compares two JSON objects.
+    pub fn between(old: &Map<String, Value>, new: &Map<String, Value>) -> Self {
+        let mut diff = Diff::default();
+        diff.compare("", old, new);
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// The diff as JSON, with each changed field as `{"old": .., "new": ..}`.
+    pub fn to_json(&self) -> Value {
+        let changed: Map<String, Value> = self
+            .changed
+            .iter()
+            .map(|(path, (old, new))| (path.clone(), json!({ "old": old, "new": new })))
+            .collect();
+        json!({
+            "added": self.added,
+            "removed": self.removed,
+            "changed": changed,
+        })
+    }
+
+    fn compare(&mut self, prefix: &str, old: &Map<String, Value>, new: &Map<String, Value>) {
+        for (field, old_value) in old {
+            let path = format!("{}{}", prefix, field);
+            match (old_value, new.get(field)) {
+                (_, None) => {
+                    self.removed.insert(path, old_value.clone());
+                }
+                (Value::Object(old_value), Some(Value::Object(new_value))) => {
+                    self.compare(&format!("{}.", path), old_value, new_value);
+                }
+                (old_value, Some(new_value)) if old_value != new_value => {
+                    self.changed
+                        .insert(path, (old_value.clone(), new_value.clone()));
+                }
+                _ => {}
+            }
+        }
+        for (field, new_value) in new {
+            if !old.contains_key(field) {
+                self.added
+                    .insert(format!("{}{}", prefix, field), new_value.clone());
+            }
+        }
+    }
+}
+
+/// Differences between the configurations of `old` and `new`, i.e. their
+/// fields except the name and `VOLATILE_FIELDS`.
+pub fn vm_diff(old: &VM, new: &VM) -> Result<Diff, serde_json::Error> {
+    let mut old_fields = old.config_fields()?;
+    let mut new_fields = new.config_fields()?;
+    old_fields.remove("name");
+    new_fields.remove("name");
+    Ok(Diff::between(&old_fields, &new_fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample_vm;
+
+    #[test]
+    fn test_diff_paths() {
+        let a = json!({ "kept": 1, "gone": true, "nested": { "x": 1, "y": [1] } });
+        let b = json!({ "kept": 1, "new": "v", "nested": { "x": 2, "y": [1], "z": null } });
+        let diff = Diff::between(a.as_object().unwrap(), b.as_object().unwrap());
+        assert_eq!(json!(diff.added), json!({ "new": "v", "nested.z": null }));
+        assert_eq!(json!(diff.removed), json!({ "gone": true }));
+        assert_eq!(
+            diff.changed,
+            BTreeMap::from([("nested.x".to_string(), (json!(1), json!(2)))])
+        );
+    }
+
+    #[test]
+    fn test_vm_diff() {
+        let old = sample_vm("gui-vm");
+        let mut new = sample_vm("renamed-vm");
+        new.status = crate::models::VMStatus::Running;
+        assert!(vm_diff(&old, &new).unwrap().is_empty());
+        new.priority = 3;
+        let diff = vm_diff(&old, &new).unwrap();
+        assert_eq!(
+            diff.changed,
+            BTreeMap::from([("priority".to_string(), (json!(0), json!(3)))])
+        );
+        assert_eq!(
+            diff.to_json(),
+            json!({
+                "added": {},
+                "removed": {},
+                "changed": { "priority": { "old": 0, "new": 3 } },
+            })
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Probing the network for VMs that were started before they were
+//! registered.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+
+use crate::error::RegistryError;
+
+/// Upper bound on the addresses one discovery request may probe.
+pub const MAX_DISCOVERY_HOSTS: u128 = 4096;
+
+/// Probes running at the same time.
+const CONCURRENT_PROBES: usize = 64;
+
+#[async_trait]
+pub trait PortProber: Send + Sync {
+    /// Whether something accepts connections on `addr` within `timeout`.
+    async fn probe(&self, addr: SocketAddr, timeout: Duration) -> bool;
+}
+
+/// Probes with a plain `tokio::net::TcpStream::connect`.
+pub struct TcpProber;
+
+#[async_trait]
+impl PortProber for TcpProber {
+    async fn probe(&self, addr: SocketAddr, timeout: Duration) -> bool {
+        matches!(
+            tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr)).await,
+            Ok(Ok(_))
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DiscoveredVM {
+    pub ip: String,
+    pub open_port: u16,
+    /// Whether a registered VM already declares this IP.
+    pub already_registered: bool,
+}
+
+/// Parses CIDR ranges, e.g. `192.168.1.0/24`, and checks that together they
+/// stay within [`MAX_DISCOVERY_HOSTS`].
+pub fn parse_ranges(ranges: &[String]) -> Result<Vec<IpNetwork>, RegistryError> {
+    let networks = ranges
+        .iter()
+        .map(|range| {
+            range
+                .parse::<IpNetwork>()
+                .map_err(|_| RegistryError::Validation(format!("invalid IP range '{}'", range)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let hosts: u128 = networks
+        .iter()
+        .map(|network| match network {
+            IpNetwork::V4(network) => network.size() as u128,
+            IpNetwork::V6(network) => network.size(),
+        })
+        .fold(0, u128::saturating_add);
+    if hosts > MAX_DISCOVERY_HOSTS {
+        return Err(RegistryError::Validation(format!(
+            "IP ranges cover {} addresses, at most {} can be probed at once",
+            hosts, MAX_DISCOVERY_HOSTS
+        )));
+    }
+    Ok(networks)
+}
+
+/// Probes `port` on every address in `networks` and reports the ones that
+/// answer, sorted by IP.
+pub async fn discover(
+    prober: &dyn PortProber,
+    networks: &[IpNetwork],
+    port: u16,
+    timeout: Duration,
+    registered: &HashSet<IpAddr>,
+) -> Vec<DiscoveredVM> {
+    let addrs: Vec<IpAddr> = networks.iter().flat_map(IpNetwork::iter).collect();
+    let mut found: Vec<IpAddr> = stream::iter(addrs)
+        .map(|ip| async move {
+            prober
+                .probe(SocketAddr::new(ip, port), timeout)
+                .await
+                .then_some(ip)
+        })
+        .buffer_unordered(CONCURRENT_PROBES)
+        .filter_map(|ip| async move { ip })
+        .collect()
+        .await;
+    found.sort();
+    found.dedup();
+    found
+        .into_iter()
+        .map(|ip| DiscoveredVM {
+            ip: ip.to_string(),
+            open_port: port,
+            already_registered: registered.contains(&ip),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Prober answering only for a fixed set of addresses.
+    pub struct MockProber(pub HashSet<SocketAddr>);
+
+    #[async_trait]
+    impl PortProber for MockProber {
+        async fn probe(&self, addr: SocketAddr, _timeout: Duration) -> bool {
+            self.0.contains(&addr)
+        }
+    }
+
+    pub fn mock_prober() -> MockProber {
+        MockProber(HashSet::from([
+            "192.168.1.10:22".parse().unwrap(),
+            "192.168.1.3:22".parse().unwrap(),
+            "192.168.1.3:2222".parse().unwrap(),
+            "10.0.0.1:22".parse().unwrap(),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn test_discover() {
+        let networks = parse_ranges(&["192.168.1.0/24".to_string()]).unwrap();
+        let registered = HashSet::from(["192.168.1.3".parse().unwrap()]);
+        let found = discover(
+            &mock_prober(),
+            &networks,
+            22,
+            Duration::from_millis(10),
+            &registered,
+        )
+        .await;
+        assert_eq!(
+            found,
+            vec![
+                DiscoveredVM {
+                    ip: "192.168.1.3".to_string(),
+                    open_port: 22,
+                    already_registered: true,
+                },
+                DiscoveredVM {
+                    ip: "192.168.1.10".to_string(),
+                    open_port: 22,
+                    already_registered: false,
+                },
+            ]
+        );
+
+        let found = discover(
+            &mock_prober(),
+            &networks,
+            2222,
+            Duration::from_millis(10),
+            &HashSet::new(),
+        )
+        .await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].ip, "192.168.1.3");
+        assert_eq!(found[0].open_port, 2222);
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(
+            parse_ranges(&["10.0.0.0/30".to_string(), "fd00::/120".to_string()])
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(parse_ranges(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(parse_ranges(&["10.0.0.0/16".to_string()]).is_err());
+        assert!(parse_ranges(&["fd00::/64".to_string()]).is_err());
+    }
+}
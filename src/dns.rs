@@ -0,0 +1,124 @@
+//! Forward-confirmation of the DNS names declared in VM addresses.
+
+use std::io;
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+
+use crate::error::RegistryError;
+use crate::models::Addresses;
+
+#[async_trait]
+pub trait DnsResolver: Send + Sync {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// Resolves through the system resolver via `tokio::net::lookup_host`.
+pub struct SystemResolver;
+
+#[async_trait]
+impl DnsResolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs = tokio::net::lookup_host((host, 0)).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Resolves `addresses.dns_name`, checks that it points at the declared IP
+/// and records the resolved addresses. Without a DNS name, or when `enabled`
+/// is false, any previously resolved addresses are cleared.
+pub async fn confirm_dns_name(
+    resolver: &dyn DnsResolver,
+    addresses: &mut Addresses,
+    enabled: bool,
+) -> Result<(), RegistryError> {
+    addresses.resolved_ips.clear();
+    let dns_name = match &addresses.dns_name {
+        Some(dns_name) if enabled => dns_name,
+        _ => return Ok(()),
+    };
+    let declared: IpAddr = addresses
+        .ip
+        .parse()
+        .map_err(|_| RegistryError::Validation(format!("invalid IP address '{}'", addresses.ip)))?;
+    let resolved = resolver
+        .resolve(dns_name)
+        .await
+        .map_err(|e| RegistryError::Validation(format!("cannot resolve '{}': {}", dns_name, e)))?;
+    if !resolved.contains(&declared) {
+        return Err(RegistryError::Validation(format!(
+            "'{}' does not resolve to {}",
+            dns_name, declared
+        )));
+    }
+    addresses.resolved_ips = resolved.iter().map(IpAddr::to_string).collect();
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Resolver answering from a fixed table; unknown names fail.
+    pub struct MockResolver(pub HashMap<String, Vec<IpAddr>>);
+
+    #[async_trait]
+    impl DnsResolver for MockResolver {
+        async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+            self.0
+                .get(host)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such host"))
+        }
+    }
+
+    pub fn mock_resolver() -> MockResolver {
+        MockResolver(HashMap::from([(
+            "gui-vm.ghaf.local".to_string(),
+            vec!["192.168.100.3".parse().unwrap(), "fd00::3".parse().unwrap()],
+        )]))
+    }
+
+    fn named_addresses(ip: &str, dns_name: &str) -> Addresses {
+        Addresses {
+            ip: ip.to_string(),
+            vsock: "3:1234".to_string(),
+            dns_name: Some(dns_name.to_string()),
+            resolved_ips: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matching_dns_name() {
+        let mut addresses = named_addresses("192.168.100.3", "gui-vm.ghaf.local");
+        confirm_dns_name(&mock_resolver(), &mut addresses, true)
+            .await
+            .unwrap();
+        assert_eq!(addresses.resolved_ips, vec!["192.168.100.3", "fd00::3"]);
+    }
+
+    #[tokio::test]
+    async fn test_mismatching_dns_name() {
+        let mut addresses = named_addresses("192.168.100.9", "gui-vm.ghaf.local");
+        let err = confirm_dns_name(&mock_resolver(), &mut addresses, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RegistryError::Validation(_)));
+
+        let mut addresses = named_addresses("192.168.100.3", "unknown.ghaf.local");
+        assert!(confirm_dns_name(&mock_resolver(), &mut addresses, true)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_check_clears_resolved_ips() {
+        let mut addresses = named_addresses("192.168.100.9", "gui-vm.ghaf.local");
+        addresses.resolved_ips = vec!["10.9.9.9".to_string()];
+        confirm_dns_name(&mock_resolver(), &mut addresses, false)
+            .await
+            .unwrap();
+        assert!(addresses.resolved_ips.is_empty());
+    }
+}
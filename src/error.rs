@@ -0,0 +1,438 @@
+#[cfg(feature = "warp")]
+use std::convert::Infallible;
+
+use chrono::{DateTime, Utc};
+use hyper::http::header::{self, HeaderValue};
+use hyper::http::StatusCode;
+use serde::Serialize;
+use thiserror::Error;
+use uuid::Uuid;
+#[cfg(feature = "warp")]
+use warp::{Rejection, Reply};
+
+use crate::reply::{self, Response};
+use crate::topology::CycleError;
+
+const INTERNAL_ERROR_MESSAGE: &str = "Internal server error.";
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("VM '{0}' not found")]
+    NotFound(String),
+    #[error("VM '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("namespace '{0}' has reached its VM quota")]
+    QuotaExceeded(String),
+    #[error("no VM handles MIME type '{0}'")]
+    NoMimeHandler(String),
+    #[error("no VM has hostname '{0}'")]
+    UnknownHostname(String),
+    #[error("VM '{0}' has no {1} config")]
+    ConfigNotSet(String, &'static str),
+    #[error("host port {0} is not mapped")]
+    PortNotMapped(u16),
+    #[error("volume {0} is not attached")]
+    VolumeNotAttached(Uuid),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error("request body does not match its schema")]
+    SchemaViolations(Vec<SchemaViolation>),
+    #[error("VM '{0}' is sealed")]
+    Locked(String),
+    #[error("missing or invalid API token")]
+    Unauthorized,
+    #[error("this operation requires the '{0}' role")]
+    Forbidden(String),
+    #[error("only the owner of VM '{0}' or an admin may do this")]
+    NotOwner(String),
+    #[error(transparent)]
+    DependencyCycle(#[from] CycleError),
+    #[error("hypervisor query failed: {0}")]
+    Hypervisor(String),
+    #[error("redis operation timed out")]
+    Timeout,
+    #[error("too many requests; retry in {0} seconds")]
+    RateLimited(u64),
+    #[error("redis error: {0}")]
+    Redis(redis::RedisError),
+    #[error("encryption error: {0}")]
+    Encryption(String),
+    #[error("backup error: {0}")]
+    Backup(String),
+    #[error("serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl RegistryError {
+    /// Machine-readable error name used in the `error` field of responses.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RegistryError::NotFound(_) => "NotFound",
+            RegistryError::AlreadyExists(_) => "AlreadyExists",
+            RegistryError::Conflict(_) => "Conflict",
+            RegistryError::QuotaExceeded(_) => "QuotaExceeded",
+            RegistryError::NoMimeHandler(_) => "NoMimeHandler",
+            RegistryError::UnknownHostname(_) => "UnknownHostname",
+            RegistryError::ConfigNotSet(..) => "ConfigNotSet",
+            RegistryError::PortNotMapped(_) => "PortNotMapped",
+            RegistryError::VolumeNotAttached(_) => "VolumeNotAttached",
+            RegistryError::BadRequest(_) => "BadRequest",
+            RegistryError::Validation(_) | RegistryError::SchemaViolations(_) => "Validation",
+            RegistryError::Locked(_) => "Locked",
+            RegistryError::Unauthorized => "Unauthorized",
+            RegistryError::Forbidden(_) | RegistryError::NotOwner(_) => "Forbidden",
+            RegistryError::DependencyCycle(_) => "DependencyCycle",
+            RegistryError::Hypervisor(_) => "Hypervisor",
+            RegistryError::Timeout => "Timeout",
+            RegistryError::RateLimited(_) => "RateLimited",
+            RegistryError::Redis(_)
+            | RegistryError::Encryption(_)
+            | RegistryError::Backup(_)
+            | RegistryError::Serialization(_) => "Internal",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RegistryError::NotFound(_)
+            | RegistryError::NoMimeHandler(_)
+            | RegistryError::UnknownHostname(_)
+            | RegistryError::ConfigNotSet(..)
+            | RegistryError::PortNotMapped(_)
+            | RegistryError::VolumeNotAttached(_) => StatusCode::NOT_FOUND,
+            RegistryError::AlreadyExists(_)
+            | RegistryError::Conflict(_)
+            | RegistryError::QuotaExceeded(_) => StatusCode::CONFLICT,
+            RegistryError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            RegistryError::Locked(_) => StatusCode::LOCKED,
+            RegistryError::Unauthorized => StatusCode::UNAUTHORIZED,
+            RegistryError::Forbidden(_) | RegistryError::NotOwner(_) => StatusCode::FORBIDDEN,
+            RegistryError::Validation(_)
+            | RegistryError::SchemaViolations(_)
+            | RegistryError::DependencyCycle(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RegistryError::Hypervisor(_) => StatusCode::BAD_GATEWAY,
+            RegistryError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            RegistryError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            RegistryError::Redis(_)
+            | RegistryError::Encryption(_)
+            | RegistryError::Backup(_)
+            | RegistryError::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Seconds a client should wait before retrying, for transient errors.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            RegistryError::Timeout => Some(5),
+            RegistryError::RateLimited(secs) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    /// The message shown to clients. Internal errors are described only in
+    /// the server's log, as their details may reveal Redis keys, file paths
+    /// or key material problems.
+    pub fn client_message(&self) -> String {
+        match self {
+            RegistryError::Redis(_)
+            | RegistryError::Encryption(_)
+            | RegistryError::Backup(_)
+            | RegistryError::Serialization(_) => INTERNAL_ERROR_MESSAGE.to_string(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// The JSON body of the response for this error.
+    pub fn response_body(&self) -> ErrorResponse {
+        let mut body = ErrorResponse::new(self.kind(), self.client_message());
+        if let RegistryError::SchemaViolations(violations) = self {
+            body.errors = violations.clone();
+        }
+        body
+    }
+
+    /// The JSON error response for this error.
+    pub fn into_response(self) -> Response {
+        self.to_response()
+    }
+
+    /// Clients only learn the request ID of server errors, so their cause
+    /// is logged under it.
+    fn to_response(&self) -> Response {
+        let body = self.response_body();
+        if self.status_code().is_server_error() {
+            tracing::error!(request_id = %body.request_id, error = %self, "request failed");
+        }
+        error_response(self.status_code(), &body, self.retry_after_secs())
+    }
+}
+
+/// Redis reports timed-out operations as I/O errors; they are told apart
+/// so clients can retry them.
+impl From<redis::RedisError> for RegistryError {
+    fn from(e: redis::RedisError) -> Self {
+        if e.is_timeout() {
+            RegistryError::Timeout
+        } else {
+            RegistryError::Redis(e)
+        }
+    }
+}
+
+#[cfg(feature = "warp")]
+impl warp::reject::Reject for RegistryError {}
+
+#[cfg(feature = "axum")]
+impl axum::response::IntoResponse for RegistryError {
+    fn into_response(self) -> axum::response::Response {
+        self.to_response().into_response()
+    }
+}
+
+/// A field of a request body that does not match the body's JSON Schema.
+#[derive(Serialize, Debug, Clone)]
+pub struct SchemaViolation {
+    /// JSON Pointer to the field, e.g. `/addresses/ip`.
+    pub path: String,
+    pub message: String,
+}
+
+/// JSON body of every error response.
+#[derive(Serialize, Debug)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub message: String,
+    pub request_id: Uuid,
+    pub timestamp: DateTime<Utc>,
+    /// Every schema violation, for `SchemaViolations` errors.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<SchemaViolation>,
+}
+
+impl ErrorResponse {
+    pub fn new(error: &str, message: impl Into<String>) -> Self {
+        ErrorResponse {
+            error: error.to_string(),
+            message: message.into(),
+            request_id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// Builds an error response, telling the client when to retry if
+/// `retry_after_secs` is set.
+fn error_response(
+    code: StatusCode,
+    body: &ErrorResponse,
+    retry_after_secs: Option<u64>,
+) -> Response {
+    let mut response = reply::with_status(reply::json(body), code);
+    if let Some(secs) = retry_after_secs {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+    response
+}
+
+/// The answer to requests that match no route.
+pub fn not_found() -> Response {
+    let body = ErrorResponse::new("NotFound", "Not found.");
+    error_response(StatusCode::NOT_FOUND, &body, None)
+}
+
+/// The answer to requests for a route that exists with another method.
+pub fn method_not_allowed() -> Response {
+    let body = ErrorResponse::new("MethodNotAllowed", "Method not allowed.");
+    error_response(StatusCode::METHOD_NOT_ALLOWED, &body, None)
+}
+
+#[cfg(feature = "warp")]
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let response = if err.is_not_found() {
+        not_found()
+    } else if let Some(e) = err.find::<RegistryError>() {
+        e.to_response()
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        RegistryError::BadRequest(e.to_string()).into_response()
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        RegistryError::BadRequest(e.to_string()).into_response()
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        method_not_allowed()
+    } else {
+        let body = ErrorResponse::new("Internal", INTERNAL_ERROR_MESSAGE);
+        tracing::error!(request_id = %body.request_id, rejection = ?err, "request failed");
+        error_response(StatusCode::INTERNAL_SERVER_ERROR, &body, None)
+    };
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::settings::Settings;
+    use crate::state::AppState;
+    use crate::test_util::request;
+    use crate::test_util::{json_body, redis_state, register, sample_vm, test_settings};
+    use hyper::body::Bytes;
+    use hyper::http::Response;
+
+    fn assert_error(response: &Response<Bytes>, status: u16, error: &str, message: &str) {
+        assert_eq!(response.status(), status);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = json_body(response);
+        assert_eq!(body["error"], error);
+        assert_eq!(body["message"], message);
+        let request_id = body["request_id"].as_str().unwrap();
+        assert!(uuid::Uuid::parse_str(request_id).is_ok());
+        let timestamp = body["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_error_bodies() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+
+        let response = request()
+            .method("GET")
+            .path("/status/foo")
+            .reply(&api)
+            .await;
+        assert_error(&response, 404, "NotFound", "VM 'foo' not found");
+
+        register(&api, &sample_vm("foo")).await;
+        let response = register(&api, &sample_vm("foo")).await;
+        assert_error(&response, 409, "AlreadyExists", "VM 'foo' already exists");
+
+        let mut invalid = sample_vm("bar");
+        invalid.mime_types = vec!["pdf".to_string()];
+        let response = register(&api, &invalid).await;
+        assert_error(&response, 422, "Validation", "invalid MIME type 'pdf'");
+
+        let response = request()
+            .method("GET")
+            .path("/vms/by-mime?type=video/mp4")
+            .reply(&api)
+            .await;
+        assert_error(
+            &response,
+            404,
+            "NoMimeHandler",
+            "no VM handles MIME type 'video/mp4'",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_name() {
+        let api = routes(AppState::new(test_settings()).unwrap());
+        let response = register(&api, &sample_vm("bad/name")).await;
+        assert_error(&response, 422, "Validation", "invalid VM name 'bad/name'");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let settings = Settings {
+            rate_limit_per_minute: Some(1),
+            ..test_settings()
+        };
+        let api = routes(AppState::new(settings).unwrap());
+        let response = request().method("GET").path("/nowhere").reply(&api).await;
+        assert_ne!(response.status(), 429);
+        let response = request().method("GET").path("/nowhere").reply(&api).await;
+        assert_error(
+            &response,
+            429,
+            "RateLimited",
+            "too many requests; retry in 60 seconds",
+        );
+        assert_eq!(response.headers()["retry-after"], "60");
+    }
+
+    #[tokio::test]
+    async fn test_internal_errors_are_not_described() {
+        let settings = Settings {
+            redis_url: "redis://127.0.0.1:1/".to_string(),
+            ..test_settings()
+        };
+        let api = routes(AppState::new(settings).unwrap());
+        let response = request()
+            .method("GET")
+            .path("/status/foo")
+            .reply(&api)
+            .await;
+        assert_error(&response, 500, "Internal", "Internal server error.");
+    }
+
+    #[tokio::test]
+    async fn test_route_errors() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+
+        let response = request().method("PUT").path("/list").reply(&api).await;
+        assert_error(&response, 405, "MethodNotAllowed", "Method not allowed.");
+
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .body("{ not json")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(json_body(&response)["error"], "BadRequest");
+    }
+
+    #[tokio::test]
+    async fn test_schema_violations() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+
+        let mut vm = serde_json::to_value(sample_vm("foo")).unwrap();
+        vm["addresses"]["ip"] = serde_json::json!("999.1.1.1");
+        vm["vcpu_count"] = serde_json::json!(-2);
+        vm["tags"] = serde_json::json!("production");
+        let response = request()
+            .method("POST")
+            .path("/register")
+            .json(&vm)
+            .reply(&api)
+            .await;
+        assert_error(
+            &response,
+            422,
+            "Validation",
+            "request body does not match its schema",
+        );
+        let body = json_body(&response);
+        let mut paths: Vec<&str> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| {
+                assert!(!error["message"].as_str().unwrap().is_empty());
+                error["path"].as_str().unwrap()
+            })
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["/addresses/ip", "/tags", "/vcpu_count"]);
+
+        let response = request()
+            .method("GET")
+            .path("/status/foo")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+}
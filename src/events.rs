@@ -0,0 +1,266 @@
+//! VM lifecycle events: notifications published on Redis channels, and
+//! the per-VM history every record change is appended to.
+//!
+//! Every notification is a JSON object naming the event and the VM it
+//! concerns, e.g. `{"event": "reaped", "name": "gui-vm", "namespace":
+//! "default", "status": "Failed", "timestamp": "..."}`. Record changes are
+//! published on their own channel, which the registry itself listens on to
+//! serve watch requests. Notifications on both channels are also kept in a
+//! timeline of recent events across all VMs.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use redis::aio::PubSub;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::error::RegistryError;
+use crate::models::{VMStatus, DEFAULT_NAMESPACE, VM};
+use crate::state::{AppState, RedisConnection};
+
+/// Channel all VM events are published on.
+pub const VM_EVENTS_CHANNEL: &str = "ghaf:events:vm";
+
+/// Channel every change to a VM record is published on, named after its
+/// history event: `registered`, `updated`, `status_changed` or
+/// `unregistered`.
+pub const VM_CHANGES_CHANNEL: &str = "ghaf:events:vm-changes";
+
+/// Sorted set of recently published notifications scored by their Unix
+/// time in milliseconds.
+pub const TIMELINE_KEY: &str = "ghaf:events:timeline";
+
+/// Notifications kept in the timeline.
+pub const TIMELINE_MAXLEN: isize = 10_000;
+
+/// Wait before resubscribing after the change listener lost Redis.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// A notification as published on the event channels.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notification {
+    pub event: String,
+    pub name: String,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[serde(default)]
+    pub status: VMStatus,
+    #[serde(default = "Utc::now")]
+    pub timestamp: DateTime<Utc>,
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+/// The notification announcing `event` for `vm`.
+pub fn notification(event: &str, vm: &VM) -> Notification {
+    Notification {
+        event: event.to_string(),
+        name: vm.name.clone(),
+        namespace: vm.namespace.clone(),
+        status: vm.status,
+        timestamp: Utc::now(),
+    }
+}
+
+/// Queues publishing `notification` on `channel` and adding it to the
+/// timeline, dropping the oldest entries beyond [`TIMELINE_MAXLEN`]. The
+/// PUBLISH reply, the number of receivers, is the only one not ignored.
+pub fn queue_publish(
+    pipe: &mut redis::Pipeline,
+    channel: &str,
+    notification: &Notification,
+) -> Result<(), RegistryError> {
+    let payload = serde_json::to_string(notification)?;
+    pipe.zadd(
+        TIMELINE_KEY,
+        &payload,
+        notification.timestamp.timestamp_millis(),
+    )
+    .ignore()
+    .zremrangebyrank(TIMELINE_KEY, 0, -(TIMELINE_MAXLEN + 1))
+    .ignore()
+    .publish(channel, payload);
+    Ok(())
+}
+
+pub async fn publish(con: &mut RedisConnection, event: &str, vm: &VM) -> Result<(), RegistryError> {
+    publish_raw(con, &notification(event, vm)).await?;
+    Ok(())
+}
+
+/// Publishes `notification` on [`VM_EVENTS_CHANNEL`] as is, whether or not
+/// the VM it names exists. Returns the number of subscribers that got it.
+pub async fn publish_raw(
+    con: &mut RedisConnection,
+    notification: &Notification,
+) -> Result<usize, RegistryError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    queue_publish(&mut pipe, VM_EVENTS_CHANNEL, notification)?;
+    let (receivers,): (usize,) = pipe.query_async(con).await?;
+    con.count_published(1);
+    Ok(receivers)
+}
+
+/// Up to `limit` timeline notifications published at or after `since`
+/// (Unix milliseconds), newest first.
+pub async fn recent_events(
+    con: &mut RedisConnection,
+    since: i64,
+    limit: isize,
+) -> Result<Vec<Notification>, RegistryError> {
+    let raw: Vec<String> = con
+        .zrevrangebyscore_limit(TIMELINE_KEY, "+inf", since, 0, limit)
+        .await?;
+    raw.iter()
+        .map(|notification| Ok(serde_json::from_str(notification)?))
+        .collect()
+}
+
+/// Subscribes to [`VM_CHANGES_CHANNEL`] on a connection of its own.
+pub async fn subscribe(state: &AppState) -> Result<PubSub, RegistryError> {
+    let mut pubsub = state.pubsub().await?;
+    pubsub.subscribe(VM_CHANGES_CHANNEL).await?;
+    Ok(pubsub)
+}
+
+/// Hands every change notification received on `pubsub` to `changes` until
+/// the subscription ends.
+pub async fn forward(pubsub: PubSub, changes: broadcast::Sender<String>) {
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+        if let Ok(payload) = message.get_payload::<String>() {
+            // Nobody watching is not an error.
+            let _ = changes.send(payload);
+        }
+    }
+}
+
+/// Feeds `state.changes` from Redis for as long as the registry runs,
+/// resubscribing whenever the connection is lost.
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            match subscribe(&state).await {
+                Ok(pubsub) => {
+                    forward(pubsub, state.changes.clone()).await;
+                    tracing::warn!("lost the VM change subscription, resubscribing");
+                }
+                Err(e) => tracing::warn!(error = %e, "cannot subscribe to VM changes"),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    });
+}
+
+/// One change to a VM record, as kept in its history stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: VmEventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VmEventKind {
+    Registered {
+        vm: VM,
+    },
+    /// The configuration changed; `vm` is the whole new record.
+    Updated {
+        vm: VM,
+    },
+    StatusChanged {
+        status: VMStatus,
+    },
+    Unregistered,
+}
+
+impl VmEventKind {
+    /// The event name used in change notifications.
+    pub fn name(&self) -> &'static str {
+        match self {
+            VmEventKind::Registered { .. } => "registered",
+            VmEventKind::Updated { .. } => "updated",
+            VmEventKind::StatusChanged { .. } => "status_changed",
+            VmEventKind::Unregistered => "unregistered",
+        }
+    }
+}
+
+/// Replays `events`, which are in the order they happened, up to and
+/// including `at`. Returns `None` when the VM did not exist at that time.
+pub fn derive_state_at(events: &[VmEvent], at: DateTime<Utc>) -> Option<VM> {
+    let mut state: Option<VM> = None;
+    for event in events.iter().take_while(|event| event.timestamp <= at) {
+        match &event.kind {
+            VmEventKind::Registered { vm } | VmEventKind::Updated { vm } => {
+                state = Some(vm.clone())
+            }
+            VmEventKind::StatusChanged { status } => {
+                if let Some(vm) = &mut state {
+                    vm.status = *status;
+                    vm.updated_at = Some(event.timestamp);
+                }
+            }
+            VmEventKind::Unregistered => state = None,
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample_vm;
+    use chrono::Duration;
+
+    #[test]
+    fn test_derive_state_at() {
+        let start = "2024-05-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let at = |minutes: i64| start + Duration::minutes(minutes);
+        let event = |minutes: i64, kind: VmEventKind| VmEvent {
+            timestamp: at(minutes),
+            kind,
+        };
+        let registered = sample_vm("gui-vm");
+        let mut patched = registered.clone();
+        patched.status = VMStatus::Running;
+        patched.priority = 5;
+        let events = vec![
+            event(0, VmEventKind::Registered { vm: registered }),
+            event(
+                10,
+                VmEventKind::StatusChanged {
+                    status: VMStatus::Running,
+                },
+            ),
+            event(20, VmEventKind::Updated { vm: patched }),
+            event(
+                30,
+                VmEventKind::StatusChanged {
+                    status: VMStatus::Stopped,
+                },
+            ),
+            event(40, VmEventKind::Unregistered),
+        ];
+
+        assert!(derive_state_at(&events, at(-1)).is_none());
+        let vm = derive_state_at(&events, at(0)).unwrap();
+        assert_eq!((vm.status, vm.priority), (VMStatus::Registered, 0));
+        let vm = derive_state_at(&events, at(15)).unwrap();
+        assert_eq!((vm.status, vm.priority), (VMStatus::Running, 0));
+        assert_eq!(vm.updated_at, Some(at(10)));
+        let vm = derive_state_at(&events, at(20)).unwrap();
+        assert_eq!((vm.status, vm.priority), (VMStatus::Running, 5));
+        let vm = derive_state_at(&events, at(35)).unwrap();
+        assert_eq!((vm.status, vm.priority), (VMStatus::Stopped, 5));
+        assert!(derive_state_at(&events, at(40)).is_none());
+    }
+}
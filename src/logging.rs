@@ -0,0 +1,124 @@
+//! Log output: text or JSON lines on stdout, filtered by a global level with
+//! per-module overrides.
+
+use tracing::Subscriber;
+use tracing_subscriber::filter::{EnvFilter, ParseError};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::settings::{LogFormat, Settings};
+
+/// `log_level` plus one directive per entry of `log_level_overrides`.
+pub fn env_filter(settings: &Settings) -> Result<EnvFilter, ParseError> {
+    let mut filter = EnvFilter::try_new(&settings.log_level)?;
+    let mut overrides: Vec<_> = settings.log_level_overrides.iter().collect();
+    overrides.sort();
+    for (target, level) in overrides {
+        filter = filter.add_directive(format!("{}={}", target, level).parse()?);
+    }
+    Ok(filter)
+}
+
+/// JSON events carry `timestamp`, `level`, `target`, the enclosing `span`
+/// if any, `message` and every structured field at the top level.
+fn subscriber<W>(
+    settings: &Settings,
+    writer: W,
+) -> Result<Box<dyn Subscriber + Send + Sync>, ParseError>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(env_filter(settings)?)
+        .with_writer(writer);
+    Ok(match settings.log_format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .finish(),
+        ),
+    })
+}
+
+/// Installs the global subscriber. Fails on invalid level directives.
+pub fn init(settings: &Settings) -> Result<(), String> {
+    let subscriber = subscriber(settings, std::io::stdout).map_err(|e| e.to_string())?;
+    subscriber.try_init().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_module_level_override() {
+        let settings = Settings {
+            log_level: "info".to_string(),
+            log_level_overrides: [("GHAFregistryd::storage".to_string(), "debug".to_string())]
+                .into_iter()
+                .collect(),
+            log_format: LogFormat::Json,
+            ..Settings::default()
+        };
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = subscriber(&settings, move || writer.clone()).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", id = 7);
+            let _entered = span.enter();
+            tracing::debug!(target: "GHAFregistryd::storage", vm = "net-vm", "saved");
+            tracing::debug!(target: "GHAFregistryd::api", "dropped");
+            tracing::info!(target: "GHAFregistryd::api", "kept");
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let messages: Vec<&str> = events
+            .iter()
+            .map(|event| event["message"].as_str().unwrap())
+            .collect();
+        assert_eq!(messages, ["saved", "kept"]);
+        let saved = &events[0];
+        assert_eq!(saved["level"], "DEBUG");
+        assert_eq!(saved["target"], "GHAFregistryd::storage");
+        assert_eq!(saved["vm"], "net-vm");
+        assert_eq!(saved["span"]["name"], "request");
+        assert!(saved["timestamp"].is_string());
+    }
+
+    #[test]
+    fn test_invalid_level() {
+        let settings = Settings {
+            log_level_overrides: [("GHAFregistryd::api".to_string(), "loud".to_string())]
+                .into_iter()
+                .collect(),
+            ..Settings::default()
+        };
+        assert!(env_filter(&settings).is_err());
+    }
+}
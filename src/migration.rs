@@ -0,0 +1,80 @@
+//! Upgrades of stored VM records written by older versions of the registry.
+//!
+//! Records carry `schema_version`; records without one are version 0.
+//! `MIGRATIONS[n]` upgrades a record from version `n` to `n + 1`, so the
+//! chain brings any older record up to `SCHEMA_VERSION`.
+
+use serde_json::{Map, Value};
+
+use crate::error::RegistryError;
+use crate::models::DEFAULT_NAMESPACE;
+
+/// Version of the VM records this build writes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[migrate_v0_to_v1];
+
+/// Version 0 records stored a single optional `mime_type` and predate
+/// namespaces.
+fn migrate_v0_to_v1(record: &mut Map<String, Value>) {
+    if let Some(mime_type) = record.remove("mime_type") {
+        if !record.contains_key("mime_types") {
+            let mime_types = match mime_type {
+                Value::Null => Vec::new(),
+                mime_type => vec![mime_type],
+            };
+            record.insert("mime_types".to_string(), Value::Array(mime_types));
+        }
+    }
+    record
+        .entry("namespace")
+        .or_insert_with(|| Value::String(DEFAULT_NAMESPACE.to_string()));
+}
+
+/// Upgrades `record` in place; returns whether anything was migrated.
+pub fn migrate(record: &mut Value) -> Result<bool, RegistryError> {
+    let fields = record.as_object_mut().ok_or_else(|| {
+        RegistryError::Validation("stored VM record is not an object".to_string())
+    })?;
+    let version = fields
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as usize;
+    if version >= MIGRATIONS.len() {
+        return Ok(false);
+    }
+    for migration in &MIGRATIONS[version..] {
+        migration(fields);
+    }
+    fields.insert("schema_version".to_string(), Value::from(SCHEMA_VERSION));
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate_v0_to_v1() {
+        let mut record = json!({
+            "name": "legacy-vm",
+            "mime_type": "application/pdf",
+        });
+        assert!(migrate(&mut record).unwrap());
+        assert_eq!(
+            record,
+            json!({
+                "name": "legacy-vm",
+                "mime_types": ["application/pdf"],
+                "namespace": "default",
+                "schema_version": 1,
+            })
+        );
+        assert!(!migrate(&mut record).unwrap());
+
+        let mut record = json!({ "name": "legacy-vm", "mime_type": null });
+        migrate(&mut record).unwrap();
+        assert_eq!(record["mime_types"], json!([]));
+    }
+}
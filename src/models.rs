@@ -0,0 +1,638 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Utc};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct VM {
+    /// Version of the record layout; see `migration`.
+    #[serde(default)]
+    pub schema_version: u32,
+    /// Unique name of the VM, e.g. `gui-vm`.
+    pub name: String,
+    /// Group the VM belongs to, e.g. one test environment.
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Whether the VM is a system or an app VM, and how long it runs.
+    pub vm_type: VMType,
+    /// How the host reaches the VM.
+    pub addresses: Addresses,
+    /// `XDG_RUNTIME_DIR` of the VM's user session, if it has one.
+    pub xdg_run: Option<String>,
+    /// Human-readable summary shown to operators, at most 1024 characters.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// MIME types this VM opens. The legacy single-string `mime_type` field
+    /// is still accepted on input.
+    #[serde(default, alias = "mime_type", deserialize_with = "one_or_many")]
+    #[schemars(schema_with = "one_or_many_schema")]
+    pub mime_types: Vec<String>,
+    /// Lifecycle state last recorded by the registry.
+    #[serde(default)]
+    pub status: VMStatus,
+    /// When the record was last written; set by the registry.
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// When the VM's agent last reported in through `POST /vm/:name/heartbeat`.
+    #[serde(default)]
+    pub last_heartbeat_at: Option<DateTime<Utc>>,
+    /// Token of the client holding the VM's lease. The lease lasts as long
+    /// as the TTL on the VM key; see `GET /vm/:name/lease`.
+    #[serde(default)]
+    pub lease_token: Option<String>,
+    /// Names of the VMs that must be running before this one starts.
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    /// Start-up priority among VMs that can start at the same time; higher
+    /// values start first.
+    #[serde(default)]
+    pub priority: i32,
+    /// How expendable the VM is under resource contention; lower classes
+    /// are evicted first.
+    #[serde(default)]
+    pub priority_class: PriorityClass,
+    /// Services this VM provides to others, e.g. `clipboard`.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Free-form labels such as `production`, used to filter VMs. Kept
+    /// sorted so records serialize the same way every time.
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    /// Key/value labels such as `env: prod`, queried with
+    /// `GET /vms/by-label`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Traffic rules the network VM applies for this VM.
+    #[serde(default)]
+    pub firewall_rules: Vec<FirewallRule>,
+    /// NTP servers the VM synchronizes its clock with, as IP addresses or
+    /// host names.
+    #[serde(default)]
+    pub ntp_servers: Vec<String>,
+    /// IP addresses of the DNS resolvers the VM queries.
+    #[serde(default)]
+    pub dns_resolvers: Vec<String>,
+    /// Domains appended to unqualified host names, e.g. `ghaf.local`.
+    #[serde(default)]
+    pub dns_search_domains: Vec<String>,
+    /// Virtual CPUs assigned to the VM; `0` when not declared.
+    #[serde(default)]
+    pub vcpu_count: u32,
+    /// Memory ceiling of the VM in MiB; `0` when not declared.
+    #[serde(default)]
+    pub memory_limit_mb: u64,
+    /// Templates are blueprints for `POST /vm/:name/instantiate`, not live
+    /// VMs; they are left out of start-up ordering and liveness checks.
+    #[serde(default)]
+    pub is_template: bool,
+    /// Template the VM was instantiated from, if any.
+    #[serde(default)]
+    pub template_name: Option<String>,
+    /// Sealed VMs cannot be modified, run or stopped (except by an admin)
+    /// until they are unsealed; see `POST /vm/:name/seal`.
+    #[serde(default)]
+    pub sealed: bool,
+    /// Identity of the caller that registered the VM; see
+    /// `Settings.token_identities`. Only the owner and admins may change an
+    /// owned VM.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// NixOS closure the VM runs, for finding VMs affected by a vulnerable
+    /// image.
+    #[serde(default)]
+    pub image: Option<VmImage>,
+    /// Who to reach when the VM misbehaves.
+    #[serde(default)]
+    pub contact: Option<Contact>,
+    /// RFC 1123 host name of the VM, e.g. `browser`; no two VMs may share
+    /// one, ignoring case.
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+/// Fields that change while a VM runs or that the registry stamps itself;
+/// they are not part of its configuration.
+pub const VOLATILE_FIELDS: &[&str] = &[
+    "schema_version",
+    "status",
+    "updated_at",
+    "last_heartbeat_at",
+    "lease_token",
+];
+
+impl VM {
+    /// The configuration fields of the VM as a JSON object, i.e. all fields
+    /// except `VOLATILE_FIELDS`.
+    pub fn config_fields(&self) -> Result<Map<String, Value>, serde_json::Error> {
+        let Value::Object(mut fields) = serde_json::to_value(self)? else {
+            unreachable!("a VM serializes to a JSON object");
+        };
+        fields.retain(|field, _| !VOLATILE_FIELDS.contains(&field.as_str()));
+        Ok(fields)
+    }
+}
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+/// Accepts `null`, a single string or a list of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(OneOrMany::One(value)) => vec![value],
+        Some(OneOrMany::Many(values)) => values,
+    })
+}
+
+fn one_or_many_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": ["null", "string", "array"],
+        "items": { "type": "string" }
+    })
+}
+
+/// Partial update for `PATCH /vm/:name`; absent fields are left unchanged.
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PatchVM {
+    pub namespace: Option<String>,
+    pub vm_type: Option<VMType>,
+    pub addresses: Option<Addresses>,
+    pub xdg_run: Option<String>,
+    pub description: Option<String>,
+    pub mime_types: Option<Vec<String>>,
+    pub dependencies: Option<Vec<String>>,
+    pub priority: Option<i32>,
+    pub priority_class: Option<PriorityClass>,
+    pub capabilities: Option<Vec<String>>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub firewall_rules: Option<Vec<FirewallRule>>,
+    pub ntp_servers: Option<Vec<String>>,
+    pub dns_resolvers: Option<Vec<String>>,
+    pub dns_search_domains: Option<Vec<String>>,
+    pub vcpu_count: Option<u32>,
+    pub memory_limit_mb: Option<u64>,
+    pub image: Option<VmImage>,
+    pub contact: Option<Contact>,
+    pub hostname: Option<String>,
+}
+
+impl PatchVM {
+    pub fn apply(self, vm: &mut VM) {
+        if let Some(namespace) = self.namespace {
+            vm.namespace = namespace;
+        }
+        if let Some(vm_type) = self.vm_type {
+            vm.vm_type = vm_type;
+        }
+        if let Some(addresses) = self.addresses {
+            vm.addresses = addresses;
+        }
+        if let Some(xdg_run) = self.xdg_run {
+            vm.xdg_run = Some(xdg_run);
+        }
+        if let Some(description) = self.description {
+            vm.description = Some(description);
+        }
+        if let Some(mime_types) = self.mime_types {
+            vm.mime_types = mime_types;
+        }
+        if let Some(dependencies) = self.dependencies {
+            vm.dependencies = dependencies;
+        }
+        if let Some(priority) = self.priority {
+            vm.priority = priority;
+        }
+        if let Some(priority_class) = self.priority_class {
+            vm.priority_class = priority_class;
+        }
+        if let Some(capabilities) = self.capabilities {
+            vm.capabilities = capabilities;
+        }
+        if let Some(labels) = self.labels {
+            vm.labels = labels;
+        }
+        if let Some(firewall_rules) = self.firewall_rules {
+            vm.firewall_rules = firewall_rules;
+        }
+        if let Some(ntp_servers) = self.ntp_servers {
+            vm.ntp_servers = ntp_servers;
+        }
+        if let Some(dns_resolvers) = self.dns_resolvers {
+            vm.dns_resolvers = dns_resolvers;
+        }
+        if let Some(dns_search_domains) = self.dns_search_domains {
+            vm.dns_search_domains = dns_search_domains;
+        }
+        if let Some(vcpu_count) = self.vcpu_count {
+            vm.vcpu_count = vcpu_count;
+        }
+        if let Some(memory_limit_mb) = self.memory_limit_mb {
+            vm.memory_limit_mb = memory_limit_mb;
+        }
+        if let Some(image) = self.image {
+            vm.image = Some(image);
+        }
+        if let Some(contact) = self.contact {
+            vm.contact = Some(contact);
+        }
+        if let Some(hostname) = self.hostname {
+            vm.hostname = Some(hostname);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct VMType {
+    pub system_app: SystemAppType,
+    pub run_type: RunType,
+}
+
+/// Serialized as a plain string: `"System"`, `"App"`, or the custom type name
+/// (e.g. `"Driver"`). Custom names are validated at registration time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemAppType {
+    System,
+    App,
+    Custom(String),
+}
+
+impl SystemAppType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SystemAppType::System => "System",
+            SystemAppType::App => "App",
+            SystemAppType::Custom(name) => name,
+        }
+    }
+}
+
+impl Serialize for SystemAppType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl JsonSchema for SystemAppType {
+    fn schema_name() -> Cow<'static, str> {
+        "SystemAppType".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({ "type": "string" })
+    }
+}
+
+impl<'de> Deserialize<'de> for SystemAppType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.as_str() {
+            "System" => SystemAppType::System,
+            "App" => SystemAppType::App,
+            _ => SystemAppType::Custom(name),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum RunType {
+    #[serde(alias = "long_run")]
+    LongRun,
+    #[serde(alias = "one_shot")]
+    OneShot,
+}
+
+impl RunType {
+    /// Kebab-case form used in Redis key names, e.g. `ghaf:runtype:one-shot`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunType::LongRun => "long-run",
+            RunType::OneShot => "one-shot",
+        }
+    }
+}
+
+/// Admission class of a VM, from the last to be evicted under memory
+/// pressure to the first.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, JsonSchema,
+)]
+pub enum PriorityClass {
+    Critical,
+    High,
+    #[default]
+    Normal,
+    Low,
+    BestEffort,
+}
+
+impl PriorityClass {
+    pub const ALL: [PriorityClass; 5] = [
+        PriorityClass::Critical,
+        PriorityClass::High,
+        PriorityClass::Normal,
+        PriorityClass::Low,
+        PriorityClass::BestEffort,
+    ];
+
+    /// Kebab-case form used in query strings, e.g. `best-effort`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PriorityClass::Critical => "critical",
+            PriorityClass::High => "high",
+            PriorityClass::Normal => "normal",
+            PriorityClass::Low => "low",
+            PriorityClass::BestEffort => "best-effort",
+        }
+    }
+}
+
+impl std::str::FromStr for PriorityClass {
+    type Err = String;
+
+    /// Parses the kebab-case form, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PriorityClass::ALL
+            .into_iter()
+            .find(|class| class.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown priority class '{}'", s))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Addresses {
+    #[schemars(extend("format" = "ip"))]
+    pub ip: String,
+    pub vsock: String,
+    /// Host name that must resolve to `ip` when DNS validation is enabled.
+    #[serde(default)]
+    pub dns_name: Option<String>,
+    /// Addresses `dns_name` resolved to when the VM was last written. Set by
+    /// the registry; values supplied by clients are discarded.
+    #[serde(default)]
+    pub resolved_ips: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, JsonSchema)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// A NixOS closure a VM is built from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct VmImage {
+    /// The store path's hash, e.g. `0c7xp0ar4b2ny3mwbkrhfpbbb8yqn2s1`.
+    pub id: String,
+    /// `/nix/store/{id}-{name}`.
+    pub store_path: String,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// The person or team responsible for a VM.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct Contact {
+    pub name: String,
+    pub email: String,
+    /// Slack handle or channel.
+    #[serde(default)]
+    pub slack: Option<String>,
+}
+
+/// Allows traffic on the inclusive `port_range`, optionally only from (for
+/// ingress) or to (for egress) `src_cidr`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct FirewallRule {
+    pub direction: Direction,
+    pub protocol: Protocol,
+    pub port_range: (u16, u16),
+    #[serde(default)]
+    pub src_cidr: Option<String>,
+}
+
+/// A VM port exposed on the host, stored apart from the VM record so it can
+/// change without rewriting it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub vm_port: u16,
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub service_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayServer {
+    Wayland,
+    X11,
+    Headless,
+}
+
+/// How the compositor should set up rendering for a VM.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DisplayConfig {
+    pub display_server: DisplayServer,
+    #[serde(default)]
+    pub resolution: Option<(u32, u32)>,
+    #[serde(default)]
+    pub gpu_passthrough: bool,
+    /// PCI address of the passed-through GPU; required with passthrough.
+    #[serde(default)]
+    pub gpu_device_id: Option<String>,
+}
+
+/// PipeWire/PulseAudio sinks and sources a VM exposes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AudioConfig {
+    #[serde(default)]
+    pub sinks: Vec<AudioDevice>,
+    #[serde(default)]
+    pub sources: Vec<AudioDevice>,
+}
+
+/// TLS certificate, private key and optional CA chain of a VM that
+/// terminates TLS, all PEM-encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CertBundle {
+    pub cert_pem: String,
+    pub key_pem: String,
+    #[serde(default)]
+    pub ca_pem: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A note an operator attached to a VM.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    pub text: String,
+    pub author: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A log line pushed by a VM's agent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub level: LogLevel,
+    pub message: String,
+    /// When the line was logged; the time it was received when omitted.
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AudioDevice {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub sample_rate: u32,
+}
+
+/// A storage volume attached to a VM, stored apart from the VM record like
+/// its port mappings. The registry assigns `id` when none is given.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    pub path: String,
+    pub size_gb: u32,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub filesystem: Option<String>,
+}
+
+/// Lifecycle state of a registered VM as last recorded by the registry.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, JsonSchema)]
+pub enum VMStatus {
+    #[default]
+    Registered,
+    Running,
+    Stopped,
+    Failed,
+}
+
+impl VMStatus {
+    pub const ALL: [VMStatus; 4] = [
+        VMStatus::Registered,
+        VMStatus::Running,
+        VMStatus::Stopped,
+        VMStatus::Failed,
+    ];
+
+    /// Lowercase form used in Redis key names, e.g. `ghaf:state:running`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VMStatus::Registered => "registered",
+            VMStatus::Running => "running",
+            VMStatus::Stopped => "stopped",
+            VMStatus::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for VMStatus {
+    type Err = String;
+
+    /// Parses either form, e.g. `running` or `Running`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        VMStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown status '{}'", s))
+    }
+}
+
+/// A lifecycle change that can be scheduled for a later time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledAction {
+    Stop,
+    Restart,
+}
+
+impl ScheduledAction {
+    pub const ALL: [ScheduledAction; 2] = [ScheduledAction::Stop, ScheduledAction::Restart];
+
+    /// Lowercase form used in Redis key names, e.g.
+    /// `ghaf:scheduled-stop:{name}`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScheduledAction::Stop => "stop",
+            ScheduledAction::Restart => "restart",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_status_from_str() {
+        for status in VMStatus::ALL {
+            assert_eq!(status.as_str().parse::<VMStatus>(), Ok(status));
+        }
+        assert_eq!("Stopped".parse::<VMStatus>(), Ok(VMStatus::Stopped));
+        assert!("paused".parse::<VMStatus>().is_err());
+    }
+
+    #[test]
+    fn test_system_app_type_round_trip() {
+        for (value, json) in [
+            (SystemAppType::System, "\"System\""),
+            (SystemAppType::App, "\"App\""),
+            (SystemAppType::Custom("Driver".to_string()), "\"Driver\""),
+        ] {
+            assert_eq!(serde_json::to_string(&value).unwrap(), json);
+            assert_eq!(serde_json::from_str::<SystemAppType>(json).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_legacy_mime_type_field() {
+        let base = r#""name": "pdf-vm",
+            "vm_type": { "system_app": "App", "run_type": "OneShot" },
+            "addresses": { "ip": "10.0.0.5", "vsock": "5:1234" },
+            "xdg_run": null"#;
+        for (field, expected) in [
+            (r#""mime_type": "application/pdf""#, vec!["application/pdf"]),
+            (r#""mime_type": null"#, vec![]),
+            (
+                r#""mime_types": ["application/pdf", "image/png"]"#,
+                vec!["application/pdf", "image/png"],
+            ),
+        ] {
+            let vm: VM = serde_json::from_str(&format!("{{ {}, {} }}", base, field)).unwrap();
+            assert_eq!(vm.mime_types, expected);
+        }
+    }
+}
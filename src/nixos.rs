@@ -0,0 +1,225 @@
+//! Conversion of the MicroVM definitions from a NixOS module, i.e. the JSON
+//! form of `config.ghaf.virtualization.microvm`, into registry VMs.
+//!
+//! Each attribute of that set is one VM definition:
+//!
+//! ```json
+//! { "name": "gui-vm", "enable": true, "ipAddress": "192.168.100.3",
+//!   "vsockCID": 3, "type": "system", "oneShot": false,
+//!   "xdgRuntimeDir": "/run/user/1000", "mimeTypes": ["application/pdf"],
+//!   "dependsOn": ["net-vm"], "priority": 10, "namespace": "default",
+//!   "dnsName": "gui-vm.ghaf.local", "capabilities": ["display"],
+//!   "description": "Desktop compositor", "tags": ["desktop"],
+//!   "labels": { "env": "prod" }, "ntpServers": ["192.168.100.1"],
+//!   "dnsResolvers": ["192.168.100.1"], "dnsSearchDomains": ["ghaf.local"],
+//!   "vcpu": 4, "mem": 2048 }
+//! ```
+//!
+//! Only `name`, `ipAddress` and `vsockCID` are required.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::error::RegistryError;
+use crate::migration;
+use crate::models::{Addresses, RunType, SystemAppType, VMStatus, VMType, DEFAULT_NAMESPACE, VM};
+
+/// Whether the definition is enabled; `enable` defaults to true.
+pub fn is_enabled(raw: &Value) -> bool {
+    raw.get("enable").and_then(Value::as_bool).unwrap_or(true)
+}
+
+pub fn nixos_module_to_vm(raw: &Value) -> Result<VM, RegistryError> {
+    let name = required_str(raw, "name")?;
+    let invalid = |field: &str| {
+        RegistryError::Validation(format!("VM '{}': invalid '{}' attribute", name, field))
+    };
+    let optional_str = |field: &str| match raw.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(invalid(field)),
+    };
+    let string_list = |field: &str| match raw.get(field) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| {
+                item.as_str()
+                    .map(String::from)
+                    .ok_or_else(|| invalid(field))
+            })
+            .collect(),
+        Some(_) => Err(invalid(field)),
+    };
+
+    let vsock = match raw.get("vsockCID") {
+        Some(Value::Number(cid)) if cid.is_u64() => cid.to_string(),
+        Some(Value::String(cid)) => cid.clone(),
+        None => {
+            return Err(RegistryError::Validation(format!(
+                "VM '{}': missing 'vsockCID' attribute",
+                name
+            )))
+        }
+        Some(_) => return Err(invalid("vsockCID")),
+    };
+    let system_app = match optional_str("type")?.as_deref() {
+        None | Some("system") => SystemAppType::System,
+        Some("app") => SystemAppType::App,
+        Some(custom) => SystemAppType::Custom(custom.to_string()),
+    };
+    let run_type = match raw.get("oneShot") {
+        None | Some(Value::Bool(false)) => RunType::LongRun,
+        Some(Value::Bool(true)) => RunType::OneShot,
+        Some(_) => return Err(invalid("oneShot")),
+    };
+    let labels = match raw.get("labels") {
+        None | Some(Value::Null) => BTreeMap::new(),
+        Some(Value::Object(labels)) => labels
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => Ok((key.clone(), value.clone())),
+                _ => Err(invalid("labels")),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(invalid("labels")),
+    };
+    let optional_u64 = |field: &str| match raw.get(field) {
+        None | Some(Value::Null) => Ok(0),
+        Some(value) => value.as_u64().ok_or_else(|| invalid(field)),
+    };
+    let vcpu_count = u32::try_from(optional_u64("vcpu")?).map_err(|_| invalid("vcpu"))?;
+    let priority = match raw.get("priority") {
+        None => 0,
+        Some(value) => value
+            .as_i64()
+            .and_then(|priority| i32::try_from(priority).ok())
+            .ok_or_else(|| invalid("priority"))?,
+    };
+
+    Ok(VM {
+        schema_version: migration::SCHEMA_VERSION,
+        name: name.to_string(),
+        namespace: optional_str("namespace")?.unwrap_or_else(|| DEFAULT_NAMESPACE.to_string()),
+        vm_type: VMType {
+            system_app,
+            run_type,
+        },
+        addresses: Addresses {
+            ip: required_str(raw, "ipAddress")
+                .map_err(|e| named(name, e))?
+                .to_string(),
+            vsock,
+            dns_name: optional_str("dnsName")?,
+            resolved_ips: Vec::new(),
+        },
+        xdg_run: optional_str("xdgRuntimeDir")?,
+        description: optional_str("description")?,
+        mime_types: string_list("mimeTypes")?,
+        status: VMStatus::Registered,
+        updated_at: None,
+        last_heartbeat_at: None,
+        lease_token: None,
+        dependencies: string_list("dependsOn")?,
+        priority,
+        priority_class: Default::default(),
+        capabilities: string_list("capabilities")?,
+        tags: string_list("tags")?.into_iter().collect(),
+        labels,
+        firewall_rules: Vec::new(),
+        ntp_servers: string_list("ntpServers")?,
+        dns_resolvers: string_list("dnsResolvers")?,
+        dns_search_domains: string_list("dnsSearchDomains")?,
+        vcpu_count,
+        memory_limit_mb: optional_u64("mem")?,
+        is_template: false,
+        template_name: None,
+        sealed: false,
+        owner: None,
+        image: None,
+        contact: None,
+        hostname: None,
+    })
+}
+
+fn required_str<'a>(raw: &'a Value, field: &str) -> Result<&'a str, RegistryError> {
+    raw.get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RegistryError::Validation(format!("missing '{}' attribute", field)))
+}
+
+fn named(name: &str, err: RegistryError) -> RegistryError {
+    RegistryError::Validation(format!("VM '{}': {}", name, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_full_definition() {
+        let raw = json!({
+            "name": "gui-vm",
+            "enable": true,
+            "ipAddress": "192.168.100.3",
+            "vsockCID": 3,
+            "type": "system",
+            "xdgRuntimeDir": "/run/user/1000",
+            "mimeTypes": ["application/pdf", "image/png"],
+            "dependsOn": ["net-vm"],
+            "priority": 10,
+            "namespace": "laptop",
+            "vcpu": 4,
+            "mem": 2048
+        });
+        let vm = nixos_module_to_vm(&raw).unwrap();
+        assert_eq!(vm.name, "gui-vm");
+        assert_eq!(vm.namespace, "laptop");
+        assert_eq!(vm.vm_type.system_app, SystemAppType::System);
+        assert!(matches!(vm.vm_type.run_type, RunType::LongRun));
+        assert_eq!(vm.addresses.ip, "192.168.100.3");
+        assert_eq!(vm.addresses.vsock, "3");
+        assert_eq!(vm.xdg_run.as_deref(), Some("/run/user/1000"));
+        assert_eq!(vm.mime_types, vec!["application/pdf", "image/png"]);
+        assert_eq!(vm.dependencies, vec!["net-vm"]);
+        assert_eq!(vm.priority, 10);
+        assert_eq!((vm.vcpu_count, vm.memory_limit_mb), (4, 2048));
+    }
+
+    #[test]
+    fn test_minimal_definition_defaults() {
+        let raw = json!({
+            "name": "zathura-vm",
+            "ipAddress": "192.168.100.20",
+            "vsockCID": "20",
+            "type": "app",
+            "oneShot": true
+        });
+        let vm = nixos_module_to_vm(&raw).unwrap();
+        assert_eq!(vm.namespace, DEFAULT_NAMESPACE);
+        assert_eq!(vm.vm_type.system_app, SystemAppType::App);
+        assert!(matches!(vm.vm_type.run_type, RunType::OneShot));
+        assert_eq!(vm.addresses.vsock, "20");
+        assert!(vm.mime_types.is_empty());
+        assert_eq!(vm.priority, 0);
+        assert!(is_enabled(&raw));
+        assert!(!is_enabled(&json!({ "enable": false })));
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        for raw in [
+            json!({ "ipAddress": "10.0.0.1", "vsockCID": 5 }),
+            json!({ "name": "a", "vsockCID": 5 }),
+            json!({ "name": "a", "ipAddress": "10.0.0.1" }),
+            json!({ "name": "a", "ipAddress": "10.0.0.1", "vsockCID": -1 }),
+            json!({ "name": "a", "ipAddress": "10.0.0.1", "vsockCID": 5, "mimeTypes": "text/plain" }),
+            json!({ "name": "a", "ipAddress": "10.0.0.1", "vsockCID": 5, "priority": "high" }),
+            json!({ "name": "a", "ipAddress": "10.0.0.1", "vsockCID": 5, "mem": -1 }),
+        ] {
+            assert!(nixos_module_to_vm(&raw).is_err(), "{}", raw);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Periodically reconciles the recorded VM states with the hypervisor, which
+//! is the ground truth for what is actually running.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use redis::AsyncCommands;
+
+use crate::error::RegistryError;
+use crate::models::VMStatus;
+use crate::state::AppState;
+use crate::storage;
+
+#[async_trait]
+pub trait HypervisorClient: Send + Sync {
+    /// Names of the VMs the hypervisor currently has running.
+    async fn list_running(&self) -> Result<HashSet<String>, RegistryError>;
+}
+
+/// Lists the MicroVMs whose `microvm@<name>.service` systemd unit is running.
+pub struct SystemdMicrovmClient;
+
+#[async_trait]
+impl HypervisorClient for SystemdMicrovmClient {
+    async fn list_running(&self) -> Result<HashSet<String>, RegistryError> {
+        let output = tokio::process::Command::new("systemctl")
+            .args([
+                "list-units",
+                "--type=service",
+                "--state=running",
+                "--plain",
+                "--no-legend",
+                "microvm@*.service",
+            ])
+            .output()
+            .await
+            .map_err(|e| RegistryError::Hypervisor(e.to_string()))?;
+        if !output.status.success() {
+            return Err(RegistryError::Hypervisor(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(parse_microvm_units(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+}
+
+fn parse_microvm_units(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|unit| unit.strip_prefix("microvm@")?.strip_suffix(".service"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Marks as `Failed` every VM recorded as `Running` that the hypervisor does
+/// not report, returning the names of the VMs that were updated.
+pub async fn reconcile_once(
+    state: &AppState,
+    hypervisor: &dyn HypervisorClient,
+) -> Result<Vec<String>, RegistryError> {
+    let actual = hypervisor.list_running().await?;
+    let mut con = state.connection().await?;
+    let running_key = storage::state_key(VMStatus::Running);
+    let recorded: HashSet<String> = con.smembers(&running_key).await?;
+
+    let mut missing: Vec<&String> = recorded.difference(&actual).collect();
+    missing.sort();
+    let mut failed = Vec::new();
+    for name in missing {
+        match storage::set_status(&mut con, name, VMStatus::Failed).await {
+            Ok(_) => failed.push(name.clone()),
+            // The record is gone, so the set entry is stale.
+            Err(RegistryError::NotFound(_)) => con.srem::<_, _, ()>(&running_key, name).await?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(failed)
+}
+
+/// Starts the reconciler loop unless `reconcile_interval_secs` is zero.
+pub fn spawn(state: AppState, hypervisor: Arc<dyn HypervisorClient>) {
+    let interval_secs = state.settings.reconcile_interval_secs;
+    if interval_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            ticker.tick().await;
+            match reconcile_once(&state, hypervisor.as_ref()).await {
+                Ok(failed) if !failed.is_empty() => {
+                    tracing::info!(vms = %failed.join(", "), "reconciler marked VMs as failed")
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!(error = %e, "reconciler pass failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{redis_state, sample_vm};
+
+    struct MockHypervisor(HashSet<String>);
+
+    #[async_trait]
+    impl HypervisorClient for MockHypervisor {
+        async fn list_running(&self) -> Result<HashSet<String>, RegistryError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_parse_microvm_units() {
+        let output = "microvm@net-vm.service loaded active running MicroVM 'net-vm'\n\
+                      microvm@gui-vm.service loaded active running MicroVM 'gui-vm'\n\
+                      sshd.service loaded active running OpenSSH Daemon\n";
+        let names = parse_microvm_units(output);
+        assert_eq!(names.len(), 2);
+        assert!(names.contains("net-vm"));
+        assert!(names.contains("gui-vm"));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_marks_missing_vm_failed() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let mut con = ctx.state.connection().await.unwrap();
+        for name in ["alive_vm", "dead_vm"] {
+            storage::save_vm(&mut con, &mut sample_vm(name), None)
+                .await
+                .unwrap();
+            storage::set_status(&mut con, name, VMStatus::Running)
+                .await
+                .unwrap();
+        }
+
+        let hypervisor = MockHypervisor(["alive_vm".to_string()].into_iter().collect());
+        let failed = reconcile_once(&ctx.state, &hypervisor).await.unwrap();
+
+        assert_eq!(failed, vec!["dead_vm".to_string()]);
+        let dead = storage::require_vm(&mut con, "dead_vm").await.unwrap();
+        assert_eq!(dead.status, VMStatus::Failed);
+        let alive = storage::require_vm(&mut con, "alive_vm").await.unwrap();
+        assert_eq!(alive.status, VMStatus::Running);
+        let running: HashSet<String> = con
+            .smembers(storage::state_key(VMStatus::Running))
+            .await
+            .unwrap();
+        assert_eq!(running, hypervisor.0);
+    }
+}
@@ -0,0 +1,104 @@
+//! Responses as handlers build them, independent of the web framework that
+//! serves them: both the warp and the axum server send these as they are.
+
+use hyper::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::http::StatusCode;
+use hyper::Body;
+use serde::Serialize;
+
+pub type Response = hyper::Response<Body>;
+
+/// Something a handler can answer with.
+pub trait Reply {
+    fn into_response(self) -> Response;
+}
+
+impl Reply for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl Reply for StatusCode {
+    fn into_response(self) -> Response {
+        let mut response = Response::default();
+        *response.status_mut() = self;
+        response
+    }
+}
+
+impl Reply for &'static str {
+    fn into_response(self) -> Response {
+        text_plain(self)
+    }
+}
+
+impl Reply for String {
+    fn into_response(self) -> Response {
+        text_plain(self)
+    }
+}
+
+impl Reply for Vec<u8> {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(self.into());
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        response
+    }
+}
+
+fn text_plain(body: impl Into<Body>) -> Response {
+    let mut response = Response::new(body.into());
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+/// `value` as a JSON body; 500 when it cannot be serialized.
+pub fn json<T: Serialize>(value: &T) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut response = Response::new(body.into());
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(e) => {
+            tracing::error!("cannot serialize reply: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// An empty response, e.g. `204 No Content`.
+pub fn status(status: StatusCode) -> Response {
+    status.into_response()
+}
+
+pub fn with_status(reply: impl Reply, status: StatusCode) -> Response {
+    let mut response = reply.into_response();
+    *response.status_mut() = status;
+    response
+}
+
+/// `reply` with the header `name` set to `value`; 500 when either is not a
+/// valid header name or value.
+pub fn with_header<K, V>(reply: impl Reply, name: K, value: V) -> Response
+where
+    HeaderName: TryFrom<K>,
+    HeaderValue: TryFrom<V>,
+{
+    let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) else {
+        tracing::error!("cannot set an invalid header on a reply");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let mut response = reply.into_response();
+    response.headers_mut().insert(name, value);
+    response
+}
@@ -0,0 +1,155 @@
+//! Carries out VM lifecycle changes that operators scheduled for a later
+//! time.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::error::RegistryError;
+use crate::models::{ScheduledAction, VMStatus};
+use crate::state::{AppState, RedisConnection};
+use crate::storage;
+
+/// Schedules are carried out up to this long after they fall due.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Applies `action` to VM `name` the way `POST /stop` and `POST /run` do. A
+/// restart of a VM that is already stopped only starts it.
+async fn apply(
+    con: &mut RedisConnection,
+    action: ScheduledAction,
+    name: &str,
+) -> Result<(), RegistryError> {
+    let vm = storage::require_vm(con, name).await?;
+    if vm.status != VMStatus::Stopped {
+        storage::set_status(con, name, VMStatus::Stopped).await?;
+    }
+    if action == ScheduledAction::Restart {
+        storage::set_status(con, name, VMStatus::Running).await?;
+    }
+    Ok(())
+}
+
+/// Applies every scheduled action that is due at `now` and returns the VMs
+/// it was applied to. Schedules of VMs unregistered in the meantime are
+/// dropped.
+pub async fn run_due(
+    con: &mut RedisConnection,
+    now: DateTime<Utc>,
+) -> Result<Vec<(ScheduledAction, String)>, RegistryError> {
+    let mut applied = Vec::new();
+    for action in ScheduledAction::ALL {
+        for name in storage::take_due_actions(con, action, now).await? {
+            match apply(con, action, &name).await {
+                Ok(()) => applied.push((action, name)),
+                Err(RegistryError::NotFound(_)) => {}
+                Err(e) => tracing::warn!(
+                    vm = %name,
+                    action = action.as_str(),
+                    error = %e,
+                    "scheduled action failed"
+                ),
+            }
+        }
+    }
+    Ok(applied)
+}
+
+/// Starts polling for due schedules.
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let result = match state.connection().await {
+                Ok(mut con) => run_due(&mut con, Utc::now()).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(applied) => {
+                    for (action, name) in applied {
+                        tracing::info!(vm = %name, action = action.as_str(), "applied scheduled action");
+                    }
+                }
+                Err(e) => tracing::error!(error = %e, "scheduler pass failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::VmEventKind;
+    use crate::test_util::{redis_state, sample_vm};
+
+    async fn start(con: &mut RedisConnection, name: &str) {
+        storage::save_vm(con, &mut sample_vm(name), None)
+            .await
+            .unwrap();
+        storage::set_status(con, name, VMStatus::Running)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_due_stops_vm() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let mut con = ctx.state.connection().await.unwrap();
+        start(&mut con, "timed-vm").await;
+        let stop_at = Utc::now() + chrono::Duration::seconds(1);
+        storage::schedule_action(&mut con, ScheduledAction::Stop, "timed-vm", stop_at)
+            .await
+            .unwrap();
+
+        assert!(run_due(&mut con, Utc::now()).await.unwrap().is_empty());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            run_due(&mut con, Utc::now()).await.unwrap(),
+            vec![(ScheduledAction::Stop, "timed-vm".to_string())]
+        );
+
+        let vm = storage::require_vm(&mut con, "timed-vm").await.unwrap();
+        assert_eq!(vm.status, VMStatus::Stopped);
+        let pending = storage::get_scheduled_action(&mut con, ScheduledAction::Stop, "timed-vm")
+            .await
+            .unwrap();
+        assert_eq!(pending, None);
+        assert!(run_due(&mut con, Utc::now()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_due_restarts_vm() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let mut con = ctx.state.connection().await.unwrap();
+        start(&mut con, "restart-vm").await;
+        let restart_at = Utc::now() + chrono::Duration::seconds(1);
+        storage::schedule_action(&mut con, ScheduledAction::Restart, "restart-vm", restart_at)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            run_due(&mut con, Utc::now()).await.unwrap(),
+            vec![(ScheduledAction::Restart, "restart-vm".to_string())]
+        );
+
+        let statuses: Vec<VMStatus> = storage::list_vm_events(&mut con, "restart-vm")
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                VmEventKind::StatusChanged { status } => Some(status),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            [VMStatus::Running, VMStatus::Stopped, VMStatus::Running]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! JSON Schemas of request bodies, generated from their Rust types. Bodies
+//! are checked against them before deserialization so that clients get
+//! every violation at once, with the path of the offending field.
+
+use std::net::IpAddr;
+use std::sync::LazyLock;
+
+use jsonschema::Validator;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use crate::error::{RegistryError, SchemaViolation};
+use crate::models::{PatchVM, VM};
+
+pub static VM_SCHEMA: LazyLock<Validator> = LazyLock::new(compile::<VM>);
+
+/// The schema of `VM` as published for clients, in JSON Schema Draft 7,
+/// which more form generators understand than the 2020-12 dialect used for
+/// validation. Field descriptions come from the doc comments.
+pub static VM_SCHEMA_DOCUMENT: LazyLock<Value> = LazyLock::new(|| {
+    let schema = schemars::generate::SchemaSettings::draft07()
+        .into_generator()
+        .into_root_schema_for::<VM>();
+    serde_json::to_value(schema).expect("a generated schema serializes to JSON")
+});
+pub static PATCH_VM_SCHEMA: LazyLock<Validator> = LazyLock::new(compile::<PatchVM>);
+
+/// Compiles the schema of `T`. Formats are asserted, not just annotated, and
+/// `ip` accepts IPv4 and IPv6 addresses.
+pub fn compile<T: JsonSchema>() -> Validator {
+    let schema = serde_json::to_value(schemars::schema_for!(T))
+        .expect("a generated schema serializes to JSON");
+    jsonschema::options()
+        .should_validate_formats(true)
+        .with_format("ip", |value: &str| value.parse::<IpAddr>().is_ok())
+        .build(&schema)
+        .expect("a generated schema is valid")
+}
+
+/// Fails with every violation of `schema` by `instance`.
+pub fn check(schema: &Validator, instance: &Value) -> Result<(), RegistryError> {
+    let violations: Vec<SchemaViolation> = schema
+        .iter_errors(instance)
+        .map(|error| SchemaViolation {
+            path: error.instance_path().to_string(),
+            message: error.to_string(),
+        })
+        .collect();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(RegistryError::SchemaViolations(violations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample_vm;
+    use serde_json::json;
+
+    fn paths(schema: &Validator, instance: &Value) -> Vec<String> {
+        match check(schema, instance) {
+            Ok(()) => Vec::new(),
+            Err(RegistryError::SchemaViolations(violations)) => {
+                violations.into_iter().map(|v| v.path).collect()
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_vm_schema_document() {
+        let schema = &*VM_SCHEMA_DOCUMENT;
+        assert_eq!(schema["$schema"], "http://json-schema.org/draft-07/schema#");
+        jsonschema::draft7::meta::validate(schema).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        for (field, property) in properties {
+            assert!(
+                property["description"].is_string(),
+                "{} has no description",
+                field
+            );
+        }
+        let vm = serde_json::to_value(sample_vm("foo")).unwrap();
+        assert!(jsonschema::draft7::is_valid(schema, &vm));
+    }
+
+    #[test]
+    fn test_vm_schema() {
+        let mut vm = serde_json::to_value(sample_vm("foo")).unwrap();
+        assert!(paths(&VM_SCHEMA, &vm).is_empty());
+
+        vm["mime_type"] = json!("application/pdf");
+        vm["mime_types"] = json!("application/pdf");
+        assert!(paths(&VM_SCHEMA, &vm).is_empty());
+
+        vm["addresses"]["ip"] = json!("fe80::1");
+        assert!(paths(&VM_SCHEMA, &vm).is_empty());
+
+        vm["addresses"]["ip"] = json!("not-an-ip");
+        vm["priority"] = json!("high");
+        vm["vm_type"]["run_type"] = json!("Forever");
+        let mut found = paths(&VM_SCHEMA, &vm);
+        found.sort();
+        assert_eq!(found, ["/addresses/ip", "/priority", "/vm_type/run_type"]);
+    }
+
+    #[test]
+    fn test_patch_vm_schema() {
+        assert!(paths(&PATCH_VM_SCHEMA, &json!({ "priority": 3 })).is_empty());
+        assert_eq!(
+            paths(&PATCH_VM_SCHEMA, &json!({ "status": "Running" })),
+            [""]
+        );
+        assert_eq!(
+            paths(&PATCH_VM_SCHEMA, &json!({ "vcpu_count": -1 })),
+            ["/vcpu_count"]
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! Connection handling for the HTTP listeners: binding the configured TCP
+//! and UNIX sockets, TCP options on accepted sockets, closing of
+//! connections that stay idle for too long and timing out slow requests.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{future, Stream};
+use http_body::combinators::UnsyncBoxBody;
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::{header, StatusCode};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Request};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::time::{Instant, Sleep};
+
+use crate::error::ErrorResponse;
+use crate::reply::{self, Response};
+use crate::settings::{ListenerConfig, Settings};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A response of any body type, so that the server's own responses can be
+/// sent alongside those of the web framework.
+type AnyResponse = hyper::Response<UnsyncBoxBody<Bytes, BoxError>>;
+
+/// Wraps a connection so that it fails with `TimedOut` once no bytes have
+/// been read or written for `timeout`, which makes hyper drop it.
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    fn touch(&mut self) {
+        let deadline = Instant::now() + self.timeout;
+        self.deadline.as_mut().reset(deadline);
+    }
+
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        self.deadline.as_mut().poll(cx).is_ready()
+    }
+}
+
+fn idle_error() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connection idle timeout")
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    self.touch();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending if self.poll_expired(cx) => Poll::Ready(Err(idle_error())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => {
+                if written > 0 {
+                    self.touch();
+                }
+                Poll::Ready(Ok(written))
+            }
+            Poll::Pending if self.poll_expired(cx) => Poll::Ready(Err(idle_error())),
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Pause after an accept failure that is not specific to one connection,
+/// e.g. `EMFILE`; such errors persist for a while, so retrying at once would
+/// only spin. hyper's `AddrIncoming` waits the same.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether a failed accept only lost the connection being accepted, so the
+/// next one can be accepted right away.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Calls `accept` on `listener` for ever and yields the connections it
+/// returns; `Ok(None)` is a connection it accepted but dropped. Errors are
+/// logged and retried rather than yielded, since hyper stops serving a
+/// listener at the first error of its stream.
+fn accept_forever<L, T, F, Fut>(listener: L, accept: F) -> impl Stream<Item = io::Result<T>>
+where
+    F: Fn(Arc<L>) -> Fut,
+    Fut: Future<Output = io::Result<Option<T>>>,
+{
+    futures_util::stream::unfold(
+        (Arc::new(listener), accept),
+        |(listener, accept)| async move {
+            loop {
+                match accept(listener.clone()).await {
+                    Ok(Some(connection)) => return Some((Ok(connection), (listener, accept))),
+                    Ok(None) => {}
+                    Err(e) if is_connection_error(&e) => {
+                        tracing::debug!(error = %e, "accepted connection was lost");
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            error = %e,
+                            "cannot accept connections, retrying in {:?}",
+                            ACCEPT_ERROR_BACKOFF
+                        );
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Applies `TCP_NODELAY` and TCP keep-alive probing to an accepted socket.
+fn configure_tcp(stream: &TcpStream, settings: &Settings) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let keepalive =
+        TcpKeepalive::new().with_time(Duration::from_secs(settings.keep_alive_timeout_secs));
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Turns a listener into the stream of configured connections to serve.
+/// A connection whose options cannot be set, typically because the peer
+/// already reset it, is logged and dropped.
+pub fn tcp_incoming(
+    listener: TcpListener,
+    settings: &Settings,
+) -> impl Stream<Item = io::Result<IdleTimeout<TcpStream>>> {
+    let settings = settings.clone();
+    accept_forever(listener, move |listener: Arc<TcpListener>| {
+        let settings = settings.clone();
+        async move {
+            let (stream, peer) = listener.accept().await?;
+            if let Err(e) = configure_tcp(&stream, &settings) {
+                tracing::warn!(%peer, error = %e, "cannot configure connection, dropping it");
+                return Ok(None);
+            }
+            let idle = Duration::from_secs(settings.idle_connection_timeout_secs);
+            Ok(Some(IdleTimeout::new(stream, idle)))
+        }
+    })
+}
+
+/// Accepts connections on a UNIX socket. TCP options do not apply, but idle
+/// connections are closed all the same.
+pub fn unix_incoming(
+    listener: UnixListener,
+    settings: &Settings,
+) -> impl Stream<Item = io::Result<IdleTimeout<UnixStream>>> {
+    let idle = Duration::from_secs(settings.idle_connection_timeout_secs);
+    accept_forever(listener, move |listener: Arc<UnixListener>| async move {
+        let (stream, _) = listener.accept().await?;
+        Ok(Some(IdleTimeout::new(stream, idle)))
+    })
+}
+
+/// Sent when a request is not answered within `request_timeout_secs`,
+/// with the JSON body of every other error. `Connection: close` makes hyper
+/// close the connection once it is sent.
+fn request_timeout_response() -> Response {
+    let body = ErrorResponse::new("RequestTimeout", "Request timed out.");
+    let mut response = reply::with_status(reply::json(&body), StatusCode::REQUEST_TIMEOUT);
+    response.headers_mut().insert(
+        header::CONNECTION,
+        header::HeaderValue::from_static("close"),
+    );
+    response
+}
+
+fn any_response<B>(response: hyper::Response<B>) -> AnyResponse
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    response.map(|body| body.map_err(Into::into).boxed_unsync())
+}
+
+/// Serves `service`, the API as warp or axum serves it, on the connections
+/// of `incoming`. Reading the request body and running the handler must
+/// finish within `request_timeout_secs`, else the handler is dropped and
+/// the client gets 408. A client that does not finish sending the headers
+/// in that time is disconnected.
+async fn serve_incoming<T, B, S, I>(service: T, incoming: S, settings: &Settings)
+where
+    T: Service<Request<Body>, Response = hyper::Response<B>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    T::Future: Send,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    S: Stream<Item = io::Result<I>> + Send,
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let timeout = Duration::from_secs(settings.request_timeout_secs);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        future::ok::<_, Infallible>(service_fn(move |request| {
+            let mut service = service.clone();
+            async move {
+                match tokio::time::timeout(timeout, service.call(request)).await {
+                    Ok(response) => response.map(any_response),
+                    Err(_) => Ok(any_response(request_timeout_response())),
+                }
+            }
+        }))
+    });
+    let server = hyper::Server::builder(accept::from_stream(incoming))
+        .http1_header_read_timeout(timeout)
+        .serve(make_service);
+    if let Err(e) = server.await {
+        tracing::error!(error = %e, "server error");
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds `config`, replacing a stale socket file left by an earlier run.
+    pub async fn bind(config: &ListenerConfig) -> io::Result<Self> {
+        match config {
+            ListenerConfig::Tcp { addr } => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenerConfig::Unix { path } => {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp".to_string(),
+            },
+            Listener::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => path.display().to_string(),
+                    None => "unix".to_string(),
+                },
+                Err(_) => "unix".to_string(),
+            },
+        }
+    }
+}
+
+/// Serves `service` on every listener concurrently until all of them stop.
+pub async fn run<T, B>(service: T, listeners: Vec<Listener>, settings: &Settings)
+where
+    T: Service<Request<Body>, Response = hyper::Response<B>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    T::Future: Send,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            tracing::info!(address = %listener.describe(), "listening");
+            let service = service.clone();
+            let settings = settings.clone();
+            match listener {
+                Listener::Tcp(listener) => tokio::spawn(async move {
+                    let incoming = tcp_incoming(listener, &settings);
+                    serve_incoming(service, incoming, &settings).await
+                }),
+                Listener::Unix(listener) => tokio::spawn(async move {
+                    let incoming = unix_incoming(listener, &settings);
+                    serve_incoming(service, incoming, &settings).await
+                }),
+            }
+        })
+        .collect();
+    for task in tasks {
+        if let Err(e) = task.await {
+            tracing::error!(error = %e, "listener task failed");
+        }
+    }
+}
+
+pub async fn serve<T, B>(service: T, settings: &Settings) -> io::Result<()>
+where
+    T: Service<Request<Body>, Response = hyper::Response<B>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    T::Future: Send,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    if settings.listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no listeners configured",
+        ));
+    }
+    let mut listeners = Vec::new();
+    for config in &settings.listeners {
+        listeners.push(Listener::bind(config).await?);
+    }
+    run(service, listeners, settings).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers `GET /ping` with `pong`.
+    #[cfg(feature = "warp")]
+    fn ping(
+    ) -> impl Service<Request<Body>, Response = Response, Error = Infallible, Future = impl Send>
+           + Clone
+           + Send {
+        use warp::Filter;
+
+        warp::service(warp::path("ping").map(|| "pong"))
+    }
+
+    #[cfg(feature = "axum")]
+    fn ping() -> axum::Router {
+        axum::Router::new().route("/ping", axum::routing::get(|| async { "pong" }))
+    }
+
+    async fn start_with<T, B>(service: T, settings: Settings) -> std::net::SocketAddr
+    where
+        T: Service<Request<Body>, Response = hyper::Response<B>, Error = Infallible>
+            + Clone
+            + Send
+            + 'static,
+        T::Future: Send,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let incoming = tcp_incoming(listener, &settings);
+            serve_incoming(service, incoming, &settings).await
+        });
+        addr
+    }
+
+    async fn start(settings: Settings) -> std::net::SocketAddr {
+        start_with(ping(), settings).await
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut buf = vec![0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors_do_not_end_the_stream() {
+        use futures_util::StreamExt;
+        use std::collections::VecDeque;
+        use std::sync::Mutex;
+
+        let outcomes = Mutex::new(VecDeque::from([
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            Ok(Some(1)),
+            Err(io::Error::from_raw_os_error(24)), // EMFILE
+            Ok(None),
+            Ok(Some(2)),
+        ]));
+        let started = Instant::now();
+        let accepted: Vec<u32> = accept_forever(outcomes, |outcomes| async move {
+            outcomes.lock().unwrap().pop_front().unwrap()
+        })
+        .take(2)
+        .map(Result::unwrap)
+        .collect()
+        .await;
+        assert_eq!(accepted, [1, 2]);
+        // Only the EMFILE is waited out.
+        assert!(started.elapsed() >= ACCEPT_ERROR_BACKOFF);
+        assert!(started.elapsed() < ACCEPT_ERROR_BACKOFF * 2);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_serves_sequential_requests() {
+        let addr = start(Settings::default()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut stream).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert!(response.ends_with("pong"), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let settings = Settings::default();
+        let dir = std::env::temp_dir().join(format!("ghaf-registry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("api.sock");
+        let configs = [
+            ListenerConfig::Tcp {
+                addr: "127.0.0.1:0".parse().unwrap(),
+            },
+            ListenerConfig::Tcp {
+                addr: "127.0.0.1:0".parse().unwrap(),
+            },
+            ListenerConfig::Unix {
+                path: socket.clone(),
+            },
+        ];
+        let mut listeners = Vec::new();
+        let mut addrs = Vec::new();
+        for config in &configs {
+            let listener = Listener::bind(config).await.unwrap();
+            if let Listener::Tcp(tcp) = &listener {
+                addrs.push(tcp.local_addr().unwrap());
+            }
+            listeners.push(listener);
+        }
+        assert_ne!(addrs[0].port(), addrs[1].port());
+        tokio::spawn(async move { run(ping(), listeners, &settings).await });
+
+        for addr in addrs {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            assert!(read_response(&mut stream).await.ends_with("pong"));
+        }
+        let mut stream = UnixStream::connect(&socket).await.unwrap();
+        stream
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).ends_with("pong"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_closed() {
+        let settings = Settings {
+            idle_connection_timeout_secs: 1,
+            ..Settings::default()
+        };
+        let addr = start(settings).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response(&mut stream).await.ends_with("pong"));
+
+        let mut buf = [0; 16];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("server should close the idle connection");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_times_out() {
+        let settings = Settings {
+            request_timeout_secs: 1,
+            ..Settings::default()
+        };
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = finished.clone();
+        let slow = move || {
+            let flag = flag.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                flag.store(true, Ordering::SeqCst);
+                "done"
+            }
+        };
+        #[cfg(feature = "warp")]
+        let service = {
+            use warp::Filter;
+
+            let upload = warp::path("upload")
+                .and(warp::body::bytes())
+                .map(|_| "uploaded");
+            warp::service(warp::path("slow").then(slow).or(upload))
+        };
+        #[cfg(feature = "axum")]
+        let service = {
+            use axum::routing::{get, post};
+
+            axum::Router::new()
+                .route("/slow", get(slow))
+                .route("/upload", post(|_: Bytes| async { "uploaded" }))
+        };
+        let addr = start_with(service, settings).await;
+
+        let started = std::time::Instant::now();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(
+            head.to_lowercase()
+                .contains("content-type: application/json"),
+            "{}",
+            head
+        );
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["error"], "RequestTimeout");
+        assert_eq!(body["message"], "Request timed out.");
+        assert!(body["request_id"].is_string());
+        let mut buf = [0; 16];
+        assert!(matches!(stream.read(&mut buf).await, Ok(0) | Err(_)));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(
+            !finished.load(Ordering::SeqCst),
+            "handler was not cancelled"
+        );
+
+        // The body never arrives.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    }
+}
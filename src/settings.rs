@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use chrono::NaiveTime;
+use hyper::http::header::{HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use crate::auth::Role;
+
+/// Environment variable naming an optional JSON configuration file.
+pub const CONFIG_ENV: &str = "GHAF_REGISTRY_CONFIG";
+
+/// Runtime configuration. Every field has a default, so a config file only
+/// needs to list the values it overrides.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub redis_url: String,
+    /// Seconds a single Redis operation, or opening a connection, may take
+    /// before the request fails with 503.
+    pub redis_op_timeout_secs: u64,
+    /// Sockets the API is served on; all share the same routes and state.
+    pub listeners: Vec<ListenerConfig>,
+    /// Seconds between reconciler passes; `0` disables the reconciler.
+    pub reconcile_interval_secs: u64,
+    /// Idle time before TCP keep-alive probes are sent on a connection.
+    pub keep_alive_timeout_secs: u64,
+    /// Connections with no traffic for this long are closed.
+    pub idle_connection_timeout_secs: u64,
+    /// Seconds a client has to send a request and the registry has to
+    /// answer it; slower requests get 408 and their connection is closed.
+    pub request_timeout_secs: u64,
+    /// Seconds a `GET /vms?watch=true` response streams changes before the
+    /// registry ends it and watchers reconnect. Keep it below
+    /// `idle_connection_timeout_secs`, which would cut quiet watches short.
+    pub watch_timeout_secs: u64,
+    /// Serve `POST /admin/simulate-event`, which publishes made-up VM
+    /// events for testing event consumers.
+    pub enable_simulation_endpoints: bool,
+    /// Requests each client, told apart by address, may make per minute;
+    /// further requests get 429. Unset disables rate limiting.
+    pub rate_limit_per_minute: Option<u32>,
+    /// Forward-confirm `addresses.dns_name` against `addresses.ip` when VMs
+    /// are registered or updated.
+    pub validate_dns: bool,
+    /// Overrides for the security headers added to every response, keyed by
+    /// header name. An empty value removes that header. A config file with
+    /// an invalid header name or value is rejected.
+    pub security_headers: HashMap<String, String>,
+    /// Bearer tokens accepted by the API and the role each one grants. When
+    /// empty, authentication is disabled.
+    pub api_tokens: HashMap<String, Role>,
+    /// Identity of the holder of each bearer token, e.g. a user name. VMs
+    /// registered with a token are owned by its identity.
+    pub token_identities: HashMap<String, String>,
+    /// File holding the AES-256 key VM records are encrypted with, as 32
+    /// raw bytes or base64. Records are stored in plaintext when unset.
+    pub encryption_key_file: Option<PathBuf>,
+    /// Refuse to read plaintext and `enc:v1:` values, which are not bound to
+    /// their Redis key. Set once every value has been saved again under
+    /// `encryption_key_file`, and so rewritten as `enc:v2:`.
+    pub strict_encryption: bool,
+    /// Maximum number of VMs per namespace; unlisted namespaces are
+    /// unlimited.
+    pub namespace_quotas: HashMap<String, u32>,
+    /// Directory `POST /admin/backup` writes archives to and
+    /// `POST /admin/restore` reads them from.
+    pub backup_dir: PathBuf,
+    /// Seconds without a heartbeat after which a running VM counts as stale,
+    /// unless a request names its own threshold.
+    pub stale_threshold_secs: u64,
+    /// Seconds since the last heartbeat or statistics sample after which
+    /// `GET /vm/:name/liveness` reports a VM as not alive.
+    pub liveness_threshold_secs: u64,
+    /// VMs that at least this many other VMs depend on, directly or not,
+    /// are reported as critical by `GET /vms/dependency-depth`.
+    pub critical_dependents: usize,
+    /// Audit log entries older than this many days are dropped by
+    /// compaction, unless a request names its own threshold.
+    pub audit_retention_days: u32,
+    /// UTC time of day, e.g. `"03:00:00"`, at which audit logs are compacted
+    /// automatically; unset disables automatic compaction.
+    pub audit_compact_at: Option<NaiveTime>,
+    /// Default level of log events, e.g. `"info"`.
+    pub log_level: String,
+    /// Levels for single modules, keyed by target, e.g.
+    /// `{ "GHAFregistryd::storage": "debug" }`.
+    pub log_level_overrides: HashMap<String, String>,
+    pub log_format: LogFormat,
+}
+
+/// How log events are written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+/// One socket to accept API connections on, written in config files as e.g.
+/// `{ "type": "tcp", "addr": "127.0.0.1:3030" }` or
+/// `{ "type": "unix", "path": "/run/ghaf-registry.sock" }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ListenerConfig {
+    Tcp { addr: SocketAddr },
+    Unix { path: PathBuf },
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            redis_url: "redis://127.0.0.1/".to_string(),
+            redis_op_timeout_secs: 5,
+            listeners: vec![ListenerConfig::Tcp {
+                addr: SocketAddr::from(([127, 0, 0, 1], 3030)),
+            }],
+            reconcile_interval_secs: 60,
+            keep_alive_timeout_secs: 75,
+            idle_connection_timeout_secs: 120,
+            request_timeout_secs: 30,
+            watch_timeout_secs: 60,
+            enable_simulation_endpoints: false,
+            rate_limit_per_minute: None,
+            validate_dns: false,
+            security_headers: HashMap::new(),
+            api_tokens: HashMap::new(),
+            token_identities: HashMap::new(),
+            encryption_key_file: None,
+            strict_encryption: false,
+            namespace_quotas: HashMap::new(),
+            backup_dir: PathBuf::from("/var/lib/ghaf-registry/backups"),
+            stale_threshold_secs: 300,
+            liveness_threshold_secs: 300,
+            critical_dependents: 3,
+            audit_retention_days: 30,
+            audit_compact_at: None,
+            log_level: "info".to_string(),
+            log_level_overrides: HashMap::new(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads settings from the file named by `GHAF_REGISTRY_CONFIG`, or
+    /// returns the defaults when the variable is unset.
+    pub fn load() -> Result<Self, String> {
+        match std::env::var(CONFIG_ENV) {
+            Ok(path) => {
+                let raw = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("cannot read {}: {}", path, e))?;
+                let settings: Settings = serde_json::from_str(&raw)
+                    .map_err(|e| format!("invalid config {}: {}", path, e))?;
+                settings
+                    .validate()
+                    .map_err(|e| format!("invalid config {}: {}", path, e))?;
+                Ok(settings)
+            }
+            Err(_) => Ok(Settings::default()),
+        }
+    }
+
+    /// Rejects values that parse but cannot be used.
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in &self.security_headers {
+            HeaderName::try_from(name.as_str())
+                .map_err(|_| format!("invalid security header name '{}'", name))?;
+            HeaderValue::try_from(value.as_str())
+                .map_err(|_| format!("invalid value for security header '{}'", name))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_security_header(name: &str, value: &str) -> Settings {
+        Settings {
+            security_headers: [(name.to_string(), value.to_string())].into(),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn test_invalid_security_headers_are_rejected() {
+        assert!(Settings::default().validate().is_ok());
+        assert!(with_security_header("Referrer-Policy", "no-referrer")
+            .validate()
+            .is_ok());
+        assert!(with_security_header("Cache-Control", "").validate().is_ok());
+        assert_eq!(
+            with_security_header("Bad Header", "x").validate(),
+            Err("invalid security header name 'Bad Header'".to_string())
+        );
+        assert_eq!(
+            with_security_header("X-Frame-Options", "DENY\r\n").validate(),
+            Err("invalid value for security header 'X-Frame-Options'".to_string())
+        );
+    }
+}
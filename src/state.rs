@@ -0,0 +1,428 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::aio::{ConnectionLike, MultiplexedConnection, PubSub};
+use redis::{Client, Cmd, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::crypto::{self, RecordCipher};
+use crate::discovery::{PortProber, TcpProber};
+use crate::dns::{DnsResolver, SystemResolver};
+use crate::error::RegistryError;
+use crate::settings::Settings;
+
+/// A handle on the registry's Redis connection that also knows how VM
+/// records are encoded at rest. It implements `ConnectionLike`, so all Redis
+/// commands work on it directly. Every command fails with a timeout error
+/// after `op_timeout`, so a slow server cannot hold a handler forever.
+///
+/// All handles share one multiplexed TCP connection, so commands that change
+/// connection state (`WATCH`, `SELECT`, `SUBSCRIBE`) must not be used;
+/// pipelines, including `MULTI`/`EXEC` ones, are sent as one unit and are
+/// safe.
+pub struct RedisConnection {
+    inner: MultiplexedConnection,
+    /// Generation of the shared connection `inner` was cloned from.
+    generation: u64,
+    shared: SharedConnection,
+    cipher: Option<Arc<RecordCipher>>,
+    op_timeout: Duration,
+    published: Arc<AtomicU64>,
+    _open: Gauged,
+}
+
+/// Commands in a row that may time out on the shared connection before it
+/// is presumed dead and reopened.
+const MAX_CONSECUTIVE_TIMEOUTS: u32 = 3;
+
+/// The multiplexed connection handed out by `AppState::connection`, once
+/// established; cleared when it breaks so the next caller reconnects.
+#[derive(Default)]
+struct Shared {
+    connection: Mutex<Option<MultiplexedConnection>>,
+    /// Counts the connections opened, so a handle on a connection that has
+    /// since been replaced cannot clear its successor. Only changes while
+    /// `connection` is locked.
+    generation: AtomicU64,
+    /// Commands in a row that timed out on the current connection.
+    timeouts: AtomicU32,
+    /// Whether `connection` holds a connection, readable without the lock.
+    established: AtomicBool,
+}
+
+type SharedConnection = Arc<Shared>;
+
+/// Counts itself in a gauge for as long as it lives.
+struct Gauged(Arc<AtomicUsize>);
+
+impl Gauged {
+    fn new(gauge: &Arc<AtomicUsize>) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Gauged(gauge.clone())
+    }
+}
+
+impl Drop for Gauged {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the registry's Redis connections, in the terms of a
+/// connection pool. The pool is the one shared multiplexed connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Connections open: 1 once the shared connection is established.
+    pub size: usize,
+    /// Open connections that can take a command. Handles never exhaust a
+    /// multiplexed connection, so this is `size`.
+    pub available: usize,
+    /// Callers waiting for the shared connection to be established.
+    pub waiting: usize,
+    /// Connections the registry opens at most.
+    pub max_size: usize,
+    /// Handles on the shared connection held by handlers and background
+    /// tasks.
+    pub handles: usize,
+}
+
+impl RedisConnection {
+    /// Encrypts a serialized VM record to be stored under the Redis key
+    /// `key` when encryption is enabled. The ciphertext is bound to `key`.
+    pub fn encode_record(&self, json: String, key: &str) -> Result<String, RegistryError> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&json, key),
+            None => Ok(json),
+        }
+    }
+
+    /// Returns the serialized VM record stored as `raw` under `key`.
+    pub fn decode_record(&self, raw: String, key: &str) -> Result<String, RegistryError> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&raw, key),
+            None if crypto::is_encrypted(&raw) => Err(RegistryError::Encryption(
+                "record is encrypted but no encryption key is configured".to_string(),
+            )),
+            None => Ok(raw),
+        }
+    }
+
+    /// Counts `messages` published on the event channels; see
+    /// `AppState::published_count`.
+    pub fn count_published(&self, messages: u64) {
+        self.published.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    /// Drops the shared connection after an I/O failure, or after
+    /// `MAX_CONSECUTIVE_TIMEOUTS` timeouts in a row, so the next
+    /// `AppState::connection` call opens a new one. Failures of a
+    /// connection that has already been replaced are ignored.
+    async fn check<T>(&self, result: RedisResult<T>) -> RedisResult<T> {
+        if self.shared.generation.load(Ordering::Acquire) != self.generation {
+            return result;
+        }
+        let broken = match &result {
+            Ok(_) => {
+                self.shared.timeouts.store(0, Ordering::Relaxed);
+                false
+            }
+            Err(e) if e.is_timeout() => {
+                self.shared.timeouts.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_CONSECUTIVE_TIMEOUTS
+            }
+            Err(e) => e.is_io_error() || e.is_connection_dropped(),
+        };
+        if broken {
+            let mut connection = self.shared.connection.lock().await;
+            if self.shared.generation.load(Ordering::Acquire) == self.generation {
+                connection.take();
+                self.shared.established.store(false, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+/// The error Redis itself uses for timed-out I/O, so `RedisError::is_timeout`
+/// recognises it.
+fn timed_out() -> RedisError {
+    std::io::Error::from(std::io::ErrorKind::TimedOut).into()
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let result = tokio::time::timeout(self.op_timeout, self.inner.req_packed_command(cmd))
+                .await
+                .unwrap_or_else(|_| Err(timed_out()));
+            self.check(result).await
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let request = self.inner.req_packed_commands(cmd, offset, count);
+            let result = tokio::time::timeout(self.op_timeout, request)
+                .await
+                .unwrap_or_else(|_| Err(timed_out()));
+            self.check(result).await
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+/// Change notifications buffered per watcher before it starts missing them.
+const CHANGES_CAPACITY: usize = 256;
+
+/// Shared state handed to every request handler and background task.
+#[derive(Clone)]
+pub struct AppState {
+    pub settings: Arc<Settings>,
+    pub resolver: Arc<dyn DnsResolver>,
+    pub prober: Arc<dyn PortProber>,
+    /// Change notifications from `events::VM_CHANGES_CHANNEL`, fed by
+    /// `events::spawn`.
+    pub changes: broadcast::Sender<String>,
+    redis: Client,
+    shared: SharedConnection,
+    cipher: Option<Arc<RecordCipher>>,
+    open: Arc<AtomicUsize>,
+    connecting: Arc<AtomicUsize>,
+    published: Arc<AtomicU64>,
+}
+
+/// The state as request handlers take it, e.g.
+/// `StateExtension(state): StateExtension<Arc<AppState>>`, so that their
+/// signatures do not depend on the web framework extracting it: warp's
+/// `api::with_state` filter or axum's router state.
+#[derive(Clone)]
+pub struct StateExtension<T>(pub T);
+
+impl AppState {
+    pub fn new(settings: Settings) -> Result<Self, RegistryError> {
+        let redis = Client::open(settings.redis_url.as_str())?;
+        let cipher = match &settings.encryption_key_file {
+            Some(path) => Some(Arc::new(
+                RecordCipher::from_key_file(path)?.strict(settings.strict_encryption),
+            )),
+            None => None,
+        };
+        Ok(AppState {
+            settings: Arc::new(settings),
+            resolver: Arc::new(SystemResolver),
+            prober: Arc::new(TcpProber),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+            redis,
+            shared: Arc::default(),
+            cipher,
+            open: Arc::default(),
+            connecting: Arc::default(),
+            published: Arc::default(),
+        })
+    }
+
+    /// A dedicated connection for subscribing to channels, which the
+    /// shared connection cannot do.
+    pub async fn pubsub(&self) -> Result<PubSub, RegistryError> {
+        let op_timeout = Duration::from_secs(self.settings.redis_op_timeout_secs);
+        let con = tokio::time::timeout(op_timeout, self.redis.get_async_connection())
+            .await
+            .map_err(|_| RegistryError::Timeout)??;
+        Ok(con.into_pubsub())
+    }
+
+    /// A handle on the shared multiplexed connection, which is opened by the
+    /// first caller. Concurrent callers wait for that instead of opening
+    /// connections of their own.
+    pub async fn connection(&self) -> Result<RedisConnection, RegistryError> {
+        let op_timeout = Duration::from_secs(self.settings.redis_op_timeout_secs);
+        let connecting = Gauged::new(&self.connecting);
+        let (inner, generation) = tokio::time::timeout(op_timeout, async {
+            let mut connection = self.shared.connection.lock().await;
+            let generation = self.shared.generation.load(Ordering::Acquire);
+            if let Some(con) = connection.as_ref() {
+                return Ok((con.clone(), generation));
+            }
+            let con = self.redis.get_multiplexed_tokio_connection().await?;
+            *connection = Some(con.clone());
+            self.shared.established.store(true, Ordering::Relaxed);
+            self.shared.timeouts.store(0, Ordering::Relaxed);
+            self.shared
+                .generation
+                .store(generation + 1, Ordering::Release);
+            Ok::<_, RedisError>((con, generation + 1))
+        })
+        .await
+        .map_err(|_| RegistryError::Timeout)??;
+        drop(connecting);
+        Ok(RedisConnection {
+            inner,
+            generation,
+            shared: self.shared.clone(),
+            cipher: self.cipher.clone(),
+            op_timeout,
+            published: self.published.clone(),
+            _open: Gauged::new(&self.open),
+        })
+    }
+
+    /// Handles are cheap clones of one connection rather than pooled
+    /// connections, so the pool has at most one connection.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        let size = usize::from(self.shared.established.load(Ordering::Relaxed));
+        ConnectionStats {
+            size,
+            available: size,
+            waiting: self.connecting.load(Ordering::Relaxed),
+            max_size: 1,
+            handles: self.open.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Messages this registry published on the event channels since it
+    /// started.
+    pub fn published_count(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::net::TcpListener;
+
+    use super::{AppState, RedisConnection};
+    use crate::api::routes;
+    use crate::settings::Settings;
+    use crate::test_util::{redis_state, register, request, sample_vm};
+
+    /// A server that accepts connections, counting them, but never answers
+    /// a command.
+    async fn silent_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                sockets.push(socket);
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_slow_redis_times_out() {
+        let (addr, _) = silent_server().await;
+        let settings = Settings {
+            redis_url: format!("redis://{}/", addr),
+            redis_op_timeout_secs: 1,
+            ..Settings::default()
+        };
+        let api = routes(AppState::new(settings).unwrap());
+
+        let started = Instant::now();
+        let response = request().method("GET").path("/list").reply(&api).await;
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["retry-after"], "5");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"], "Timeout");
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_repeated_timeouts() {
+        let (addr, accepted) = silent_server().await;
+        let state = AppState::new(Settings {
+            redis_url: format!("redis://{}/", addr),
+            redis_op_timeout_secs: 1,
+            ..Settings::default()
+        })
+        .unwrap();
+        let ping = |mut con: RedisConnection| async move {
+            let result = redis::cmd("PING").query_async::<_, String>(&mut con).await;
+            assert!(result.unwrap_err().is_timeout());
+        };
+        let mut handles = Vec::new();
+        for _ in 0..7 {
+            handles.push(state.connection().await.unwrap());
+        }
+        let mut stale = handles.split_off(4);
+
+        // A single timeout does not give up on the connection.
+        ping(handles.pop().unwrap()).await;
+        let _same = state.connection().await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        let mut first = handles.into_iter();
+        tokio::join!(
+            ping(first.next().unwrap()),
+            ping(first.next().unwrap()),
+            ping(first.next().unwrap()),
+        );
+        let _replacement = state.connection().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // Timeouts of handles on the replaced connection leave its
+        // successor alone.
+        tokio::join!(
+            ping(stale.pop().unwrap()),
+            ping(stale.pop().unwrap()),
+            ping(stale.pop().unwrap()),
+        );
+        let _replacement = state.connection().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_connection() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let api = routes(ctx.state.clone());
+        let vms = [
+            sample_vm("mux-vm-1"),
+            sample_vm("mux-vm-2"),
+            sample_vm("mux-vm-3"),
+        ];
+        let (a, b, c) = tokio::join!(
+            register(&api, &vms[0]),
+            register(&api, &vms[1]),
+            register(&api, &vms[2]),
+        );
+        assert_eq!([a.status(), b.status(), c.status()], [200; 3]);
+        let list = || request().method("GET").path("/list").reply(&api);
+        let status = || request().method("GET").path("/status/mux-vm-2").reply(&api);
+        let (a, b, c, d) = tokio::join!(list(), status(), list(), status());
+        for response in [a, b, c, d] {
+            assert_eq!(response.status(), 200);
+        }
+
+        let client_id = || async {
+            let mut con = ctx.state.connection().await.unwrap();
+            redis::cmd("CLIENT")
+                .arg("ID")
+                .query_async::<_, i64>(&mut con)
+                .await
+                .unwrap()
+        };
+        let (first, second) = tokio::join!(client_id(), client_id());
+        assert_eq!(first, second);
+    }
+}
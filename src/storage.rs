@@ -0,0 +1,1529 @@
+//! Redis layout:
+//!
+//! * `ghaf:vm:{name}` — the VM record as JSON, encrypted when an encryption
+//!   key is configured (see `crypto`). Index keys stay plaintext.
+//! * `ghaf:state:{status}` — set of VM names currently in `status`.
+//! * `ghaf:runtype:{run_type}` — set of VM names of a run type, e.g.
+//!   `ghaf:runtype:one-shot`.
+//! * `ghaf:sealed-vms` — set of the names of sealed VMs.
+//! * `ghaf:owner:{identity}` — set of VM names owned by an identity.
+//! * `ghaf:image:{id}` — set of VM names running a NixOS image.
+//! * `ghaf:contact:{email}` — set of VM names with a contact email,
+//!   lowercased.
+//! * `ghaf:hostname-index` — hash of VM hostnames, lowercased, to the one
+//!   VM that declares each.
+//! * `ghaf:ip:{ip}` / `ghaf:vsock:{vsock}` — sets of VM names declaring an
+//!   address.
+//! * `ghaf:capability:{cap}` — set of VM names providing a capability.
+//! * `ghaf:known-capabilities` — set of every capability a VM has ever
+//!   provided; unlike the index, it keeps capabilities whose providers are
+//!   all gone.
+//! * `ghaf:tag:{tag}` — set of VM names carrying a tag.
+//! * `ghaf:label-index:{key}:{value}` — set of VM names carrying a label.
+//! * `ghaf:mime:{type}` — set of VM names that handle a MIME type.
+//! * `ghaf:mime-index` — hash routing each MIME type to the one VM that opens
+//!   it. The first VM to claim a type keeps the route until it drops the
+//!   type; the route then moves to another handler, if any.
+//! * `ghaf:ports:{name}` — hash of the VM's port mappings keyed by host port.
+//! * `ghaf:volumes:{name}` — hash of the VM's storage volumes keyed by
+//!   volume id.
+//! * `ghaf:display:{name}` — the VM's display configuration as JSON.
+//! * `ghaf:audio:{name}` — the VM's audio devices as JSON.
+//! * `ghaf:certs:{name}` — the VM's TLS `CertBundle` as JSON, with the
+//!   private key encrypted like VM records.
+//! * `ghaf:namespace-count:{namespace}` — number of VMs in a namespace,
+//!   claimed against the namespace quota before a VM enters it.
+//! * `ghaf:idempotency:{scope}:{key}` — cached response of a POST request
+//!   sent with an `Idempotency-Key` header; expires after 24 hours. `scope`
+//!   identifies the caller's credentials, so keys are never shared between
+//!   callers.
+//! * `ghaf:stats:{name}` / `ghaf:stats-history:{name}` — hash of the VM's
+//!   current statistics and list of earlier samples. The hash's
+//!   `timestamp` field, RFC 3339 or Unix seconds, is when they were taken.
+//! * `ghaf:audit:{name}` — sorted set of a VM's audit log entries scored by
+//!   their Unix timestamp; trimmed by `audit::compact`.
+//! * `ghaf:vm-events:{name}` — stream of the VM's record changes (see
+//!   `events::VmEvent`), encrypted like the record; capped at about
+//!   `VM_EVENTS_MAXLEN` entries and kept after the VM is unregistered.
+//!   Each entry is also announced on `events::VM_CHANGES_CHANNEL`.
+//! * `ghaf:annotations:{name}` — sorted set of the operators' `Annotation`s
+//!   on a VM as JSON, scored by their Unix time in milliseconds.
+//! * `ghaf:log:{name}` — list of the `LogEntry` lines pushed by the VM's
+//!   agent as JSON, oldest first; capped at `VM_LOG_MAXLEN` entries.
+//! * `ghaf:events:timeline` — sorted set of the notifications published on
+//!   the event channels as JSON, scored by their Unix time in milliseconds;
+//!   capped at `events::TIMELINE_MAXLEN` entries.
+//! * `ghaf:mailbox:{name}` — list of JSON notifications queued for a VM's
+//!   agent to poll; expires 24 hours after the last push.
+//! * `ghaf:scheduled-{action}:{name}` — JSON RFC 3339 time at which a
+//!   `ScheduledAction` (`stop` or `restart`) is to be applied to the VM;
+//!   expires an hour after that time.
+//! * `ghaf:scheduled-{action}s` — sorted set of VM names with a pending
+//!   action, scored by its Unix timestamp; polled by `scheduler`.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::error::RegistryError;
+use crate::events::{self, VmEvent, VmEventKind};
+use crate::migration;
+use crate::models::{
+    Annotation, AudioConfig, CertBundle, DisplayConfig, LogEntry, PortMapping, RunType,
+    ScheduledAction, VMStatus, Volume, VM,
+};
+use crate::state::RedisConnection;
+
+pub const VM_KEY_PREFIX: &str = "ghaf:vm:";
+
+pub fn vm_key(name: &str) -> String {
+    format!("{}{}", VM_KEY_PREFIX, name)
+}
+
+pub fn state_key(status: VMStatus) -> String {
+    format!("ghaf:state:{}", status.as_str())
+}
+
+pub fn run_type_key(run_type: RunType) -> String {
+    format!("ghaf:runtype:{}", run_type.as_str())
+}
+
+pub const SEALED_VMS_KEY: &str = "ghaf:sealed-vms";
+
+pub fn owner_key(identity: &str) -> String {
+    format!("ghaf:owner:{}", identity)
+}
+
+pub fn image_key(id: &str) -> String {
+    format!("ghaf:image:{}", id)
+}
+
+pub fn contact_key(email: &str) -> String {
+    format!("ghaf:contact:{}", email.to_lowercase())
+}
+
+pub const HOSTNAME_INDEX_KEY: &str = "ghaf:hostname-index";
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ghaf:ip:{}", ip)
+}
+
+pub fn vsock_key(vsock: &str) -> String {
+    format!("ghaf:vsock:{}", vsock)
+}
+
+pub const PORTS_KEY_PREFIX: &str = "ghaf:ports:";
+
+pub fn ports_key(name: &str) -> String {
+    format!("{}{}", PORTS_KEY_PREFIX, name)
+}
+
+pub fn volumes_key(name: &str) -> String {
+    format!("ghaf:volumes:{}", name)
+}
+
+pub fn display_key(name: &str) -> String {
+    format!("ghaf:display:{}", name)
+}
+
+pub const AUDIO_KEY_PREFIX: &str = "ghaf:audio:";
+
+pub fn audio_key(name: &str) -> String {
+    format!("{}{}", AUDIO_KEY_PREFIX, name)
+}
+
+pub const CERTS_KEY_PREFIX: &str = "ghaf:certs:";
+
+pub fn certs_key(name: &str) -> String {
+    format!("{}{}", CERTS_KEY_PREFIX, name)
+}
+
+pub fn namespace_count_key(namespace: &str) -> String {
+    format!("ghaf:namespace-count:{}", namespace)
+}
+
+/// Reads, compares and increments a namespace counter in one step.
+const QUOTA_INCR_LUA: &str = include_str!("../scripts/quota_incr.lua");
+
+/// Sets a key only if it still holds the value read earlier.
+const COMPARE_AND_SET_LUA: &str = include_str!("../scripts/compare_and_set.lua");
+
+/// Claims a hostname unless another VM holds it.
+const HOSTNAME_CLAIM_LUA: &str = include_str!("../scripts/hostname_claim.lua");
+
+/// Releases a hostname only if the VM releasing it holds it.
+const HOSTNAME_RELEASE_LUA: &str = include_str!("../scripts/hostname_release.lua");
+
+pub fn idempotency_key(scope: &str, key: &str) -> String {
+    format!("ghaf:idempotency:{}:{}", scope, key)
+}
+
+pub fn stats_key(name: &str) -> String {
+    format!("ghaf:stats:{}", name)
+}
+
+pub fn stats_history_key(name: &str) -> String {
+    format!("ghaf:stats-history:{}", name)
+}
+
+pub fn annotations_key(name: &str) -> String {
+    format!("ghaf:annotations:{}", name)
+}
+
+pub fn log_key(name: &str) -> String {
+    format!("ghaf:log:{}", name)
+}
+
+/// Log lines kept per VM.
+pub const VM_LOG_MAXLEN: isize = 10_000;
+
+pub const AUDIT_KEY_PREFIX: &str = "ghaf:audit:";
+
+pub fn audit_key(name: &str) -> String {
+    format!("{}{}", AUDIT_KEY_PREFIX, name)
+}
+
+pub fn vm_events_key(name: &str) -> String {
+    format!("ghaf:vm-events:{}", name)
+}
+
+/// Approximate number of events kept per VM.
+const VM_EVENTS_MAXLEN: usize = 1000;
+
+pub fn mailbox_key(name: &str) -> String {
+    format!("ghaf:mailbox:{}", name)
+}
+
+/// Undelivered notifications expire 24 hours after the last one was queued.
+const MAILBOX_TTL_SECS: usize = 24 * 60 * 60;
+
+pub fn scheduled_key(action: ScheduledAction, name: &str) -> String {
+    format!("ghaf:scheduled-{}:{}", action.as_str(), name)
+}
+
+pub fn schedule_queue_key(action: ScheduledAction) -> String {
+    format!("ghaf:scheduled-{}s", action.as_str())
+}
+
+/// A due schedule stays readable for an hour, until the scheduler has
+/// carried it out.
+const SCHEDULE_GRACE_SECS: i64 = 60 * 60;
+
+pub const CAPABILITY_KEY_PREFIX: &str = "ghaf:capability:";
+
+pub fn capability_key(capability: &str) -> String {
+    format!("{}{}", CAPABILITY_KEY_PREFIX, capability)
+}
+
+pub const KNOWN_CAPABILITIES_KEY: &str = "ghaf:known-capabilities";
+
+pub fn tag_key(tag: &str) -> String {
+    format!("ghaf:tag:{}", tag)
+}
+
+pub fn label_key(key: &str, value: &str) -> String {
+    format!("ghaf:label-index:{}:{}", key, value)
+}
+
+pub const MIME_INDEX_KEY: &str = "ghaf:mime-index";
+
+pub fn mime_key(mime_type: &str) -> String {
+    format!("ghaf:mime:{}", mime_type)
+}
+
+/// Queues removal of every index entry that points at `vm`. MIME routes are
+/// left to `repair_mime_routes`, which needs to read the handler sets.
+fn unindex_vm(pipe: &mut redis::Pipeline, vm: &VM) {
+    pipe.srem(state_key(vm.status), &vm.name).ignore();
+    pipe.srem(run_type_key(vm.vm_type.run_type), &vm.name)
+        .ignore();
+    pipe.srem(SEALED_VMS_KEY, &vm.name).ignore();
+    if let Some(owner) = &vm.owner {
+        pipe.srem(owner_key(owner), &vm.name).ignore();
+    }
+    if let Some(image) = &vm.image {
+        pipe.srem(image_key(&image.id), &vm.name).ignore();
+    }
+    if let Some(contact) = &vm.contact {
+        pipe.srem(contact_key(&contact.email), &vm.name).ignore();
+    }
+    if let Some(hostname) = &vm.hostname {
+        pipe.cmd("EVAL")
+            .arg(HOSTNAME_RELEASE_LUA)
+            .arg(1)
+            .arg(HOSTNAME_INDEX_KEY)
+            .arg(hostname.to_lowercase())
+            .arg(&vm.name)
+            .ignore();
+    }
+    pipe.srem(ip_key(&vm.addresses.ip), &vm.name).ignore();
+    pipe.srem(vsock_key(&vm.addresses.vsock), &vm.name).ignore();
+    for capability in &vm.capabilities {
+        pipe.srem(capability_key(capability), &vm.name).ignore();
+    }
+    for tag in &vm.tags {
+        pipe.srem(tag_key(tag), &vm.name).ignore();
+    }
+    for (key, value) in &vm.labels {
+        pipe.srem(label_key(key, value), &vm.name).ignore();
+    }
+    for mime_type in &vm.mime_types {
+        pipe.srem(mime_key(mime_type), &vm.name).ignore();
+    }
+}
+
+/// Queues creation of every index entry that points at `vm`.
+fn index_vm(pipe: &mut redis::Pipeline, vm: &VM) {
+    pipe.sadd(state_key(vm.status), &vm.name).ignore();
+    pipe.sadd(run_type_key(vm.vm_type.run_type), &vm.name)
+        .ignore();
+    if vm.sealed {
+        pipe.sadd(SEALED_VMS_KEY, &vm.name).ignore();
+    }
+    if let Some(owner) = &vm.owner {
+        pipe.sadd(owner_key(owner), &vm.name).ignore();
+    }
+    if let Some(image) = &vm.image {
+        pipe.sadd(image_key(&image.id), &vm.name).ignore();
+    }
+    if let Some(contact) = &vm.contact {
+        pipe.sadd(contact_key(&contact.email), &vm.name).ignore();
+    }
+    if let Some(hostname) = &vm.hostname {
+        pipe.hset_nx(HOSTNAME_INDEX_KEY, hostname.to_lowercase(), &vm.name)
+            .ignore();
+    }
+    pipe.sadd(ip_key(&vm.addresses.ip), &vm.name).ignore();
+    pipe.sadd(vsock_key(&vm.addresses.vsock), &vm.name).ignore();
+    for capability in &vm.capabilities {
+        pipe.sadd(capability_key(capability), &vm.name).ignore();
+        pipe.sadd(KNOWN_CAPABILITIES_KEY, capability).ignore();
+    }
+    for tag in &vm.tags {
+        pipe.sadd(tag_key(tag), &vm.name).ignore();
+    }
+    for (key, value) in &vm.labels {
+        pipe.sadd(label_key(key, value), &vm.name).ignore();
+    }
+    for mime_type in &vm.mime_types {
+        pipe.sadd(mime_key(mime_type), &vm.name).ignore();
+        pipe.hset_nx(MIME_INDEX_KEY, mime_type, &vm.name).ignore();
+    }
+}
+
+/// Key prefixes of the indexes `reindex` rebuilds, by index name. The MIME
+/// routes in `MIME_INDEX_KEY` are rebuilt as well.
+const INDEXES: &[(&str, &str)] = &[
+    ("state", "ghaf:state:"),
+    ("runtype", "ghaf:runtype:"),
+    ("sealed", SEALED_VMS_KEY),
+    ("owner", "ghaf:owner:"),
+    ("image", "ghaf:image:"),
+    ("contact", "ghaf:contact:"),
+    ("hostname", HOSTNAME_INDEX_KEY),
+    ("ip", "ghaf:ip:"),
+    ("vsock", "ghaf:vsock:"),
+    ("capability", CAPABILITY_KEY_PREFIX),
+    ("tag", "ghaf:tag:"),
+    ("label", "ghaf:label-index:"),
+    ("mime", "ghaf:mime:"),
+    ("namespace-count", "ghaf:namespace-count:"),
+];
+
+/// Names of the indexes rebuilt by `reindex`.
+pub fn index_names() -> Vec<&'static str> {
+    let mut names: Vec<&str> = INDEXES.iter().map(|(name, _)| *name).collect();
+    names.push("mime-index");
+    names
+}
+
+/// Drops every index and rebuilds it from the VM records in one
+/// transaction, so readers never see a partial index. MIME routes whose VM
+/// still handles the type are kept; the rest are assigned as on
+/// registration. Returns the number of VMs indexed.
+pub async fn reindex(con: &mut RedisConnection) -> Result<usize, RegistryError> {
+    let vms = list_vms(con).await?;
+    let routes: BTreeMap<String, String> = con.hgetall(MIME_INDEX_KEY).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (_, prefix) in INDEXES {
+        for suffix in scan_names(con, prefix).await? {
+            pipe.del(format!("{}{}", prefix, suffix)).ignore();
+        }
+    }
+    pipe.del(MIME_INDEX_KEY).ignore();
+    for (mime_type, name) in &routes {
+        if vms
+            .iter()
+            .any(|vm| vm.name == *name && vm.mime_types.contains(mime_type))
+        {
+            pipe.hset(MIME_INDEX_KEY, mime_type, name).ignore();
+        }
+    }
+    let mut namespace_counts: BTreeMap<&str, u32> = BTreeMap::new();
+    for vm in &vms {
+        index_vm(&mut pipe, vm);
+        *namespace_counts.entry(&vm.namespace).or_default() += 1;
+    }
+    for (namespace, count) in namespace_counts {
+        pipe.set(namespace_count_key(namespace), count).ignore();
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    Ok(vms.len())
+}
+
+/// Moves the routes of `dropped` MIME types that still point at `name` to
+/// another handler, or removes them when no handler is left.
+async fn repair_mime_routes(
+    con: &mut RedisConnection,
+    name: &str,
+    dropped: &[&String],
+) -> Result<(), RegistryError> {
+    for &mime_type in dropped {
+        let route: Option<String> = con.hget(MIME_INDEX_KEY, mime_type).await?;
+        if route.as_deref() != Some(name) {
+            continue;
+        }
+        let mut handlers: Vec<String> = con.smembers(mime_key(mime_type)).await?;
+        handlers.sort();
+        match handlers.first() {
+            Some(next) => {
+                con.hset::<_, _, _, ()>(MIME_INDEX_KEY, mime_type, next)
+                    .await?
+            }
+            None => con.hdel::<_, _, ()>(MIME_INDEX_KEY, mime_type).await?,
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_vm(con: &mut RedisConnection, name: &str) -> Result<Option<VM>, RegistryError> {
+    let raw: Option<String> = con.get(vm_key(name)).await?;
+    match raw {
+        Some(raw) => Ok(Some(load_vm(con, name, raw).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Claims `vm.name` by writing its record unless one exists, and returns
+/// whether the claim succeeded. `save_vm` completes the registration; if it
+/// is not saved after all, `release_vm_name` gives the name back.
+pub async fn claim_vm_name(con: &mut RedisConnection, vm: &VM) -> Result<bool, RegistryError> {
+    let key = vm_key(&vm.name);
+    let record = con.encode_record(serde_json::to_string(vm)?, &key)?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(&key)
+        .arg(record)
+        .arg("NX")
+        .query_async(con)
+        .await?;
+    Ok(claimed.is_some())
+}
+
+/// Releases a name claimed by `claim_vm_name` whose VM was never saved.
+pub async fn release_vm_name(con: &mut RedisConnection, name: &str) -> Result<(), RegistryError> {
+    con.del::<_, ()>(vm_key(name)).await?;
+    Ok(())
+}
+
+/// Parses a stored VM record, migrating records written under an older
+/// schema. A migrated record is written back unless it changed meanwhile;
+/// the check runs in a script since the connection is shared and cannot
+/// `WATCH`.
+async fn load_vm(con: &mut RedisConnection, name: &str, raw: String) -> Result<VM, RegistryError> {
+    let key = vm_key(name);
+    let mut record: serde_json::Value =
+        serde_json::from_str(&con.decode_record(raw.clone(), &key)?)?;
+    if !migration::migrate(&mut record)? {
+        return Ok(serde_json::from_value(record)?);
+    }
+    let vm: VM = serde_json::from_value(record)?;
+    let upgraded = con.encode_record(serde_json::to_string(&vm)?, &key)?;
+    redis::Script::new(COMPARE_AND_SET_LUA)
+        .key(key)
+        .arg(raw)
+        .arg(upgraded)
+        .invoke_async::<_, i32>(con)
+        .await?;
+    Ok(vm)
+}
+
+pub async fn require_vm(con: &mut RedisConnection, name: &str) -> Result<VM, RegistryError> {
+    get_vm(con, name)
+        .await?
+        .ok_or_else(|| RegistryError::NotFound(name.to_string()))
+}
+
+/// Like `require_vm`, but fails with `Locked` when the VM is sealed.
+pub async fn require_unsealed_vm(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<VM, RegistryError> {
+    let vm = require_vm(con, name).await?;
+    if vm.sealed {
+        return Err(RegistryError::Locked(vm.name));
+    }
+    Ok(vm)
+}
+
+/// Queues appending `kind` to the history of `vm` and announcing it on
+/// [`events::VM_CHANGES_CHANNEL`] and in the event timeline.
+fn record_event(
+    con: &RedisConnection,
+    pipe: &mut redis::Pipeline,
+    vm: &VM,
+    kind: VmEventKind,
+) -> Result<(), RegistryError> {
+    events::queue_publish(
+        pipe,
+        events::VM_CHANGES_CHANNEL,
+        &events::notification(kind.name(), vm),
+    )?;
+    pipe.ignore();
+    let name = &vm.name;
+    let event = VmEvent {
+        timestamp: Utc::now(),
+        kind,
+    };
+    let payload = con.encode_record(serde_json::to_string(&event)?, &vm_events_key(name))?;
+    pipe.cmd("XADD")
+        .arg(vm_events_key(name))
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(VM_EVENTS_MAXLEN)
+        .arg("*")
+        .arg("event")
+        .arg(payload)
+        .ignore();
+    Ok(())
+}
+
+/// The history event for replacing `previous` with `vm`, if anything but
+/// heartbeats and timestamps changed.
+fn change_event(vm: &VM, previous: Option<&VM>) -> Result<Option<VmEventKind>, RegistryError> {
+    let Some(previous) = previous else {
+        return Ok(Some(VmEventKind::Registered { vm: vm.clone() }));
+    };
+    if vm.config_fields()? != previous.config_fields()? {
+        Ok(Some(VmEventKind::Updated { vm: vm.clone() }))
+    } else if vm.status != previous.status {
+        Ok(Some(VmEventKind::StatusChanged { status: vm.status }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Stamps `vm.updated_at` and the schema version, and writes `vm`, its
+/// index entries and its history event in one transaction. `previous` is
+/// the record being replaced, if any, so its stale index entries are
+/// dropped.
+pub async fn save_vm(
+    con: &mut RedisConnection,
+    vm: &mut VM,
+    previous: Option<&VM>,
+) -> Result<(), RegistryError> {
+    vm.updated_at = Some(Utc::now());
+    vm.schema_version = migration::SCHEMA_VERSION;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some(previous) = previous {
+        unindex_vm(&mut pipe, previous);
+    }
+    let record = con.encode_record(serde_json::to_string(vm)?, &vm_key(&vm.name))?;
+    pipe.set(vm_key(&vm.name), record).ignore();
+    index_vm(&mut pipe, vm);
+    let event = change_event(vm, previous)?;
+    let published = event.is_some();
+    if let Some(event) = event {
+        record_event(con, &mut pipe, vm, event)?;
+    }
+    if let Some(previous) = previous.filter(|previous| previous.namespace != vm.namespace) {
+        pipe.decr(namespace_count_key(&previous.namespace), 1)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    if published {
+        con.count_published(1);
+    }
+    tracing::debug!(vm = %vm.name, status = vm.status.as_str(), "saved VM record");
+    if let Some(previous) = previous {
+        let dropped: Vec<&String> = previous
+            .mime_types
+            .iter()
+            .filter(|mime_type| !vm.mime_types.contains(mime_type))
+            .collect();
+        repair_mime_routes(con, &vm.name, &dropped).await?;
+    }
+    Ok(())
+}
+
+pub async fn delete_vm(con: &mut RedisConnection, vm: &VM) -> Result<(), RegistryError> {
+    delete_vms(con, std::slice::from_ref(vm)).await
+}
+
+/// Deletes all of `vms` and their index entries in one transaction.
+pub async fn delete_vms(con: &mut RedisConnection, vms: &[VM]) -> Result<(), RegistryError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for vm in vms {
+        unindex_vm(&mut pipe, vm);
+        pipe.del(vm_key(&vm.name)).ignore();
+        pipe.decr(namespace_count_key(&vm.namespace), 1).ignore();
+        pipe.del(ports_key(&vm.name)).ignore();
+        pipe.del(volumes_key(&vm.name)).ignore();
+        pipe.del(display_key(&vm.name)).ignore();
+        pipe.del(audio_key(&vm.name)).ignore();
+        pipe.del(certs_key(&vm.name)).ignore();
+        pipe.del(stats_key(&vm.name)).ignore();
+        pipe.del(stats_history_key(&vm.name)).ignore();
+        pipe.del(log_key(&vm.name)).ignore();
+        pipe.del(annotations_key(&vm.name)).ignore();
+        pipe.del(mailbox_key(&vm.name)).ignore();
+        for action in ScheduledAction::ALL {
+            pipe.del(scheduled_key(action, &vm.name)).ignore();
+            pipe.zrem(schedule_queue_key(action), &vm.name).ignore();
+        }
+        record_event(con, &mut pipe, vm, VmEventKind::Unregistered)?;
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    con.count_published(vms.len() as u64);
+    for vm in vms {
+        let dropped: Vec<&String> = vm.mime_types.iter().collect();
+        repair_mime_routes(con, &vm.name, &dropped).await?;
+    }
+    Ok(())
+}
+
+/// The VM that declares `hostname`, ignoring case.
+pub async fn hostname_holder(
+    con: &mut RedisConnection,
+    hostname: &str,
+) -> Result<Option<String>, RegistryError> {
+    Ok(con
+        .hget(HOSTNAME_INDEX_KEY, hostname.to_lowercase())
+        .await?)
+}
+
+/// Claims `hostname` for VM `name` unless another VM holds it, and returns
+/// the VM holding it afterwards. Saving the VM keeps the claim; if it is
+/// not saved after all, `release_hostname` gives the claim back.
+pub async fn claim_hostname(
+    con: &mut RedisConnection,
+    hostname: &str,
+    name: &str,
+) -> Result<String, RegistryError> {
+    Ok(redis::Script::new(HOSTNAME_CLAIM_LUA)
+        .key(HOSTNAME_INDEX_KEY)
+        .arg(hostname.to_lowercase())
+        .arg(name)
+        .invoke_async(con)
+        .await?)
+}
+
+/// Releases `hostname` if VM `name` holds it.
+pub async fn release_hostname(
+    con: &mut RedisConnection,
+    hostname: &str,
+    name: &str,
+) -> Result<(), RegistryError> {
+    redis::Script::new(HOSTNAME_RELEASE_LUA)
+        .key(HOSTNAME_INDEX_KEY)
+        .arg(hostname.to_lowercase())
+        .arg(name)
+        .invoke_async::<_, i32>(con)
+        .await?;
+    Ok(())
+}
+
+/// Counts one more VM in `namespace`, unless that would exceed `quota`.
+/// Returns whether the slot was claimed. The slot is released again when
+/// the VM is deleted or moves to another namespace.
+pub async fn claim_namespace_slot(
+    con: &mut RedisConnection,
+    namespace: &str,
+    quota: Option<u32>,
+) -> Result<bool, RegistryError> {
+    let key = namespace_count_key(namespace);
+    match quota {
+        Some(quota) => {
+            let claimed: i32 = redis::Script::new(QUOTA_INCR_LUA)
+                .key(key)
+                .arg(quota)
+                .invoke_async(con)
+                .await?;
+            Ok(claimed == 1)
+        }
+        None => {
+            con.incr::<_, _, ()>(key, 1).await?;
+            Ok(true)
+        }
+    }
+}
+
+pub async fn release_namespace_slot(
+    con: &mut RedisConnection,
+    namespace: &str,
+) -> Result<(), RegistryError> {
+    con.decr::<_, _, ()>(namespace_count_key(namespace), 1)
+        .await?;
+    Ok(())
+}
+
+/// Sets the status of VM `name`, recording starts and stops in its audit
+/// log.
+pub async fn set_status(
+    con: &mut RedisConnection,
+    name: &str,
+    status: VMStatus,
+) -> Result<VM, RegistryError> {
+    let previous = require_vm(con, name).await?;
+    let mut vm = previous.clone();
+    vm.status = status;
+    save_vm(con, &mut vm, Some(&previous)).await?;
+    audit::record_audit_event(con, name, None, &previous, &vm).await?;
+    Ok(vm)
+}
+
+/// Seals or unseals VM `name`.
+pub async fn set_sealed(
+    con: &mut RedisConnection,
+    name: &str,
+    sealed: bool,
+) -> Result<VM, RegistryError> {
+    let previous = require_vm(con, name).await?;
+    let mut vm = previous.clone();
+    vm.sealed = sealed;
+    save_vm(con, &mut vm, Some(&previous)).await?;
+    Ok(vm)
+}
+
+/// Hands VM `name` over to `owner`.
+pub async fn set_owner(
+    con: &mut RedisConnection,
+    name: &str,
+    owner: &str,
+) -> Result<VM, RegistryError> {
+    let previous = require_vm(con, name).await?;
+    let mut vm = previous.clone();
+    vm.owner = Some(owner.to_string());
+    save_vm(con, &mut vm, Some(&previous)).await?;
+    Ok(vm)
+}
+
+/// Records a heartbeat from the VM's agent.
+pub async fn record_heartbeat(con: &mut RedisConnection, name: &str) -> Result<VM, RegistryError> {
+    let previous = require_vm(con, name).await?;
+    let mut vm = previous.clone();
+    vm.last_heartbeat_at = Some(Utc::now());
+    save_vm(con, &mut vm, Some(&previous)).await?;
+    Ok(vm)
+}
+
+/// Running VMs that have not sent a heartbeat for more than `threshold`;
+/// templates are never stale.
+/// VMs that never sent one are judged by when they were last written, which
+/// for a running VM is when it was started.
+pub async fn list_stale_vms(
+    con: &mut RedisConnection,
+    threshold: Duration,
+) -> Result<Vec<VM>, RegistryError> {
+    let cutoff = Utc::now() - threshold;
+    let mut vms = list_vms(con).await?;
+    vms.retain(|vm| {
+        vm.status == VMStatus::Running
+            && !vm.is_template
+            && vm
+                .last_heartbeat_at
+                .or(vm.updated_at)
+                .is_some_and(|seen| seen < cutoff)
+    });
+    Ok(vms)
+}
+
+/// When the current statistics of VM `name` were taken, if they are
+/// timestamped in a format the registry understands.
+pub async fn stats_timestamp(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Option<DateTime<Utc>>, RegistryError> {
+    let raw: Option<String> = con.hget(stats_key(name), "timestamp").await?;
+    Ok(raw.and_then(|raw| {
+        DateTime::parse_from_rfc3339(&raw)
+            .map(|at| at.with_timezone(&Utc))
+            .ok()
+            .or_else(|| DateTime::from_timestamp(raw.parse().ok()?, 0))
+    }))
+}
+
+/// Drops the current statistics of VM `name` and their history.
+pub async fn clear_stats(con: &mut RedisConnection, name: &str) -> Result<(), RegistryError> {
+    con.del::<_, ()>(&[stats_key(name), stats_history_key(name)])
+        .await?;
+    Ok(())
+}
+
+/// Appends `entry` to the audit log of VM `name`, scored by the current
+/// time. Entries quote old and new field values, so they are encrypted
+/// like VM records.
+pub async fn append_audit_entry(
+    con: &mut RedisConnection,
+    name: &str,
+    entry: &serde_json::Value,
+) -> Result<(), RegistryError> {
+    let key = audit_key(name);
+    let entry = con.encode_record(entry.to_string(), &key)?;
+    con.zadd::<_, _, _, ()>(key, entry, Utc::now().timestamp())
+        .await?;
+    Ok(())
+}
+
+/// The whole audit log of VM `name`, oldest first.
+pub async fn audit_entries(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Vec<String>, RegistryError> {
+    recent_audit_entries(con, name, 0).await
+}
+
+/// The last `limit` entries of the audit log of VM `name`, oldest first;
+/// all of them when `limit` is 0.
+pub async fn recent_audit_entries(
+    con: &mut RedisConnection,
+    name: &str,
+    limit: isize,
+) -> Result<Vec<String>, RegistryError> {
+    let key = audit_key(name);
+    let entries: Vec<String> = con.zrange(&key, -limit, -1).await?;
+    entries
+        .into_iter()
+        .map(|entry| con.decode_record(entry, &key))
+        .collect()
+}
+
+/// The last `limit` samples of the statistics history of VM `name`, oldest
+/// first.
+pub async fn recent_stats(
+    con: &mut RedisConnection,
+    name: &str,
+    limit: isize,
+) -> Result<Vec<String>, RegistryError> {
+    Ok(con.lrange(stats_history_key(name), -limit, -1).await?)
+}
+
+/// The recorded history of VM `name`, oldest first.
+pub async fn list_vm_events(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Vec<VmEvent>, RegistryError> {
+    let key = vm_events_key(name);
+    let entries: Vec<(String, Vec<(String, String)>)> = redis::cmd("XRANGE")
+        .arg(&key)
+        .arg("-")
+        .arg("+")
+        .query_async(con)
+        .await?;
+    let mut events = Vec::with_capacity(entries.len());
+    for (_, fields) in entries {
+        for (_, payload) in fields.into_iter().filter(|(field, _)| field == "event") {
+            events.push(serde_json::from_str(&con.decode_record(payload, &key)?)?);
+        }
+    }
+    Ok(events)
+}
+
+/// Seconds until the VM key expires, or `None` when it has no TTL.
+pub async fn vm_ttl(con: &mut RedisConnection, name: &str) -> Result<Option<u64>, RegistryError> {
+    let ttl: i64 = con.ttl(vm_key(name)).await?;
+    Ok(u64::try_from(ttl).ok())
+}
+
+pub async fn add_annotation(
+    con: &mut RedisConnection,
+    name: &str,
+    annotation: &Annotation,
+) -> Result<(), RegistryError> {
+    con.zadd::<_, _, _, ()>(
+        annotations_key(name),
+        serde_json::to_string(annotation)?,
+        annotation.timestamp.timestamp_millis(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The last `limit` annotations of VM `name`, oldest first.
+pub async fn recent_annotations(
+    con: &mut RedisConnection,
+    name: &str,
+    limit: isize,
+) -> Result<Vec<Annotation>, RegistryError> {
+    let raw: Vec<String> = con.zrange(annotations_key(name), -limit, -1).await?;
+    raw.iter()
+        .map(|annotation| Ok(serde_json::from_str(annotation)?))
+        .collect()
+}
+
+/// Appends `entry` to the log of VM `name`, dropping the oldest lines
+/// beyond `VM_LOG_MAXLEN`.
+pub async fn append_log(
+    con: &mut RedisConnection,
+    name: &str,
+    entry: &LogEntry,
+) -> Result<(), RegistryError> {
+    let key = log_key(name);
+    redis::pipe()
+        .atomic()
+        .rpush(&key, serde_json::to_string(entry)?)
+        .ignore()
+        .ltrim(&key, -VM_LOG_MAXLEN, -1)
+        .ignore()
+        .query_async::<_, ()>(con)
+        .await?;
+    Ok(())
+}
+
+/// Every line of the log of VM `name`, oldest first.
+pub async fn get_log(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Vec<LogEntry>, RegistryError> {
+    let raw: Vec<String> = con.lrange(log_key(name), 0, -1).await?;
+    raw.iter()
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+pub async fn clear_log(con: &mut RedisConnection, name: &str) -> Result<(), RegistryError> {
+    con.del::<_, ()>(log_key(name)).await?;
+    Ok(())
+}
+
+/// Queues `message` in the mailbox of VM `name` and renews its expiry.
+pub async fn push_notification(
+    con: &mut RedisConnection,
+    name: &str,
+    message: &serde_json::Value,
+) -> Result<(), RegistryError> {
+    let key = mailbox_key(name);
+    redis::pipe()
+        .atomic()
+        .rpush(&key, message.to_string())
+        .ignore()
+        .expire(&key, MAILBOX_TTL_SECS)
+        .ignore()
+        .query_async::<_, ()>(con)
+        .await?;
+    Ok(())
+}
+
+/// Removes and returns every notification queued for VM `name`, oldest
+/// first.
+pub async fn take_notifications(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Vec<serde_json::Value>, RegistryError> {
+    let key = mailbox_key(name);
+    let (raw,): (Vec<String>,) = redis::pipe()
+        .atomic()
+        .lrange(&key, 0, -1)
+        .ltrim(&key, 1, 0)
+        .ignore()
+        .query_async(con)
+        .await?;
+    raw.iter()
+        .map(|message| Ok(serde_json::from_str(message)?))
+        .collect()
+}
+
+/// Schedules `action` for VM `name` at `at`, replacing any earlier
+/// schedule of the same action.
+pub async fn schedule_action(
+    con: &mut RedisConnection,
+    action: ScheduledAction,
+    name: &str,
+    at: DateTime<Utc>,
+) -> Result<(), RegistryError> {
+    let ttl = (at - Utc::now()).num_seconds().max(0) + SCHEDULE_GRACE_SECS;
+    redis::pipe()
+        .atomic()
+        .set_ex(
+            scheduled_key(action, name),
+            serde_json::to_string(&at)?,
+            ttl as usize,
+        )
+        .ignore()
+        .zadd(schedule_queue_key(action), name, at.timestamp())
+        .ignore()
+        .query_async::<_, ()>(con)
+        .await?;
+    Ok(())
+}
+
+/// When `action` is scheduled for VM `name`, if it is.
+pub async fn get_scheduled_action(
+    con: &mut RedisConnection,
+    action: ScheduledAction,
+    name: &str,
+) -> Result<Option<DateTime<Utc>>, RegistryError> {
+    let raw: Option<String> = con.get(scheduled_key(action, name)).await?;
+    Ok(raw.map(|raw| serde_json::from_str(&raw)).transpose()?)
+}
+
+/// Drops the pending `action` of VM `name`. Returns whether one was
+/// scheduled.
+pub async fn cancel_scheduled_action(
+    con: &mut RedisConnection,
+    action: ScheduledAction,
+    name: &str,
+) -> Result<bool, RegistryError> {
+    let (removed,): (u32,) = redis::pipe()
+        .atomic()
+        .zrem(schedule_queue_key(action), name)
+        .del(scheduled_key(action, name))
+        .ignore()
+        .query_async(con)
+        .await?;
+    Ok(removed > 0)
+}
+
+/// Removes and returns the VMs whose scheduled `action` is due at `now`. A
+/// name is returned only to the caller that removed it, so concurrent
+/// schedulers never apply an action twice.
+pub async fn take_due_actions(
+    con: &mut RedisConnection,
+    action: ScheduledAction,
+    now: DateTime<Utc>,
+) -> Result<Vec<String>, RegistryError> {
+    let due: Vec<String> = con
+        .zrangebyscore(schedule_queue_key(action), "-inf", now.timestamp())
+        .await?;
+    let mut taken = Vec::new();
+    for name in due {
+        if cancel_scheduled_action(con, action, &name).await? {
+            taken.push(name);
+        }
+    }
+    Ok(taken)
+}
+
+/// Names (key suffixes) of all keys starting with `prefix`, sorted.
+pub async fn scan_names(
+    con: &mut RedisConnection,
+    prefix: &str,
+) -> Result<Vec<String>, RegistryError> {
+    let mut names = Vec::new();
+    {
+        let mut keys = con.scan_match::<_, String>(format!("{}*", prefix)).await?;
+        while let Some(key) = keys.next_item().await {
+            names.push(key[prefix.len()..].to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Deletes every `ghaf:*` key, leaving other keys in the database alone.
+/// Returns the number of keys deleted.
+pub async fn delete_all(con: &mut RedisConnection) -> Result<u64, RegistryError> {
+    let keys: Vec<String> = scan_names(con, "ghaf:")
+        .await?
+        .into_iter()
+        .map(|name| format!("ghaf:{}", name))
+        .collect();
+    let mut deleted = 0;
+    for chunk in keys.chunks(500) {
+        deleted += con.del::<_, u64>(chunk).await?;
+    }
+    Ok(deleted)
+}
+
+pub async fn list_vm_names(con: &mut RedisConnection) -> Result<Vec<String>, RegistryError> {
+    scan_names(con, VM_KEY_PREFIX).await
+}
+
+/// Fetches the named VMs in one `MGET`, skipping names with no record.
+pub async fn get_vms(
+    con: &mut RedisConnection,
+    names: &[String],
+) -> Result<Vec<VM>, RegistryError> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<String> = names.iter().map(|name| vm_key(name)).collect();
+    let raw: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query_async(con).await?;
+    let mut vms = Vec::new();
+    for (name, raw) in names.iter().zip(raw) {
+        if let Some(raw) = raw {
+            vms.push(load_vm(con, name, raw).await?);
+        }
+    }
+    Ok(vms)
+}
+
+/// Looks up each name with one pipelined `GET`, keeping the input order.
+pub async fn lookup_vms(
+    con: &mut RedisConnection,
+    names: &[String],
+) -> Result<Vec<Option<VM>>, RegistryError> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut pipe = redis::pipe();
+    for name in names {
+        pipe.get(vm_key(name));
+    }
+    let raw: Vec<Option<String>> = pipe.query_async(con).await?;
+    let mut vms = Vec::new();
+    for (name, raw) in names.iter().zip(raw) {
+        vms.push(match raw {
+            Some(raw) => Some(load_vm(con, name, raw).await?),
+            None => None,
+        });
+    }
+    Ok(vms)
+}
+
+/// VMs carrying `tag`, sorted by name.
+pub async fn list_tagged_vms(
+    con: &mut RedisConnection,
+    tag: &str,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(tag_key(tag)).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+/// Sealed VMs, sorted by name.
+pub async fn list_sealed_vms(con: &mut RedisConnection) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(SEALED_VMS_KEY).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+/// VMs owned by `identity`, sorted by name.
+pub async fn list_owned_vms(
+    con: &mut RedisConnection,
+    identity: &str,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(owner_key(identity)).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+/// VMs running the image with hash `id`, sorted by name.
+pub async fn list_vms_by_image(
+    con: &mut RedisConnection,
+    id: &str,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(image_key(id)).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+/// VMs whose contact has `email`, compared case-insensitively, sorted by
+/// name.
+pub async fn list_vms_by_contact(
+    con: &mut RedisConnection,
+    email: &str,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(contact_key(email)).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+/// VMs labelled `key: value`, sorted by name.
+pub async fn list_labeled_vms(
+    con: &mut RedisConnection,
+    key: &str,
+    value: &str,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(label_key(key, value)).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+/// One-shot VMs that are registered but have not been run, sorted by name;
+/// templates are left out.
+pub async fn list_pending_one_shot_vms(
+    con: &mut RedisConnection,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con
+        .sinter(&[
+            run_type_key(RunType::OneShot),
+            state_key(VMStatus::Registered),
+        ])
+        .await?;
+    names.sort();
+    let mut vms = get_vms(con, &names).await?;
+    vms.retain(|vm| !vm.is_template);
+    Ok(vms)
+}
+
+/// VMs currently in `status`, sorted by name.
+pub async fn list_vms_in_state(
+    con: &mut RedisConnection,
+    status: VMStatus,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut names: Vec<String> = con.smembers(state_key(status)).await?;
+    names.sort();
+    get_vms(con, &names).await
+}
+
+pub async fn list_templates(con: &mut RedisConnection) -> Result<Vec<VM>, RegistryError> {
+    let mut vms = list_vms(con).await?;
+    vms.retain(|vm| vm.is_template);
+    Ok(vms)
+}
+
+pub async fn list_vms(con: &mut RedisConnection) -> Result<Vec<VM>, RegistryError> {
+    let names = list_vm_names(con).await?;
+    get_vms(con, &names).await
+}
+
+pub async fn list_namespace_vms(
+    con: &mut RedisConnection,
+    namespace: &str,
+) -> Result<Vec<VM>, RegistryError> {
+    let mut vms = list_vms(con).await?;
+    vms.retain(|vm| vm.namespace == namespace);
+    Ok(vms)
+}
+
+pub async fn get_ports(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Vec<PortMapping>, RegistryError> {
+    let raw: Vec<String> = con.hvals(ports_key(name)).await?;
+    let mut ports = raw
+        .iter()
+        .map(|raw| serde_json::from_str(raw))
+        .collect::<Result<Vec<PortMapping>, _>>()?;
+    ports.sort_by_key(|port| port.host_port);
+    Ok(ports)
+}
+
+/// Adds `ports` to the VM's mappings, first dropping all existing ones when
+/// `replace` is set.
+pub async fn save_ports(
+    con: &mut RedisConnection,
+    name: &str,
+    ports: &[PortMapping],
+    replace: bool,
+) -> Result<(), RegistryError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if replace {
+        pipe.del(ports_key(name)).ignore();
+    }
+    for port in ports {
+        pipe.hset(
+            ports_key(name),
+            port.host_port,
+            serde_json::to_string(port)?,
+        )
+        .ignore();
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    Ok(())
+}
+
+/// Removes one mapping; returns whether it existed.
+pub async fn remove_port(
+    con: &mut RedisConnection,
+    name: &str,
+    host_port: u16,
+) -> Result<bool, RegistryError> {
+    let removed: u32 = con.hdel(ports_key(name), host_port).await?;
+    Ok(removed > 0)
+}
+
+/// Host ports claimed by each VM, keyed by VM name.
+pub async fn list_all_ports(
+    con: &mut RedisConnection,
+) -> Result<Vec<(String, Vec<PortMapping>)>, RegistryError> {
+    let names = scan_names(con, PORTS_KEY_PREFIX).await?;
+    let mut all = Vec::new();
+    for name in names {
+        let ports = get_ports(con, &name).await?;
+        all.push((name, ports));
+    }
+    Ok(all)
+}
+
+/// The VM's volumes, ordered by path.
+pub async fn get_volumes(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Vec<Volume>, RegistryError> {
+    let raw: Vec<String> = con.hvals(volumes_key(name)).await?;
+    let mut volumes = raw
+        .iter()
+        .map(|raw| serde_json::from_str(raw))
+        .collect::<Result<Vec<Volume>, _>>()?;
+    volumes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(volumes)
+}
+
+/// Adds `volume`, replacing any volume with the same id.
+pub async fn save_volume(
+    con: &mut RedisConnection,
+    name: &str,
+    volume: &Volume,
+) -> Result<(), RegistryError> {
+    con.hset::<_, _, _, ()>(
+        volumes_key(name),
+        volume.id.to_string(),
+        serde_json::to_string(volume)?,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Removes one volume; returns whether it was attached.
+pub async fn remove_volume(
+    con: &mut RedisConnection,
+    name: &str,
+    id: Uuid,
+) -> Result<bool, RegistryError> {
+    let removed: u32 = con.hdel(volumes_key(name), id.to_string()).await?;
+    Ok(removed > 0)
+}
+
+pub async fn get_display_config(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Option<DisplayConfig>, RegistryError> {
+    let raw: Option<String> = con.get(display_key(name)).await?;
+    match raw {
+        Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_display_config(
+    con: &mut RedisConnection,
+    name: &str,
+    config: &DisplayConfig,
+) -> Result<(), RegistryError> {
+    con.set::<_, _, ()>(display_key(name), serde_json::to_string(config)?)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_audio_config(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Option<AudioConfig>, RegistryError> {
+    let raw: Option<String> = con.get(audio_key(name)).await?;
+    match raw {
+        Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_audio_config(
+    con: &mut RedisConnection,
+    name: &str,
+    config: &AudioConfig,
+) -> Result<(), RegistryError> {
+    con.set::<_, _, ()>(audio_key(name), serde_json::to_string(config)?)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_certs(
+    con: &mut RedisConnection,
+    name: &str,
+) -> Result<Option<CertBundle>, RegistryError> {
+    let raw: Option<String> = con.get(certs_key(name)).await?;
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+    let mut bundle: CertBundle = serde_json::from_str(&raw)?;
+    bundle.key_pem = con.decode_record(bundle.key_pem, &certs_key(name))?;
+    Ok(Some(bundle))
+}
+
+/// Replaces the VM's certificate bundle, encrypting the private key.
+pub async fn save_certs(
+    con: &mut RedisConnection,
+    name: &str,
+    bundle: &CertBundle,
+) -> Result<(), RegistryError> {
+    let stored = CertBundle {
+        key_pem: con.encode_record(bundle.key_pem.clone(), &certs_key(name))?,
+        ..bundle.clone()
+    };
+    con.set::<_, _, ()>(certs_key(name), serde_json::to_string(&stored)?)
+        .await?;
+    Ok(())
+}
+
+/// Certificate bundles of all VMs that have one, keyed by VM name.
+pub async fn list_certs(
+    con: &mut RedisConnection,
+) -> Result<Vec<(String, CertBundle)>, RegistryError> {
+    let mut all = Vec::new();
+    for name in scan_names(con, CERTS_KEY_PREFIX).await? {
+        if let Some(bundle) = get_certs(con, &name).await? {
+            all.push((name, bundle));
+        }
+    }
+    Ok(all)
+}
+
+/// Audio configurations of all VMs that have one, keyed by VM name.
+pub async fn list_audio_configs(
+    con: &mut RedisConnection,
+) -> Result<Vec<(String, AudioConfig)>, RegistryError> {
+    let mut all = Vec::new();
+    for name in scan_names(con, AUDIO_KEY_PREFIX).await? {
+        if let Some(config) = get_audio_config(con, &name).await? {
+            all.push((name, config));
+        }
+    }
+    Ok(all)
+}
+
+/// Every indexed capability with its providers, both sorted.
+pub async fn capability_matrix(
+    con: &mut RedisConnection,
+) -> Result<BTreeMap<String, Vec<String>>, RegistryError> {
+    let mut matrix = BTreeMap::new();
+    for capability in scan_names(con, CAPABILITY_KEY_PREFIX).await? {
+        let mut providers: Vec<String> = con.smembers(capability_key(&capability)).await?;
+        if providers.is_empty() {
+            continue;
+        }
+        providers.sort();
+        matrix.insert(capability, providers);
+    }
+    Ok(matrix)
+}
+
+/// Every capability a VM has provided, including those indexed before
+/// `KNOWN_CAPABILITIES_KEY` was kept, sorted.
+pub async fn known_capabilities(con: &mut RedisConnection) -> Result<Vec<String>, RegistryError> {
+    let mut known: Vec<String> = con.smembers(KNOWN_CAPABILITIES_KEY).await?;
+    known.extend(scan_names(con, CAPABILITY_KEY_PREFIX).await?);
+    known.sort();
+    known.dedup();
+    Ok(known)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{redis_state, redis_state_with, sample_vm, test_settings};
+
+    #[tokio::test]
+    async fn test_hostname_claims() {
+        let Some(ctx) = redis_state().await else {
+            return;
+        };
+        let mut con = ctx.state.connection().await.unwrap();
+        let holder = claim_hostname(&mut con, "Shared", "host-a").await.unwrap();
+        assert_eq!(holder, "host-a");
+        let holder = claim_hostname(&mut con, "shared", "host-b").await.unwrap();
+        assert_eq!(holder, "host-a");
+
+        // Neither releasing nor deleting another VM that names the
+        // hostname drops the holder's claim.
+        release_hostname(&mut con, "shared", "host-b")
+            .await
+            .unwrap();
+        let mut other = sample_vm("host-b");
+        other.hostname = Some("shared".to_string());
+        delete_vm(&mut con, &other).await.unwrap();
+        let holder = hostname_holder(&mut con, "shared").await.unwrap();
+        assert_eq!(holder.as_deref(), Some("host-a"));
+
+        release_hostname(&mut con, "SHARED", "host-a")
+            .await
+            .unwrap();
+        assert_eq!(hostname_holder(&mut con, "shared").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_records() {
+        let dir = std::env::temp_dir().join(format!("ghaf-registry-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("record.key");
+        std::fs::write(&key_file, [42u8; 32]).unwrap();
+        let settings = crate::settings::Settings {
+            encryption_key_file: Some(key_file),
+            ..test_settings()
+        };
+        let Some(ctx) = redis_state_with(settings).await else {
+            return;
+        };
+        let mut con = ctx.state.connection().await.unwrap();
+        let mut vm = sample_vm("vault-vm");
+        vm.xdg_run = Some("/run/user/1000".to_string());
+        vm.mime_types = vec!["application/pdf".to_string()];
+        save_vm(&mut con, &mut vm, None).await.unwrap();
+
+        let raw: String = redis::cmd("GET")
+            .arg(vm_key("vault-vm"))
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!(crate::crypto::is_encrypted(&raw));
+        assert!(!raw.contains("vault-vm") && !raw.contains("/run/user"));
+
+        let stored = require_vm(&mut con, "vault-vm").await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&stored).unwrap(),
+            serde_json::to_value(&vm).unwrap()
+        );
+        assert_eq!(list_vms(&mut con).await.unwrap().len(), 1);
+        // Index keys stay readable.
+        let handlers: Vec<String> = con.smembers(mime_key("application/pdf")).await.unwrap();
+        assert_eq!(handlers, vec!["vault-vm"]);
+
+        let mut patched = vm.clone();
+        patched.xdg_run = Some("/run/user/2000".to_string());
+        crate::audit::record_audit_event(&mut con, "vault-vm", None, &vm, &patched)
+            .await
+            .unwrap();
+        let raw: Vec<String> = con.zrange(audit_key("vault-vm"), 0, -1).await.unwrap();
+        assert_eq!(raw.len(), 1);
+        assert!(crate::crypto::is_encrypted(&raw[0]));
+        assert!(!raw[0].contains("/run/user"));
+        let entries = audit_entries(&mut con, "vault-vm").await.unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&entries[0]).unwrap();
+        assert_eq!(entry["changed"]["xdg_run"]["new"], "/run/user/2000");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_record_is_migrated() {
+        let Some(ctx) = crate::test_util::redis_state().await else {
+            return;
+        };
+        let mut con = ctx.state.connection().await.unwrap();
+        let legacy = serde_json::json!({
+            "name": "legacy-vm",
+            "vm_type": { "system_app": "App", "run_type": "OneShot" },
+            "addresses": { "ip": "10.0.0.5", "vsock": "5:1234" },
+            "xdg_run": null,
+            "mime_type": "application/pdf",
+        });
+        con.set::<_, _, ()>(vm_key("legacy-vm"), legacy.to_string())
+            .await
+            .unwrap();
+
+        let vm = require_vm(&mut con, "legacy-vm").await.unwrap();
+        assert_eq!(vm.schema_version, migration::SCHEMA_VERSION);
+        assert_eq!(vm.mime_types, vec!["application/pdf"]);
+        assert_eq!(vm.namespace, "default");
+
+        let raw: String = con.get(vm_key("legacy-vm")).await.unwrap();
+        let stored: serde_json::Value = serde_json::from_str(&raw).unwrap();
+        assert_eq!(stored["schema_version"], migration::SCHEMA_VERSION);
+        assert!(stored.get("mime_type").is_none());
+    }
+}
@@ -0,0 +1,238 @@
+//! Helpers shared by tests that talk to a real Redis server, and the test
+//! client that sends requests to the API under either web framework.
+//!
+//! Tests run against `GHAF_TEST_REDIS_URL` (default `redis://127.0.0.1:6379/`)
+//! and flush that database first, so they are serialised through a lock.
+//! When no server is reachable the helpers return `None` and the test is
+//! skipped.
+
+use std::future::Future;
+
+use hyper::body::Bytes;
+use hyper::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::http::{HeaderMap, Method, Request, Response};
+use serde::Serialize;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::migration;
+use crate::models::{Addresses, RunType, SystemAppType, VMType, DEFAULT_NAMESPACE, VM};
+use crate::settings::Settings;
+use crate::state::AppState;
+
+static REDIS_LOCK: Mutex<()> = Mutex::const_new(());
+
+pub struct TestContext {
+    pub state: AppState,
+    _guard: MutexGuard<'static, ()>,
+}
+
+pub fn test_settings() -> Settings {
+    Settings {
+        redis_url: std::env::var("GHAF_TEST_REDIS_URL")
+            .unwrap_or_else(|_| "redis://127.0.0.1:6379/".to_string()),
+        ..Settings::default()
+    }
+}
+
+pub async fn redis_state() -> Option<TestContext> {
+    redis_state_with(test_settings()).await
+}
+
+pub async fn redis_state_with(settings: Settings) -> Option<TestContext> {
+    let guard = REDIS_LOCK.lock().await;
+    let state = AppState::new(settings).ok()?;
+    let mut con = match state.connection().await {
+        Ok(con) => con,
+        Err(e) => {
+            eprintln!("skipping: Redis unavailable ({})", e);
+            return None;
+        }
+    };
+    redis::cmd("FLUSHDB")
+        .query_async::<_, ()>(&mut con)
+        .await
+        .ok()?;
+    Some(TestContext {
+        state,
+        _guard: guard,
+    })
+}
+
+pub fn sample_vm(name: &str) -> VM {
+    VM {
+        schema_version: migration::SCHEMA_VERSION,
+        name: name.to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        vm_type: VMType {
+            system_app: SystemAppType::System,
+            run_type: RunType::LongRun,
+        },
+        addresses: Addresses {
+            ip: "127.0.0.1".to_string(),
+            vsock: "vsock_value".to_string(),
+            dns_name: None,
+            resolved_ips: Vec::new(),
+        },
+        xdg_run: None,
+        description: None,
+        mime_types: Vec::new(),
+        status: Default::default(),
+        updated_at: None,
+        last_heartbeat_at: None,
+        lease_token: None,
+        dependencies: Vec::new(),
+        priority: 0,
+        priority_class: Default::default(),
+        capabilities: Vec::new(),
+        tags: Default::default(),
+        labels: Default::default(),
+        firewall_rules: Vec::new(),
+        ntp_servers: Vec::new(),
+        dns_resolvers: Vec::new(),
+        dns_search_domains: Vec::new(),
+        vcpu_count: 0,
+        memory_limit_mb: 0,
+        is_template: false,
+        template_name: None,
+        sealed: false,
+        owner: None,
+        image: None,
+        contact: None,
+        hostname: None,
+    }
+}
+
+/// The routes under test, as `api::routes` builds them for the web
+/// framework compiled in. Replies do not borrow the routes, so a test can
+/// spawn a request and carry on with others.
+pub trait TestClient {
+    fn reply(
+        &self,
+        request: Request<Bytes>,
+    ) -> impl Future<Output = Response<Bytes>> + Send + use<Self>;
+}
+
+#[cfg(feature = "warp")]
+impl<F> TestClient for F
+where
+    F: warp::Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    fn reply(
+        &self,
+        request: Request<Bytes>,
+    ) -> impl Future<Output = Response<Bytes>> + Send + use<F> {
+        let (parts, body) = request.into_parts();
+        let mut builder = warp::test::request()
+            .method(parts.method.as_str())
+            .path(&parts.uri.to_string())
+            .body(body);
+        for (name, value) in &parts.headers {
+            builder = builder.header(name, value);
+        }
+        let filter = self.clone();
+        async move { builder.reply(&filter).await }
+    }
+}
+
+#[cfg(feature = "axum")]
+impl TestClient for axum::Router {
+    fn reply(
+        &self,
+        request: Request<Bytes>,
+    ) -> impl Future<Output = Response<Bytes>> + Send + use<> {
+        use hyper::service::Service;
+
+        let response = self.clone().call(request.map(hyper::Body::from));
+        async move {
+            let response = match response.await {
+                Ok(response) => response,
+                Err(infallible) => match infallible {},
+            };
+            let (parts, body) = response.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            Response::from_parts(parts, body)
+        }
+    }
+}
+
+/// A request to send to a `TestClient`, built like warp's test requests.
+pub struct TestRequest {
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+pub fn request() -> TestRequest {
+    TestRequest {
+        method: Method::GET,
+        path: "/".to_string(),
+        headers: HeaderMap::new(),
+        body: Bytes::new(),
+    }
+}
+
+impl TestRequest {
+    pub fn method(mut self, method: &str) -> Self {
+        self.method = method.parse().unwrap();
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: std::fmt::Debug,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: std::fmt::Debug,
+    {
+        self.headers.append(
+            HeaderName::try_from(name).unwrap(),
+            HeaderValue::try_from(value).unwrap(),
+        );
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends `value` as a JSON body.
+    pub fn json(self, value: &impl Serialize) -> Self {
+        self.header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(value).unwrap())
+    }
+
+    pub fn reply<T: TestClient>(
+        self,
+        api: &T,
+    ) -> impl Future<Output = Response<Bytes>> + Send + use<T> {
+        let mut request = Request::builder()
+            .method(self.method)
+            .uri(self.path)
+            .body(self.body)
+            .unwrap();
+        *request.headers_mut() = self.headers;
+        api.reply(request)
+    }
+}
+
+/// Registers `vm` through `POST /register`.
+pub async fn register(api: &impl TestClient, vm: &VM) -> Response<Bytes> {
+    request()
+        .method("POST")
+        .path("/register")
+        .json(vm)
+        .reply(api)
+        .await
+}
+
+pub fn json_body(response: &Response<Bytes>) -> serde_json::Value {
+    serde_json::from_slice(response.body()).unwrap()
+}
@@ -0,0 +1,251 @@
+//! Ordering of VMs by their declared dependencies.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::models::VM;
+
+/// A dependency cycle, listed in dependency order with the first VM
+/// repeated at the end, e.g. `["a", "b", "a"]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleError {
+    pub cycle: Vec<String>,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dependency cycle: {}", self.cycle.join(" -> "))
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+/// Sorts VMs into start-up batches with Kahn's algorithm. Every VM in a batch
+/// depends only on VMs in earlier batches, so a batch can be started in
+/// parallel. Within a batch VMs are ordered by descending `priority`, then by
+/// name. Dependencies on VMs that are not in `vms` are ignored.
+pub fn topological_sort(vms: &[VM]) -> Result<Vec<Vec<String>>, CycleError> {
+    let by_name: HashMap<&str, &VM> = vms.iter().map(|vm| (vm.name.as_str(), vm)).collect();
+    let mut pending: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for vm in vms {
+        let deps: HashSet<&str> = vm
+            .dependencies
+            .iter()
+            .map(String::as_str)
+            .filter(|dep| by_name.contains_key(dep))
+            .collect();
+        for dep in &deps {
+            dependents.entry(dep).or_default().push(&vm.name);
+        }
+        pending.insert(&vm.name, deps.len());
+    }
+
+    let mut batches = Vec::new();
+    let mut ready: Vec<&str> = pending
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(name, _)| *name)
+        .collect();
+    while !ready.is_empty() {
+        for name in &ready {
+            pending.remove(name);
+        }
+        let mut next = Vec::new();
+        for name in &ready {
+            for dependent in dependents.get(name).into_iter().flatten() {
+                let count = pending.get_mut(dependent).expect("dependent is pending");
+                *count -= 1;
+                if *count == 0 {
+                    next.push(*dependent);
+                }
+            }
+        }
+        ready.sort_by(|a, b| {
+            by_name[b]
+                .priority
+                .cmp(&by_name[a].priority)
+                .then_with(|| a.cmp(b))
+        });
+        batches.push(ready.iter().map(|name| name.to_string()).collect());
+        ready = next;
+    }
+
+    if pending.is_empty() {
+        Ok(batches)
+    } else {
+        Err(find_cycle(&by_name, &pending))
+    }
+}
+
+/// A VM's place in the dependency graph.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct DependencyDepth {
+    pub name: String,
+    /// 0 without dependencies, else one more than the deepest dependency.
+    pub depth: usize,
+    /// Whether at least `critical_dependents` VMs depend on this one.
+    pub is_critical: bool,
+    /// VMs that depend on this one, directly or through other VMs.
+    pub dependents_count: usize,
+}
+
+/// The depth of every VM in the dependency graph, sorted by depth, then by
+/// name. The depth of a VM is the index of its `topological_sort` batch.
+pub fn dependency_depths(
+    vms: &[VM],
+    critical_dependents: usize,
+) -> Result<Vec<DependencyDepth>, CycleError> {
+    let batches = topological_sort(vms)?;
+    let known: HashSet<&str> = vms.iter().map(|vm| vm.name.as_str()).collect();
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for vm in vms {
+        for dep in &vm.dependencies {
+            if known.contains(dep.as_str()) {
+                dependents.entry(dep).or_default().push(&vm.name);
+            }
+        }
+    }
+    let mut depths = Vec::new();
+    for (depth, batch) in batches.into_iter().enumerate() {
+        let mut batch_depths: Vec<DependencyDepth> = batch
+            .into_iter()
+            .map(|name| {
+                let dependents_count = count_dependents(&dependents, &name);
+                DependencyDepth {
+                    name,
+                    depth,
+                    is_critical: dependents_count >= critical_dependents,
+                    dependents_count,
+                }
+            })
+            .collect();
+        batch_depths.sort_by(|a, b| a.name.cmp(&b.name));
+        depths.extend(batch_depths);
+    }
+    Ok(depths)
+}
+
+/// The VMs reachable from `name` over `dependents` edges.
+fn count_dependents(dependents: &HashMap<&str, Vec<&str>>, name: &str) -> usize {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut stack: Vec<&str> = dependents.get(name).cloned().unwrap_or_default();
+    while let Some(dependent) = stack.pop() {
+        if seen.insert(dependent) {
+            stack.extend(dependents.get(dependent).into_iter().flatten());
+        }
+    }
+    seen.len()
+}
+
+/// Walks dependency edges among the VMs Kahn's algorithm could not place
+/// until one repeats. Every such VM has a remaining dependency, so the walk
+/// always ends in a cycle.
+fn find_cycle(by_name: &HashMap<&str, &VM>, remaining: &BTreeMap<&str, usize>) -> CycleError {
+    let mut path: Vec<&str> = Vec::new();
+    let mut current = *remaining.keys().next().expect("cycle has members");
+    loop {
+        if let Some(start) = path.iter().position(|name| *name == current) {
+            let mut cycle: Vec<String> =
+                path[start..].iter().map(|name| name.to_string()).collect();
+            cycle.push(current.to_string());
+            return CycleError { cycle };
+        }
+        path.push(current);
+        current = by_name[current]
+            .dependencies
+            .iter()
+            .map(String::as_str)
+            .find(|dep| remaining.contains_key(dep))
+            .expect("unplaced VM has an unplaced dependency");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample_vm;
+
+    fn vm(name: &str, deps: &[&str], priority: i32) -> VM {
+        let mut vm = sample_vm(name);
+        vm.dependencies = deps.iter().map(|dep| dep.to_string()).collect();
+        vm.priority = priority;
+        vm
+    }
+
+    #[test]
+    fn test_batches_follow_dependencies() {
+        let vms = vec![
+            vm("gui-vm", &["net-vm", "audio-vm"], 0),
+            vm("net-vm", &[], 0),
+            vm("audio-vm", &[], 5),
+            vm("browser-vm", &["gui-vm"], 0),
+            vm("chat-vm", &["gui-vm"], 1),
+        ];
+        let order = topological_sort(&vms).unwrap();
+        assert_eq!(
+            order,
+            vec![
+                vec!["audio-vm".to_string(), "net-vm".to_string()],
+                vec!["gui-vm".to_string()],
+                vec!["chat-vm".to_string(), "browser-vm".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dependency_depths() {
+        let vms = vec![
+            vm("gui-vm", &["net-vm", "audio-vm"], 0),
+            vm("net-vm", &[], 0),
+            vm("audio-vm", &["net-vm"], 0),
+            vm("browser-vm", &["gui-vm", "net-vm"], 0),
+            vm("chat-vm", &["gui-vm"], 0),
+            vm("admin-vm", &[], 0),
+        ];
+        let depths = dependency_depths(&vms, 3).unwrap();
+        let summary: Vec<(&str, usize, bool, usize)> = depths
+            .iter()
+            .map(|d| (d.name.as_str(), d.depth, d.is_critical, d.dependents_count))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("admin-vm", 0, false, 0),
+                ("net-vm", 0, true, 4),
+                ("audio-vm", 1, true, 3),
+                ("gui-vm", 2, false, 2),
+                ("browser-vm", 3, false, 0),
+                ("chat-vm", 3, false, 0),
+            ]
+        );
+        assert!(dependency_depths(&[vm("a", &["a"], 0)], 3).is_err());
+    }
+
+    #[test]
+    fn test_unknown_dependencies_are_ignored() {
+        let vms = vec![vm("a", &["not-registered"], 0)];
+        assert_eq!(topological_sort(&vms).unwrap(), vec![vec!["a".to_string()]]);
+    }
+
+    #[test]
+    fn test_cycle_is_reported() {
+        let vms = vec![
+            vm("root", &[], 0),
+            vm("a", &["root", "b"], 0),
+            vm("b", &["c"], 0),
+            vm("c", &["a"], 0),
+        ];
+        let err = topological_sort(&vms).unwrap_err();
+        assert_eq!(err.cycle, vec!["a", "b", "c", "a"]);
+        assert_eq!(err.to_string(), "dependency cycle: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn test_self_dependency_is_a_cycle() {
+        let err = topological_sort(&[vm("a", &["a"], 0)]).unwrap_err();
+        assert_eq!(err.cycle, vec!["a", "a"]);
+    }
+}
@@ -0,0 +1,704 @@
+//! Checks applied to VM definitions before they are written to Redis.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+
+use ipnetwork::IpNetwork;
+use regex::Regex;
+
+use crate::error::RegistryError;
+use crate::models::{
+    AudioConfig, CertBundle, Contact, DisplayConfig, FirewallRule, PortMapping, SystemAppType,
+    VmImage, Volume, VM,
+};
+
+static CUSTOM_TYPE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z][a-zA-Z0-9_-]{0,31}$").unwrap());
+
+/// No `/` or `:`, so names fit in URL paths and Redis keys unescaped.
+static NAME_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9._-]{0,63}$").unwrap());
+
+/// DNS-label style, so namespaces can appear in URL paths unescaped.
+static NAMESPACE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?$").unwrap());
+
+static CAPABILITY_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-z][a-z0-9-]{0,63}$").unwrap());
+
+static TAG_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-z0-9-]{1,32}$").unwrap());
+
+/// No `:`, which separates key and value in `ghaf:label-index:{key}:{value}`.
+static LABEL_KEY_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-z0-9][a-z0-9._/-]{0,62}$").unwrap());
+
+static LABEL_VALUE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9._-]{1,63}$").unwrap());
+
+/// Host names only; IP addresses are accepted separately.
+static NTP_SERVER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9.-]{1,253}$").unwrap());
+
+/// Dot-separated DNS labels of at most 63 characters, optionally ending in
+/// a dot; the overall length is checked separately.
+static DOMAIN_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^([a-zA-Z0-9]([a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?\.)*[a-zA-Z0-9]([a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?\.?$")
+        .unwrap()
+});
+
+/// Nix store hashes are 32 characters of Nix's base32 alphabet, which
+/// leaves out `e`, `o`, `t` and `u`.
+static NIX_HASH_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[0-9a-df-np-sv-z]{32}$").unwrap());
+
+/// Deliberately loose: one `@` with something on both sides and a dot in
+/// the domain.
+static EMAIL_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap());
+
+/// `type/subtype` built from RFC 6838 restricted-name characters.
+static MIME_TYPE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9!#$&^_.+-]{0,126}/[a-zA-Z0-9][a-zA-Z0-9!#$&^_.+-]{0,126}$")
+        .unwrap()
+});
+
+pub fn validate_vm(vm: &VM) -> Result<(), RegistryError> {
+    match lint_vm(vm).into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Every problem `validate_vm` would reject `vm` for, in the order it checks
+/// for them.
+pub fn lint_vm(vm: &VM) -> Vec<RegistryError> {
+    [
+        validate_name(&vm.name),
+        validate_namespace(&vm.namespace),
+        vm.xdg_run.as_deref().map_or(Ok(()), validate_xdg_path),
+        vm.description
+            .as_deref()
+            .map_or(Ok(()), validate_description),
+        validate_system_app_type(&vm.vm_type.system_app),
+        validate_mime_types(&vm.mime_types),
+        validate_capabilities(&vm.capabilities),
+        validate_tags(&vm.tags),
+        validate_labels(&vm.labels),
+        validate_firewall_rules(&vm.firewall_rules),
+        validate_ntp_servers(&vm.ntp_servers),
+        validate_dns_resolvers(&vm.dns_resolvers),
+        validate_search_domains(&vm.dns_search_domains),
+        vm.image.as_ref().map_or(Ok(()), validate_image),
+        vm.contact.as_ref().map_or(Ok(()), validate_contact),
+        vm.hostname.as_deref().map_or(Ok(()), validate_hostname),
+        validate_dependencies(vm),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect()
+}
+
+pub fn validate_mime_types(mime_types: &[String]) -> Result<(), RegistryError> {
+    match mime_types
+        .iter()
+        .find(|mime_type| !MIME_TYPE_RE.is_match(mime_type))
+    {
+        Some(invalid) => Err(RegistryError::Validation(format!(
+            "invalid MIME type '{}'",
+            invalid
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn validate_capabilities(capabilities: &[String]) -> Result<(), RegistryError> {
+    match capabilities
+        .iter()
+        .find(|capability| !CAPABILITY_RE.is_match(capability))
+    {
+        Some(invalid) => Err(RegistryError::Validation(format!(
+            "invalid capability '{}'",
+            invalid
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn validate_tags<'a>(tags: impl IntoIterator<Item = &'a String>) -> Result<(), RegistryError> {
+    match tags.into_iter().find(|tag| !TAG_RE.is_match(tag)) {
+        Some(invalid) => Err(RegistryError::Validation(format!(
+            "invalid tag '{}'",
+            invalid
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), RegistryError> {
+    for (key, value) in labels {
+        if !LABEL_KEY_RE.is_match(key) {
+            return Err(RegistryError::Validation(format!(
+                "invalid label key '{}'",
+                key
+            )));
+        }
+        if !LABEL_VALUE_RE.is_match(value) {
+            return Err(RegistryError::Validation(format!(
+                "invalid value '{}' for label '{}'",
+                value, key
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_firewall_rules(rules: &[FirewallRule]) -> Result<(), RegistryError> {
+    for rule in rules {
+        let (start, end) = rule.port_range;
+        if start > end {
+            return Err(RegistryError::Validation(format!(
+                "invalid port range {}-{}",
+                start, end
+            )));
+        }
+        if let Some(cidr) = &rule.src_cidr {
+            cidr.parse::<IpNetwork>()
+                .map_err(|_| RegistryError::Validation(format!("invalid CIDR '{}'", cidr)))?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_ntp_servers(servers: &[String]) -> Result<(), RegistryError> {
+    match servers
+        .iter()
+        .find(|server| server.parse::<IpAddr>().is_err() && !NTP_SERVER_RE.is_match(server))
+    {
+        Some(invalid) => Err(RegistryError::Validation(format!(
+            "invalid NTP server '{}'",
+            invalid
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn validate_dns_resolvers(resolvers: &[String]) -> Result<(), RegistryError> {
+    match resolvers
+        .iter()
+        .find(|resolver| resolver.parse::<IpAddr>().is_err())
+    {
+        Some(invalid) => Err(RegistryError::Validation(format!(
+            "invalid DNS resolver '{}', expected an IP address",
+            invalid
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn validate_search_domains(domains: &[String]) -> Result<(), RegistryError> {
+    match domains
+        .iter()
+        .find(|domain| domain.len() > 253 || !DOMAIN_RE.is_match(domain))
+    {
+        Some(invalid) => Err(RegistryError::Validation(format!(
+            "invalid DNS search domain '{}'",
+            invalid
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn validate_image_id(id: &str) -> Result<(), RegistryError> {
+    if NIX_HASH_RE.is_match(id) {
+        Ok(())
+    } else {
+        Err(RegistryError::Validation(format!(
+            "invalid image id '{}', expected a Nix store hash",
+            id
+        )))
+    }
+}
+
+fn validate_image(image: &VmImage) -> Result<(), RegistryError> {
+    validate_image_id(&image.id)?;
+    let prefix = format!("/nix/store/{}-", image.id);
+    if !image
+        .store_path
+        .strip_prefix(&prefix)
+        .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+    {
+        return Err(RegistryError::Validation(format!(
+            "store path '{}' does not belong to image '{}'",
+            image.store_path, image.id
+        )));
+    }
+    Ok(())
+}
+
+fn validate_contact(contact: &Contact) -> Result<(), RegistryError> {
+    if contact.name.trim().is_empty() {
+        return Err(RegistryError::Validation(
+            "contact name must not be empty".to_string(),
+        ));
+    }
+    if !EMAIL_RE.is_match(&contact.email) {
+        return Err(RegistryError::Validation(format!(
+            "invalid contact email '{}'",
+            contact.email
+        )));
+    }
+    Ok(())
+}
+
+/// RFC 1123 host names are DNS names without the trailing dot.
+fn validate_hostname(hostname: &str) -> Result<(), RegistryError> {
+    if hostname.len() > 253 || hostname.ends_with('.') || !DOMAIN_RE.is_match(hostname) {
+        return Err(RegistryError::Validation(format!(
+            "invalid hostname '{}'",
+            hostname
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `vm` lists itself among its dependencies, which would leave it
+/// waiting on itself forever.
+fn has_self_dependency(vm: &VM) -> bool {
+    vm.dependencies.contains(&vm.name)
+}
+
+fn validate_dependencies(vm: &VM) -> Result<(), RegistryError> {
+    if has_self_dependency(vm) {
+        return Err(RegistryError::Validation(
+            "VM cannot declare itself as a dependency".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_port_mappings(ports: &[PortMapping]) -> Result<(), RegistryError> {
+    for (i, port) in ports.iter().enumerate() {
+        if port.host_port == 0 || port.vm_port == 0 {
+            return Err(RegistryError::Validation(
+                "port 0 cannot be mapped".to_string(),
+            ));
+        }
+        if ports[..i]
+            .iter()
+            .any(|other| other.host_port == port.host_port)
+        {
+            return Err(RegistryError::Validation(format!(
+                "host port {} is mapped twice",
+                port.host_port
+            )));
+        }
+    }
+    Ok(())
+}
+
+const SAMPLE_RATES: &[u32] = &[8000, 16000, 44100, 48000, 96000];
+
+pub fn validate_audio_config(config: &AudioConfig) -> Result<(), RegistryError> {
+    for device in config.sinks.iter().chain(&config.sources) {
+        if device.name.is_empty() {
+            return Err(RegistryError::Validation(
+                "audio device name must not be empty".to_string(),
+            ));
+        }
+        if !SAMPLE_RATES.contains(&device.sample_rate) {
+            return Err(RegistryError::Validation(format!(
+                "unsupported sample rate {} for audio device '{}'",
+                device.sample_rate, device.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_display_config(config: &DisplayConfig) -> Result<(), RegistryError> {
+    if config.gpu_passthrough && config.gpu_device_id.is_none() {
+        return Err(RegistryError::Validation(
+            "gpu_device_id is required with gpu_passthrough".to_string(),
+        ));
+    }
+    if let Some((width, height)) = config.resolution {
+        if width == 0 || height == 0 {
+            return Err(RegistryError::Validation(format!(
+                "invalid resolution {}x{}",
+                width, height
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `pem` holds a PEM block whose label ends with `label`, e.g.
+/// `PRIVATE KEY` also matches `EC PRIVATE KEY`.
+fn is_pem(pem: &str, label: &str) -> bool {
+    pem.lines().any(|line| {
+        line.trim()
+            .strip_prefix("-----BEGIN ")
+            .and_then(|rest| rest.strip_suffix("-----"))
+            .is_some_and(|found| found.ends_with(label))
+    })
+}
+
+/// Checks the PEM framing only; the registry does not parse certificates.
+pub fn validate_cert_bundle(bundle: &CertBundle) -> Result<(), RegistryError> {
+    if !is_pem(&bundle.cert_pem, "CERTIFICATE") {
+        return Err(RegistryError::Validation(
+            "cert_pem is not a PEM certificate".to_string(),
+        ));
+    }
+    if !is_pem(&bundle.key_pem, "PRIVATE KEY") {
+        return Err(RegistryError::Validation(
+            "key_pem is not a PEM private key".to_string(),
+        ));
+    }
+    if let Some(ca_pem) = &bundle.ca_pem {
+        if !is_pem(ca_pem, "CERTIFICATE") {
+            return Err(RegistryError::Validation(
+                "ca_pem is not a PEM certificate".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn has_parent_component(path: &str) -> bool {
+    path.split('/').any(|component| component == "..")
+}
+
+/// `xdg_run` must be an absolute path under `/run/` without `..`
+/// components, so it cannot point anywhere else on the host.
+fn validate_xdg_path(path: &str) -> Result<(), RegistryError> {
+    if path.starts_with("/run/") && !has_parent_component(path) {
+        Ok(())
+    } else {
+        Err(RegistryError::Validation(format!(
+            "invalid xdg_run path '{}'",
+            path
+        )))
+    }
+}
+
+pub fn validate_volume(volume: &Volume) -> Result<(), RegistryError> {
+    if !volume.path.starts_with('/') || has_parent_component(&volume.path) {
+        return Err(RegistryError::Validation(format!(
+            "invalid volume path '{}'",
+            volume.path
+        )));
+    }
+    if volume.size_gb == 0 {
+        return Err(RegistryError::Validation(
+            "volume size must be at least 1 GB".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+const MAX_DESCRIPTION_CHARS: usize = 1024;
+
+fn validate_description(description: &str) -> Result<(), RegistryError> {
+    if description.chars().count() > MAX_DESCRIPTION_CHARS {
+        return Err(RegistryError::Validation(format!(
+            "description is longer than {} characters",
+            MAX_DESCRIPTION_CHARS
+        )));
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<(), RegistryError> {
+    if NAME_RE.is_match(name) {
+        Ok(())
+    } else {
+        Err(RegistryError::Validation(format!(
+            "invalid VM name '{}'",
+            name
+        )))
+    }
+}
+
+fn validate_namespace(namespace: &str) -> Result<(), RegistryError> {
+    if NAMESPACE_RE.is_match(namespace) {
+        Ok(())
+    } else {
+        Err(RegistryError::Validation(format!(
+            "invalid namespace '{}'",
+            namespace
+        )))
+    }
+}
+
+fn validate_system_app_type(system_app: &SystemAppType) -> Result<(), RegistryError> {
+    match system_app {
+        SystemAppType::Custom(name) if !CUSTOM_TYPE_RE.is_match(name) => Err(
+            RegistryError::Validation(format!("invalid custom VM type '{}'", name)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Direction, DisplayServer, Protocol};
+    use crate::test_util::sample_vm;
+
+    #[test]
+    fn test_custom_type_names() {
+        for valid in ["Driver", "Service", "sandbox_v2", "a", "Net-Helper"] {
+            let system_app = SystemAppType::Custom(valid.to_string());
+            assert!(validate_system_app_type(&system_app).is_ok(), "{}", valid);
+        }
+        let too_long = format!("A{}", "b".repeat(32));
+        for invalid in [
+            "",
+            "1driver",
+            "-x",
+            "has space",
+            "dot.ted",
+            too_long.as_str(),
+        ] {
+            let system_app = SystemAppType::Custom(invalid.to_string());
+            assert!(
+                validate_system_app_type(&system_app).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_firewall_rules() {
+        let rule = |port_range, src_cidr: Option<&str>| FirewallRule {
+            direction: Direction::Ingress,
+            protocol: Protocol::Tcp,
+            port_range,
+            src_cidr: src_cidr.map(String::from),
+        };
+        assert!(validate_firewall_rules(&[
+            rule((22, 22), None),
+            rule((8000, 8080), Some("192.168.100.0/24")),
+            rule((53, 53), Some("fd00::/64")),
+        ])
+        .is_ok());
+        assert!(validate_firewall_rules(&[rule((443, 80), None)]).is_err());
+        assert!(validate_firewall_rules(&[rule((80, 80), Some("192.168.100.0/33"))]).is_err());
+        assert!(validate_firewall_rules(&[rule((80, 80), Some("lan"))]).is_err());
+    }
+
+    #[test]
+    fn test_display_config() {
+        let mut config = DisplayConfig {
+            display_server: DisplayServer::Headless,
+            resolution: None,
+            gpu_passthrough: false,
+            gpu_device_id: None,
+        };
+        assert!(validate_display_config(&config).is_ok());
+        config.resolution = Some((0, 1080));
+        assert!(validate_display_config(&config).is_err());
+        config.resolution = Some((1920, 1080));
+        config.gpu_passthrough = true;
+        assert!(validate_display_config(&config).is_err());
+        config.gpu_device_id = Some("0000:00:02.0".to_string());
+        assert!(validate_display_config(&config).is_ok());
+    }
+
+    #[test]
+    fn test_names() {
+        for valid in ["foo", "browser-vm", "run_test_vm", "vm.2"] {
+            assert!(validate_name(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["", "-vm", "..", "with/slash", "ghaf:vm", "with space"] {
+            assert!(validate_name(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_namespaces() {
+        for valid in ["default", "test-env", "a", "env2"] {
+            assert!(validate_namespace(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["", "Test", "-env", "env-", "with/slash"] {
+            assert!(validate_namespace(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_dns_settings() {
+        let valid = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        assert!(validate_dns_resolvers(&valid(&["192.168.100.1", "fd00::1"])).is_ok());
+        for invalid in ["ns.ghaf.local", "1.1.1", ""] {
+            assert!(
+                validate_dns_resolvers(&valid(&[invalid])).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert!(validate_search_domains(&valid(&["ghaf.local", "lan", "example.com."])).is_ok());
+        let long_label = format!("{}.local", "a".repeat(64));
+        for invalid in [
+            "",
+            "-bad.local",
+            "bad-.local",
+            "a..b",
+            "with space",
+            &long_label,
+        ] {
+            assert!(
+                validate_search_domains(&valid(&[invalid])).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_images() {
+        let image = |id: &str, store_path: &str| VmImage {
+            id: id.to_string(),
+            store_path: store_path.to_string(),
+            version: None,
+        };
+        let id = "0c7xp0ar4b2ny3mwbkrhfpbbb8yqn2s1";
+        let store_path = format!("/nix/store/{}-nixos-system", id);
+        assert!(validate_image(&image(id, &store_path)).is_ok());
+        // Hex hashes containing `e` are not Nix base32.
+        let hex = "0123456789abcdef0123456789abcdef";
+        let hex_path = format!("/nix/store/{}-nixos-system", hex);
+        assert!(validate_image(&image(hex, &hex_path)).is_err());
+        assert!(validate_image(&image("0c7xp0ar", "/nix/store/0c7xp0ar-x")).is_err());
+        for invalid in [
+            format!("/nix/store/{}", id),
+            format!("/nix/store/{}-", id),
+            format!("/nix/store/{}-a/b", id),
+            format!("/tmp/{}-nixos-system", id),
+        ] {
+            assert!(validate_image(&image(id, &invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_contacts() {
+        let contact = |name: &str, email: &str| Contact {
+            name: name.to_string(),
+            email: email.to_string(),
+            slack: None,
+        };
+        assert!(validate_contact(&contact("On-call", "oncall@example.com")).is_ok());
+        assert!(validate_contact(&contact(" ", "oncall@example.com")).is_err());
+        for invalid in [
+            "oncall",
+            "oncall@",
+            "@example.com",
+            "on call@example.com",
+            "a@b@c.d",
+            "oncall@localhost",
+        ] {
+            assert!(
+                validate_contact(&contact("On-call", invalid)).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_hostnames() {
+        for valid in ["browser", "Browser-VM", "browser.ghaf.local", "1vm"] {
+            assert!(validate_hostname(valid).is_ok(), "{}", valid);
+        }
+        let long = "a".repeat(64);
+        for invalid in ["", "-vm", "vm-", "browser.", "browser_vm", "a..b", &long] {
+            assert!(validate_hostname(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_self_dependency() {
+        let mut vm = sample_vm("gui-vm");
+        assert!(!has_self_dependency(&vm));
+        vm.dependencies = vec!["net-vm".to_string()];
+        assert!(!has_self_dependency(&vm));
+        vm.dependencies.push("gui-vm".to_string());
+        assert!(has_self_dependency(&vm));
+    }
+
+    #[test]
+    fn test_labels() {
+        let labels =
+            |key: &str, value: &str| BTreeMap::from([(key.to_string(), value.to_string())]);
+        for (key, value) in [("env", "prod"), ("ghaf.io/tier", "Gold_1"), ("a", "1.2-rc")] {
+            assert!(
+                validate_labels(&labels(key, value)).is_ok(),
+                "{}={}",
+                key,
+                value
+            );
+        }
+        for (key, value) in [
+            ("env:x", "prod"),
+            ("Env", "prod"),
+            ("env", ""),
+            ("env", "a:b"),
+        ] {
+            assert!(
+                validate_labels(&labels(key, value)).is_err(),
+                "{}={}",
+                key,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_xdg_paths() {
+        for valid in ["/run/user/1000", "/run/user/1000/", "/run/ghaf..vm"] {
+            assert!(validate_xdg_path(valid).is_ok(), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "run/user/1000",
+            "user/1000",
+            "/tmp/xdg",
+            "/run",
+            "/run/../etc",
+            "/run/user/../../root",
+            "/run/user/1000/..",
+        ] {
+            assert!(validate_xdg_path(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_volume_paths() {
+        let volume = |path: &str| Volume {
+            id: uuid::Uuid::new_v4(),
+            path: path.to_string(),
+            size_gb: 10,
+            read_only: false,
+            filesystem: None,
+        };
+        for valid in ["/var/lib/vms/data.img", "/dev/vdb", "/srv/..hidden"] {
+            assert!(validate_volume(&volume(valid)).is_ok(), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "data.img",
+            "./data.img",
+            "/var/../etc/shadow",
+            "/srv/..",
+        ] {
+            assert!(validate_volume(&volume(invalid)).is_err(), "{}", invalid);
+        }
+        let empty = Volume {
+            size_gb: 0,
+            ..volume("/var/lib/vms/empty.img")
+        };
+        assert!(validate_volume(&empty).is_err());
+    }
+}